@@ -0,0 +1,303 @@
+//! Core dumps of a stopped inferior, written by gcore. They are ELF core files laid out like the
+//! ones the kernel writes: a PT_NOTE segment describing the process and its registers, then a
+//! PT_LOAD segment holding the contents of each region of memory it has mapped.
+
+use crate::inferior::Inferior;
+use crate::library::{self, Mapping};
+use nix::sys::ptrace;
+use std::io::Write;
+use std::os::unix::fs::MetadataExt;
+
+const PAGE_SIZE: u64 = 4096;
+const ELF_HEADER_LEN: u64 = 64;
+const PROGRAM_HEADER_LEN: u64 = 56;
+
+const ET_CORE: u16 = 4;
+const EM_X86_64: u16 = 62;
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+
+const NT_PRSTATUS: u32 = 1;
+const NT_FPREGSET: u32 = 2;
+const NT_PRPSINFO: u32 = 3;
+const NT_AUXV: u32 = 6;
+const NT_FILE: u32 = 0x4649_4c45;
+
+/// Writes a core file of the inferior to path. Memory that can't be read, such as [vsyscall],
+/// gets a segment with no contents.
+pub fn write_core(path: &str, inferior: &Inferior) -> std::io::Result<()> {
+    let pid = inferior.pid();
+    let mappings = library::read_mappings(pid)?;
+    let notes = notes(inferior, &mappings)?;
+    let contents: Vec<Vec<u8>> = mappings
+        .iter()
+        .map(|mapping| {
+            if !mapping.perms.starts_with('r') {
+                return Vec::new();
+            }
+            let len = (mapping.end - mapping.start) as usize;
+            inferior.read_region(mapping.start, len).unwrap_or_default()
+        })
+        .collect();
+
+    let num_headers = mappings.len() as u64 + 1;
+    let notes_offset = ELF_HEADER_LEN + num_headers * PROGRAM_HEADER_LEN;
+    let mut header = elf_header(num_headers as u16);
+    push_program_header(
+        &mut header,
+        PT_NOTE,
+        0,
+        notes_offset,
+        0,
+        notes.len() as u64,
+        0,
+    );
+    // Memory starts on the page after the notes, as in the kernel's core files
+    let memory_offset = align(notes_offset + notes.len() as u64, PAGE_SIZE);
+    let mut offset = memory_offset;
+    for (mapping, bytes) in mappings.iter().zip(&contents) {
+        push_program_header(
+            &mut header,
+            PT_LOAD,
+            segment_flags(&mapping.perms),
+            offset,
+            mapping.start,
+            bytes.len() as u64,
+            mapping.end - mapping.start,
+        );
+        offset += bytes.len() as u64;
+    }
+
+    let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+    file.write_all(&header)?;
+    file.write_all(&notes)?;
+    let padding = memory_offset - notes_offset - notes.len() as u64;
+    file.write_all(&vec![0_u8; padding as usize])?;
+    for bytes in &contents {
+        file.write_all(bytes)?;
+    }
+    file.flush()
+}
+
+fn elf_header(num_headers: u16) -> Vec<u8> {
+    let mut header = b"\x7fELF".to_vec();
+    // 64-bit, little-endian, ELF version 1, System V ABI, then padding
+    header.extend_from_slice(&[2, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    header.extend_from_slice(&ET_CORE.to_le_bytes());
+    header.extend_from_slice(&EM_X86_64.to_le_bytes());
+    header.extend_from_slice(&1_u32.to_le_bytes()); // e_version
+    header.extend_from_slice(&0_u64.to_le_bytes()); // e_entry
+    header.extend_from_slice(&ELF_HEADER_LEN.to_le_bytes()); // e_phoff
+    header.extend_from_slice(&0_u64.to_le_bytes()); // e_shoff
+    header.extend_from_slice(&0_u32.to_le_bytes()); // e_flags
+    header.extend_from_slice(&(ELF_HEADER_LEN as u16).to_le_bytes());
+    header.extend_from_slice(&(PROGRAM_HEADER_LEN as u16).to_le_bytes());
+    header.extend_from_slice(&num_headers.to_le_bytes());
+    // No section headers
+    header.extend_from_slice(&[0; 6]);
+    header
+}
+
+fn push_program_header(
+    header: &mut Vec<u8>,
+    kind: u32,
+    flags: u32,
+    offset: u64,
+    vaddr: u64,
+    file_size: u64,
+    mem_size: u64,
+) {
+    let alignment = if kind == PT_LOAD { PAGE_SIZE } else { 4 };
+    header.extend_from_slice(&kind.to_le_bytes());
+    header.extend_from_slice(&flags.to_le_bytes());
+    for value in &[offset, vaddr, 0, file_size, mem_size, alignment] {
+        header.extend_from_slice(&value.to_le_bytes());
+    }
+}
+
+fn segment_flags(perms: &str) -> u32 {
+    let mut flags = 0;
+    for (letter, flag) in perms.chars().zip(&[PF_R, PF_W, PF_X]) {
+        if letter != '-' {
+            flags |= flag;
+        }
+    }
+    flags
+}
+
+/// Returns the notes describing the process: its status and registers, its name and command
+/// line, its auxiliary vector, the files it has mapped, and its floating point registers.
+fn notes(inferior: &Inferior, mappings: &[Mapping]) -> std::io::Result<Vec<u8>> {
+    let pid = inferior.pid();
+    let stat = ProcessStat::read(pid.as_raw())?;
+    let regs = inferior.get_registers().map_err(to_io_error)?;
+    let fp_registers = inferior.get_fp_registers().ok();
+    let signal = ptrace::getsiginfo(pid).map_or(0, |info| info.si_signo);
+
+    // struct elf_prstatus
+    let mut prstatus = Vec::new();
+    prstatus.extend_from_slice(&signal.to_le_bytes()); // si_signo
+    prstatus.extend_from_slice(&[0; 8]); // si_code, si_errno
+    prstatus.extend_from_slice(&(signal as i16).to_le_bytes()); // pr_cursig
+    prstatus.extend_from_slice(&[0; 18]); // padding, pr_sigpend, pr_sighold
+    for id in &[pid.as_raw(), stat.ppid, stat.pgrp, stat.session] {
+        prstatus.extend_from_slice(&id.to_le_bytes());
+    }
+    prstatus.extend_from_slice(&[0; 64]); // pr_utime, pr_stime, pr_cutime, pr_cstime
+    for value in &[
+        regs.r15,
+        regs.r14,
+        regs.r13,
+        regs.r12,
+        regs.rbp,
+        regs.rbx,
+        regs.r11,
+        regs.r10,
+        regs.r9,
+        regs.r8,
+        regs.rax,
+        regs.rcx,
+        regs.rdx,
+        regs.rsi,
+        regs.rdi,
+        regs.orig_rax,
+        regs.rip,
+        regs.cs,
+        regs.eflags,
+        regs.rsp,
+        regs.ss,
+        regs.fs_base,
+        regs.gs_base,
+        regs.ds,
+        regs.es,
+        regs.fs,
+        regs.gs,
+    ] {
+        prstatus.extend_from_slice(&value.to_le_bytes());
+    }
+    prstatus.extend_from_slice(&(fp_registers.is_some() as u32).to_le_bytes());
+    prstatus.extend_from_slice(&[0; 4]);
+
+    // struct elf_prpsinfo
+    let metadata = std::fs::metadata(format!("/proc/{}", pid))?;
+    let mut prpsinfo = Vec::new();
+    // Traced processes are in state t, which the kernel's core files show as T
+    let state = stat.state.to_ascii_uppercase();
+    prpsinfo.push("RSDTZW".find(state).unwrap_or(0) as u8);
+    prpsinfo.push(state as u8);
+    prpsinfo.extend_from_slice(&[0; 14]); // pr_zomb, pr_nice, padding, pr_flag
+    prpsinfo.extend_from_slice(&metadata.uid().to_le_bytes());
+    prpsinfo.extend_from_slice(&metadata.gid().to_le_bytes());
+    for id in &[pid.as_raw(), stat.ppid, stat.pgrp, stat.session] {
+        prpsinfo.extend_from_slice(&id.to_le_bytes());
+    }
+    prpsinfo.extend_from_slice(&fixed_len_string(stat.command.as_bytes(), 16));
+    let mut args = std::fs::read(format!("/proc/{}/cmdline", pid))?;
+    if args.last() == Some(&0) {
+        args.pop();
+    }
+    for byte in args.iter_mut().filter(|byte| **byte == 0) {
+        *byte = b' ';
+    }
+    prpsinfo.extend_from_slice(&fixed_len_string(&args, 80));
+
+    let mut notes = Vec::new();
+    push_note(&mut notes, NT_PRSTATUS, &prstatus);
+    push_note(&mut notes, NT_PRPSINFO, &prpsinfo);
+    let auxv = std::fs::read(format!("/proc/{}/auxv", pid))?;
+    push_note(&mut notes, NT_AUXV, &auxv);
+    push_note(&mut notes, NT_FILE, &mapped_files(mappings));
+    if let Some(fp_registers) = fp_registers {
+        push_note(&mut notes, NT_FPREGSET, &fp_registers);
+    }
+    Ok(notes)
+}
+
+/// Returns the contents of an NT_FILE note: the regions of memory that map files, and which
+/// part of which file each maps.
+fn mapped_files(mappings: &[Mapping]) -> Vec<u8> {
+    let files: Vec<&Mapping> = mappings
+        .iter()
+        .filter(|mapping| mapping.path.starts_with('/'))
+        .collect();
+    let mut desc = Vec::new();
+    desc.extend_from_slice(&(files.len() as u64).to_le_bytes());
+    desc.extend_from_slice(&PAGE_SIZE.to_le_bytes());
+    for mapping in &files {
+        for value in &[mapping.start, mapping.end, mapping.offset / PAGE_SIZE] {
+            desc.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+    for mapping in &files {
+        desc.extend_from_slice(mapping.path.as_bytes());
+        desc.push(0);
+    }
+    desc
+}
+
+/// Appends a note with the name "CORE", padding its name and contents to 4 bytes.
+fn push_note(notes: &mut Vec<u8>, kind: u32, desc: &[u8]) {
+    let name = b"CORE\0";
+    notes.extend_from_slice(&(name.len() as u32).to_le_bytes());
+    notes.extend_from_slice(&(desc.len() as u32).to_le_bytes());
+    notes.extend_from_slice(&kind.to_le_bytes());
+    notes.extend_from_slice(name);
+    notes.resize(align(notes.len() as u64, 4) as usize, 0);
+    notes.extend_from_slice(desc);
+    notes.resize(align(notes.len() as u64, 4) as usize, 0);
+}
+
+/// Truncates or pads a string with nulls to len bytes, leaving room for a null at the end.
+fn fixed_len_string(bytes: &[u8], len: usize) -> Vec<u8> {
+    let mut string = bytes[..bytes.len().min(len - 1)].to_vec();
+    string.resize(len, 0);
+    string
+}
+
+fn align(value: u64, alignment: u64) -> u64 {
+    value.div_ceil(alignment) * alignment
+}
+
+fn to_io_error(err: nix::Error) -> std::io::Error {
+    std::io::Error::other(err.to_string())
+}
+
+/// Fields of /proc/pid/stat that go in a core file
+struct ProcessStat {
+    command: String,
+    state: char,
+    ppid: i32,
+    pgrp: i32,
+    session: i32,
+}
+
+impl ProcessStat {
+    fn read(pid: i32) -> std::io::Result<ProcessStat> {
+        let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid))?;
+        // The command is in parentheses, and may itself contain spaces and parentheses
+        let (start, end) = match (stat.find('('), stat.rfind(')')) {
+            (Some(start), Some(end)) if start < end => (start, end),
+            _ => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "malformed /proc/pid/stat",
+                ))
+            }
+        };
+        let fields: Vec<&str> = stat[end + 1..].split_whitespace().collect();
+        let field = |idx: usize| fields.get(idx).and_then(|field| field.parse().ok());
+        Ok(ProcessStat {
+            command: stat[start + 1..end].to_string(),
+            state: fields
+                .first()
+                .and_then(|state| state.chars().next())
+                .unwrap_or('R'),
+            ppid: field(1).unwrap_or(0),
+            pgrp: field(2).unwrap_or(0),
+            session: field(3).unwrap_or(0),
+        })
+    }
+}
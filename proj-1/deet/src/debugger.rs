@@ -1,16 +1,144 @@
-use crate::debugger_command::DebuggerCommand;
-use crate::dwarf_data::{DwarfData, Error as DwarfError, Line};
-use crate::inferior::{Inferior, Status};
+use crate::coredump;
+use crate::debugger_command::{DebuggerCommand, RunArgs};
+use crate::dwarf_data::{DwarfData, Error as DwarfError, Line, Location, Type, TypeKind, Variable};
+use crate::examine;
+use crate::expression::{self, Scalar};
+use crate::inferior::{EventKind, Inferior, ProcessEvent, Status, WatchKind, NUM_WATCH_SLOTS};
+use crate::library::{self, Library};
+use crate::record;
+use crate::settings::{self, Settings};
+use crate::style::Style;
+use crate::syscall;
+use nix::sys::signal::Signal;
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
+use std::collections::{BTreeMap, VecDeque};
+
+/// Longest string x/s shows before cutting it off, like gdb's default print limit
+const MAX_STRING_LEN: usize = 200;
+/// Most array elements print shows before cutting the array off
+const MAX_ARRAY_ELEMENTS: usize = 200;
+/// Deepest the stack is unwound, in case it is corrupted
+const MAX_FRAMES: usize = 1024;
+/// Number of lines shown before and after the current line when the inferior stops
+const SOURCE_CONTEXT: usize = 2;
+/// Most user commands that can be running inside one another, like gdb's max-user-call-depth
+const MAX_USER_CALL_DEPTH: usize = 1024;
 
 pub struct Debugger {
     target: String,
     history_path: String,
+    /// File the commands and aliases the user defines at the prompt are saved to, to be defined
+    /// again in later sessions
+    commands_path: String,
     readline: Editor<()>,
     debug_data: DwarfData,
-    breakpoints: Vec<u64>,
+    /// Breakpoints indexed by breakpoint number. Deleted breakpoints leave a None behind so that
+    /// the other breakpoints keep their numbers.
+    breakpoints: Vec<Option<UserBreakpoint>>,
     inferior: Option<Inferior>,
+    /// Shared libraries the inferior has loaded, updated whenever the dynamic linker reports
+    /// loading or unloading some
+    libraries: Vec<Library>,
+    /// Arguments and redirections the inferior is run with, kept from one run to the next
+    run_args: RunArgs,
+    /// Format of the last x command, whose letter and unit size later ones default to
+    examine_format: examine::Format,
+    /// Address just past the memory shown by the last x command, where x continues by default
+    examine_next: Option<u64>,
+    /// Frame that print and other commands looking at variables work in, counting up from the
+    /// innermost (0). Goes back to the innermost frame whenever the inferior runs.
+    selected_frame: usize,
+    /// File and line where list continues when run without an argument, just after the lines it
+    /// printed last. Forgotten when the inferior stops, so that list shows where it stopped.
+    list_next: Option<(String, usize)>,
+    settings: Settings,
+    /// Whether to print the system calls the inferior makes as it runs, like strace
+    trace_syscalls: bool,
+    /// Lines from command files being run, which are read before the prompt is shown again
+    pending_commands: VecDeque<String>,
+    /// Whether the line being run was typed at the prompt, rather than read from a command file
+    /// or a user command's definition
+    typed_at_prompt: bool,
+    /// Set for --batch: commands only come from command files or standard input, and deet quits
+    /// once they have run rather than prompting for more
+    batch: bool,
+    /// Status the last inferior exited with, or 128 plus the signal that killed it, which deet
+    /// exits with in batch mode. An inferior that stops for a signal such as SIGSEGV is counted
+    /// as killed by it, since it is likely to be left there.
+    exit_status: i32,
+    /// Commands defined with define, by name, and the lines they run
+    user_commands: BTreeMap<String, Vec<String>>,
+    /// For each user command being run, innermost last, how many lines were queued after its
+    /// definition. Once no more than that are left, it has finished.
+    user_calls: Vec<usize>,
+    /// Aliases defined with alias, and the commands they stand for
+    aliases: BTreeMap<String, String>,
+}
+
+/// A function call on the inferior's stack
+#[derive(Clone, Copy, Debug)]
+struct Frame {
+    /// Where the function is executing: %rip in the innermost frame, and the return address of
+    /// the call it made in the others
+    pc: u64,
+    /// Canonical frame address: the stack pointer before the call was made, which is just above
+    /// the return address. Locals are located relative to it.
+    cfa: u64,
+    rbp: u64,
+    /// Where the function saved its caller's %rbp, unless its prologue hasn't done so yet
+    rbp_slot: Option<u64>,
+}
+
+/// A breakpoint as the user set it. Disabled breakpoints are remembered, but not written into
+/// the inferior.
+#[derive(Clone, Debug)]
+struct UserBreakpoint {
+    /// Zero for a breakpoint in a shared library that hasn't been loaded yet
+    addr: u64,
+    enabled: bool,
+    /// Set for watchpoints, which use a debug register instead of a trap instruction
+    watch: Option<Watchpoint>,
+    /// Set for breakpoints on functions in shared libraries, which are looked up again each time
+    /// libraries are loaded, since a library may be loaded anywhere
+    library_function: Option<String>,
+    /// Set for catchpoints, which stop at a process event rather than at an address
+    catch: Option<Catchpoint>,
+}
+
+#[derive(Clone, Debug)]
+struct Catchpoint {
+    kind: EventKind,
+    /// Number of times the current inferior has stopped at this catchpoint
+    hits: usize,
+}
+
+#[derive(Clone, Debug)]
+struct Watchpoint {
+    kind: WatchKind,
+    /// Number of bytes watched (1, 2, 4 or 8)
+    len: usize,
+    /// What the user asked to watch, for messages
+    expr: String,
+    /// Set when watching a global variable, which moves along with a position-independent
+    /// executable
+    global: bool,
+    /// Debug register holding this watchpoint in the current inferior
+    slot: Option<usize>,
+    /// Value the watched memory had when last checked
+    value: u64,
+    /// Number of times the current inferior has stopped at this watchpoint
+    hits: usize,
+}
+
+impl Watchpoint {
+    fn describe(&self) -> &'static str {
+        match self.kind {
+            WatchKind::Write => "Hardware watchpoint",
+            WatchKind::Read => "Hardware read watchpoint",
+            WatchKind::Access => "Hardware access (read/write) watchpoint",
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -21,7 +149,7 @@ pub struct Breakpoint {
 
 impl Breakpoint {
     pub fn new(addr: u64) -> Breakpoint {
-        return Breakpoint { addr, orig_byte: 0 };
+        Breakpoint { addr, orig_byte: 0 }
     }
 
     pub fn set_orig_byte(&mut self, orig_byte: u8) {
@@ -38,7 +166,7 @@ impl Breakpoint {
 
 impl Debugger {
     /// Initializes the debugger.
-    pub fn new(target: &str) -> Debugger {
+    pub fn new(target: &str, batch: bool) -> Debugger {
         let debug_data = match DwarfData::from_file(target) {
             Ok(val) => val,
             Err(DwarfError::ErrorOpeningFile) => {
@@ -51,169 +179,2716 @@ impl Debugger {
             }
         };
 
-        debug_data.print();
+        if !batch {
+            debug_data.print();
+        }
 
         let history_path = format!("{}/.deet_history", std::env::var("HOME").unwrap());
+        let commands_path = format!("{}/.deet_commands", std::env::var("HOME").unwrap());
         let mut readline = Editor::<()>::new();
         let _ = readline.load_history(&history_path);
 
         Debugger {
             target: target.to_string(),
             history_path,
+            commands_path,
             readline,
             debug_data,
             breakpoints: vec![],
             inferior: None,
+            libraries: Vec::new(),
+            run_args: RunArgs::default(),
+            examine_format: examine::Format::default(),
+            examine_next: None,
+            selected_frame: 0,
+            list_next: None,
+            settings: Settings::default(),
+            trace_syscalls: false,
+            pending_commands: VecDeque::new(),
+            typed_at_prompt: false,
+            batch,
+            exit_status: 0,
+            user_commands: BTreeMap::new(),
+            user_calls: Vec::new(),
+            aliases: BTreeMap::new(),
+        }
+    }
+
+    /// Queues the commands and aliases defined in earlier sessions to be defined again, ahead of
+    /// any other commands queued.
+    pub fn load_user_commands(&mut self) {
+        if let Ok(commands) = std::fs::read_to_string(&self.commands_path) {
+            self.queue_commands(&commands);
+        }
+    }
+
+    /// Returns the status deet exits with in batch mode: that of the last inferior to exit.
+    pub fn exit_status(&self) -> i32 {
+        self.exit_status
+    }
+
+    /// Runs the commands in a file, as if they were typed at the prompt, before reading any more
+    /// from the user or from the file running this one. Blank lines and lines starting with #
+    /// are skipped.
+    pub fn source(&mut self, path: &str) {
+        let script = match std::fs::read_to_string(path) {
+            Ok(script) => script,
+            Err(err) => {
+                println!("Could not read {}: {}", path, err);
+                return;
+            }
+        };
+        self.queue_commands(&script);
+    }
+
+    /// Queues lines of commands to run before reading any more.
+    pub fn queue_commands(&mut self, commands: &str) {
+        for line in commands.lines().rev() {
+            self.pending_commands.push_front(line.to_string());
         }
     }
 
-    fn run_from_cont(&mut self) {
+    /// Resumes the inferior, delivering signal to it if one is given, and reports where it stops.
+    fn run_from_cont(&mut self, signal: Option<Signal>) {
         if self.inferior.is_none() {
             println!("Error: not tracking any process");
             return;
         }
-        let status = self.inferior.as_mut().unwrap().cont().unwrap();
+        self.selected_frame = 0;
+        let mut status = self.inferior.as_mut().unwrap().cont(signal).unwrap();
+        // Library events and watchpoint traps that don't count as hits are skipped over
+        while let Status::Stopped(nix::sys::signal::SIGTRAP, pc) = status {
+            if self.inferior.as_ref().unwrap().is_library_event(pc as u64) {
+                self.load_libraries();
+            } else if self.check_watchpoints() != Some(false) {
+                break;
+            }
+            status = self.inferior.as_mut().unwrap().cont(None).unwrap();
+        }
+        self.report_stop(status);
+    }
+
+    /// Prints why the inferior stopped, and forgets it if it exited.
+    fn report_stop(&mut self, status: Status) {
         match status {
-            Status::Signaled(sig) => println!("\nChild signaled (signal {})", sig),
+            Status::Signaled(sig) => {
+                let banner = format!("Child signaled (signal {})", sig);
+                println!("\n{}", self.settings.paint(Style::Banner, &banner));
+                self.inferior = None;
+                self.exit_status = 128 + sig as i32;
+            }
             Status::Exited(code) => {
-                println!("Child exited (status {})", code);
+                let banner = format!("Child exited (status {})", code);
+                println!("{}", self.settings.paint(Style::Banner, &banner));
                 self.inferior = None;
+                self.exit_status = code;
             }
             Status::Stopped(sig, line_info) => {
-                println!("Child stopped (signal {})", sig);
+                let banner = format!("Child stopped (signal {})", sig);
+                println!("{}", self.settings.paint(Style::Banner, &banner));
                 if sig == nix::sys::signal::SIGTRAP {
+                    self.print_location(line_info);
+                } else {
+                    self.exit_status = 128 + sig as i32;
+                }
+            }
+            Status::Caught(event, pc) => {
+                for (idx, breakpoint) in self.breakpoints.iter_mut().enumerate() {
+                    let catch = match breakpoint {
+                        Some(breakpoint) if breakpoint.enabled => breakpoint.catch.as_mut(),
+                        _ => None,
+                    };
+                    if let Some(catch) = catch.filter(|catch| catch.kind.catches(&event)) {
+                        catch.hits += 1;
+                        let banner = format!("Catchpoint {} ({})", idx, event);
+                        println!("\n{}", self.settings.paint(Style::Banner, &banner));
+                    }
+                }
+                match &event {
+                    ProcessEvent::SyscallEntry(syscall) => println!("{}", syscall.call),
+                    // A traced call has been printed with its result already
+                    ProcessEvent::SyscallReturn(syscall, value) if !self.trace_syscalls => {
+                        println!("{} = {}", syscall.call, syscall.format_result(*value))
+                    }
+                    _ => (),
+                }
+                // Process events happen in system calls, which there's seldom a line for
+                if self.debug_data_at(pc).get_line_from_addr(pc).is_some() {
+                    self.print_location(pc);
+                } else {
+                    let addr = format!("{:#x}", pc);
                     println!(
-                        "Stopped at {}",
-                        self.debug_data
-                            .get_line_from_addr(line_info)
-                            .unwrap_or(Line {
-                                file: String::from(""),
-                                number: 0,
-                                address: 0,
-                            })
+                        "Stopped at {}{}",
+                        self.settings.paint(Style::Address, &addr),
+                        self.symbolize(pc as u64)
                     );
                 }
             }
         }
     }
 
-    fn flush_inferior(&mut self) {
-        if self.inferior.is_some() {
-            self.inferior.as_mut().unwrap().kill();
-            self.inferior = None;
+    fn print_location(&mut self, addr: usize) {
+        self.list_next = None;
+        let debug_data = self.debug_data_at(addr);
+        let line = debug_data.get_line_from_addr(addr).unwrap_or(Line {
+            file: String::from(""),
+            number: 0,
+            address: 0,
+        });
+        println!("Stopped at {}", self.format_line(&line));
+        self.print_source_context(&line);
+    }
+
+    /// Formats line as file:number, with the file name styled.
+    fn format_line(&self, line: &Line) -> String {
+        let file = self.settings.paint(Style::File, &line.file);
+        format!("{}:{}", file, line.number)
+    }
+
+    /// Prints the source line the inferior is stopped at, marked with an arrow, along with the
+    /// SOURCE_CONTEXT lines on either side of it. Prints nothing if the source can't be read.
+    fn print_source_context(&self, line: &Line) {
+        let lines = match self.read_source(&line.file) {
+            Ok(lines) if line.number >= 1 && line.number <= lines.len() => lines,
+            _ => return,
+        };
+        let first = line.number.saturating_sub(SOURCE_CONTEXT).max(1);
+        let last = (line.number + SOURCE_CONTEXT).min(lines.len());
+        let width = last.to_string().len();
+        for number in first..=last {
+            let padded = format!("{:>width$}", number, width = width);
+            if number == line.number {
+                let text = format!("=> {}\t{}", padded, lines[number - 1]);
+                println!("{}", self.settings.paint(Style::CurrentLine, &text));
+            } else {
+                let padded = self.settings.paint(Style::LineNumber, &padded);
+                println!("   {}\t{}", padded, lines[number - 1]);
+            }
         }
     }
 
-    pub fn run(&mut self) {
+    /// Runs the inferior to the start of the next source line, running any functions called on
+    /// the way to completion instead of stepping into them. Without line information for where
+    /// the inferior is stopped, steps over a single instruction.
+    fn next_line(&mut self) {
+        let inferior = match self.inferior.as_ref() {
+            Some(inferior) => inferior,
+            None => {
+                println!("Error: not tracking any process");
+                return;
+            }
+        };
+        let rip = inferior.get_registers().unwrap().rip as usize;
+        let start_line = self.debug_data_at(rip).get_line_from_addr(rip);
         loop {
-            match self.get_next_command() {
-                DebuggerCommand::Run(args) => {
-                    self.flush_inferior();
-                    if let Some(inferior) =
-                        Inferior::new(&self.target, &args, &mut self.breakpoints)
-                    {
-                        self.inferior = Some(inferior);
-                        self.run_from_cont();
-                    } else {
-                        println!("Error starting subprocess");
-                    }
-                }
-                DebuggerCommand::Quit => {
-                    self.flush_inferior();
+            let pc = match self.step_instruction(true) {
+                Ok(pc) => pc,
+                Err(status) => {
+                    self.report_stop(status);
                     return;
                 }
-                DebuggerCommand::Continue => {
-                    self.run_from_cont();
-                }
-                DebuggerCommand::Backtrace => {
-                    self.inferior
-                        .as_mut()
-                        .unwrap()
-                        .print_backtrace(&self.debug_data)
-                        .unwrap();
-                }
-                DebuggerCommand::AddBreakpoint(arg) => {
-                    let target_addr = match parse_address(&arg.to_string(), &self.debug_data) {
-                        Some(val) => val,
-                        None => 0,
-                    };
-
-                    if target_addr == 0 {
-                        println!("Doesn't match an address, a line or a function name");
-                    } else {
-                        self.breakpoints.push(target_addr);
-                        println!("Set breakpoint {} at {}", self.breakpoints.len() - 1, arg);
-                        self.add_breakpoint_to_process(target_addr);
-                    }
+            };
+            let line = self.debug_data_at(pc).get_line_from_addr(pc);
+            // Jumping back to the start of the same line (e.g. in a loop) doesn't count, and
+            // returning into the middle of the caller's line finishes that line first
+            let new_line = match (&start_line, &line) {
+                (Some(start), Some(line)) => {
+                    (line.file != start.file || line.number != start.number)
+                        && self.debug_data_at(pc).is_line_start(pc)
                 }
+                _ => true,
+            };
+            if new_line {
+                self.print_location(pc);
+                return;
             }
         }
     }
 
-    fn add_breakpoint_to_process(&mut self, breakpoint: u64) {
-        if self.inferior.is_some() {
-            self.inferior.as_mut().unwrap().add_breakpoint(breakpoint);
+    /// Executes one machine instruction, running calls to completion if over_calls is set, and
+    /// prints the instruction the inferior is stopped at next.
+    fn single_instruction(&mut self, over_calls: bool) {
+        if self.inferior.is_none() {
+            println!("Error: not tracking any process");
+            return;
+        }
+        match self.step_instruction(over_calls) {
+            Ok(pc) => {
+                self.print_location(pc);
+                self.print_instruction(pc);
+            }
+            Err(status) => self.report_stop(status),
         }
     }
 
-    fn get_next_command(&mut self) -> DebuggerCommand {
+    /// Runs the inferior backwards through the recording: over one instruction, or if by_line is
+    /// set, to the start of the source line executed before the one it is on.
+    fn reverse_step(&mut self, by_line: bool) {
+        let inferior = match self.inferior.as_ref() {
+            Some(inferior) => inferior,
+            None => {
+                println!("Error: not tracking any process");
+                return;
+            }
+        };
+        if inferior.recorded_instructions().is_none() {
+            println!("Process record is not started.");
+            return;
+        }
+        self.selected_frame = 0;
+        let rip = inferior.get_registers().unwrap().rip as usize;
+        let line_at = |debugger: &Debugger, pc: usize| {
+            let line = debugger.debug_data_at(pc).get_line_from_addr(pc)?;
+            Some((line.file, line.number))
+        };
+        let start_line = line_at(self, rip);
+        // The line being stepped back to the start of, once it has been reached
+        let mut target_line = None;
         loop {
-            match self.readline.readline("(deet) ") {
-                Err(ReadlineError::Interrupted) => {
-                    println!("Type \"quit\" to exit");
+            let inferior = self.inferior.as_mut().unwrap();
+            if !inferior.reverse_step().unwrap() {
+                println!("\nNo more reverse-execution history.");
+                break;
+            }
+            if !by_line {
+                break;
+            }
+            if target_line.is_none() {
+                let pc = inferior.get_registers().unwrap().rip as usize;
+                // Code without line information, such as library functions, is stepped through
+                target_line = line_at(self, pc).filter(|line| Some(line) != start_line.as_ref());
+            }
+            // The line started where the instruction before it is on a different one
+            let prev_pc = self.inferior.as_ref().unwrap().last_recorded_pc();
+            let prev_line = prev_pc.and_then(|pc| line_at(self, pc as usize));
+            if target_line.is_some() && prev_line != target_line {
+                break;
+            }
+        }
+        self.refresh_watchpoint_values();
+        let pc = self.inferior.as_ref().unwrap().get_registers().unwrap().rip as usize;
+        self.print_location(pc);
+        if !by_line {
+            self.print_instruction(pc);
+        }
+    }
+
+    /// Starts recording the inferior's execution, for reverse-step and reverse-stepi, or with
+    /// "stop", stops.
+    fn record(&mut self, arg: Option<String>) {
+        let inferior = match self.inferior.as_mut() {
+            Some(inferior) => inferior,
+            None => {
+                println!("Error: not tracking any process");
+                return;
+            }
+        };
+        match arg.as_deref() {
+            None | Some("full") => {
+                if !inferior.start_recording() {
+                    println!(
+                        "The process is already being recorded.  Use \"record stop\" to stop \
+                         recording first."
+                    );
                 }
-                Err(ReadlineError::Eof) => {
-                    return DebuggerCommand::Quit;
+            }
+            Some("stop") => {
+                if inferior.stop_recording() {
+                    println!("Process record is stopped and all execution logs are deleted.");
+                } else {
+                    println!("Process record is not started.");
                 }
+            }
+            _ => println!("Usage: record [full|stop]"),
+        }
+    }
+
+    /// Prints how much of the inferior's execution has been recorded.
+    fn print_record_info(&self) {
+        let recorded = self
+            .inferior
+            .as_ref()
+            .and_then(|inferior| inferior.recorded_instructions());
+        match recorded {
+            Some(count) => {
+                println!("Log contains {} instructions.", count);
+                println!("Max logged instructions is {}.", record::RECORD_LIMIT);
+            }
+            None => println!("No recording is currently active."),
+        }
+    }
+
+    /// Prints the address of the instruction at addr, where it is in its function, and the
+    /// instruction itself.
+    fn print_instruction(&self, addr: usize) {
+        let instruction = match self.inferior.as_ref().unwrap().disassemble(addr as u64) {
+            Ok((instruction, _)) => instruction,
+            Err(err) => format!("<cannot read memory: {}>", err),
+        };
+        println!(
+            "{:#x}{}:\t{}",
+            addr,
+            self.symbolize(addr as u64),
+            instruction
+        );
+    }
+
+    /// Returns the function or global variable containing addr, and how far into it addr is, in
+    /// the form " <name+offset>" that follows addresses in gdb's output. Returns an empty string
+    /// if there is no such symbol.
+    fn symbolize(&self, addr: u64) -> String {
+        let debug_data = self.debug_data_at(addr as usize);
+        let symbol = match debug_data.get_function_containing(addr as usize) {
+            Some(func) => Some((func.name.as_str(), func.address + debug_data.load_bias())),
+            None => self
+                .debug_data
+                .get_global_variable_at(addr as usize)
+                .and_then(|var| match var.location {
+                    Location::Address(start) => {
+                        Some((var.name.as_str(), start + self.debug_data.load_bias()))
+                    }
+                    Location::FramePointerOffset(_) => None,
+                }),
+        };
+        match symbol {
+            Some((name, start)) if addr as usize == start => format!(" <{}>", name),
+            Some((name, start)) => format!(" <{}+{}>", name, addr as usize - start),
+            None => String::new(),
+        }
+    }
+
+    /// Shows inferior memory in the format given to x/NFU. Without an address, carries on after
+    /// the memory the last x command showed.
+    fn examine(&mut self, spec: Option<String>, address: Option<String>) {
+        if self.inferior.is_none() {
+            println!("Error: not tracking any process");
+            return;
+        }
+        let format = match spec {
+            Some(spec) => match examine::Format::parse(&spec, &self.examine_format) {
+                Ok(format) => format,
                 Err(err) => {
-                    panic!("Unexpected I/O error: {:?}", err);
+                    println!("{}", err);
+                    return;
                 }
-                Ok(line) => {
-                    if line.trim().len() == 0 {
-                        continue;
+            },
+            None => examine::Format {
+                count: 1,
+                ..self.examine_format
+            },
+        };
+        let addr = match address {
+            Some(address) => match self.parse_memory_address(&address) {
+                Ok(addr) => addr,
+                Err(err) => {
+                    println!("{}", err);
+                    return;
+                }
+            },
+            None => match self.examine_next {
+                Some(addr) => addr,
+                None => {
+                    println!("Argument required (starting display address).");
+                    return;
+                }
+            },
+        };
+        self.examine_format = format;
+        let next = match format.letter {
+            's' => self.examine_strings(addr, format.count),
+            'i' => self.examine_instructions(addr, format.count),
+            _ => self.examine_units(addr, &format),
+        };
+        match next {
+            Ok(next) => self.examine_next = Some(next),
+            Err(addr) => println!("Cannot access memory at address {:#x}", addr),
+        }
+    }
+
+    /// Prints count units of memory starting at addr, several to a line. Returns the address
+    /// after them, or the address that couldn't be read.
+    fn examine_units(&self, addr: u64, format: &examine::Format) -> Result<u64, u64> {
+        let inferior = self.inferior.as_ref().unwrap();
+        let bytes = inferior
+            .read_bytes(addr, format.count * format.unit)
+            .or(Err(addr))?;
+        let line_len = format.unit * format.per_line();
+        for (idx, line) in bytes.chunks(line_len).enumerate() {
+            let line_addr = addr + (idx * line_len) as u64;
+            let units: Vec<String> = line
+                .chunks(format.unit)
+                .map(|unit| format.format_unit(unit))
+                .collect();
+            println!(
+                "{:#x}{}:\t{}",
+                line_addr,
+                self.symbolize(line_addr),
+                units.join("\t")
+            );
+        }
+        Ok(addr + bytes.len() as u64)
+    }
+
+    /// Prints count NUL-terminated strings starting at addr. Returns the address after them, or
+    /// the address that couldn't be read.
+    fn examine_strings(&self, mut addr: u64, count: usize) -> Result<u64, u64> {
+        for _ in 0..count {
+            let (string, terminated) = self.read_string(addr)?;
+            println!(
+                "{:#x}{}:\t{}{}",
+                addr,
+                self.symbolize(addr),
+                examine::quote_string(&string),
+                if terminated { "" } else { "..." }
+            );
+            addr += string.len() as u64 + terminated as u64;
+        }
+        Ok(addr)
+    }
+
+    /// Reads a NUL-terminated string at addr, up to MAX_STRING_LEN bytes of it. Returns the
+    /// string and whether it ended within that length, or the address that couldn't be read.
+    fn read_string(&self, addr: u64) -> Result<(Vec<u8>, bool), u64> {
+        let inferior = self.inferior.as_ref().unwrap();
+        let mut string = Vec::new();
+        while string.len() < MAX_STRING_LEN {
+            let byte_addr = addr + string.len() as u64;
+            let byte = inferior.read_bytes(byte_addr, 1).or(Err(byte_addr))?[0];
+            if byte == 0 {
+                return Ok((string, true));
+            }
+            string.push(byte);
+        }
+        Ok((string, false))
+    }
+
+    /// Prints count instructions starting at addr, marking the one the inferior is stopped at.
+    /// Returns the address after them, or the address that couldn't be read.
+    fn examine_instructions(&self, mut addr: u64, count: usize) -> Result<u64, u64> {
+        let inferior = self.inferior.as_ref().unwrap();
+        let rip = inferior.get_registers().unwrap().rip;
+        for _ in 0..count {
+            let (instruction, len) = inferior.disassemble(addr).or(Err(addr))?;
+            println!(
+                "{}{:#x}{}:\t{}",
+                if addr == rip { "=> " } else { "   " },
+                addr,
+                self.symbolize(addr),
+                instruction
+            );
+            addr += len as u64;
+        }
+        Ok(addr)
+    }
+
+    /// Evaluates the address given to a command that reads memory, such as 0x404028, $sp or
+    /// p->next. Numbers and pointers stand for the address they hold, and any other variable for
+    /// its own address.
+    fn parse_memory_address(&self, address: &str) -> Result<u64, String> {
+        let value = expression::evaluate(&expression::parse(address)?, self)?;
+        match (&value.value_type.kind, value.address) {
+            (TypeKind::Pointer(_), _) | (_, None) => Ok(value.to_scalar()?.as_integer() as u64),
+            (_, Some(addr)) => Ok(addr),
+        }
+    }
+
+    /// Writes the inferior's memory from start up to end into a file, for dump memory FILE START
+    /// END. Start and end are expressions, like the address of an x command.
+    fn dump_memory(&self, args: &[String]) {
+        let (file, start, end) = match args {
+            [kind, file, start, end] if kind == "memory" => (file, start, end),
+            _ => {
+                println!("Usage: dump memory <file> <start> <end>");
+                return;
+            }
+        };
+        let inferior = match self.inferior.as_ref() {
+            Some(inferior) => inferior,
+            None => {
+                println!("Error: not tracking any process");
+                return;
+            }
+        };
+        let (start, end) = match (
+            self.parse_memory_address(start),
+            self.parse_memory_address(end),
+        ) {
+            (Ok(start), Ok(end)) => (start, end),
+            (Err(message), _) | (_, Err(message)) => {
+                println!("{}", message);
+                return;
+            }
+        };
+        if end <= start {
+            println!("Invalid memory address range (start >= end).");
+            return;
+        }
+        let bytes = match inferior.read_bytes(start, (end - start) as usize) {
+            Ok(bytes) => bytes,
+            Err(_) => {
+                println!("Cannot access memory at address {:#x}", start);
+                return;
+            }
+        };
+        if let Err(err) = std::fs::write(file, &bytes) {
+            println!("{}: {}", file, err);
+        }
+    }
+
+    /// Saves the inferior's memory and registers in an ELF core file, named core.PID unless a file
+    /// name is given.
+    fn generate_core(&self, file: Option<String>) {
+        let inferior = match self.inferior.as_ref() {
+            Some(inferior) => inferior,
+            None => {
+                println!("Error: not tracking any process");
+                return;
+            }
+        };
+        if inferior.is_remote() {
+            println!("Can't create a core file of a remote process.");
+            return;
+        }
+        let path = file.unwrap_or_else(|| format!("core.{}", inferior.pid()));
+        match coredump::write_core(&path, inferior) {
+            Ok(()) => println!("Saved corefile {}", path),
+            Err(err) => println!("Can't create corefile {}: {}", path, err),
+        }
+    }
+
+    /// Takes a checkpoint, a stopped copy of the inferior that restart can go back to.
+    fn checkpoint(&mut self) {
+        let inferior = match self.inferior.as_mut() {
+            Some(inferior) => inferior,
+            None => {
+                println!("Error: not tracking any process");
+                return;
+            }
+        };
+        // fork only copies the thread that calls it
+        if inferior.thread_ids().len() > 1 {
+            println!("Can't checkpoint a multi-threaded program.");
+            return;
+        }
+        match inferior.checkpoint() {
+            Ok((idx, pid)) => println!("checkpoint {}: fork returned pid {}.", idx, pid),
+            Err(err) => println!("Can't take a checkpoint: {}", err),
+        }
+    }
+
+    /// Goes back to a checkpoint, replacing the process being debugged with a copy of it.
+    fn restart(&mut self, arg: Option<String>) {
+        let inferior = match self.inferior.as_mut() {
+            Some(inferior) => inferior,
+            None => {
+                println!("Error: not tracking any process");
+                return;
+            }
+        };
+        let idx = match arg.as_ref().map(|arg| arg.parse::<usize>()) {
+            Some(Ok(idx)) => idx,
+            Some(Err(_)) => {
+                println!("No checkpoint number {}", arg.unwrap());
+                return;
+            }
+            None => {
+                println!("Usage: restart <checkpoint number>");
+                return;
+            }
+        };
+        match inferior.restart(idx) {
+            Ok(Some(pid)) => println!("Switching to process {}", pid),
+            Ok(None) => {
+                println!("No checkpoint number {}", idx);
+                return;
+            }
+            Err(err) => {
+                println!("Can't restart checkpoint {}: {}", idx, err);
+                return;
+            }
+        }
+        self.selected_frame = 0;
+        let rip = inferior.get_registers().unwrap().rip;
+        self.print_location(rip as usize);
+    }
+
+    /// Prints the checkpoints, with where each was taken.
+    fn print_checkpoints(&self) {
+        let checkpoints = self
+            .inferior
+            .as_ref()
+            .map(|inferior| inferior.checkpoints())
+            .unwrap_or_default();
+        if checkpoints.is_empty() {
+            println!("No checkpoints.");
+            return;
+        }
+        for (idx, pid, pc) in checkpoints {
+            let pc = pc as usize;
+            match self.debug_data_at(pc).get_line_from_addr(pc) {
+                Some(line) => println!(
+                    "{:>3} process {} at {:#x}, file {}, line {}",
+                    idx, pid, pc, line.file, line.number
+                ),
+                None => println!("{:>3} process {} at {:#x}", idx, pid, pc),
+            }
+        }
+    }
+
+    /// Carries out an assignment given to set var or set {type}: either "lvalue = value", where
+    /// the lvalue is an expression such as p->count, or "{type} address = value". The value is
+    /// an expression too, and is written into the inferior's memory.
+    fn assign(&mut self, assignment: &str) {
+        if self.inferior.is_none() {
+            println!("Error: not tracking any process");
+            return;
+        }
+        let (target, value) = match assignment.split_once('=') {
+            Some((target, value)) if !value.trim().is_empty() => (target.trim(), value.trim()),
+            _ => {
+                println!("Expected an assignment of the form <target> = <value>");
+                return;
+            }
+        };
+        let destination = match target.strip_prefix('{') {
+            Some(typed) => match typed.split_once('}') {
+                Some((type_name, address)) => {
+                    let type_name = type_name.split_whitespace().collect::<Vec<_>>().join(" ");
+                    match c_type(&type_name) {
+                        Some(value_type) => self
+                            .parse_memory_address(address.trim())
+                            .map(|addr| (addr, value_type)),
+                        None => Err(format!("No symbol \"{}\" in current context.", type_name)),
                     }
-                    self.readline.add_history_entry(line.as_str());
-                    if let Err(err) = self.readline.save_history(&self.history_path) {
-                        println!(
-                            "Warning: failed to save history file at {}: {}",
-                            self.history_path, err
+                }
+                None => Err(String::from("Missing } after the type")),
+            },
+            None => expression::parse(target)
+                .and_then(|target| expression::evaluate(&target, self))
+                .and_then(|target| match target.address {
+                    Some(addr) => Ok((addr, target.value_type)),
+                    None => Err(String::from("Left operand of assignment is not an lvalue.")),
+                }),
+        };
+        let (addr, value_type) = match destination {
+            Ok(destination) => destination,
+            Err(err) => {
+                println!("{}", err);
+                return;
+            }
+        };
+        let bytes = match expression::parse(value)
+            .and_then(|value| expression::evaluate(&value, self))
+            .and_then(|value| value.to_scalar())
+            .and_then(|value| encode_value(value, &value_type))
+        {
+            Ok(bytes) => bytes,
+            Err(err) => {
+                println!("{}", err);
+                return;
+            }
+        };
+        if self
+            .inferior
+            .as_mut()
+            .unwrap()
+            .write_bytes(addr, &bytes)
+            .is_err()
+        {
+            println!("Cannot access memory at address {:#x}", addr);
+        }
+    }
+
+    /// Evaluates an expression and prints its value, formatted according to its type.
+    fn print_expression(&self, text: Option<String>) {
+        let text = match text {
+            Some(text) => text,
+            None => {
+                println!("Usage: print <expression>");
+                return;
+            }
+        };
+        match expression::parse(&text).and_then(|parsed| expression::evaluate(&parsed, self)) {
+            Ok(value) => println!(
+                "{} = {}",
+                text,
+                self.format_data(&value.bytes, &value.value_type)
+            ),
+            Err(err) => println!("{}", err),
+        }
+    }
+
+    /// Formats a value read from the inferior's memory, given as little-endian bytes, according
+    /// to its type: structs member by member, arrays element by element, and pointers to char
+    /// along with the string they point to.
+    fn format_data(&self, bytes: &[u8], value_type: &Type) -> String {
+        match &value_type.kind {
+            TypeKind::Base => format_value(bytes, value_type, &self.settings),
+            TypeKind::Pointer(pointee) => {
+                let pointer = bytes
+                    .iter()
+                    .rev()
+                    .fold(0_u64, |value, byte| value << 8 | *byte as u64);
+                let pointee = pointee.and_then(|pointee| self.debug_data.get_type(pointee));
+                if pointer != 0 && pointee.is_some_and(is_char) {
+                    if let Ok((string, terminated)) = self.read_string(pointer) {
+                        return format!(
+                            "{:#x} {}{}",
+                            pointer,
+                            examine::quote_string(&string),
+                            if terminated { "" } else { "..." }
                         );
                     }
-                    let tokens: Vec<&str> = line.split_whitespace().collect();
-                    if let Some(cmd) = DebuggerCommand::from_tokens(&tokens) {
-                        return cmd;
-                    } else {
-                        println!("Unrecognized command.");
-                    }
                 }
+                format!("({}) {:#x}", value_type.name, pointer)
+            }
+            TypeKind::Struct(members) => {
+                let members: Vec<String> = members
+                    .iter()
+                    .map(|member| {
+                        let member_type = self.debug_data.get_type(member.type_offset);
+                        let value = member_type
+                            .and_then(|member_type| {
+                                let member_bytes =
+                                    bytes.get(member.offset..member.offset + member_type.size)?;
+                                Some(self.format_data(member_bytes, member_type))
+                            })
+                            .unwrap_or_else(|| String::from("<unknown>"));
+                        format!("{} = {}", member.name, value)
+                    })
+                    .collect();
+                format!("{{{}}}", members.join(", "))
             }
+            TypeKind::Array(element, counts) => match self.debug_data.get_type(*element) {
+                Some(element_type) => self.format_array(bytes, element_type, counts),
+                None => String::from("<unknown>"),
+            },
         }
     }
-}
 
-fn parse_address(addr: &str, dwarf_data: &DwarfData) -> Option<u64> {
-    let mut is_hex = false;
-    let addr_without_0x = if addr.to_lowercase().starts_with("*0x") {
-        is_hex = true;
-        &addr[3..]
-    } else {
-        &addr
-    };
-    match u64::from_str_radix(addr_without_0x, 16).ok() {
-        Some(val) => {
-            if is_hex {
-                return Some(val as u64);
+    /// Formats an array with the given number of elements in each dimension. Arrays of char are
+    /// shown as strings.
+    fn format_array(&self, bytes: &[u8], element_type: &Type, counts: &[usize]) -> String {
+        let (count, inner_counts) = match counts.split_first() {
+            Some(split) => split,
+            None => return String::from("{}"),
+        };
+        if inner_counts.is_empty() && is_char(element_type) {
+            let len = bytes
+                .iter()
+                .position(|byte| *byte == 0)
+                .unwrap_or(bytes.len());
+            return examine::quote_string(&bytes[..len]);
+        }
+        let stride = element_type.size * inner_counts.iter().product::<usize>();
+        let elements: Vec<String> = bytes
+            .chunks(stride.max(1))
+            .take((*count).min(MAX_ARRAY_ELEMENTS))
+            .map(|element| {
+                if inner_counts.is_empty() {
+                    self.format_data(element, element_type)
+                } else {
+                    self.format_array(element, element_type, inner_counts)
+                }
+            })
+            .collect();
+        let more = if *count > MAX_ARRAY_ELEMENTS {
+            "..."
+        } else {
+            ""
+        };
+        format!("{{{}{}}}", elements.join(", "), more)
+    }
+
+    /// Looks up a variable visible in the selected frame: a local variable or parameter of its
+    /// function, or else a global variable.
+    fn find_variable(&self, name: &str) -> Option<&Variable> {
+        let pc = self.current_frame()?.pc as usize;
+        self.debug_data_at(pc)
+            .get_function_containing(pc)
+            .and_then(|func| func.variables.iter().find(|var| var.name == name))
+            .or_else(|| self.debug_data.get_global_variable(name))
+    }
+
+    /// Returns the address of a variable in the inferior. Locals are located relative to the
+    /// given frame's canonical frame address.
+    fn variable_address(&self, var: &Variable, frame: &Frame) -> u64 {
+        match var.location {
+            Location::Address(addr) => (addr + self.debug_data.load_bias()) as u64,
+            Location::FramePointerOffset(offset) => (frame.cfa as i64 + offset as i64) as u64,
+        }
+    }
+
+    /// Unwinds the inferior's stack, returning its frames from the innermost out. Stops at main,
+    /// at a frame outside any function in the debugging information, at a frame pointer that
+    /// doesn't point further up the stack, or after MAX_FRAMES frames.
+    fn frames(&self) -> Vec<Frame> {
+        if self.inferior.is_none() {
+            return Vec::new();
+        }
+        self.unwind(self.innermost_frame())
+    }
+
+    /// Unwinds a stack starting from its innermost frame, as frames does for the main thread.
+    fn unwind(&self, mut frame: Frame) -> Vec<Frame> {
+        let inferior = self.inferior.as_ref().unwrap();
+        let mut frames = Vec::new();
+        loop {
+            frames.push(frame);
+            let pc = frame.pc as usize;
+            // Frames without debugging information, such as ones in the C library, are walked
+            // through by their frame pointers as well, since the crash may well be in one
+            let func = self.debug_data_at(pc).get_function_containing(pc);
+            if func.is_some_and(|func| func.name == "main") || frames.len() == MAX_FRAMES {
+                break;
             }
-            match dwarf_data.get_addr_for_line(None, val as usize) {
-                Some(val) => Some(val as u64),
-                None => None,
+            let caller_rbp = match frame.rbp_slot {
+                Some(slot) => match inferior.read_value(slot, 8) {
+                    Ok(rbp) => rbp,
+                    Err(_) => break,
+                },
+                None => frame.rbp,
+            };
+            // The stack grows down, so a caller's frame is always above its callee's
+            if caller_rbp < frame.cfa || caller_rbp % 8 != 0 {
+                break;
             }
+            let pc = match inferior.read_value(frame.cfa - 8, 8) {
+                Ok(pc) if pc != 0 => pc,
+                _ => break,
+            };
+            frame = Frame {
+                pc,
+                cfa: caller_rbp + 16,
+                rbp: caller_rbp,
+                rbp_slot: Some(caller_rbp),
+            };
         }
-        None => {
-            return match dwarf_data.get_addr_for_function(None, addr) {
-                Some(val) => Some(val as u64),
-                None => None,
+        frames
+    }
+
+    /// Returns the frame of the function the inferior is stopped in.
+    fn innermost_frame(&self) -> Frame {
+        let regs = self.inferior.as_ref().unwrap().get_registers().unwrap();
+        self.frame_from_registers(&regs)
+    }
+
+    /// Returns the frame of the function a thread stopped with the given registers is in.
+    fn frame_from_registers(&self, regs: &libc::user_regs_struct) -> Frame {
+        let slot = self.return_address_slot(regs);
+        Frame {
+            pc: regs.rip,
+            cfa: slot + 8,
+            rbp: regs.rbp,
+            // Until the prologue pushes %rbp, the return address is at the top of the stack
+            rbp_slot: Some(slot - 8).filter(|_| slot != regs.rsp),
+        }
+    }
+
+    /// Returns the selected frame, or None if there is no inferior.
+    fn current_frame(&self) -> Option<Frame> {
+        self.inferior.as_ref()?;
+        if self.selected_frame == 0 {
+            // Only the innermost frame is needed, so there's no need to unwind the stack
+            return Some(self.innermost_frame());
+        }
+        self.frames().get(self.selected_frame).copied()
+    }
+
+    /// Selects a frame (for frame N) or moves the selection up or down by some number of frames
+    /// (for up and down), then prints the selected frame.
+    fn select_frame(&mut self, arg: Option<String>, direction: Option<isize>) {
+        if self.inferior.is_none() {
+            println!("No stack.");
+            return;
+        }
+        let number = match arg.map(|arg| arg.parse::<usize>()) {
+            Some(Ok(number)) => Some(number),
+            Some(Err(_)) => {
+                println!("Invalid number of frames");
+                return;
+            }
+            None => None,
+        };
+        let frames = self.frames();
+        let target = match direction {
+            None => number.unwrap_or(self.selected_frame) as isize,
+            Some(direction) => {
+                self.selected_frame as isize + direction * number.unwrap_or(1) as isize
+            }
+        };
+        if target < 0 {
+            println!("Bottom (innermost) frame selected; you cannot go down.");
+            return;
+        }
+        if target as usize >= frames.len() {
+            if direction.is_some() {
+                println!("Initial frame selected; you cannot go up.");
+            } else {
+                println!("No frame at level {}.", target);
+            }
+            return;
+        }
+        self.selected_frame = target as usize;
+        self.list_next = None;
+        self.print_frame(self.selected_frame, &frames[self.selected_frame]);
+    }
+
+    /// Prints where the selected frame is on the stack, and where it saved the registers it
+    /// restores when it returns.
+    fn print_frame_info(&self) {
+        if self.inferior.is_none() {
+            println!("No stack.");
+            return;
+        }
+        let frames = self.frames();
+        let frame = match frames.get(self.selected_frame) {
+            Some(frame) => frame,
+            None => {
+                println!("No frame selected.");
+                return;
+            }
+        };
+        let inferior = self.inferior.as_ref().unwrap();
+        let rip_slot = frame.cfa - 8;
+        println!(
+            "Stack level {}, frame at {:#x}:",
+            self.selected_frame, frame.cfa
+        );
+        let debug_data = self.debug_data_at(frame.pc as usize);
+        let function = debug_data
+            .get_function_from_addr(frame.pc as usize)
+            .unwrap_or(String::from("??"));
+        let saved_rip = match inferior.read_value(rip_slot, 8) {
+            Ok(saved_rip) => format!("{:#x}", saved_rip),
+            Err(_) => String::from("<unavailable>"),
+        };
+        match debug_data.get_line_from_addr(frame.pc as usize) {
+            Some(line) => println!(
+                " rip = {:#x} in {} ({}); saved rip = {}",
+                frame.pc, function, line, saved_rip
+            ),
+            None => println!(
+                " rip = {:#x} in {}; saved rip = {}",
+                frame.pc, function, saved_rip
+            ),
+        }
+        let mut relations = Vec::new();
+        if let Some(caller) = frames.get(self.selected_frame + 1) {
+            relations.push(format!("called by frame at {:#x}", caller.cfa));
+        }
+        if let Some(callee) = self.selected_frame.checked_sub(1).map(|idx| frames[idx]) {
+            relations.push(format!("caller of frame at {:#x}", callee.cfa));
+        }
+        if !relations.is_empty() {
+            println!(" {}", relations.join(", "));
+        }
+        println!(
+            " Frame pointer (rbp) is {:#x}, previous frame's sp is {:#x}",
+            frame.rbp, frame.cfa
+        );
+        println!(" Saved registers:");
+        match frame.rbp_slot {
+            Some(rbp_slot) => println!("  rbp at {:#x}, rip at {:#x}", rbp_slot, rip_slot),
+            None => println!("  rip at {:#x}", rip_slot),
+        }
+    }
+
+    /// Prints the backtrace for bt [all] [full] [N]. As in gdb, N limits it to the innermost N
+    /// frames, and -N to the outermost N; with full, each frame's local variables are printed too.
+    /// With all, every thread's backtrace is printed.
+    fn print_backtrace(&self, args: &[String]) {
+        let mut all_threads = false;
+        let mut full = false;
+        let mut limit = None;
+        for arg in args {
+            match arg.as_str() {
+                "all" => all_threads = true,
+                "full" | "-full" => full = true,
+                _ => match arg.parse::<isize>() {
+                    Ok(count) if limit.is_none() => limit = Some(count),
+                    _ => {
+                        println!("Usage: backtrace [all] [full] [N]");
+                        return;
+                    }
+                },
+            }
+        }
+        let inferior = match self.inferior.as_ref() {
+            Some(inferior) => inferior,
+            None => {
+                println!("No stack.");
+                return;
+            }
+        };
+        if !all_threads {
+            self.print_frames(&self.frames(), full, limit);
+            return;
+        }
+        // Every thread is stopped before any is unwound, so that the backtraces are a snapshot
+        // of one moment. Only the main thread is traced, so the others have to be attached to.
+        let threads = inferior.thread_ids();
+        let registers: Vec<_> = threads
+            .iter()
+            .map(|tid| {
+                if *tid == inferior.pid() {
+                    inferior.get_registers()
+                } else {
+                    inferior.attach_thread(*tid)
+                }
+            })
+            .collect();
+        for (number, (tid, regs)) in threads.iter().zip(&registers).enumerate() {
+            println!(
+                "\nThread {} (LWP {}) \"{}\":",
+                number + 1,
+                tid,
+                inferior.thread_name(*tid)
+            );
+            match regs {
+                Ok(regs) => {
+                    let frames = self.unwind(self.frame_from_registers(regs));
+                    self.print_frames(&frames, full, limit);
+                }
+                Err(err) => println!("Cannot stop thread: {}", err),
+            }
+        }
+        for (tid, regs) in threads.iter().zip(&registers) {
+            if *tid != inferior.pid() && regs.is_ok() {
+                inferior.detach_thread(*tid);
+            }
+        }
+    }
+
+    /// Prints frames of a backtrace, limited and with locals as for bt.
+    fn print_frames(&self, frames: &[Frame], full: bool, limit: Option<isize>) {
+        let (start, end) = match limit {
+            Some(count) if count < 0 => (
+                frames.len().saturating_sub(count.unsigned_abs()),
+                frames.len(),
+            ),
+            Some(count) => (0, frames.len().min(count as usize)),
+            None => (0, frames.len()),
+        };
+        for (number, frame) in frames.iter().enumerate().take(end).skip(start) {
+            self.print_frame(number, frame);
+            if full {
+                self.print_locals(frame);
+            }
+        }
+        if end < frames.len() {
+            println!("(More stack frames follow...)");
+        }
+    }
+
+    /// Prints the values of the local variables of the function running in a frame.
+    fn print_locals(&self, frame: &Frame) {
+        let pc = frame.pc as usize;
+        let variables = match self.debug_data_at(pc).get_function_containing(pc) {
+            Some(func) if !func.variables.is_empty() => &func.variables,
+            _ => {
+                println!("No locals.");
+                return;
+            }
+        };
+        let inferior = self.inferior.as_ref().unwrap();
+        for var in variables {
+            let addr = self.variable_address(var, frame);
+            match inferior.read_bytes(addr, var.entity_type.size) {
+                Ok(bytes) => println!(
+                    "        {} = {}",
+                    var.name,
+                    self.format_data(&bytes, &var.entity_type)
+                ),
+                Err(_) => println!(
+                    "        {} = <error: Cannot access memory at address {:#x}>",
+                    var.name, addr
+                ),
+            }
+        }
+    }
+
+    fn print_frame(&self, number: usize, frame: &Frame) {
+        let debug_data = self.debug_data_at(frame.pc as usize);
+        let function = debug_data
+            .get_function_from_addr(frame.pc as usize)
+            .unwrap_or(String::from("??"));
+        let addr = format!("{:#x}", frame.pc);
+        let addr = self.settings.paint(Style::Address, &addr);
+        let function = self.settings.paint(Style::Function, &function);
+        match debug_data.get_line_from_addr(frame.pc as usize) {
+            Some(line) => {
+                let line = self.format_line(&line);
+                println!("#{:<3}{} in {} ({})", number, addr, function, line)
+            }
+            None => println!("#{:<3}{} in {}", number, addr, function),
+        }
+    }
+
+    /// Executes one instruction, running it to completion if it is a call and over_calls is set.
+    /// Returns the new %rip, or the status of the inferior if it stopped for some other reason on
+    /// the way: it exited or got a signal, or reached a breakpoint or watchpoint.
+    fn step_instruction(&mut self, over_calls: bool) -> Result<usize, Status> {
+        self.selected_frame = 0;
+        let inferior = self.inferior.as_mut().unwrap();
+        let start_rip = inferior.get_registers().unwrap().rip;
+        let is_call = over_calls && inferior.is_call_instruction(start_rip).unwrap_or(false);
+        let status = inferior.step().unwrap();
+        let pc = match status {
+            Status::Stopped(nix::sys::signal::SIGTRAP, pc) => pc,
+            _ => return Err(status),
+        };
+        if self.check_watchpoints() == Some(true)
+            || self.inferior.as_ref().unwrap().is_breakpoint(pc as u64)
+        {
+            return Err(status);
+        }
+        if !is_call {
+            return Ok(pc);
+        }
+
+        let call_sp = self.inferior.as_ref().unwrap().get_registers().unwrap().rsp;
+        self.run_until_return(call_sp)
+    }
+
+    /// Runs the current function to completion and prints the value it returned.
+    fn finish(&mut self) {
+        let inferior = match self.inferior.as_ref() {
+            Some(inferior) => inferior,
+            None => {
+                println!("Error: not tracking any process");
+                return;
+            }
+        };
+        // %rbp may not point at a frame, as at the entry point or without a frame pointer
+        let slot = self.return_address_slot(&inferior.get_registers().unwrap());
+        if inferior.read_value(slot, 8).is_err() {
+            println!("cannot find the return address");
+            return;
+        }
+        let rip = inferior.get_registers().unwrap().rip as usize;
+        let debug_data = self.debug_data_at(rip);
+        let function = debug_data.get_function_containing(rip);
+        println!(
+            "Run till exit from {} ({})",
+            function.map_or("??", |func| func.name.as_str()),
+            debug_data.get_line_from_addr(rip).unwrap_or(Line {
+                file: String::from(""),
+                number: 0,
+                address: 0,
+            })
+        );
+        let return_type = function.and_then(|func| func.return_type.clone());
+        match self.run_until_return(slot) {
+            Ok(pc) => {
+                self.print_location(pc);
+                if let Some(return_type) = return_type {
+                    let rax = self.inferior.as_ref().unwrap().get_registers().unwrap().rax;
+                    println!(
+                        "Value returned is {}",
+                        format_return_value(rax, &return_type, &self.settings)
+                    );
+                }
+            }
+            Err(status) => self.report_stop(status),
+        }
+    }
+
+    /// Returns the address of the stack slot holding the return address of the function a thread
+    /// stopped with the given registers is in. It is at the top of the stack until the function's
+    /// prologue pushes %rbp, and just above the saved %rbp once %rbp points at it.
+    fn return_address_slot(&self, regs: &libc::user_regs_struct) -> u64 {
+        let inferior = self.inferior.as_ref().unwrap();
+        let debug_data = self.debug_data_at(regs.rip as usize);
+        if let Some(func) = debug_data.get_function_containing(regs.rip as usize) {
+            let start = (func.address + debug_data.load_bias()) as u64;
+            let prologue = inferior.read_bytes(start, 8).unwrap_or_default();
+            // push %rbp, possibly after endbr64
+            let push_addr = if prologue.starts_with(&[0xf3, 0x0f, 0x1e, 0xfa]) {
+                start + 4
+            } else {
+                start
+            };
+            if prologue.get((push_addr - start) as usize) == Some(&0x55) {
+                if regs.rip <= push_addr {
+                    return regs.rsp;
+                } else if regs.rip == push_addr + 1 {
+                    return regs.rsp + 8;
+                }
+            }
+        } else if let Ok(addr) = inferior.read_value(regs.rsp, 8) {
+            // Functions without debugging information, such as strlen in the C library, often
+            // don't push %rbp at all. If the top of the stack is a return address into a function
+            // that does have it, nothing has been pushed.
+            let caller = addr as usize;
+            let debug_data = self.debug_data_at(caller);
+            if debug_data.get_function_containing(caller).is_some() {
+                return regs.rsp;
+            }
+        }
+        regs.rbp + 8
+    }
+
+    /// Runs the inferior until the function whose return address is stored at slot returns.
+    /// Returns the new %rip, or the status of the inferior if it stopped for some other reason
+    /// on the way.
+    fn run_until_return(&mut self, slot: u64) -> Result<usize, Status> {
+        self.selected_frame = 0;
+        // A recursive call can reach the return address first, but with the stack pointer
+        // further down
+        let inferior = self.inferior.as_mut().unwrap();
+        let return_addr = inferior.read_value(slot, 8).unwrap();
+        inferior.set_temp_breakpoint(return_addr);
+        let result = loop {
+            let status = self.inferior.as_mut().unwrap().cont(None).unwrap();
+            let pc = match status {
+                Status::Stopped(nix::sys::signal::SIGTRAP, pc) => pc,
+                _ => break Err(status),
+            };
+            if self.inferior.as_ref().unwrap().is_library_event(pc as u64) {
+                self.load_libraries();
+                continue;
+            }
+            match self.check_watchpoints() {
+                Some(true) => break Err(status),
+                Some(false) => continue,
+                None => {}
+            }
+            let inferior = self.inferior.as_ref().unwrap();
+            if inferior.is_breakpoint(pc as u64) {
+                break Err(status);
+            }
+            if pc as u64 == return_addr && inferior.get_registers().unwrap().rsp > slot {
+                break Ok(pc);
+            }
+        };
+        if let Ok(_) | Err(Status::Stopped(..)) = result {
+            self.inferior.as_mut().unwrap().clear_temp_breakpoint();
+        }
+        result
+    }
+
+    /// Starts a new inferior, stopped before its first instruction, in place of the current one.
+    /// Returns false if it couldn't be started.
+    fn start_inferior(&mut self) -> bool {
+        let run_args = self.run_args.clone();
+        let started = self
+            .begin_inferior(|target, breakpoints| Inferior::new(target, &run_args, breakpoints));
+        if !started {
+            println!("Error starting subprocess");
+        }
+        started
+    }
+
+    /// Debugs the process running under gdbserver at address, for target remote.
+    fn connect_remote(&mut self, address: &str) -> bool {
+        self.begin_inferior(|target, breakpoints| Inferior::connect(address, target, breakpoints))
+    }
+
+    /// Starts debugging the process new_inferior starts or connects to, which is given the
+    /// executable and the addresses of the breakpoints in it. Returns false if there isn't one.
+    fn begin_inferior<F>(&mut self, new_inferior: F) -> bool
+    where
+        F: FnOnce(&str, &[Option<u64>]) -> Option<Inferior>,
+    {
+        self.flush_inferior();
+        self.selected_frame = 0;
+        // Only enabled breakpoints are written into the new process; watchpoints are armed
+        // separately once it is running. A position-independent executable may be loaded
+        // somewhere else this time, so breakpoints are passed as addresses in the executable,
+        // and they and watchpoints on global variables are moved along with it.
+        // Breakpoints in shared libraries wait for the libraries to be loaded again.
+        let old_bias = self.debug_data.load_bias() as u64;
+        self.libraries.clear();
+        for breakpoint in self.breakpoints.iter_mut().flatten() {
+            if breakpoint.library_function.is_some() {
+                breakpoint.addr = 0;
+            }
+            if let Some(catch) = &mut breakpoint.catch {
+                catch.hits = 0;
+            }
+        }
+        let breakpoints: Vec<Option<u64>> = self
+            .breakpoints
+            .iter()
+            .map(|bp| {
+                bp.as_ref()
+                    .filter(|bp| bp.enabled && bp.watch.is_none() && bp.addr != 0)
+                    .map(|bp| bp.addr.wrapping_sub(old_bias))
+            })
+            .collect();
+        match new_inferior(&self.target, &breakpoints) {
+            Some(mut inferior) => {
+                inferior.set_follow_fork_mode(self.settings.follow_fork_mode);
+                inferior.set_trace_syscalls(self.trace_syscalls);
+                inferior.set_caught_events(self.caught_events());
+                let load_bias = inferior.load_bias();
+                for breakpoint in self.breakpoints.iter_mut().flatten() {
+                    let in_executable = match &breakpoint.watch {
+                        Some(watch) => watch.global,
+                        None => true,
+                    };
+                    if in_executable && breakpoint.addr != 0 {
+                        breakpoint.addr = breakpoint.addr.wrapping_sub(old_bias) + load_bias;
+                    }
+                }
+                self.debug_data.set_load_bias(load_bias as usize);
+                self.inferior = Some(inferior);
+                // The dynamic linker is already mapped
+                self.load_libraries();
+                for idx in 0..self.breakpoints.len() {
+                    self.arm_watchpoint(idx);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Connects to gdbserver for target remote host:port, in place of any inferior there is.
+    fn target(&mut self, args: Vec<String>) {
+        let address = match args.as_slice() {
+            [kind, address] if kind == "remote" => address,
+            _ => {
+                println!("Usage: target remote <host>:<port>");
+                return;
+            }
+        };
+        if self.inferior.is_some()
+            && !self.confirm("A program is being debugged already.  Kill it?")
+        {
+            return;
+        }
+        println!("Remote debugging using {}", address);
+        if self.connect_remote(address) {
+            let inferior = self.inferior.as_ref().unwrap();
+            match inferior.get_registers() {
+                Ok(regs) => self.print_location(regs.rip as usize),
+                Err(err) => println!("Could not read the remote registers: {:?}", err),
+            }
+        }
+    }
+
+    /// Brings the list of shared libraries up to date with what the inferior has mapped, and
+    /// sets the breakpoints waiting for functions in newly loaded libraries.
+    fn load_libraries(&mut self) {
+        let mapped = self.inferior.as_ref().unwrap().libraries(&self.target);
+        self.libraries.retain(|library| {
+            mapped
+                .iter()
+                .any(|(path, load_bias)| *path == library.path && *load_bias == library.load_bias)
+        });
+        for (path, load_bias) in mapped {
+            if !self.libraries.iter().any(|library| library.path == path) {
+                self.libraries.push(Library::load(&path, load_bias));
+            }
+        }
+        for idx in 0..self.breakpoints.len() {
+            let function = match &self.breakpoints[idx] {
+                Some(breakpoint) if breakpoint.addr == 0 => match &breakpoint.library_function {
+                    Some(function) => function.clone(),
+                    None => continue,
+                },
+                _ => continue,
+            };
+            let addr = match self.library_function_address(&function) {
+                Some(addr) => addr,
+                None => continue,
+            };
+            let breakpoint = self.breakpoints[idx].as_mut().unwrap();
+            breakpoint.addr = addr;
+            println!("Set breakpoint {} at {:#x}: {}", idx, addr, function);
+            if breakpoint.enabled {
+                self.add_breakpoint_to_process(addr);
+            }
+        }
+    }
+
+    /// Returns where a function in one of the loaded shared libraries is.
+    fn library_function_address(&self, name: &str) -> Option<u64> {
+        self.libraries
+            .iter()
+            .find_map(|library| library.function_address(name))
+    }
+
+    /// Returns the debugging information describing the code at addr: that of the shared library
+    /// it is in, if any has it, or else the executable's.
+    fn debug_data_at(&self, addr: usize) -> &DwarfData {
+        self.libraries
+            .iter()
+            .filter_map(|library| library.debug_data.as_ref())
+            .find(|debug_data| debug_data.get_function_containing(addr).is_some())
+            .unwrap_or(&self.debug_data)
+    }
+
+    fn flush_inferior(&mut self) {
+        if self.inferior.is_some() {
+            self.inferior.as_mut().unwrap().kill();
+            self.inferior = None;
+        }
+    }
+
+    pub fn run(&mut self) {
+        loop {
+            match self.get_next_command() {
+                DebuggerCommand::Run(run_args) => {
+                    if !self.confirm_restart() {
+                        continue;
+                    }
+                    if !run_args.is_empty() {
+                        self.run_args = run_args;
+                    }
+                    if self.start_inferior() {
+                        self.run_from_cont(None);
+                    }
+                }
+                DebuggerCommand::Start(run_args) => {
+                    if !self.confirm_restart() {
+                        continue;
+                    }
+                    if !run_args.is_empty() {
+                        self.run_args = run_args;
+                    }
+                    if self.debug_data.get_addr_for_function(None, "main").is_none() {
+                        println!("Error: no main function to stop at");
+                        continue;
+                    }
+                    if self.start_inferior() {
+                        // Only now is it known where main was loaded
+                        let main_addr =
+                            self.debug_data.get_addr_for_function(None, "main").unwrap() as u64;
+                        println!("Set temporary breakpoint at main");
+                        self.inferior
+                            .as_mut()
+                            .unwrap()
+                            .set_temp_breakpoint(main_addr);
+                        self.run_from_cont(None);
+                        if let Some(inferior) = self.inferior.as_mut() {
+                            inferior.clear_temp_breakpoint();
+                        }
+                    }
+                }
+                DebuggerCommand::Kill => {
+                    if self.inferior.is_none() {
+                        println!("Error: not tracking any process");
+                    } else if !self.confirm("Kill the program being debugged?") {
+                        continue;
+                    }
+                    self.flush_inferior();
+                }
+                DebuggerCommand::Quit => {
+                    if let Some(inferior) = self.inferior.as_ref() {
+                        let question = format!(
+                            "A debugging session is active.\n\n\tInferior 1 [process {}] will be \
+                             killed.\n\nQuit anyway?",
+                            inferior.pid()
+                        );
+                        if !self.confirm(&question) {
+                            continue;
+                        }
+                    }
+                    self.flush_inferior();
+                    return;
+                }
+                DebuggerCommand::Continue => {
+                    self.run_from_cont(None);
+                }
+                DebuggerCommand::Signal(name) => {
+                    let name = match name {
+                        Some(name) => name.to_uppercase(),
+                        None => {
+                            println!("Usage: signal <signal name, e.g. SIGUSR1, or 0 for none>");
+                            continue;
+                        }
+                    };
+                    let signal = if name == "0" {
+                        None
+                    } else {
+                        let name = if name.starts_with("SIG") {
+                            name
+                        } else {
+                            format!("SIG{}", name)
+                        };
+                        match name.parse::<Signal>() {
+                            Ok(signal) => Some(signal),
+                            Err(_) => {
+                                println!("Unknown signal {}", name);
+                                continue;
+                            }
+                        }
+                    };
+                    if let Some(signal) = signal {
+                        if self.inferior.is_some() {
+                            println!("Continuing with signal {}", signal);
+                        }
+                    }
+                    self.run_from_cont(signal);
+                }
+                DebuggerCommand::Next => {
+                    self.next_line();
+                }
+                DebuggerCommand::Finish => {
+                    self.finish();
+                }
+                DebuggerCommand::StepInstruction => {
+                    self.single_instruction(false);
+                }
+                DebuggerCommand::NextInstruction => {
+                    self.single_instruction(true);
+                }
+                DebuggerCommand::ReverseStep => {
+                    self.reverse_step(true);
+                }
+                DebuggerCommand::ReverseStepInstruction => {
+                    self.reverse_step(false);
+                }
+                DebuggerCommand::Up(count) => {
+                    self.select_frame(count, Some(1));
+                }
+                DebuggerCommand::Down(count) => {
+                    self.select_frame(count, Some(-1));
+                }
+                DebuggerCommand::Frame(number) => {
+                    self.select_frame(number, None);
+                }
+                DebuggerCommand::Backtrace(args) => {
+                    self.print_backtrace(&args);
+                }
+                DebuggerCommand::AddBreakpoint(arg) => {
+                    let target_addr =
+                        parse_address(&arg.to_string(), &self.debug_data).unwrap_or_default();
+
+                    if target_addr == 0 && is_function_name(&arg) {
+                        // Maybe the function is in a shared library, loaded or yet to be
+                        let addr = self.library_function_address(&arg).unwrap_or(0);
+                        self.breakpoints.push(Some(UserBreakpoint {
+                            addr,
+                            enabled: true,
+                            watch: None,
+                            library_function: Some(arg.clone()),
+                            catch: None,
+                        }));
+                        let idx = self.breakpoints.len() - 1;
+                        if addr == 0 {
+                            println!("Function \"{}\" not defined.", arg);
+                            println!("Breakpoint {} ({}) pending.", idx, arg);
+                        } else {
+                            println!("Set breakpoint {} at {:#x}: {}", idx, addr, arg);
+                            self.add_breakpoint_to_process(addr);
+                        }
+                    } else if target_addr == 0 {
+                        println!("Doesn't match an address, a line or a function name");
+                    } else {
+                        self.breakpoints.push(Some(UserBreakpoint {
+                            addr: target_addr,
+                            enabled: true,
+                            watch: None,
+                            library_function: None,
+                            catch: None,
+                        }));
+                        println!("Set breakpoint {} at {}", self.breakpoints.len() - 1, arg);
+                        self.add_breakpoint_to_process(target_addr);
+                    }
+                }
+                DebuggerCommand::AddWatchpoint(kind, args) => {
+                    self.add_watchpoint(kind, args);
+                }
+                DebuggerCommand::AddCatchpoint(args) => {
+                    let args: Vec<&str> = args.iter().map(|s| s.as_str()).collect();
+                    let kind = match args.as_slice() {
+                        ["fork"] => EventKind::Fork,
+                        ["vfork"] => EventKind::Vfork,
+                        ["exec"] => EventKind::Exec,
+                        ["exit"] => EventKind::Exit,
+                        ["syscall"] => EventKind::Syscall(None),
+                        ["syscall", name] => {
+                            match syscall::number(name).or_else(|| name.parse().ok()) {
+                                Some(number) => EventKind::Syscall(Some(number)),
+                                None => {
+                                    println!("Unknown syscall name '{}'.", name);
+                                    continue;
+                                }
+                            }
+                        }
+                        _ => {
+                            println!("Usage: catch fork|vfork|exec|exit|syscall [name]");
+                            continue;
+                        }
+                    };
+                    self.breakpoints.push(Some(UserBreakpoint {
+                        addr: 0,
+                        enabled: true,
+                        watch: None,
+                        library_function: None,
+                        catch: Some(Catchpoint { kind, hits: 0 }),
+                    }));
+                    println!("Catchpoint {} ({})", self.breakpoints.len() - 1, kind);
+                    self.update_caught_events();
+                }
+                DebuggerCommand::DeleteBreakpoint(arg) => {
+                    if let Some(idx) = self.parse_breakpoint_number(arg, "delete") {
+                        self.disarm(idx);
+                        self.breakpoints[idx] = None;
+                        self.update_caught_events();
+                        println!("Deleted breakpoint {}", idx);
+                    }
+                }
+                DebuggerCommand::EnableBreakpoint(arg) => {
+                    if let Some(idx) = self.parse_breakpoint_number(arg, "enable") {
+                        let registers_full = self.watchpoints_in_use() == NUM_WATCH_SLOTS;
+                        let breakpoint = self.breakpoints[idx].as_mut().unwrap();
+                        if !breakpoint.enabled {
+                            if breakpoint.watch.is_some() && registers_full {
+                                println!(
+                                    "No free hardware watchpoint registers ({} in use)",
+                                    NUM_WATCH_SLOTS
+                                );
+                                continue;
+                            }
+                            breakpoint.enabled = true;
+                            if breakpoint.watch.is_some() {
+                                self.arm_watchpoint(idx);
+                            } else {
+                                let addr = breakpoint.addr;
+                                self.add_breakpoint_to_process(addr);
+                            }
+                            self.update_caught_events();
+                        }
+                    }
+                }
+                DebuggerCommand::DisableBreakpoint(arg) => {
+                    if let Some(idx) = self.parse_breakpoint_number(arg, "disable") {
+                        if self.breakpoints[idx].as_ref().unwrap().enabled {
+                            self.disarm(idx);
+                            self.breakpoints[idx].as_mut().unwrap().enabled = false;
+                            self.update_caught_events();
+                        }
+                    }
+                }
+                DebuggerCommand::Set(args) => match args.split_first() {
+                    Some((setting, values)) if setting == "args" => {
+                        let values: Vec<&str> = values.iter().map(|s| s.as_str()).collect();
+                        self.run_args = RunArgs::parse(&values);
+                    }
+                    Some((setting, values)) if setting == "var" || setting == "variable" => {
+                        self.assign(&values.join(" "));
+                    }
+                    Some((setting, _)) if setting.starts_with('{') => {
+                        self.assign(&args.join(" "));
+                    }
+                    Some((setting, values)) if settings::NAMES.contains(&setting.as_str()) => {
+                        if let Err(usage) = self.settings.set(setting, values) {
+                            println!("{}", usage);
+                        } else if let Some(inferior) = self.inferior.as_mut() {
+                            inferior.set_follow_fork_mode(self.settings.follow_fork_mode);
+                        }
+                    }
+                    _ => {
+                        println!("Usage: set args [arguments...]");
+                        println!("       set confirm on|off");
+                        println!("       set follow-fork-mode parent|child");
+                        println!("       set listsize <number of lines>");
+                        println!("       set output-radix 8|10|16");
+                        println!("       set style on|off");
+                        println!("       set var <variable> = <value>");
+                        println!("       set {{<type>}} <address> = <value>");
+                    }
+                },
+                DebuggerCommand::Show(setting) => match setting.as_deref() {
+                    Some("args") => println!(
+                        "Argument list to give program being debugged when it is started is \"{}\".",
+                        self.run_args
+                    ),
+                    Some("user") => self.print_user_commands(),
+                    Some(name) => match self.settings.show(name) {
+                        Some(description) => println!("{}", description),
+                        None => println!(
+                            "Usage: show [args|confirm|follow-fork-mode|listsize|output-radix|style|user]"
+                        ),
+                    },
+                    None => {
+                        for name in settings::NAMES.iter() {
+                            println!("{}:  {}", name, self.settings.show(name).unwrap());
+                        }
+                    }
+                },
+                DebuggerCommand::Checkpoint => {
+                    self.checkpoint();
+                }
+                DebuggerCommand::Restart(arg) => {
+                    self.restart(arg);
+                }
+                DebuggerCommand::DeleteCheckpoint(arg) => {
+                    let inferior = match self.inferior.as_mut() {
+                        Some(inferior) => inferior,
+                        None => {
+                            println!("Error: not tracking any process");
+                            continue;
+                        }
+                    };
+                    match arg.as_ref().map(|arg| arg.parse::<usize>()) {
+                        Some(Ok(idx)) if inferior.delete_checkpoint(idx) => {
+                            println!("Deleted checkpoint {}", idx)
+                        }
+                        Some(_) => println!("No checkpoint number {}", arg.unwrap()),
+                        None => println!("Usage: delete checkpoint <checkpoint number>"),
+                    }
+                }
+                DebuggerCommand::Define(name) => {
+                    self.define(name);
+                }
+                DebuggerCommand::Alias(definition) => {
+                    self.alias(definition);
+                }
+                DebuggerCommand::Target(args) => {
+                    self.target(args);
+                }
+                DebuggerCommand::Source(path) => match path {
+                    Some(path) => self.source(&path),
+                    None => println!("Usage: source <file>"),
+                },
+                DebuggerCommand::Record(arg) => {
+                    self.record(arg);
+                }
+                DebuggerCommand::Trace(arg) => {
+                    match arg.as_deref() {
+                        Some("on") => self.trace_syscalls = true,
+                        Some("off") => self.trace_syscalls = false,
+                        None => {
+                            let state = if self.trace_syscalls { "on" } else { "off" };
+                            println!("Tracing of system calls is {}.", state);
+                            continue;
+                        }
+                        _ => {
+                            println!("Usage: trace [on|off]");
+                            continue;
+                        }
+                    }
+                    if let Some(inferior) = self.inferior.as_mut() {
+                        inferior.set_trace_syscalls(self.trace_syscalls);
+                    }
+                }
+                DebuggerCommand::Print(text) => {
+                    self.print_expression(text);
+                }
+                DebuggerCommand::Examine(spec, address) => {
+                    self.examine(spec, address);
+                }
+                DebuggerCommand::Dump(args) => {
+                    self.dump_memory(&args);
+                }
+                DebuggerCommand::GenerateCore(file) => {
+                    self.generate_core(file);
+                }
+                DebuggerCommand::List(arg) => {
+                    self.list(arg);
+                }
+                DebuggerCommand::Info(args) => match args.first().map(|s| s.as_str()) {
+                    Some("b") | Some("break") | Some("breakpoints") => self.print_breakpoints(),
+                    Some("f") | Some("frame") => self.print_frame_info(),
+                    Some("functions") => self.print_functions(args.get(1).map(|s| s.as_str())),
+                    Some("proc") if args.get(1).map(|s| s.as_str()) == Some("mappings") => {
+                        self.print_mappings()
+                    }
+                    Some("checkpoints") => self.print_checkpoints(),
+                    Some("rec") | Some("record") => self.print_record_info(),
+                    _ => println!(
+                        "Usage: info break|checkpoints|frame|functions|proc mappings|record"
+                    ),
+                },
+            }
+        }
+    }
+
+    /// Returns the process events that enabled catchpoints stop at.
+    fn caught_events(&self) -> Vec<EventKind> {
+        self.breakpoints
+            .iter()
+            .flatten()
+            .filter(|breakpoint| breakpoint.enabled)
+            .filter_map(|breakpoint| Some(breakpoint.catch.as_ref()?.kind))
+            .collect()
+    }
+
+    /// Tells the inferior which process events to stop at, after catchpoints have changed.
+    fn update_caught_events(&mut self) {
+        let events = self.caught_events();
+        if let Some(inferior) = self.inferior.as_mut() {
+            inferior.set_caught_events(events);
+        }
+    }
+
+    fn add_breakpoint_to_process(&mut self, breakpoint: u64) {
+        // Breakpoints pending on a library being loaded have nowhere to go yet, and catchpoints
+        // have no address at all
+        if let Some(inferior) = self.inferior.as_mut().filter(|_| breakpoint != 0) {
+            inferior.add_breakpoint(breakpoint);
+        }
+    }
+
+    /// Takes breakpoint idx out of the inferior: its trap instruction, or its debug register if it
+    /// is a watchpoint.
+    fn disarm(&mut self, idx: usize) {
+        let breakpoint = self.breakpoints[idx].as_mut().unwrap();
+        if !breakpoint.enabled {
+            return;
+        }
+        let addr = breakpoint.addr;
+        if let Some(watch) = &mut breakpoint.watch {
+            if let (Some(slot), Some(inferior)) = (watch.slot.take(), self.inferior.as_mut()) {
+                inferior.clear_watchpoint(slot);
+            }
+            return;
+        }
+        // Another breakpoint at the same address still needs the trap
+        let still_needed = self.breakpoints.iter().enumerate().any(|(other, bp)| {
+            other != idx
+                && bp
+                    .as_ref()
+                    .is_some_and(|bp| bp.enabled && bp.watch.is_none() && bp.addr == addr)
+        });
+        if still_needed || addr == 0 {
+            return;
+        }
+        if let Some(inferior) = self.inferior.as_mut() {
+            inferior.remove_breakpoint(addr);
+        }
+    }
+
+    /// Sets a watchpoint on a global variable or on memory given as *0xADDR, optionally followed
+    /// by the number of bytes to watch (1, 2, 4 or 8). Raw addresses are watched for as many bytes
+    /// as their alignment allows, up to 8.
+    fn add_watchpoint(&mut self, kind: WatchKind, args: Vec<String>) {
+        let expr = match args.first() {
+            Some(expr) => expr.clone(),
+            None => {
+                println!("Usage: watch <variable> | watch *0xADDR [bytes]");
+                return;
+            }
+        };
+        let target = if expr.to_lowercase().starts_with("*0x") {
+            u64::from_str_radix(&expr[3..], 16).ok().and_then(|addr| {
+                let len = match args.get(1) {
+                    Some(len) => len.parse::<usize>().ok()?,
+                    None => [8, 4, 2, 1]
+                        .iter()
+                        .copied()
+                        .find(|len| addr % *len as u64 == 0)
+                        .unwrap(),
+                };
+                Some((addr, len))
+            })
+        } else {
+            self.debug_data
+                .get_global_variable(&expr)
+                .and_then(|var| match var.location {
+                    Location::Address(addr) => Some((
+                        (addr + self.debug_data.load_bias()) as u64,
+                        var.entity_type.size,
+                    )),
+                    Location::FramePointerOffset(_) => None,
+                })
+        };
+        let global = !expr.starts_with('*');
+        let (addr, len) = match target {
+            Some(target) => target,
+            None => {
+                println!("Doesn't match an address or a global variable");
+                return;
+            }
+        };
+        if ![1, 2, 4, 8].contains(&len) || addr % len as u64 != 0 {
+            println!("Can only watch 1, 2, 4 or 8 bytes aligned to their size");
+            return;
+        }
+        if self.watchpoints_in_use() == NUM_WATCH_SLOTS {
+            println!(
+                "No free hardware watchpoint registers ({} in use)",
+                NUM_WATCH_SLOTS
+            );
+            return;
+        }
+        let watch = Watchpoint {
+            kind,
+            len,
+            expr,
+            global,
+            slot: None,
+            value: 0,
+            hits: 0,
+        };
+        println!(
+            "{} {}: {}",
+            watch.describe(),
+            self.breakpoints.len(),
+            watch.expr
+        );
+        self.breakpoints.push(Some(UserBreakpoint {
+            addr,
+            enabled: true,
+            watch: Some(watch),
+            library_function: None,
+            catch: None,
+        }));
+        self.arm_watchpoint(self.breakpoints.len() - 1);
+    }
+
+    /// Returns the number of enabled watchpoints, each of which needs a debug register.
+    fn watchpoints_in_use(&self) -> usize {
+        self.breakpoints
+            .iter()
+            .flatten()
+            .filter(|bp| bp.enabled && bp.watch.is_some())
+            .count()
+    }
+
+    /// Puts breakpoint idx in a debug register of the inferior, if it is an enabled watchpoint,
+    /// and remembers the current value of the watched memory.
+    fn arm_watchpoint(&mut self, idx: usize) {
+        let (inferior, breakpoint) = match (self.inferior.as_mut(), self.breakpoints[idx].as_mut())
+        {
+            (Some(inferior), Some(breakpoint)) if breakpoint.enabled => (inferior, breakpoint),
+            _ => return,
+        };
+        let addr = breakpoint.addr;
+        let watch = match &mut breakpoint.watch {
+            Some(watch) => watch,
+            None => return,
+        };
+        watch.hits = 0;
+        match inferior.set_watchpoint(addr, watch.len, watch.kind) {
+            Ok(slot) => {
+                watch.slot = Some(slot);
+                watch.value = inferior.read_value(addr, watch.len).unwrap_or(0);
+            }
+            Err(err) => println!("WARNING: Cannot set watchpoint {}: {}", idx, err),
+        }
+    }
+
+    /// Reads the values watchpoints compare against again, after the inferior's memory has been
+    /// put back to what it was by going backwards.
+    fn refresh_watchpoint_values(&mut self) {
+        let inferior = self.inferior.as_ref().unwrap();
+        for breakpoint in self.breakpoints.iter_mut().flatten() {
+            let addr = breakpoint.addr;
+            if let Some(watch) = breakpoint.watch.as_mut() {
+                watch.value = inferior.read_value(addr, watch.len).unwrap_or(0);
+            }
+        }
+    }
+
+    /// Reports the watchpoints that stopped the inferior. Returns None if no watchpoint did, and
+    /// Some(false) if the stop doesn't count and the inferior should just be continued: like gdb,
+    /// write watchpoints only stop when the value changes, and since x86 read watchpoints also
+    /// trap on writes, a read watchpoint whose value changed saw a write rather than a read.
+    fn check_watchpoints(&mut self) -> Option<bool> {
+        let inferior = self.inferior.as_mut().unwrap();
+        let slots = inferior.triggered_watchpoints();
+        if slots.is_empty() {
+            return None;
+        }
+        let mut stop = false;
+        for (idx, breakpoint) in self.breakpoints.iter_mut().enumerate() {
+            let addr = match breakpoint {
+                Some(breakpoint) => breakpoint.addr,
+                None => continue,
+            };
+            let watch = match breakpoint.as_mut().and_then(|bp| bp.watch.as_mut()) {
+                Some(watch) if watch.slot.is_some_and(|slot| slots.contains(&slot)) => watch,
+                _ => continue,
+            };
+            let value = inferior.read_value(addr, watch.len).unwrap_or(0);
+            let changed = value != watch.value;
+            let old_value = watch.value;
+            watch.value = value;
+            match watch.kind {
+                WatchKind::Write if !changed => continue,
+                WatchKind::Read if changed => continue,
+                _ => {}
+            }
+            stop = true;
+            watch.hits += 1;
+            let banner = format!("{} {}: {}", watch.describe(), idx, watch.expr);
+            println!("\n{}", self.settings.paint(Style::Banner, &banner));
+            if changed {
+                let old_value = self.settings.format_integer(old_value, watch.len, true);
+                println!("\nOld value = {}", old_value);
+                println!(
+                    "New value = {}",
+                    self.settings.format_integer(value, watch.len, true)
+                );
+            } else {
+                let value = self.settings.format_integer(value, watch.len, true);
+                println!("\nValue = {}", value);
+            }
+        }
+        Some(stop)
+    }
+
+    /// Parses the breakpoint number given to a command, printing an error and returning None if
+    /// it is missing or there is no such breakpoint.
+    fn parse_breakpoint_number(&self, arg: Option<String>, command: &str) -> Option<usize> {
+        let arg = match arg {
+            Some(arg) => arg,
+            None => {
+                println!("Usage: {} <breakpoint number>", command);
+                return None;
+            }
+        };
+        match arg.parse::<usize>() {
+            Ok(idx) if self.breakpoints.get(idx).is_some_and(|bp| bp.is_some()) => Some(idx),
+            _ => {
+                println!("No breakpoint number {}", arg);
+                None
+            }
+        }
+    }
+
+    /// Prints every breakpoint with its location, whether it is enabled and how many times the
+    /// current inferior has hit it.
+    fn print_breakpoints(&self) {
+        if self.breakpoints.iter().flatten().next().is_none() {
+            println!("No breakpoints.");
+            return;
+        }
+        println!("{:<5}{:<5}{:<20}{:<6}What", "Num", "Enb", "Address", "Hits");
+        for (idx, breakpoint) in self.breakpoints.iter().enumerate() {
+            let breakpoint = match breakpoint {
+                Some(breakpoint) => breakpoint,
+                None => continue,
+            };
+            let addr = breakpoint.addr as usize;
+            let debug_data = self.debug_data_at(addr);
+            let function = debug_data
+                .get_function_from_addr(addr)
+                .unwrap_or(String::from("??"));
+            let what = match debug_data.get_line_from_addr(addr) {
+                Some(line) => format!("{} at {}", function, line),
+                None => function,
+            };
+            let what = match &breakpoint.watch {
+                Some(watch) => format!("{} {}", watch.describe(), watch.expr),
+                None => what,
+            };
+            let hits = match (&self.inferior, &breakpoint.watch) {
+                (Some(_), Some(watch)) => watch.hits,
+                (Some(inferior), None) => inferior.get_hit_count(breakpoint.addr),
+                (None, _) => 0,
+            };
+            let hits = match (&self.inferior, &breakpoint.catch) {
+                (Some(_), Some(catch)) => catch.hits,
+                _ => hits,
+            };
+            let (address, what) = match (&breakpoint.library_function, &breakpoint.catch) {
+                (Some(function), _) if breakpoint.addr == 0 => {
+                    (String::from("<PENDING>"), function.clone())
+                }
+                (_, Some(catch)) => (String::new(), format!("Catchpoint {}", catch.kind)),
+                _ => (format!("{:#x}", breakpoint.addr), what),
+            };
+            println!(
+                "{:<5}{:<5}{:<20}{:<6}{}",
+                idx,
+                if breakpoint.enabled { "y" } else { "n" },
+                address,
+                hits,
+                what
+            );
+        }
+    }
+
+    /// Prints the functions in the executable and the shared libraries loaded so far that have
+    /// debugging information, by source file. With a search string, prints only the functions
+    /// whose names contain it.
+    fn print_functions(&self, search: Option<&str>) {
+        match search {
+            Some(search) => println!("All functions matching \"{}\":", search),
+            None => println!("All defined functions:"),
+        }
+        let files = std::iter::once(&self.debug_data)
+            .chain(
+                self.libraries
+                    .iter()
+                    .filter_map(|library| library.debug_data.as_ref()),
+            )
+            .flat_map(|debug_data| debug_data.get_functions_by_file());
+        for (file, functions) in files {
+            let mut functions: Vec<(usize, String)> = functions
+                .iter()
+                .map(|func| (func.line_number, func.full_name()))
+                .filter(|(_, name)| search.is_none_or(|search| name.contains(search)))
+                .collect();
+            if functions.is_empty() {
+                continue;
+            }
+            functions.sort_by(|a, b| a.1.cmp(&b.1));
+            println!("\nFile {}:", file);
+            for (line_number, name) in functions {
+                println!("{}:\t{};", line_number, name);
+            }
+        }
+    }
+
+    /// Prints the regions of memory mapped into the inferior, with their permissions and the files
+    /// they map, for info proc mappings.
+    fn print_mappings(&self) {
+        let pid = match self.inferior.as_ref() {
+            Some(inferior) if inferior.is_remote() => {
+                println!("Can't read the memory map of a remote process.");
+                return;
+            }
+            Some(inferior) => inferior.pid(),
+            None => {
+                println!("No current process.");
+                return;
+            }
+        };
+        let mappings = match library::read_mappings(pid) {
+            Ok(mappings) => mappings,
+            Err(err) => {
+                println!("Could not read the memory map of process {}: {}", pid, err);
+                return;
+            }
+        };
+        println!("process {}", pid);
+        println!("Mapped address spaces:\n");
+        println!(
+            "{:>18} {:>18} {:>10} {:>10}  {:<5}  objfile",
+            "Start Addr", "End Addr", "Size", "Offset", "Perms"
+        );
+        for mapping in mappings {
+            let line = format!(
+                "{:>18} {:>18} {:>10} {:>10}  {:<5}  {}",
+                format!("{:#x}", mapping.start),
+                format!("{:#x}", mapping.end),
+                format!("{:#x}", mapping.end - mapping.start),
+                format!("{:#x}", mapping.offset),
+                mapping.perms,
+                mapping.path
+            );
+            // Anonymous memory has no path to end the line
+            println!("{}", line.trim_end());
+        }
+    }
+
+    /// Prints list_size numbered lines of source for list: centered on a line given as N or
+    /// FILE:N, or on the start of a function. Without an argument, list continues after the lines
+    /// it printed last, or else centers on where the selected frame is stopped, or on main if
+    /// there is no inferior.
+    fn list(&mut self, arg: Option<String>) {
+        let (file, first) = match (arg, self.list_next.clone()) {
+            (None, Some(next)) => next,
+            (arg, _) => {
+                let location = match arg {
+                    Some(arg) => self.parse_source_location(&arg),
+                    None => self
+                        .stop_line()
+                        .or_else(|| self.function_line("main"))
+                        .ok_or_else(|| String::from("No default source file.")),
+                };
+                match location {
+                    Ok(line) => {
+                        let half = self.settings.list_size / 2;
+                        let first = line.number.saturating_sub(half).max(1);
+                        (line.file, first)
+                    }
+                    Err(message) => {
+                        println!("{}", message);
+                        return;
+                    }
+                }
+            }
+        };
+        let lines = match self.read_source(&file) {
+            Ok(lines) => lines,
+            Err(err) => {
+                println!("{}: {}", file, err);
+                return;
+            }
+        };
+        if first > lines.len() {
+            println!(
+                "Line number {} out of range; \"{}\" has {} lines.",
+                first,
+                file,
+                lines.len()
+            );
+            return;
+        }
+        let last = (first + self.settings.list_size - 1).min(lines.len());
+        for number in first..=last {
+            let number_text = self.settings.paint(Style::LineNumber, &number.to_string());
+            println!("{}\t{}", number_text, lines[number - 1]);
+        }
+        self.list_next = Some((file, last + 1));
+    }
+
+    /// Parses the argument of list: a line number in the file listed last, FILE:N, or a
+    /// function name.
+    fn parse_source_location(&self, location: &str) -> Result<Line, String> {
+        if let Ok(number) = location.parse::<usize>() {
+            let file = match &self.list_next {
+                Some((file, _)) => Some(file.clone()),
+                None => self
+                    .stop_line()
+                    .or_else(|| self.function_line("main"))
+                    .map(|line| line.file),
+            };
+            return match file {
+                Some(file) => Ok(Line {
+                    file,
+                    number,
+                    address: 0,
+                }),
+                None => Err(String::from("No default source file.")),
+            };
+        }
+        if let Some((file, number)) = location.rsplit_once(':') {
+            if let Ok(number) = number.parse::<usize>() {
+                return match self.source_file(file) {
+                    Some(file) => Ok(Line {
+                        file,
+                        number,
+                        address: 0,
+                    }),
+                    None => Err(format!("No source file named {}.", file)),
+                };
+            }
+        }
+        self.function_line(location)
+            .ok_or_else(|| format!("Function \"{}\" not defined.", location))
+    }
+
+    /// Returns the source line the selected frame is stopped at.
+    fn stop_line(&self) -> Option<Line> {
+        let pc = self.current_frame()?.pc as usize;
+        self.debug_data_at(pc).get_line_from_addr(pc)
+    }
+
+    /// Returns the source line where a function in the executable or a loaded library starts.
+    fn function_line(&self, name: &str) -> Option<Line> {
+        let addr = self
+            .debug_data
+            .get_addr_for_function(None, name)
+            .map(|addr| addr as u64)
+            .or_else(|| self.library_function_address(name))? as usize;
+        self.debug_data_at(addr).get_line_from_addr(addr)
+    }
+
+    /// Returns the path the debugging information gives for a source file named by the user,
+    /// who may leave out its directory.
+    fn source_file(&self, name: &str) -> Option<String> {
+        std::iter::once(&self.debug_data)
+            .chain(
+                self.libraries
+                    .iter()
+                    .filter_map(|library| library.debug_data.as_ref()),
+            )
+            .find_map(|debug_data| {
+                let addr = debug_data.get_addr_for_line(Some(name), 1)?;
+                Some(debug_data.get_line_from_addr(addr)?.file)
+            })
+    }
+
+    /// Reads the lines of a source file named in the debugging information. A relative path is
+    /// looked for in the current directory, and then next to the executable.
+    fn read_source(&self, file: &str) -> std::io::Result<Vec<String>> {
+        let path = std::path::Path::new(file);
+        let contents = match std::fs::read_to_string(path) {
+            Err(err) if path.is_relative() => {
+                let target_dir = std::path::Path::new(&self.target)
+                    .parent()
+                    .unwrap_or(std::path::Path::new(""));
+                std::fs::read_to_string(target_dir.join(path)).map_err(|_| err)?
+            }
+            contents => contents?,
+        };
+        Ok(contents.lines().map(|line| line.to_string()).collect())
+    }
+
+    fn get_next_command(&mut self) -> DebuggerCommand {
+        loop {
+            let queued = self.pending_commands.len();
+            self.user_calls.retain(|rest| *rest < queued);
+            let prompt = format!("{} ", self.settings.paint(Style::Prompt, "(deet)"));
+            let line = match self.read_line(&prompt) {
+                Some(line) => line,
+                None => return DebuggerCommand::Quit,
+            };
+            if line.trim_start().starts_with('#') {
+                continue;
+            }
+            let mut tokens: Vec<&str> = line.split_whitespace().collect();
+            let expanded;
+            if let Some(command) = self.aliases.get(tokens[0]) {
+                expanded = format!("{} {}", command, tokens[1..].join(" "));
+                tokens = expanded.split_whitespace().collect();
+            }
+            if let Some(body) = self.user_commands.get(tokens[0]) {
+                // A command that runs itself would otherwise never stop, so the lines left of
+                // every user command being run are dropped
+                if self.user_calls.len() == MAX_USER_CALL_DEPTH {
+                    println!("Max user call depth exceeded -- command aborted.");
+                    let rest = self.user_calls[0];
+                    self.pending_commands
+                        .drain(..self.pending_commands.len() - rest);
+                    self.user_calls.clear();
+                    continue;
+                }
+                self.user_calls.push(self.pending_commands.len());
+                for line in body.iter().rev() {
+                    self.pending_commands.push_front(line.clone());
+                }
+                continue;
+            }
+            if let Some(cmd) = DebuggerCommand::from_tokens(&tokens) {
+                return cmd;
+            } else {
+                println!("Unrecognized command.");
+            }
+        }
+    }
+
+    /// Reads the next line that isn't blank: from the queued commands if there are any, and
+    /// otherwise from the user, after showing prompt. Returns None at the end of the input, or
+    /// once batch mode has run all its commands.
+    fn read_line(&mut self, prompt: &str) -> Option<String> {
+        while let Some(line) = self.pending_commands.pop_front() {
+            if !line.trim().is_empty() {
+                self.typed_at_prompt = false;
+                return Some(line);
+            }
+        }
+        if self.batch {
+            return None;
+        }
+        loop {
+            match self.readline.readline(prompt) {
+                Err(ReadlineError::Interrupted) => {
+                    println!("Type \"quit\" to exit");
+                }
+                Err(ReadlineError::Eof) => {
+                    return None;
+                }
+                Err(err) => {
+                    panic!("Unexpected I/O error: {:?}", err);
+                }
+                Ok(line) => {
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    self.readline.add_history_entry(line.as_str());
+                    if let Err(err) = self.readline.save_history(&self.history_path) {
+                        println!(
+                            "Warning: failed to save history file at {}: {}",
+                            self.history_path, err
+                        );
+                    }
+                    self.typed_at_prompt = true;
+                    return Some(line);
+                }
+            }
+        }
+    }
+
+    /// Asks whether to start the inferior again, if it is running already.
+    fn confirm_restart(&mut self) -> bool {
+        self.inferior.is_none()
+            || self.confirm(
+                "The program being debugged has been started already.\nStart it from the beginning?",
+            )
+    }
+
+    /// Asks the user a yes or no question, unless set confirm is off, and returns true for yes.
+    /// Commands that weren't typed at the prompt don't ask, as though the answer was yes.
+    fn confirm(&mut self, question: &str) -> bool {
+        if !self.settings.confirm || !self.typed_at_prompt {
+            return true;
+        }
+        loop {
+            match self.readline.readline(&format!("{} (y or n) ", question)) {
+                Ok(answer) => match answer.trim() {
+                    "y" | "yes" => return true,
+                    "n" | "no" => return false,
+                    _ => println!("Please answer y or n."),
+                },
+                Err(ReadlineError::Interrupted) => return false,
+                Err(_) => {
+                    println!("EOF [answered Y; input not from terminal]");
+                    return true;
+                }
+            }
+        }
+    }
+
+    /// Defines a command that runs the lines that follow, up to one saying just "end". Commands
+    /// defined at the prompt are saved for later sessions.
+    fn define(&mut self, name: Option<String>) {
+        let at_prompt = self.typed_at_prompt;
+        if at_prompt {
+            println!(
+                "Type commands for definition of \"{}\".",
+                name.as_deref().unwrap_or("")
+            );
+            println!("End with a line saying just \"end\".");
+        }
+        // The body is read even if the command can't be defined, so that it isn't run instead
+        let mut body = Vec::new();
+        loop {
+            match self.read_line(">") {
+                Some(line) if line.trim() == "end" => break,
+                Some(line) => body.push(line.trim().to_string()),
+                None => return,
+            }
+        }
+        let name = match name {
+            Some(name) => name,
+            None => {
+                println!("Usage: define <name>");
+                return;
+            }
+        };
+        if DebuggerCommand::from_tokens(&vec![name.as_str()]).is_some() {
+            println!("Can't redefine the built-in command {}.", name);
+            return;
+        }
+        self.aliases.remove(&name);
+        self.user_commands.insert(name, body);
+        if at_prompt {
+            self.save_user_commands();
+        }
+    }
+
+    /// Defines an alias for a command, given as "name = command". The command can include
+    /// arguments, to which any given to the alias are added.
+    fn alias(&mut self, definition: Option<String>) {
+        let (name, command) = match definition.as_ref().and_then(|d| d.split_once('=')) {
+            Some((name, command)) if !name.trim().is_empty() && !command.trim().is_empty() => {
+                (name.trim().to_string(), command.trim().to_string())
+            }
+            _ => {
+                println!("Usage: alias <name> = <command>");
+                return;
+            }
+        };
+        if name.contains(char::is_whitespace) {
+            println!("Alias names can't contain spaces.");
+            return;
+        }
+        if DebuggerCommand::from_tokens(&vec![name.as_str()]).is_some() {
+            println!("Can't redefine the built-in command {}.", name);
+            return;
+        }
+        self.user_commands.remove(&name);
+        self.aliases.insert(name, command);
+        if self.typed_at_prompt {
+            self.save_user_commands();
+        }
+    }
+
+    /// Writes the user's commands and aliases to commands_path, as the define and alias commands
+    /// that define them.
+    fn save_user_commands(&self) {
+        let mut commands = String::new();
+        for (name, body) in &self.user_commands {
+            commands.push_str(&format!("define {}\n", name));
+            for line in body {
+                commands.push_str(&format!("  {}\n", line));
+            }
+            commands.push_str("end\n");
+        }
+        for (name, command) in &self.aliases {
+            commands.push_str(&format!("alias {} = {}\n", name, command));
+        }
+        if let Err(err) = std::fs::write(&self.commands_path, commands) {
+            println!(
+                "Warning: failed to save user commands at {}: {}",
+                self.commands_path, err
+            );
+        }
+    }
+
+    /// Prints the commands the user has defined, and the aliases.
+    fn print_user_commands(&self) {
+        for (name, body) in &self.user_commands {
+            println!("User command \"{}\":", name);
+            for line in body {
+                println!("  {}", line);
+            }
+            println!();
+        }
+        for (name, command) in &self.aliases {
+            println!("alias {} = {}", name, command);
+        }
+    }
+}
+
+/// Formats a value returned in %rax according to the function's return type.
+fn format_return_value(rax: u64, return_type: &Type, settings: &Settings) -> String {
+    let len = return_type.size.clamp(1, 8);
+    if return_type.name == "float" || return_type.name.contains("double") {
+        // Floating-point values are returned in %xmm0
+        String::from("(floating-point values aren't supported)")
+    } else {
+        let signed = !return_type.name.contains("unsigned") && return_type.name != "_Bool";
+        settings.format_integer(rax, len, signed)
+    }
+}
+
+/// Formats a value read from the inferior's memory, given as little-endian bytes, according to
+/// its type.
+fn format_value(bytes: &[u8], value_type: &Type, settings: &Settings) -> String {
+    if bytes.is_empty() || bytes.len() > 8 {
+        let words: Vec<String> = bytes.iter().map(|byte| format!("{:#04x}", byte)).collect();
+        return format!("{{{}}}", words.join(", "));
+    }
+    let value = bytes
+        .iter()
+        .rev()
+        .fold(0_u64, |value, byte| value << 8 | *byte as u64);
+    let name = value_type.name.as_str();
+    if name == "float" && bytes.len() == 4 {
+        format!("{}", f32::from_bits(value as u32))
+    } else if name.contains("double") && bytes.len() == 8 {
+        format!("{}", f64::from_bits(value))
+    } else if name == "_Bool" {
+        format!("{}", value != 0)
+    } else if name.ends_with("char") && bytes.len() == 1 {
+        let number = settings.format_integer(value, 1, name != "unsigned char");
+        format!("{} {}", number, examine::quote_char(value as u8))
+    } else {
+        settings.format_integer(value, bytes.len(), !name.contains("unsigned"))
+    }
+}
+
+/// Returns true for the char types, whose arrays and pointers are shown as strings.
+fn is_char(value_type: &Type) -> bool {
+    value_type.size == 1 && value_type.name.ends_with("char")
+}
+
+/// Returns the type with the given C name, for the builtin types set {type} can write.
+fn c_type(name: &str) -> Option<Type> {
+    let size = if name.ends_with('*') {
+        8
+    } else {
+        match name
+            .trim_start_matches("unsigned ")
+            .trim_start_matches("signed ")
+        {
+            "char" | "_Bool" | "int8_t" | "uint8_t" => 1,
+            "short" | "short int" | "int16_t" | "uint16_t" => 2,
+            "int" | "unsigned" | "float" | "int32_t" | "uint32_t" => 4,
+            "long" | "long int" | "long long" | "long long int" | "double" | "size_t"
+            | "int64_t" | "uint64_t" => 8,
+            _ => return None,
+        }
+    };
+    Some(Type::new(name.to_string(), size))
+}
+
+/// Encodes a number as the little-endian bytes of a value of the given type, converting it
+/// between integer and floating point as C assignment would.
+fn encode_value(value: Scalar, value_type: &Type) -> Result<Vec<u8>, String> {
+    let len = value_type.size;
+    let is_number = matches!(value_type.kind, TypeKind::Base | TypeKind::Pointer(_));
+    if !is_number || len == 0 || len > 8 {
+        return Err(format!("Can't write a value of type {}", value_type.name));
+    }
+    if value_type.name == "float" || value_type.name.contains("double") {
+        return Ok(match len {
+            4 => (value.as_float() as f32).to_le_bytes().to_vec(),
+            8 => value.as_float().to_le_bytes().to_vec(),
+            _ => return Err(format!("Can't write a {}-byte floating-point value", len)),
+        });
+    }
+    Ok(value.as_integer().to_le_bytes()[..len].to_vec())
+}
+
+impl expression::Context for Debugger {
+    fn variable(&self, name: &str) -> Option<(u64, Type)> {
+        let frame = self.current_frame()?;
+        self.find_variable(name)
+            .map(|var| (self.variable_address(var, &frame), var.entity_type.clone()))
+    }
+
+    fn register(&self, name: &str) -> Option<u64> {
+        let regs = self.inferior.as_ref()?.get_registers().ok()?;
+        if self.selected_frame > 0 {
+            // Registers that can be recovered for an outer frame
+            let frame = self.current_frame()?;
+            match name {
+                "rip" | "pc" => return Some(frame.pc),
+                "rsp" | "sp" => return Some(frame.cfa),
+                "rbp" | "fp" => return Some(frame.rbp),
+                _ => {}
+            }
+        }
+        register_value(&regs, name)
+    }
+
+    fn get_type(&self, offset: usize) -> Option<&Type> {
+        self.debug_data.get_type(offset)
+    }
+
+    fn type_offset(&self, name: &str) -> Option<usize> {
+        self.debug_data.get_type_offset(name)
+    }
+
+    fn read_memory(&self, addr: u64, len: usize) -> Option<Vec<u8>> {
+        self.inferior.as_ref()?.read_bytes(addr, len).ok()
+    }
+}
+
+/// Returns the value of the register with the given name (without the $), if there is one.
+fn register_value(regs: &libc::user_regs_struct, name: &str) -> Option<u64> {
+    Some(match name {
+        "rax" => regs.rax,
+        "rbx" => regs.rbx,
+        "rcx" => regs.rcx,
+        "rdx" => regs.rdx,
+        "rsi" => regs.rsi,
+        "rdi" => regs.rdi,
+        "rbp" | "fp" => regs.rbp,
+        "rsp" | "sp" => regs.rsp,
+        "r8" => regs.r8,
+        "r9" => regs.r9,
+        "r10" => regs.r10,
+        "r11" => regs.r11,
+        "r12" => regs.r12,
+        "r13" => regs.r13,
+        "r14" => regs.r14,
+        "r15" => regs.r15,
+        "rip" | "pc" => regs.rip,
+        "eflags" => regs.eflags,
+        _ => return None,
+    })
+}
+
+/// Returns true if a breakpoint location is taken by parse_address to be a function name, rather
+/// than a line number or an address.
+fn is_function_name(location: &str) -> bool {
+    !location.is_empty() && !location.starts_with('*') && u64::from_str_radix(location, 16).is_err()
+}
+
+fn parse_address(addr: &str, dwarf_data: &DwarfData) -> Option<u64> {
+    let mut is_hex = false;
+    let addr_without_0x = if addr.to_lowercase().starts_with("*0x") {
+        is_hex = true;
+        &addr[3..]
+    } else {
+        addr
+    };
+    match u64::from_str_radix(addr_without_0x, 16).ok() {
+        Some(val) => {
+            if is_hex {
+                return Some(val);
             }
+            dwarf_data
+                .get_addr_for_line(None, val as usize)
+                .map(|val| val as u64)
         }
+        None => dwarf_data
+            .get_addr_for_function(None, addr)
+            .map(|val| val as u64),
     }
 }
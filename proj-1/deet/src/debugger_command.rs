@@ -1,28 +1,246 @@
+use crate::inferior::WatchKind;
+use std::fmt;
+
 pub enum DebuggerCommand {
     Quit,
-    Run(Vec<String>),
+    Kill,
+    Run(RunArgs),
+    Start(RunArgs),
     Continue,
-    Backtrace,
-    AddBreakpoint(String)
+    Signal(Option<String>),
+    Next,
+    Finish,
+    StepInstruction,
+    NextInstruction,
+    ReverseStep,
+    ReverseStepInstruction,
+    Backtrace(Vec<String>),
+    Up(Option<String>),
+    Down(Option<String>),
+    Frame(Option<String>),
+    AddBreakpoint(String),
+    AddWatchpoint(WatchKind, Vec<String>),
+    AddCatchpoint(Vec<String>),
+    DeleteBreakpoint(Option<String>),
+    DeleteCheckpoint(Option<String>),
+    EnableBreakpoint(Option<String>),
+    DisableBreakpoint(Option<String>),
+    Info(Vec<String>),
+    List(Option<String>),
+    Dump(Vec<String>),
+    GenerateCore(Option<String>),
+    Print(Option<String>),
+    Examine(Option<String>, Option<String>),
+    Trace(Option<String>),
+    Checkpoint,
+    Restart(Option<String>),
+    Record(Option<String>),
+    Target(Vec<String>),
+    Source(Option<String>),
+    Define(Option<String>),
+    Alias(Option<String>),
+    Set(Vec<String>),
+    Show(Option<String>),
 }
 
 impl DebuggerCommand {
     pub fn from_tokens(tokens: &Vec<&str>) -> Option<DebuggerCommand> {
         match tokens[0] {
             "q" | "quit" => Some(DebuggerCommand::Quit),
-            "r" | "run" => {
-                let args = tokens[1..].to_vec();
-                Some(DebuggerCommand::Run(
-                    args.iter().map(|s| s.to_string()).collect(),
-                ))
-            },
+            "k" | "kill" => Some(DebuggerCommand::Kill),
+            "r" | "run" => Some(DebuggerCommand::Run(RunArgs::parse(&tokens[1..]))),
+            "start" => Some(DebuggerCommand::Start(RunArgs::parse(&tokens[1..]))),
             "c" | "cont" | "continue" => Some(DebuggerCommand::Continue),
-            "bt" | "back" | "backtrace" => Some(DebuggerCommand::Backtrace),
+            "signal" => Some(DebuggerCommand::Signal(
+                tokens.get(1).map(|s| s.to_string()),
+            )),
+            "n" | "next" => Some(DebuggerCommand::Next),
+            "fin" | "finish" => Some(DebuggerCommand::Finish),
+            "si" | "stepi" => Some(DebuggerCommand::StepInstruction),
+            "ni" | "nexti" => Some(DebuggerCommand::NextInstruction),
+            "rs" | "reverse-step" => Some(DebuggerCommand::ReverseStep),
+            "rsi" | "reverse-stepi" => Some(DebuggerCommand::ReverseStepInstruction),
+            "bt" | "back" | "backtrace" => Some(DebuggerCommand::Backtrace(
+                tokens[1..].iter().map(|s| s.to_string()).collect(),
+            )),
+            "thread"
+                if tokens.get(1..3) == Some(&["apply", "all"])
+                    && ["bt", "back", "backtrace"].contains(tokens.get(3).unwrap_or(&"")) =>
+            {
+                let mut args = vec![String::from("all")];
+                args.extend(tokens[4..].iter().map(|s| s.to_string()));
+                Some(DebuggerCommand::Backtrace(args))
+            }
+            "up" => Some(DebuggerCommand::Up(tokens.get(1).map(|s| s.to_string()))),
+            "down" => Some(DebuggerCommand::Down(tokens.get(1).map(|s| s.to_string()))),
+            "f" | "frame" => Some(DebuggerCommand::Frame(tokens.get(1).map(|s| s.to_string()))),
             "break" => {
-                let arg = tokens[1].to_string();
+                // C++ function names can have spaces in their parameter lists
+                let arg = tokens[1..].join(" ");
                 Some(DebuggerCommand::AddBreakpoint(arg))
             }
+            "watch" | "rwatch" | "awatch" => {
+                let kind = match tokens[0] {
+                    "watch" => WatchKind::Write,
+                    "rwatch" => WatchKind::Read,
+                    _ => WatchKind::Access,
+                };
+                Some(DebuggerCommand::AddWatchpoint(
+                    kind,
+                    tokens[1..].iter().map(|s| s.to_string()).collect(),
+                ))
+            }
+            "catch" => Some(DebuggerCommand::AddCatchpoint(
+                tokens[1..].iter().map(|s| s.to_string()).collect(),
+            )),
+            "d" | "delete" if tokens.get(1) == Some(&"checkpoint") => Some(
+                DebuggerCommand::DeleteCheckpoint(tokens.get(2).map(|s| s.to_string())),
+            ),
+            "d" | "delete" => Some(DebuggerCommand::DeleteBreakpoint(
+                tokens.get(1).map(|s| s.to_string()),
+            )),
+            "enable" => Some(DebuggerCommand::EnableBreakpoint(
+                tokens.get(1).map(|s| s.to_string()),
+            )),
+            "disable" => Some(DebuggerCommand::DisableBreakpoint(
+                tokens.get(1).map(|s| s.to_string()),
+            )),
+            "p" | "print" => Some(DebuggerCommand::Print(
+                Some(tokens[1..].join(" ")).filter(|name| !name.is_empty()),
+            )),
+            "checkpoint" => Some(DebuggerCommand::Checkpoint),
+            "restart" => Some(DebuggerCommand::Restart(
+                tokens.get(1).map(|s| s.to_string()),
+            )),
+            "rec" | "record" => Some(DebuggerCommand::Record(
+                tokens.get(1).map(|s| s.to_string()),
+            )),
+            "target" => Some(DebuggerCommand::Target(
+                tokens[1..].iter().map(|s| s.to_string()).collect(),
+            )),
+            "source" => Some(DebuggerCommand::Source(
+                Some(tokens[1..].join(" ")).filter(|path| !path.is_empty()),
+            )),
+            "define" => Some(DebuggerCommand::Define(
+                tokens.get(1).map(|s| s.to_string()),
+            )),
+            "alias" => Some(DebuggerCommand::Alias(
+                Some(tokens[1..].join(" ")).filter(|definition| !definition.is_empty()),
+            )),
+            "trace" => Some(DebuggerCommand::Trace(tokens.get(1).map(|s| s.to_string()))),
+            "set" => Some(DebuggerCommand::Set(
+                tokens[1..].iter().map(|s| s.to_string()).collect(),
+            )),
+            "show" => Some(DebuggerCommand::Show(tokens.get(1).map(|s| s.to_string()))),
+            _ if tokens[0] == "x" || tokens[0].starts_with("x/") => {
+                let address = tokens[1..].join(" ");
+                Some(DebuggerCommand::Examine(
+                    tokens[0].strip_prefix("x/").map(|spec| spec.to_string()),
+                    Some(address).filter(|address| !address.is_empty()),
+                ))
+            }
+            "i" | "info" => Some(DebuggerCommand::Info(
+                tokens[1..].iter().map(|s| s.to_string()).collect(),
+            )),
+            "dump" => Some(DebuggerCommand::Dump(
+                tokens[1..].iter().map(|s| s.to_string()).collect(),
+            )),
+            "gcore" => Some(DebuggerCommand::GenerateCore(
+                tokens.get(1).map(|s| s.to_string()),
+            )),
+            "l" | "list" => Some(DebuggerCommand::List(
+                Some(tokens[1..].join(" ")).filter(|location| !location.is_empty()),
+            )),
             _ => None,
         }
     }
 }
+
+/// What the inferior is run with: its arguments, and files to connect its standard input and
+/// output to instead of deet's
+#[derive(Clone, Debug, Default)]
+pub struct RunArgs {
+    pub args: Vec<String>,
+    pub stdin: Option<String>,
+    pub stdout: Option<String>,
+}
+
+impl RunArgs {
+    /// Parses arguments given to run, which may include redirections like a shell's: `< file`
+    /// and `> file`, with or without a space before the file.
+    pub fn parse(tokens: &[&str]) -> RunArgs {
+        let mut run_args = RunArgs::default();
+        let mut tokens = tokens.iter();
+        while let Some(token) = tokens.next() {
+            let (redirect, file) = match token.chars().next() {
+                Some(redirect @ '<') | Some(redirect @ '>') => (redirect, &token[1..]),
+                _ => {
+                    run_args.args.push(token.to_string());
+                    continue;
+                }
+            };
+            let file = if file.is_empty() {
+                match tokens.next() {
+                    Some(file) => file,
+                    None => {
+                        // Nothing to redirect to; pass it on as an ordinary argument
+                        run_args.args.push(token.to_string());
+                        continue;
+                    }
+                }
+            } else {
+                file
+            };
+            if redirect == '<' {
+                run_args.stdin = Some(file.to_string());
+            } else {
+                run_args.stdout = Some(file.to_string());
+            }
+        }
+        run_args
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.args.is_empty() && self.stdin.is_none() && self.stdout.is_none()
+    }
+}
+
+impl fmt::Display for RunArgs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut words = self.args.clone();
+        if let Some(stdin) = &self.stdin {
+            words.push(format!("< {}", stdin));
+        }
+        if let Some(stdout) = &self.stdout {
+            words.push(format!("> {}", stdout));
+        }
+        write!(f, "{}", words.join(" "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_arguments_and_redirections() {
+        let run_args = RunArgs::parse(&["-v", "<", "in.txt", ">out.txt", "file"]);
+        assert_eq!(run_args.args, vec!["-v", "file"]);
+        assert_eq!(run_args.stdin.as_deref(), Some("in.txt"));
+        assert_eq!(run_args.stdout.as_deref(), Some("out.txt"));
+        assert_eq!(run_args.to_string(), "-v file < in.txt > out.txt");
+    }
+
+    #[test]
+    fn redirection_without_a_file_is_an_argument() {
+        let run_args = RunArgs::parse(&["a", ">"]);
+        assert_eq!(run_args.args, vec!["a", ">"]);
+        assert_eq!(run_args.stdout, None);
+    }
+
+    #[test]
+    fn no_arguments_is_empty() {
+        assert!(RunArgs::parse(&[]).is_empty());
+        assert!(!RunArgs::parse(&["<in.txt"]).is_empty());
+    }
+}
@@ -1,9 +1,14 @@
 use crate::gimli_wrapper;
 use addr2line::Context;
 use object::Object;
+use std::collections::HashMap;
 use std::convert::TryInto;
+use std::path::Path;
 use std::{fmt, fs};
 
+/// Where distributions install separate debugging information files
+const DEBUG_DIR: &str = "/usr/lib/debug";
+
 #[derive(Debug)]
 pub enum Error {
     ErrorOpeningFile,
@@ -13,6 +18,10 @@ pub enum Error {
 pub struct DwarfData {
     files: Vec<File>,
     addr2line: Context<addr2line::gimli::EndianRcSlice<addr2line::gimli::RunTimeEndian>>,
+    /// How far a position-independent executable was moved from the addresses in its debugging
+    /// information when it was loaded. Addresses passed to and returned from the lookups below
+    /// are the ones in the running process, so they are translated by it.
+    load_bias: usize,
 }
 
 impl fmt::Debug for DwarfData {
@@ -31,19 +40,35 @@ impl DwarfData {
     pub fn from_file(path: &str) -> Result<DwarfData, Error> {
         let file = fs::File::open(path).or(Err(Error::ErrorOpeningFile))?;
         let mmap = unsafe { memmap::Mmap::map(&file).or(Err(Error::ErrorOpeningFile))? };
-        let object = object::File::parse(&*mmap)
-            .or_else(|e| Err(gimli_wrapper::Error::ObjectError(e.to_string())))?;
+        let object = object::File::parse(&mmap)
+            .map_err(|e| gimli_wrapper::Error::ObjectError(e.to_string()))?;
         let endian = if object.is_little_endian() {
             gimli::RunTimeEndian::Little
         } else {
             gimli::RunTimeEndian::Big
         };
+        // A stripped binary may have had its debugging information moved to a separate file
+        if object.section_data_by_name(".debug_info").is_none() {
+            if let Some(debug_path) = find_debug_file(path, &object) {
+                return DwarfData::from_file(&debug_path);
+            }
+        }
         Ok(DwarfData {
             files: gimli_wrapper::load_file(&object, endian)?,
-            addr2line: Context::new(&object).or_else(|e| Err(gimli_wrapper::Error::from(e)))?,
+            addr2line: Context::new(&object).map_err(gimli_wrapper::Error::from)?,
+            load_bias: 0,
         })
     }
 
+    pub fn load_bias(&self) -> usize {
+        self.load_bias
+    }
+
+    /// Sets where the executable was loaded, once a process running it has started.
+    pub fn set_load_bias(&mut self, load_bias: usize) {
+        self.load_bias = load_bias;
+    }
+
     #[allow(dead_code)]
     fn get_target_file(&self, file: &str) -> Option<&File> {
         self.files.iter().find(|f| {
@@ -55,14 +80,15 @@ impl DwarfData {
     pub fn get_addr_for_line(&self, file: Option<&str>, line_number: usize) -> Option<usize> {
         let target_file = match file {
             Some(filename) => self.get_target_file(filename)?,
-            None => self.files.get(0)?,
+            None => self.files.first()?,
         };
         Some(
             target_file
                 .lines
                 .iter()
                 .find(|line| line.number >= line_number)?
-                .address,
+                .address
+                + self.load_bias,
         )
     }
 
@@ -73,13 +99,15 @@ impl DwarfData {
                 self.get_target_file(filename)?
                     .functions
                     .iter()
-                    .find(|func| func.name == func_name)?
-                    .address,
+                    .find(|func| func.is_named(func_name))?
+                    .address
+                    + self.load_bias,
             ),
             None => {
                 for file in &self.files {
-                    if let Some(func) = file.functions.iter().find(|func| func.name == func_name) {
-                        return Some(func.address);
+                    let func = file.functions.iter().find(|func| func.is_named(func_name));
+                    if let Some(func) = func {
+                        return Some(func.address + self.load_bias);
                     }
                 }
                 None
@@ -87,11 +115,48 @@ impl DwarfData {
         }
     }
 
+    #[allow(dead_code)]
+    pub fn get_global_variable(&self, name: &str) -> Option<&Variable> {
+        self.files
+            .iter()
+            .flat_map(|file| file.global_variables.iter())
+            .find(|var| var.name == name)
+    }
+
+    /// Returns the type at the given offset in the debugging information, which is how types
+    /// refer to the types they are made of.
+    pub fn get_type(&self, offset: usize) -> Option<&Type> {
+        self.files.iter().find_map(|file| file.types.get(&offset))
+    }
+
+    /// Returns the offset of a type with the given name.
+    pub fn get_type_offset(&self, name: &str) -> Option<usize> {
+        self.files.iter().find_map(|file| {
+            file.types
+                .iter()
+                .find(|(_, file_type)| file_type.name == name)
+                .map(|(offset, _)| *offset)
+        })
+    }
+
+    /// Returns the global variable whose storage contains addr.
+    #[allow(dead_code)]
+    pub fn get_global_variable_at(&self, addr: usize) -> Option<&Variable> {
+        let addr = addr.wrapping_sub(self.load_bias);
+        self.files
+            .iter()
+            .flat_map(|file| file.global_variables.iter())
+            .find(|var| match var.location {
+                Location::Address(start) => start <= addr && addr < start + var.entity_type.size,
+                Location::FramePointerOffset(_) => false,
+            })
+    }
+
     #[allow(dead_code)]
     pub fn get_line_from_addr(&self, curr_addr: usize) -> Option<Line> {
         let location = self
             .addr2line
-            .find_location(curr_addr.try_into().unwrap())
+            .find_location(curr_addr.wrapping_sub(self.load_bias).try_into().unwrap())
             .ok()??;
         Some(Line {
             file: location.file?.to_string(),
@@ -100,15 +165,43 @@ impl DwarfData {
         })
     }
 
+    /// Returns the function whose code contains addr.
+    #[allow(dead_code)]
+    pub fn get_function_containing(&self, addr: usize) -> Option<&Function> {
+        let addr = addr.wrapping_sub(self.load_bias);
+        self.files
+            .iter()
+            .flat_map(|file| file.functions.iter())
+            .find(|func| func.address <= addr && addr < func.address + func.text_length)
+    }
+
+    /// Returns true if addr is the first instruction of a line, rather than somewhere in the
+    /// middle of one.
+    #[allow(dead_code)]
+    pub fn is_line_start(&self, addr: usize) -> bool {
+        let addr = addr.wrapping_sub(self.load_bias);
+        self.files
+            .iter()
+            .any(|file| file.lines.iter().any(|line| line.address == addr))
+    }
+
     #[allow(dead_code)]
     pub fn get_function_from_addr(&self, curr_addr: usize) -> Option<String> {
         let frame = self
             .addr2line
-            .find_frames(curr_addr.try_into().unwrap())
+            .find_frames(curr_addr.wrapping_sub(self.load_bias).try_into().unwrap())
             .ok()?
             .next()
             .ok()??;
-        Some(frame.function?.raw_name().ok()?.to_string())
+        Some(demangle(&frame.function?.raw_name().ok()?))
+    }
+
+    /// Returns the functions defined in each source file, by file name.
+    pub fn get_functions_by_file(&self) -> Vec<(&str, &[Function])> {
+        self.files
+            .iter()
+            .map(|file| (file.name.as_str(), file.functions.as_slice()))
+            .collect()
     }
 
     #[allow(dead_code)]
@@ -148,21 +241,132 @@ impl DwarfData {
     }
 }
 
+/// Demangles a Rust or C++ symbol name, leaving other names (such as C's) as they are. Rust names
+/// lose the hash at their end, as in the backtraces Rust programs print.
+pub fn demangle(name: &str) -> String {
+    if let Ok(demangled) = rustc_demangle::try_demangle(name) {
+        return format!("{:#}", demangled);
+    }
+    if name.starts_with("_Z") {
+        if let Ok(symbol) = cpp_demangle::Symbol::new(name) {
+            return symbol.to_string();
+        }
+    }
+    name.to_string()
+}
+
+/// Looks for a binary's separate debugging information file: by its build ID under
+/// /usr/lib/debug/.build-id, and then by the name in its .gnu_debuglink section, next to the
+/// binary, in a .debug directory next to it, and under /usr/lib/debug. As in gdb, a file found by
+/// name must have the checksum recorded in .gnu_debuglink.
+fn find_debug_file(path: &str, object: &object::File) -> Option<String> {
+    if let Some(build_id) = object
+        .section_data_by_name(".note.gnu.build-id")
+        .and_then(|note| parse_build_id(&note))
+    {
+        let hex: String = build_id
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        if hex.len() > 2 {
+            let debug_path = format!("{}/.build-id/{}/{}.debug", DEBUG_DIR, &hex[..2], &hex[2..]);
+            if Path::new(&debug_path).is_file() {
+                return Some(debug_path);
+            }
+        }
+    }
+
+    let link = object.section_data_by_name(".gnu_debuglink")?;
+    let name_len = link.iter().position(|byte| *byte == 0)?;
+    let name = std::str::from_utf8(&link[..name_len]).ok()?;
+    // The name is padded to a multiple of 4 bytes and followed by the checksum
+    let crc_offset = (name_len + 4) & !3;
+    let crc = u32::from_le_bytes(link.get(crc_offset..crc_offset + 4)?.try_into().ok()?);
+    let dir = fs::canonicalize(path).ok()?.parent()?.to_path_buf();
+    let candidates = [
+        dir.join(name),
+        dir.join(".debug").join(name),
+        Path::new(DEBUG_DIR)
+            .join(dir.strip_prefix("/").ok()?)
+            .join(name),
+    ];
+    candidates
+        .iter()
+        .find(|candidate| fs::read(candidate).is_ok_and(|data| crc32(&data) == crc))
+        .map(|candidate| candidate.to_string_lossy().into_owned())
+}
+
+/// Returns the build ID in a .note.gnu.build-id section.
+fn parse_build_id(note: &[u8]) -> Option<Vec<u8>> {
+    let word = |offset: usize| -> Option<usize> {
+        Some(u32::from_le_bytes(note.get(offset..offset + 4)?.try_into().ok()?) as usize)
+    };
+    // NT_GNU_BUILD_ID
+    if word(8)? != 3 {
+        return None;
+    }
+    // The owner name ("GNU") is padded to a multiple of 4 bytes, and followed by the ID
+    let start = 12 + ((word(0)? + 3) & !3);
+    Some(note.get(start..start + word(4)?)?.to_vec())
+}
+
+/// Computes the CRC-32 that .gnu_debuglink records for the debugging information file.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0_u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
 #[derive(Debug, Clone, Default)]
 pub struct Type {
     pub name: String,
     pub size: usize,
+    pub kind: TypeKind,
 }
 
 impl Type {
     pub fn new(name: String, size: usize) -> Self {
         Type {
-            name: name,
-            size: size,
+            name,
+            size,
+            kind: TypeKind::Base,
         }
     }
 }
 
+/// What a type is made of. Types that make up other types are referred to by their offset in the
+/// debugging information (see DwarfData::get_type), since a struct may point to itself.
+#[derive(Debug, Clone, Default)]
+pub enum TypeKind {
+    /// A number, character, boolean or enum
+    #[default]
+    Base,
+    /// A pointer to the type at the given offset, or to void
+    Pointer(Option<usize>),
+    /// A struct or union
+    Struct(Vec<Member>),
+    /// An array of the type at the given offset, with the given number of elements in each
+    /// dimension
+    Array(usize, Vec<usize>),
+}
+
+#[derive(Debug, Clone)]
+pub struct Member {
+    pub name: String,
+    /// Offset of the member from the start of the struct, in bytes
+    pub offset: usize,
+    pub type_offset: usize,
+}
+
 #[derive(Clone)]
 pub enum Location {
     Address(usize),
@@ -196,10 +400,33 @@ pub struct Variable {
 #[derive(Debug, Default, Clone)]
 pub struct Function {
     pub name: String,
+    /// Mangled name of a Rust or C++ function, which unlike its name includes its namespace
+    pub linkage_name: Option<String>,
     pub address: usize,
     pub text_length: usize,
     pub line_number: usize, // Line number in source file
     pub variables: Vec<Variable>,
+    pub return_type: Option<Type>, // None for void functions
+}
+
+impl Function {
+    /// Returns the function's name qualified by its namespace, if it has one, as in backtraces.
+    pub fn full_name(&self) -> String {
+        match &self.linkage_name {
+            Some(linkage_name) => demangle(linkage_name),
+            None => self.name.clone(),
+        }
+    }
+
+    /// Returns true if name refers to the function: by its name, its mangled name, or its
+    /// qualified name with or without the parameter list of a C++ name.
+    pub fn is_named(&self, name: &str) -> bool {
+        if self.name == name || self.linkage_name.as_deref() == Some(name) {
+            return true;
+        }
+        let full_name = self.full_name();
+        full_name == name || full_name.split('(').next() == Some(name)
+    }
 }
 
 #[derive(Debug, Default, Clone)]
@@ -208,6 +435,8 @@ pub struct File {
     pub global_variables: Vec<Variable>,
     pub functions: Vec<Function>,
     pub lines: Vec<Line>,
+    /// Types declared in the file, by their offset in the debugging information
+    pub types: HashMap<usize, Type>,
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -0,0 +1,171 @@
+//! Formatting of inferior memory for the x command, which like gdb's shows memory as x/NFU: N
+//! units of U bytes each, in format F.
+
+/// Number of bytes in each unit size letter
+const UNITS: [(char, usize); 4] = [('b', 1), ('h', 2), ('w', 4), ('g', 8)];
+
+#[derive(Clone, Copy, Debug)]
+pub struct Format {
+    /// Number of units to show
+    pub count: usize,
+    /// x (hex), d (signed decimal), u (unsigned decimal), o (octal), t (binary), c (char),
+    /// f (float), s (string) or i (instruction)
+    pub letter: char,
+    /// Size of each unit in bytes (1, 2, 4 or 8). Ignored for strings and instructions.
+    pub unit: usize,
+}
+
+impl Default for Format {
+    fn default() -> Format {
+        Format {
+            count: 1,
+            letter: 'x',
+            unit: 4,
+        }
+    }
+}
+
+impl Format {
+    /// Parses the NFU after the slash of an x command. As in gdb, the format letter and unit size
+    /// are remembered between x commands, so those not given are taken from last.
+    pub fn parse(spec: &str, last: &Format) -> Result<Format, String> {
+        let digits = spec.chars().take_while(|c| c.is_ascii_digit()).count();
+        let mut format = Format {
+            count: if digits == 0 {
+                1
+            } else {
+                spec[..digits]
+                    .parse()
+                    .map_err(|_| format!("Invalid number \"{}\".", &spec[..digits]))?
+            },
+            ..*last
+        };
+        for letter in spec[digits..].chars() {
+            if let Some((_, unit)) = UNITS.iter().find(|(unit, _)| *unit == letter) {
+                format.unit = *unit;
+            } else if "xduotcfsi".contains(letter) {
+                format.letter = letter;
+            } else {
+                return Err(format!("Undefined output format \"{}\".", letter));
+            }
+        }
+        if format.letter == 'c' {
+            format.unit = 1;
+        }
+        Ok(format)
+    }
+
+    /// Returns how many units are shown on each line.
+    pub fn per_line(&self) -> usize {
+        match self.unit {
+            8 => 2,
+            4 => 4,
+            _ => 8,
+        }
+    }
+
+    /// Formats one unit of memory, given as little-endian bytes.
+    pub fn format_unit(&self, bytes: &[u8]) -> String {
+        let value = bytes
+            .iter()
+            .rev()
+            .fold(0_u64, |value, byte| value << 8 | *byte as u64);
+        match self.letter {
+            'd' => format!("{}", as_signed(value, self.unit)),
+            'u' => format!("{}", value),
+            'o' if value == 0 => String::from("0"),
+            'o' => format!("0{:o}", value),
+            't' => format!("{:0width$b}", value, width = self.unit * 8),
+            'c' => format!("{} {}", as_signed(value, 1), quote_char(value as u8)),
+            'f' if self.unit == 4 => format!("{}", f32::from_bits(value as u32)),
+            'f' if self.unit == 8 => format!("{}", f64::from_bits(value)),
+            'f' => format!("{}", as_signed(value, self.unit)),
+            _ => format!("0x{:0width$x}", value, width = self.unit * 2),
+        }
+    }
+}
+
+/// Quotes a character the way C source would, escaping it if it isn't printable.
+pub fn quote_char(byte: u8) -> String {
+    format!("'{}'", escape(byte, '\''))
+}
+
+/// Quotes a string the way C source would, escaping characters that aren't printable.
+pub fn quote_string(bytes: &[u8]) -> String {
+    let escaped: String = bytes.iter().map(|byte| escape(*byte, '"')).collect();
+    format!("\"{}\"", escaped)
+}
+
+fn escape(byte: u8, quote: char) -> String {
+    match byte {
+        b'\n' => String::from("\\n"),
+        b'\t' => String::from("\\t"),
+        b'\r' => String::from("\\r"),
+        b'\\' => String::from("\\\\"),
+        _ if byte as char == quote => format!("\\{}", quote),
+        0x20..=0x7e => (byte as char).to_string(),
+        _ => format!("\\{:03o}", byte),
+    }
+}
+
+/// Sign-extends a len-byte value, so that negative numbers print as such.
+pub fn as_signed(value: u64, len: usize) -> i64 {
+    let shift = 64 - 8 * len as u32;
+    ((value << shift) as i64) >> shift
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_count_letter_and_unit() {
+        let format = Format::parse("8xb", &Format::default()).unwrap();
+        assert_eq!((format.count, format.letter, format.unit), (8, 'x', 1));
+        let format = Format::parse("gd", &Format::default()).unwrap();
+        assert_eq!((format.count, format.letter, format.unit), (1, 'd', 8));
+    }
+
+    #[test]
+    fn remembers_letter_and_unit_from_last() {
+        let last = Format {
+            count: 4,
+            letter: 'u',
+            unit: 2,
+        };
+        let format = Format::parse("3", &last).unwrap();
+        assert_eq!((format.count, format.letter, format.unit), (3, 'u', 2));
+        let format = Format::parse("o", &last).unwrap();
+        assert_eq!((format.count, format.letter, format.unit), (1, 'o', 2));
+    }
+
+    #[test]
+    fn chars_are_one_byte() {
+        let format = Format::parse("2cw", &Format::default()).unwrap();
+        assert_eq!((format.count, format.letter, format.unit), (2, 'c', 1));
+    }
+
+    #[test]
+    fn rejects_unknown_letters() {
+        assert_eq!(
+            Format::parse("4q", &Format::default()).unwrap_err(),
+            "Undefined output format \"q\"."
+        );
+    }
+
+    #[test]
+    fn formats_units() {
+        let format = |letter, unit| Format {
+            count: 1,
+            letter,
+            unit,
+        };
+        assert_eq!(format('x', 2).format_unit(&[0x34, 0x12]), "0x1234");
+        assert_eq!(format('d', 1).format_unit(&[0xff]), "-1");
+        assert_eq!(format('u', 1).format_unit(&[0xff]), "255");
+        assert_eq!(format('o', 1).format_unit(&[8]), "010");
+        assert_eq!(format('t', 1).format_unit(&[5]), "00000101");
+        assert_eq!(format('c', 1).format_unit(b"\n"), "10 '\\n'");
+        assert_eq!(format('f', 4).format_unit(&1.5_f32.to_le_bytes()), "1.5");
+    }
+}
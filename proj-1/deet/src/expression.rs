@@ -0,0 +1,796 @@
+//! Expressions in C syntax, as given to print, x and set var: numbers, variables, registers ($sp),
+//! arithmetic, comparisons, dereferencing, member access and array indexing. An expression is
+//! parsed once and can then be evaluated each time the inferior stops.
+
+use crate::dwarf_data::{Type, TypeKind};
+
+/// Binary operators, from the loosest binding to the tightest
+const BINARY_OPERATORS: [&[&str]; 10] = [
+    &["||"],
+    &["&&"],
+    &["|"],
+    &["^"],
+    &["&"],
+    &["==", "!="],
+    &["<", "<=", ">", ">="],
+    &["<<", ">>"],
+    &["+", "-"],
+    &["*", "/", "%"],
+];
+
+/// Operators and punctuation, with the longer ones first so that they are matched first
+const OPERATORS: [&str; 26] = [
+    "->", "==", "!=", "<=", ">=", "&&", "||", "<<", ">>", "+", "-", "*", "/", "%", "<", ">", "!",
+    "~", "&", "|", "^", "(", ")", "[", "]", ".",
+];
+
+#[derive(Debug, Clone)]
+pub enum Expression {
+    Integer(u64),
+    Float(f64),
+    Variable(String),
+    /// A register, named without the $
+    Register(String),
+    Unary(&'static str, Box<Expression>),
+    Binary(&'static str, Box<Expression>, Box<Expression>),
+    /// A member of a struct (a.b). a->b is parsed as (*a).b.
+    Member(Box<Expression>, String),
+    Index(Box<Expression>, Box<Expression>),
+}
+
+/// What an expression needs to know about the inferior to be evaluated
+pub trait Context {
+    /// Returns the address and type of a variable visible where the inferior is stopped.
+    fn variable(&self, name: &str) -> Option<(u64, Type)>;
+    /// Returns the value of a register, named without the $.
+    fn register(&self, name: &str) -> Option<u64>;
+    /// Returns the type at the given offset in the debugging information.
+    fn get_type(&self, offset: usize) -> Option<&Type>;
+    /// Returns the offset in the debugging information of a type with the given name.
+    fn type_offset(&self, name: &str) -> Option<usize>;
+    fn read_memory(&self, addr: u64, len: usize) -> Option<Vec<u8>>;
+}
+
+/// The result of evaluating an expression
+#[derive(Debug, Clone)]
+pub struct Value {
+    pub value_type: Type,
+    /// The value's little-endian bytes
+    pub bytes: Vec<u8>,
+    /// Where the value is stored in the inferior, unless it was computed by the debugger
+    pub address: Option<u64>,
+}
+
+impl Value {
+    fn integer(value: i64) -> Value {
+        Value {
+            value_type: Type::new(String::from("long"), 8),
+            bytes: value.to_le_bytes().to_vec(),
+            address: None,
+        }
+    }
+
+    fn unsigned(value: u64) -> Value {
+        Value {
+            value_type: Type::new(String::from("unsigned long"), 8),
+            bytes: value.to_le_bytes().to_vec(),
+            address: None,
+        }
+    }
+
+    fn boolean(value: bool) -> Value {
+        Value {
+            value_type: Type::new(String::from("int"), 4),
+            bytes: (value as i32).to_le_bytes().to_vec(),
+            address: None,
+        }
+    }
+
+    fn float(value: f64) -> Value {
+        Value {
+            value_type: Type::new(String::from("double"), 8),
+            bytes: value.to_le_bytes().to_vec(),
+            address: None,
+        }
+    }
+
+    fn pointer(value: u64, value_type: Type) -> Value {
+        Value {
+            value_type,
+            bytes: value.to_le_bytes().to_vec(),
+            address: None,
+        }
+    }
+
+    /// Returns the value as a number, for arithmetic. Arrays stand for the address of their first
+    /// element, as in C, and addresses are unsigned.
+    pub fn to_scalar(&self) -> Result<Scalar, String> {
+        let raw = self
+            .bytes
+            .iter()
+            .take(8)
+            .rev()
+            .fold(0_u64, |value, byte| value << 8 | *byte as u64);
+        let name = self.value_type.name.as_str();
+        match self.value_type.kind {
+            TypeKind::Base if name == "float" && self.bytes.len() == 4 => {
+                Ok(Scalar::Float(f32::from_bits(raw as u32) as f64))
+            }
+            TypeKind::Base if name.contains("double") && self.bytes.len() == 8 => {
+                Ok(Scalar::Float(f64::from_bits(raw)))
+            }
+            TypeKind::Base if self.bytes.is_empty() || self.bytes.len() > 8 => {
+                Err(String::from("Value can't be used as a number."))
+            }
+            TypeKind::Base if name.contains("unsigned") || name == "_Bool" => {
+                Ok(Scalar::Unsigned(raw))
+            }
+            TypeKind::Base => {
+                let shift = 64 - 8 * self.bytes.len() as u32;
+                Ok(Scalar::Integer(((raw << shift) as i64) >> shift))
+            }
+            TypeKind::Pointer(_) => Ok(Scalar::Unsigned(raw)),
+            TypeKind::Array(..) => self
+                .address
+                .map(Scalar::Unsigned)
+                .ok_or_else(|| String::from("Array has no address.")),
+            TypeKind::Struct(_) => Err(String::from(
+                "Argument to arithmetic operation not a number or boolean.",
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Scalar {
+    Integer(i64),
+    Unsigned(u64),
+    Float(f64),
+}
+
+impl Scalar {
+    pub fn as_float(self) -> f64 {
+        match self {
+            Scalar::Integer(value) => value as f64,
+            Scalar::Unsigned(value) => value as f64,
+            Scalar::Float(value) => value,
+        }
+    }
+
+    pub fn as_integer(self) -> i64 {
+        match self {
+            Scalar::Integer(value) => value,
+            Scalar::Unsigned(value) => value as i64,
+            Scalar::Float(value) => value as i64,
+        }
+    }
+
+    fn is_true(self) -> bool {
+        self.as_float() != 0.0
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Integer(u64),
+    Float(f64),
+    Character(u8),
+    Identifier(String),
+    Register(String),
+    Operator(&'static str),
+}
+
+/// Splits an expression into tokens, each with its position in the text
+fn tokenize(text: &str) -> Result<Vec<(Token, usize)>, String> {
+    let mut tokens = Vec::new();
+    let mut pos = 0;
+    while pos < text.len() {
+        let rest = &text[pos..];
+        let c = rest.chars().next().unwrap();
+        if c.is_whitespace() {
+            pos += c.len_utf8();
+            continue;
+        }
+        let (token, len) = if c.is_ascii_digit() {
+            let len = rest
+                .find(|c: char| !c.is_ascii_alphanumeric() && c != '.')
+                .unwrap_or(rest.len());
+            let number = &rest[..len];
+            let token = if let Some(hex) = number.strip_prefix("0x") {
+                u64::from_str_radix(hex, 16).ok().map(Token::Integer)
+            } else if number.contains('.') {
+                number.parse().ok().map(Token::Float)
+            } else {
+                number.parse().ok().map(Token::Integer)
+            };
+            (token.ok_or(format!("Invalid number \"{}\".", number))?, len)
+        } else if c.is_ascii_alphabetic() || c == '_' || c == '$' {
+            let len = rest[1..]
+                .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                .map_or(rest.len(), |len| len + 1);
+            let token = match rest[..len].strip_prefix('$') {
+                Some(register) => Token::Register(register.to_string()),
+                None => Token::Identifier(rest[..len].to_string()),
+            };
+            (token, len)
+        } else if c == '\'' {
+            let bytes = rest.as_bytes();
+            match (bytes.get(1), bytes.get(2)) {
+                (Some(value), Some(b'\'')) => (Token::Character(*value), 3),
+                _ => return Err(String::from("Unmatched single quote.")),
+            }
+        } else {
+            match OPERATORS.iter().find(|op| rest.starts_with(*op)) {
+                Some(op) => (Token::Operator(op), op.len()),
+                None => return Err(format!("Invalid character '{}' in expression.", c)),
+            }
+        };
+        tokens.push((token, pos));
+        pos += len;
+    }
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    text: &'a str,
+    tokens: Vec<(Token, usize)>,
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(token, _)| token)
+    }
+
+    /// Consumes the next token if it is one of the given operators.
+    fn eat_operator(&mut self, ops: &[&str]) -> Option<&'static str> {
+        match self.peek() {
+            Some(Token::Operator(op)) if ops.contains(op) => {
+                let op = *op;
+                self.pos += 1;
+                Some(op)
+            }
+            _ => None,
+        }
+    }
+
+    fn syntax_error(&self) -> String {
+        let rest = match self.tokens.get(self.pos) {
+            Some((_, offset)) => &self.text[*offset..],
+            None => "",
+        };
+        format!("A syntax error in expression, near `{}'.", rest)
+    }
+
+    fn parse_binary(&mut self, level: usize) -> Result<Expression, String> {
+        if level == BINARY_OPERATORS.len() {
+            return self.parse_unary();
+        }
+        let mut left = self.parse_binary(level + 1)?;
+        while let Some(op) = self.eat_operator(BINARY_OPERATORS[level]) {
+            let right = self.parse_binary(level + 1)?;
+            left = Expression::Binary(op, Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn parse_unary(&mut self) -> Result<Expression, String> {
+        match self.eat_operator(&["-", "!", "~", "*", "&"]) {
+            Some(op) => Ok(Expression::Unary(op, Box::new(self.parse_unary()?))),
+            None => self.parse_postfix(),
+        }
+    }
+
+    fn parse_postfix(&mut self) -> Result<Expression, String> {
+        let mut expression = self.parse_primary()?;
+        while let Some(op) = self.eat_operator(&[".", "->", "["]) {
+            expression = match op {
+                "[" => {
+                    let index = self.parse_binary(0)?;
+                    self.eat_operator(&["]"])
+                        .ok_or_else(|| self.syntax_error())?;
+                    Expression::Index(Box::new(expression), Box::new(index))
+                }
+                _ => {
+                    let member = match self.peek() {
+                        Some(Token::Identifier(member)) => member.clone(),
+                        _ => return Err(self.syntax_error()),
+                    };
+                    self.pos += 1;
+                    if op == "->" {
+                        expression = Expression::Unary("*", Box::new(expression));
+                    }
+                    Expression::Member(Box::new(expression), member)
+                }
+            };
+        }
+        Ok(expression)
+    }
+
+    fn parse_primary(&mut self) -> Result<Expression, String> {
+        let token = self.peek().cloned().ok_or_else(|| self.syntax_error())?;
+        let expression = match token {
+            Token::Integer(value) => Expression::Integer(value),
+            Token::Float(value) => Expression::Float(value),
+            Token::Character(value) => Expression::Integer(value as u64),
+            Token::Identifier(name) => Expression::Variable(name),
+            Token::Register(name) => Expression::Register(name),
+            Token::Operator("(") => {
+                self.pos += 1;
+                let expression = self.parse_binary(0)?;
+                self.eat_operator(&[")"])
+                    .ok_or_else(|| self.syntax_error())?;
+                return Ok(expression);
+            }
+            Token::Operator(_) => return Err(self.syntax_error()),
+        };
+        self.pos += 1;
+        Ok(expression)
+    }
+}
+
+/// Parses an expression.
+pub fn parse(text: &str) -> Result<Expression, String> {
+    let mut parser = Parser {
+        text,
+        tokens: tokenize(text)?,
+        pos: 0,
+    };
+    let expression = parser.parse_binary(0)?;
+    if parser.pos < parser.tokens.len() {
+        return Err(parser.syntax_error());
+    }
+    Ok(expression)
+}
+
+/// Evaluates an expression where the inferior is stopped.
+pub fn evaluate(expression: &Expression, context: &dyn Context) -> Result<Value, String> {
+    match expression {
+        // As in C, a literal too big for a long is an unsigned long
+        Expression::Integer(value) if *value > i64::MAX as u64 => Ok(Value::unsigned(*value)),
+        Expression::Integer(value) => Ok(Value::integer(*value as i64)),
+        Expression::Float(value) => Ok(Value::float(*value)),
+        Expression::Variable(name) => {
+            let (addr, value_type) = context
+                .variable(name)
+                .ok_or(format!("No symbol \"{}\" in current context.", name))?;
+            read(context, addr, value_type)
+        }
+        Expression::Register(name) => context
+            .register(name)
+            .map(|value| Value::integer(value as i64))
+            .ok_or(format!("Invalid register \"{}\"", name)),
+        Expression::Unary(op, operand) => {
+            let operand = evaluate(operand, context)?;
+            match *op {
+                "*" => dereference(context, &operand),
+                "&" => address_of(context, &operand),
+                "-" => match operand.to_scalar()? {
+                    Scalar::Integer(value) => Ok(Value::integer(value.wrapping_neg())),
+                    Scalar::Unsigned(value) => Ok(Value::unsigned(value.wrapping_neg())),
+                    Scalar::Float(value) => Ok(Value::float(-value)),
+                },
+                "!" => Ok(Value::boolean(!operand.to_scalar()?.is_true())),
+                _ => match operand.to_scalar()? {
+                    Scalar::Unsigned(value) => Ok(Value::unsigned(!value)),
+                    value => Ok(Value::integer(!value.as_integer())),
+                },
+            }
+        }
+        // The right operand of && and || is only evaluated if the left one doesn't decide the
+        // result, so that p && p->count works when p is NULL
+        Expression::Binary(op @ ("&&" | "||"), left, right) => {
+            let left = evaluate(left, context)?.to_scalar()?.is_true();
+            if left == (*op == "||") {
+                return Ok(Value::boolean(left));
+            }
+            let right = evaluate(right, context)?.to_scalar()?.is_true();
+            Ok(Value::boolean(right))
+        }
+        Expression::Binary(op, left, right) => {
+            let left = evaluate(left, context)?;
+            let right = evaluate(right, context)?;
+            binary(context, op, &left, &right)
+        }
+        Expression::Member(base, member_name) => {
+            let base = evaluate(base, context)?;
+            let members = match &base.value_type.kind {
+                TypeKind::Struct(members) => members,
+                _ => {
+                    return Err(String::from(
+                        "Attempt to extract a component of a value that is not a structure.",
+                    ))
+                }
+            };
+            let member = members
+                .iter()
+                .find(|member| member.name == *member_name)
+                .ok_or(format!("There is no member named {}.", member_name))?;
+            let member_type = context
+                .get_type(member.type_offset)
+                .ok_or(format!("Unknown type for member {}.", member_name))?
+                .clone();
+            let bytes = base
+                .bytes
+                .get(member.offset..member.offset + member_type.size)
+                .ok_or(format!("Member {} is out of bounds.", member_name))?
+                .to_vec();
+            Ok(Value {
+                value_type: member_type,
+                bytes,
+                address: base.address.map(|addr| addr + member.offset as u64),
+            })
+        }
+        Expression::Index(base, index) => {
+            let base = evaluate(base, context)?;
+            let index = evaluate(index, context)?.to_scalar()?.as_integer();
+            let (element_type, addr) = match &base.value_type.kind {
+                TypeKind::Array(element, counts) => {
+                    let element_type = element_type(context, *element, counts)?;
+                    let addr = base
+                        .address
+                        .ok_or_else(|| String::from("Array has no address."))?;
+                    (element_type, addr)
+                }
+                TypeKind::Pointer(Some(pointee)) => {
+                    let pointee = context
+                        .get_type(*pointee)
+                        .ok_or_else(|| String::from("Unknown type pointed to."))?;
+                    (pointee.clone(), base.to_scalar()?.as_integer() as u64)
+                }
+                _ => {
+                    return Err(String::from(
+                        "cannot subscript something that is not an array or pointer",
+                    ))
+                }
+            };
+            let addr = addr.wrapping_add((index * element_type.size as i64) as u64);
+            read(context, addr, element_type)
+        }
+    }
+}
+
+/// Reads a value of the given type from the inferior's memory.
+fn read(context: &dyn Context, addr: u64, value_type: Type) -> Result<Value, String> {
+    let bytes = context
+        .read_memory(addr, value_type.size)
+        .ok_or(format!("Cannot access memory at address {:#x}", addr))?;
+    Ok(Value {
+        value_type,
+        bytes,
+        address: Some(addr),
+    })
+}
+
+/// Returns the type of the elements of an array with the given dimensions. The elements of a
+/// multi-dimensional array are arrays themselves.
+fn element_type(context: &dyn Context, element: usize, counts: &[usize]) -> Result<Type, String> {
+    let element_type = context
+        .get_type(element)
+        .ok_or_else(|| String::from("Unknown array element type."))?;
+    if counts.len() <= 1 {
+        return Ok(element_type.clone());
+    }
+    let dimensions: String = counts[1..]
+        .iter()
+        .map(|count| format!("[{}]", count))
+        .collect();
+    Ok(Type {
+        name: format!("{}{}", element_type.name, dimensions),
+        size: element_type.size * counts[1..].iter().product::<usize>(),
+        kind: TypeKind::Array(element, counts[1..].to_vec()),
+    })
+}
+
+/// Returns the type pointed to by a pointer, or the element type of an array, which is what
+/// dereferencing and pointer arithmetic work on. Returns None for anything else.
+fn pointee_type(context: &dyn Context, value: &Value) -> Option<Result<Type, String>> {
+    match &value.value_type.kind {
+        TypeKind::Pointer(Some(pointee)) => Some(
+            context
+                .get_type(*pointee)
+                .cloned()
+                .ok_or_else(|| String::from("Unknown type pointed to.")),
+        ),
+        // Pointer arithmetic on void * works a byte at a time, as with gcc
+        TypeKind::Pointer(None) => Some(Ok(Type::new(String::from("void"), 1))),
+        TypeKind::Array(element, counts) => Some(element_type(context, *element, counts)),
+        _ => None,
+    }
+}
+
+/// Returns the type of a pointer to the given type, if the debugging information declares it.
+fn pointer_to(context: &dyn Context, pointee: &Type) -> Type {
+    Type {
+        name: format!("{} *", pointee.name),
+        size: 8,
+        kind: TypeKind::Pointer(context.type_offset(&pointee.name)),
+    }
+}
+
+fn dereference(context: &dyn Context, value: &Value) -> Result<Value, String> {
+    let pointee = match (&value.value_type.kind, pointee_type(context, value)) {
+        (TypeKind::Pointer(None), _) | (_, None) => {
+            return Err(String::from(
+                "Attempt to take contents of a non-pointer value.",
+            ))
+        }
+        (_, Some(pointee)) => pointee?,
+    };
+    read(context, value.to_scalar()?.as_integer() as u64, pointee)
+}
+
+fn address_of(context: &dyn Context, value: &Value) -> Result<Value, String> {
+    let addr = value
+        .address
+        .ok_or_else(|| String::from("Attempt to take address of value not located in memory."))?;
+    Ok(Value::pointer(addr, pointer_to(context, &value.value_type)))
+}
+
+fn binary(context: &dyn Context, op: &str, left: &Value, right: &Value) -> Result<Value, String> {
+    let (a, b) = (left.to_scalar()?, right.to_scalar()?);
+
+    // Pointer arithmetic moves by whole elements
+    if op == "+" || op == "-" {
+        match (pointee_type(context, left), pointee_type(context, right)) {
+            (Some(_), Some(pointee)) if op == "-" => {
+                let size = pointee?.size.max(1) as i64;
+                return Ok(Value::integer(
+                    a.as_integer().wrapping_sub(b.as_integer()) / size,
+                ));
+            }
+            (Some(pointee), None) => {
+                let pointee = pointee?;
+                let offset = b.as_integer().wrapping_mul(pointee.size.max(1) as i64);
+                let addr = if op == "+" {
+                    a.as_integer().wrapping_add(offset)
+                } else {
+                    a.as_integer().wrapping_sub(offset)
+                };
+                return Ok(Value::pointer(addr as u64, pointer_to(context, &pointee)));
+            }
+            (None, Some(pointee)) if op == "+" => {
+                let pointee = pointee?;
+                let offset = a.as_integer().wrapping_mul(pointee.size.max(1) as i64);
+                let addr = b.as_integer().wrapping_add(offset);
+                return Ok(Value::pointer(addr as u64, pointer_to(context, &pointee)));
+            }
+            _ => {}
+        }
+    }
+
+    // As in C, integers are operated on as unsigned if either of them is unsigned
+    match (a, b) {
+        (Scalar::Integer(a), Scalar::Integer(b)) => {
+            let result = match op {
+                "+" => a.wrapping_add(b),
+                "-" => a.wrapping_sub(b),
+                "*" => a.wrapping_mul(b),
+                "/" | "%" if b == 0 => return Err(String::from("Division by zero")),
+                "/" => a.wrapping_div(b),
+                "%" => a.wrapping_rem(b),
+                "&" => a & b,
+                "|" => a | b,
+                "^" => a ^ b,
+                "<<" => a.wrapping_shl(b as u32),
+                ">>" => a.wrapping_shr(b as u32),
+                _ => return Ok(Value::boolean(compare(op, Some(a.cmp(&b))))),
+            };
+            return Ok(Value::integer(result));
+        }
+        (Scalar::Float(_), _) | (_, Scalar::Float(_)) => {}
+        _ => {
+            let (a, b) = (a.as_integer() as u64, b.as_integer() as u64);
+            let result = match op {
+                "+" => a.wrapping_add(b),
+                "-" => a.wrapping_sub(b),
+                "*" => a.wrapping_mul(b),
+                "/" | "%" if b == 0 => return Err(String::from("Division by zero")),
+                "/" => a / b,
+                "%" => a % b,
+                "&" => a & b,
+                "|" => a | b,
+                "^" => a ^ b,
+                "<<" => a.wrapping_shl(b as u32),
+                ">>" => a.wrapping_shr(b as u32),
+                _ => return Ok(Value::boolean(compare(op, Some(a.cmp(&b))))),
+            };
+            return Ok(Value::unsigned(result));
+        }
+    }
+
+    let (a, b) = (a.as_float(), b.as_float());
+    let result = match op {
+        "+" => a + b,
+        "-" => a - b,
+        "*" => a * b,
+        "/" => a / b,
+        "%" | "&" | "|" | "^" | "<<" | ">>" => {
+            return Err(String::from(
+                "Integer only operation on a floating-point value.",
+            ))
+        }
+        _ => return Ok(Value::boolean(compare(op, a.partial_cmp(&b)))),
+    };
+    Ok(Value::float(result))
+}
+
+/// Carries out a comparison operator, given how its operands are ordered. Operands that can't be
+/// ordered (NaNs) are only unequal.
+fn compare(op: &str, ordering: Option<std::cmp::Ordering>) -> bool {
+    let ordering = match ordering {
+        Some(ordering) => ordering,
+        None => return op == "!=",
+    };
+    match op {
+        "==" => ordering.is_eq(),
+        "!=" => ordering.is_ne(),
+        "<" => ordering.is_lt(),
+        "<=" => ordering.is_le(),
+        ">" => ordering.is_gt(),
+        _ => ordering.is_ge(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dwarf_data::Member;
+
+    /// An inferior stopped with these variables in scope:
+    ///
+    ///     int n = -5;                        // at 0x1000
+    ///     unsigned long big = 1UL << 63;     // at 0x2000
+    ///     struct counter { int count; int limit; } c = { 3, 10 };    // at 0x3000
+    ///     struct counter *p = NULL;          // at 0x4000
+    ///     struct counter *q = &c;            // at 0x4008
+    struct TestContext {
+        types: Vec<Type>,
+        memory: Vec<(u64, Vec<u8>)>,
+    }
+
+    impl TestContext {
+        fn new() -> TestContext {
+            let int = Type::new(String::from("int"), 4);
+            let counter = Type {
+                name: String::from("struct counter"),
+                size: 8,
+                kind: TypeKind::Struct(vec![
+                    Member {
+                        name: String::from("count"),
+                        offset: 0,
+                        type_offset: 0,
+                    },
+                    Member {
+                        name: String::from("limit"),
+                        offset: 4,
+                        type_offset: 0,
+                    },
+                ]),
+            };
+            let pointer = Type {
+                name: String::from("struct counter *"),
+                size: 8,
+                kind: TypeKind::Pointer(Some(1)),
+            };
+            let mut counter_bytes = 3_i32.to_le_bytes().to_vec();
+            counter_bytes.extend_from_slice(&10_i32.to_le_bytes());
+            TestContext {
+                types: vec![int, counter, pointer],
+                memory: vec![
+                    (0x1000, (-5_i32).to_le_bytes().to_vec()),
+                    (0x2000, (1_u64 << 63).to_le_bytes().to_vec()),
+                    (0x3000, counter_bytes),
+                    (0x4000, 0_u64.to_le_bytes().to_vec()),
+                    (0x4008, 0x3000_u64.to_le_bytes().to_vec()),
+                ],
+            }
+        }
+    }
+
+    impl Context for TestContext {
+        fn variable(&self, name: &str) -> Option<(u64, Type)> {
+            match name {
+                "n" => Some((0x1000, self.types[0].clone())),
+                "big" => Some((0x2000, Type::new(String::from("unsigned long"), 8))),
+                "c" => Some((0x3000, self.types[1].clone())),
+                "p" => Some((0x4000, self.types[2].clone())),
+                "q" => Some((0x4008, self.types[2].clone())),
+                _ => None,
+            }
+        }
+
+        fn register(&self, name: &str) -> Option<u64> {
+            match name {
+                "rip" => Some(0x401000),
+                _ => None,
+            }
+        }
+
+        fn get_type(&self, offset: usize) -> Option<&Type> {
+            self.types.get(offset)
+        }
+
+        fn type_offset(&self, name: &str) -> Option<usize> {
+            self.types.iter().position(|t| t.name == name)
+        }
+
+        fn read_memory(&self, addr: u64, len: usize) -> Option<Vec<u8>> {
+            self.memory.iter().find_map(|(start, bytes)| {
+                let offset = addr.checked_sub(*start)? as usize;
+                bytes.get(offset..offset + len).map(|bytes| bytes.to_vec())
+            })
+        }
+    }
+
+    fn eval(text: &str) -> Result<Scalar, String> {
+        evaluate(&parse(text)?, &TestContext::new())?.to_scalar()
+    }
+
+    #[test]
+    fn arithmetic_follows_c_precedence() {
+        assert_eq!(eval("1 + 2 * 3"), Ok(Scalar::Integer(7)));
+        assert_eq!(eval("(1 + 2) * 3"), Ok(Scalar::Integer(9)));
+        assert_eq!(eval("1 << 2 + 1"), Ok(Scalar::Integer(8)));
+        assert_eq!(eval("7 - 2 - 1"), Ok(Scalar::Integer(4)));
+        assert_eq!(eval("-7 / 2"), Ok(Scalar::Integer(-3)));
+        assert_eq!(eval("1.5 * 2"), Ok(Scalar::Float(3.0)));
+        assert_eq!(eval("'a' + 1"), Ok(Scalar::Integer(98)));
+        assert_eq!(eval("0x10 | 1"), Ok(Scalar::Integer(17)));
+    }
+
+    #[test]
+    fn rejects_invalid_expressions() {
+        assert_eq!(
+            parse("1 +").unwrap_err(),
+            "A syntax error in expression, near `'."
+        );
+        assert_eq!(
+            parse("(1 + 2").unwrap_err(),
+            "A syntax error in expression, near `'."
+        );
+        assert_eq!(
+            parse("1 @ 2").unwrap_err(),
+            "Invalid character '@' in expression."
+        );
+        assert_eq!(eval("1 / 0"), Err(String::from("Division by zero")));
+        assert_eq!(
+            eval("m"),
+            Err(String::from("No symbol \"m\" in current context."))
+        );
+    }
+
+    #[test]
+    fn reads_variables_members_and_pointers() {
+        assert_eq!(eval("n * 2"), Ok(Scalar::Integer(-10)));
+        assert_eq!(eval("c.limit - c.count"), Ok(Scalar::Integer(7)));
+        assert_eq!(eval("q->limit"), Ok(Scalar::Integer(10)));
+        assert_eq!(eval("(*q).count"), Ok(Scalar::Integer(3)));
+        assert_eq!(eval("q == &c"), Ok(Scalar::Integer(1)));
+        assert_eq!(eval("&c.limit"), Ok(Scalar::Unsigned(0x3004)));
+        assert_eq!(eval("q + 1"), Ok(Scalar::Unsigned(0x3008)));
+        assert_eq!(eval("$rip"), Ok(Scalar::Integer(0x401000)));
+        assert_eq!(
+            eval("p->count"),
+            Err(String::from("Cannot access memory at address 0x0"))
+        );
+    }
+
+    #[test]
+    fn logical_operators_short_circuit() {
+        assert_eq!(eval("p && p->count"), Ok(Scalar::Integer(0)));
+        assert_eq!(eval("!p || p->count"), Ok(Scalar::Integer(1)));
+        assert_eq!(eval("q && q->count"), Ok(Scalar::Integer(1)));
+        assert!(eval("q && p->count").is_err());
+    }
+
+    #[test]
+    fn compares_unsigned_values_as_unsigned() {
+        assert_eq!(eval("big > 1"), Ok(Scalar::Integer(1)));
+        assert_eq!(eval("big"), Ok(Scalar::Unsigned(1 << 63)));
+        assert_eq!(eval("big / 2"), Ok(Scalar::Unsigned(1 << 62)));
+        assert_eq!(eval("0xffffffffffffffff > 0"), Ok(Scalar::Integer(1)));
+        assert_eq!(eval("n < 0"), Ok(Scalar::Integer(1)));
+        assert_eq!(eval("-1 < 1"), Ok(Scalar::Integer(1)));
+    }
+}
@@ -10,7 +10,7 @@ use gimli::{UnitOffset, UnitSectionOffset};
 use object::Object;
 use std::borrow;
 //use std::io::{BufWriter, Write};
-use crate::dwarf_data::{File, Function, Line, Location, Type, Variable};
+use crate::dwarf_data::{File, Function, Line, Location, Member, Type, TypeKind, Variable};
 use std::collections::HashMap;
 use std::convert::TryInto;
 use std::fmt::Write;
@@ -39,9 +39,6 @@ pub fn load_file(object: &object::File, endian: gimli::RunTimeEndian) -> Result<
     // Create `EndianSlice`s for all of the sections.
     let dwarf = dwarf_cow.borrow(&borrow_section);
 
-    // Define a mapping from type offsets to type structs
-    let mut offset_to_type: HashMap<usize, Type> = HashMap::new();
-
     let mut compilation_units: Vec<File> = Vec::new();
 
     // Iterate over the compilation units.
@@ -49,6 +46,10 @@ pub fn load_file(object: &object::File, endian: gimli::RunTimeEndian) -> Result<
     while let Some(header) = iter.next()? {
         let unit = dwarf.unit(header)?;
 
+        // Define a mapping from type offsets to type structs. Types are read first, since
+        // variables may refer to types declared after them.
+        let offset_to_type = load_types(&unit, &dwarf)?;
+
         // Iterate over the Debugging Information Entries (DIEs) in the unit.
         let mut depth = 0;
         let mut entries = unit.entries();
@@ -72,35 +73,9 @@ pub fn load_file(object: &object::File, endian: gimli::RunTimeEndian) -> Result<
                         global_variables: Vec::new(),
                         functions: Vec::new(),
                         lines: Vec::new(),
+                        types: offset_to_type.clone(),
                     });
                 }
-                gimli::DW_TAG_base_type => {
-                    let name = if let Ok(Some(attr)) = entry.attr(gimli::DW_AT_name) {
-                        if let Ok(DebugValue::Str(name)) = get_attr_value(&attr, &unit, &dwarf) {
-                            name
-                        } else {
-                            "<unknown>".to_string()
-                        }
-                    } else {
-                        "<unknown>".to_string()
-                    };
-                    let byte_size = if let Ok(Some(attr)) = entry.attr(gimli::DW_AT_byte_size) {
-                        if let Ok(DebugValue::Uint(byte_size)) =
-                            get_attr_value(&attr, &unit, &dwarf)
-                        {
-                            byte_size
-                        } else {
-                            // TODO: report error?
-                            0
-                        }
-                    } else {
-                        // TODO: report error?
-                        0
-                    };
-                    let type_offset = entry.offset().0;
-                    offset_to_type
-                        .insert(type_offset, Type::new(name, byte_size.try_into().unwrap()));
-                }
                 gimli::DW_TAG_subprogram => {
                     let mut func: Function = Default::default();
                     let mut attrs = entry.attrs();
@@ -113,6 +88,18 @@ pub fn load_file(object: &object::File, endian: gimli::RunTimeEndian) -> Result<
                                     func.name = name;
                                 }
                             }
+                            gimli::DW_AT_linkage_name | gimli::DW_AT_MIPS_linkage_name => {
+                                if let Ok(DebugValue::Str(name)) = val {
+                                    func.linkage_name = Some(name);
+                                }
+                            }
+                            gimli::DW_AT_specification | gimli::DW_AT_abstract_origin => {
+                                let (name, linkage_name) = declaration_names(&attr, &unit, &dwarf)?;
+                                if func.name.is_empty() {
+                                    func.name = name.unwrap_or_default();
+                                }
+                                func.linkage_name = func.linkage_name.take().or(linkage_name);
+                            }
                             gimli::DW_AT_high_pc => {
                                 if let Ok(DebugValue::Uint(high_pc)) = val {
                                     func.text_length = high_pc.try_into().unwrap();
@@ -129,6 +116,11 @@ pub fn load_file(object: &object::File, endian: gimli::RunTimeEndian) -> Result<
                                     func.line_number = line_number.try_into().unwrap();
                                 }
                             }
+                            gimli::DW_AT_type => {
+                                if let Ok(DebugValue::Size(offset)) = val {
+                                    func.return_type = offset_to_type.get(&offset).cloned();
+                                }
+                            }
                             _ => {}
                         }
                     }
@@ -243,6 +235,226 @@ pub fn load_file(object: &object::File, endian: gimli::RunTimeEndian) -> Result<
     Ok(compilation_units)
 }
 
+/// A type's debugging information entry, as read before the types it refers to are known
+struct TypeEntry {
+    tag: gimli::DwTag,
+    name: Option<String>,
+    byte_size: Option<usize>,
+    /// Offset of the type this one is built on (pointed to, typedef'd, qualified or an array of)
+    target: Option<usize>,
+    members: Vec<Member>,
+    /// Number of elements in each dimension of an array
+    counts: Vec<usize>,
+}
+
+/// Typedefs and qualifiers can't nest deeper than this in a sane program
+const MAX_TYPE_DEPTH: usize = 32;
+
+/// Reads the types declared in a unit, keyed by their offset in the .debug_info section, which is
+/// what DW_AT_type attributes refer to them by.
+fn load_types<R: Reader>(
+    unit: &gimli::Unit<R>,
+    dwarf: &gimli::Dwarf<R>,
+) -> Result<HashMap<usize, Type>, Error> {
+    let mut type_entries: HashMap<usize, TypeEntry> = HashMap::new();
+    // Structs and arrays whose members or subranges are being read, with their depths
+    let mut parents: Vec<(isize, usize)> = Vec::new();
+    let mut depth = 0;
+    let mut entries = unit.entries();
+    while let Some((delta_depth, entry)) = entries.next_dfs()? {
+        depth += delta_depth;
+        while parents
+            .last()
+            .map_or(false, |(parent_depth, _)| *parent_depth >= depth)
+        {
+            parents.pop();
+        }
+        let parent = parents
+            .last()
+            .filter(|(parent_depth, _)| *parent_depth == depth - 1)
+            .map(|(_, parent)| *parent);
+
+        let mut name = None;
+        let mut byte_size = None;
+        let mut target = None;
+        let mut member_offset = 0;
+        let mut count = 0;
+        let mut attrs = entry.attrs();
+        while let Some(attr) = attrs.next()? {
+            match (attr.name(), get_attr_value(&attr, unit, dwarf)) {
+                (gimli::DW_AT_name, Ok(DebugValue::Str(value))) => name = Some(value),
+                (gimli::DW_AT_byte_size, Ok(DebugValue::Uint(value))) => {
+                    byte_size = Some(value.try_into().unwrap())
+                }
+                (gimli::DW_AT_type, Ok(DebugValue::Size(value))) => target = Some(value),
+                (gimli::DW_AT_data_member_location, Ok(DebugValue::Uint(value))) => {
+                    member_offset = value.try_into().unwrap()
+                }
+                (gimli::DW_AT_upper_bound, Ok(DebugValue::Uint(value))) => {
+                    count = (value + 1).try_into().unwrap()
+                }
+                (gimli::DW_AT_count, Ok(DebugValue::Uint(value))) => {
+                    count = value.try_into().unwrap()
+                }
+                _ => {}
+            }
+        }
+
+        match entry.tag() {
+            gimli::DW_TAG_member => {
+                let parent = parent.and_then(|parent| type_entries.get_mut(&parent));
+                if let (Some(parent), Some(type_offset)) = (parent, target) {
+                    parent.members.push(Member {
+                        name: name.unwrap_or_default(),
+                        offset: member_offset,
+                        type_offset,
+                    });
+                }
+            }
+            gimli::DW_TAG_subrange_type => {
+                if let Some(parent) = parent.and_then(|parent| type_entries.get_mut(&parent)) {
+                    parent.counts.push(count);
+                }
+            }
+            tag @ (gimli::DW_TAG_base_type
+            | gimli::DW_TAG_pointer_type
+            | gimli::DW_TAG_typedef
+            | gimli::DW_TAG_const_type
+            | gimli::DW_TAG_volatile_type
+            | gimli::DW_TAG_structure_type
+            | gimli::DW_TAG_union_type
+            | gimli::DW_TAG_enumeration_type
+            | gimli::DW_TAG_array_type) => {
+                let offset = section_offset(entry.offset(), unit);
+                if tag == gimli::DW_TAG_structure_type
+                    || tag == gimli::DW_TAG_union_type
+                    || tag == gimli::DW_TAG_array_type
+                {
+                    parents.push((depth, offset));
+                }
+                type_entries.insert(
+                    offset,
+                    TypeEntry {
+                        tag,
+                        name,
+                        byte_size,
+                        target,
+                        members: Vec::new(),
+                        counts: Vec::new(),
+                    },
+                );
+            }
+            _ => {}
+        }
+    }
+
+    Ok(type_entries
+        .keys()
+        .filter_map(|offset| Some((*offset, resolve_type(*offset, &type_entries, 0)?)))
+        .collect())
+}
+
+/// Works out the name, size and makeup of the type at offset. Typedefs take the makeup of the
+/// type they name, and qualifiers such as const are left out.
+fn resolve_type(
+    offset: usize,
+    type_entries: &HashMap<usize, TypeEntry>,
+    depth: usize,
+) -> Option<Type> {
+    let entry = type_entries.get(&offset)?;
+    if depth > MAX_TYPE_DEPTH {
+        return None;
+    }
+    let target = entry
+        .target
+        .and_then(|target| resolve_type(target, type_entries, depth + 1));
+    let name = entry
+        .name
+        .clone()
+        .unwrap_or_else(|| "<unknown>".to_string());
+    let byte_size = entry.byte_size.unwrap_or(0);
+    match entry.tag {
+        gimli::DW_TAG_base_type => Some(Type::new(name, byte_size)),
+        gimli::DW_TAG_pointer_type => {
+            let pointee = target.map_or("void".to_string(), |target| target.name);
+            Some(Type {
+                name: format!("{} *", pointee),
+                size: entry.byte_size.unwrap_or(8),
+                kind: TypeKind::Pointer(entry.target),
+            })
+        }
+        gimli::DW_TAG_typedef => target.map(|target| Type { name, ..target }),
+        gimli::DW_TAG_const_type | gimli::DW_TAG_volatile_type => target,
+        gimli::DW_TAG_structure_type | gimli::DW_TAG_union_type => Some(Type {
+            name: format!(
+                "{} {}",
+                if entry.tag == gimli::DW_TAG_union_type {
+                    "union"
+                } else {
+                    "struct"
+                },
+                name
+            ),
+            size: byte_size,
+            kind: TypeKind::Struct(entry.members.clone()),
+        }),
+        gimli::DW_TAG_enumeration_type => Some(Type::new(format!("enum {}", name), byte_size)),
+        gimli::DW_TAG_array_type => {
+            let element = target?;
+            let dimensions: String = entry
+                .counts
+                .iter()
+                .map(|count| format!("[{}]", count))
+                .collect();
+            Some(Type {
+                name: format!("{}{}", element.name, dimensions),
+                size: entry
+                    .byte_size
+                    .unwrap_or(element.size * entry.counts.iter().product::<usize>()),
+                kind: TypeKind::Array(entry.target?, entry.counts.clone()),
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Reads the name and linkage name of the declaration that a function definition refers to with
+/// DW_AT_specification or DW_AT_abstract_origin. C++ compilers give functions defined outside the
+/// class or namespace they are declared in no names of their own.
+fn declaration_names<R: Reader>(
+    attr: &gimli::Attribute<R>,
+    unit: &gimli::Unit<R>,
+    dwarf: &gimli::Dwarf<R>,
+) -> Result<(Option<String>, Option<String>), Error> {
+    let offset = match attr.value() {
+        gimli::AttributeValue::UnitRef(offset) => offset,
+        _ => return Ok((None, None)),
+    };
+    let entry = unit.entry(offset)?;
+    let mut name = None;
+    let mut linkage_name = None;
+    let mut attrs = entry.attrs();
+    while let Some(attr) = attrs.next()? {
+        match (attr.name(), get_attr_value(&attr, unit, dwarf)) {
+            (gimli::DW_AT_name, Ok(DebugValue::Str(value))) => name = Some(value),
+            (gimli::DW_AT_linkage_name, Ok(DebugValue::Str(value)))
+            | (gimli::DW_AT_MIPS_linkage_name, Ok(DebugValue::Str(value))) => {
+                linkage_name = Some(value)
+            }
+            _ => {}
+        }
+    }
+    Ok((name, linkage_name))
+}
+
+/// Converts the offset of an entry within its unit to its offset in the section
+fn section_offset<R: Reader>(offset: UnitOffset, unit: &gimli::Unit<R>) -> usize {
+    match offset.to_unit_section_offset(unit) {
+        UnitSectionOffset::DebugInfoOffset(goff) => goff.0,
+        UnitSectionOffset::DebugTypesOffset(goff) => goff.0,
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum DebugValue {
     Str(String),
@@ -253,6 +465,7 @@ pub enum DebugValue {
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
     GimliError(gimli::Error),
     Addr2lineError(addr2line::gimli::Error),
@@ -1,12 +1,181 @@
 use crate::debugger::Breakpoint;
-use crate::dwarf_data::{DwarfData, Line};
+use crate::debugger_command::RunArgs;
+use crate::library;
+use crate::record::{self, Entry, Recording};
+use crate::remote::{self, Remote};
+use crate::syscall::{self, Syscall};
+use iced_x86::{Decoder, DecoderOptions, Formatter, GasFormatter};
 use nix::sys::ptrace;
 use nix::sys::signal;
 use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
 use nix::unistd::Pid;
 use std::collections::HashMap;
+use std::fmt;
+use std::io::Read;
+use std::os::unix::io::AsRawFd;
 use std::os::unix::process::CommandExt;
-use std::process::{Child, Command};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+
+/// Offset of u_debugreg in struct user (see <sys/user.h>), for PTRACE_PEEKUSER/PTRACE_POKEUSER
+const DEBUGREG_OFFSET: usize = 848;
+
+/// Longest an x86-64 instruction can be, in bytes
+const MAX_INSTRUCTION_LEN: usize = 15;
+
+/// Number of debug registers (DR0-DR3) that can hold a watchpoint address
+pub const NUM_WATCH_SLOTS: usize = 4;
+
+/// Size of the floating point registers as PTRACE_GETFPREGS returns them
+pub const FP_REGISTERS_LEN: usize = 512;
+
+/// Pid of the inferior while it is running under cont, for interrupt_inferior; 0 otherwise
+static RUNNING_PID: AtomicI32 = AtomicI32::new(0);
+
+/// Set by interrupt_inferior once it has sent the running inferior a SIGSTOP
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Connection to gdbserver while a remote inferior is running under cont, for
+/// interrupt_inferior; -1 otherwise
+static RUNNING_REMOTE: AtomicI32 = AtomicI32::new(-1);
+
+/// SIGINT handler for deet. Ctrl+C while the inferior is running stops it with a SIGSTOP, which
+/// unlike SIGINT it can't block, so that the user gets the prompt back. A remote inferior is
+/// interrupted by gdbserver instead. At the prompt, Ctrl+C is read by rustyline.
+pub extern "C" fn interrupt_inferior(_signal: libc::c_int) {
+    let fd = RUNNING_REMOTE.load(Ordering::SeqCst);
+    if fd >= 0 {
+        let interrupt = [remote::INTERRUPT];
+        unsafe { libc::write(fd, interrupt.as_ptr() as *const libc::c_void, 1) };
+        return;
+    }
+    let pid = RUNNING_PID.load(Ordering::SeqCst);
+    if pid != 0 {
+        INTERRUPTED.store(true, Ordering::SeqCst);
+        unsafe { libc::kill(pid, libc::SIGSTOP) };
+    }
+}
+
+/// What kind of access a watchpoint breaks on
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WatchKind {
+    /// Writes (watch)
+    Write,
+    /// Reads (rwatch)
+    Read,
+    /// Reads and writes (awatch)
+    Access,
+}
+
+/// Which process deet keeps debugging when the inferior forks. The other is detached.
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum FollowForkMode {
+    #[default]
+    Parent,
+    Child,
+}
+
+impl fmt::Display for FollowForkMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FollowForkMode::Parent => write!(f, "parent"),
+            FollowForkMode::Child => write!(f, "child"),
+        }
+    }
+}
+
+/// Process events that catch can stop the inferior at
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EventKind {
+    Fork,
+    Vfork,
+    Exec,
+    Exit,
+    /// Entering and returning from the system call with the given number, or any if None
+    Syscall(Option<u64>),
+}
+
+impl EventKind {
+    /// Returns true if a catchpoint for this kind of event stops at event.
+    pub fn catches(&self, event: &ProcessEvent) -> bool {
+        match (self, event.kind()) {
+            (EventKind::Syscall(None), EventKind::Syscall(_)) => true,
+            (kind, event_kind) => *kind == event_kind,
+        }
+    }
+}
+
+impl fmt::Display for EventKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EventKind::Fork => write!(f, "fork"),
+            EventKind::Vfork => write!(f, "vfork"),
+            EventKind::Exec => write!(f, "exec"),
+            EventKind::Exit => write!(f, "exit"),
+            EventKind::Syscall(None) => write!(f, "syscall"),
+            EventKind::Syscall(Some(number)) => {
+                write!(f, "syscall '{}' [{}]", syscall::name(*number), number)
+            }
+        }
+    }
+}
+
+/// A process event the inferior was stopped at
+#[derive(Clone, Debug)]
+pub enum ProcessEvent {
+    /// Forked a new process with the given pid
+    Fork(Pid),
+    /// Vforked a new process with the given pid
+    Vfork(Pid),
+    /// Started running the program at the given path
+    Exec(String),
+    /// Is about to exit, with the given wait status
+    Exit(i32),
+    /// Is entering a system call
+    SyscallEntry(Syscall),
+    /// Has returned from a system call, with the given value
+    SyscallReturn(Syscall, i64),
+}
+
+impl ProcessEvent {
+    pub fn kind(&self) -> EventKind {
+        match self {
+            ProcessEvent::Fork(_) => EventKind::Fork,
+            ProcessEvent::Vfork(_) => EventKind::Vfork,
+            ProcessEvent::Exec(_) => EventKind::Exec,
+            ProcessEvent::Exit(_) => EventKind::Exit,
+            ProcessEvent::SyscallEntry(syscall) | ProcessEvent::SyscallReturn(syscall, _) => {
+                EventKind::Syscall(Some(syscall.number))
+            }
+        }
+    }
+}
+
+impl fmt::Display for ProcessEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProcessEvent::Fork(pid) => write!(f, "forked process {}", pid),
+            ProcessEvent::Vfork(pid) => write!(f, "vforked process {}", pid),
+            ProcessEvent::Exec(path) => write!(f, "exec'd {}", path),
+            ProcessEvent::Exit(status) if status & 0x7f == 0 => {
+                write!(f, "exiting with code {}", (status >> 8) & 0xff)
+            }
+            ProcessEvent::Exit(status) => {
+                let signo = status & 0x7f;
+                match signal::Signal::iterator().find(|sig| *sig as i32 == signo) {
+                    Some(sig) => write!(f, "exiting with signal {}", sig),
+                    None => write!(f, "exiting with signal {}", signo),
+                }
+            }
+            ProcessEvent::SyscallEntry(syscall) => {
+                write!(f, "call to syscall {}", syscall.name())
+            }
+            ProcessEvent::SyscallReturn(syscall, _) => {
+                write!(f, "returned from syscall {}", syscall.name())
+            }
+        }
+    }
+}
 
 pub enum Status {
     /// Indicates inferior stopped. Contains the signal that stopped the process, as well as the
@@ -19,44 +188,169 @@ pub enum Status {
     /// Indicates the inferior exited due to a signal. Contains the signal that killed the
     /// process.
     Signaled(signal::Signal),
+
+    /// Indicates the inferior stopped at a process event it was set to stop at with
+    /// set_caught_events. Contains the event and the current instruction pointer.
+    Caught(ProcessEvent, usize),
+}
+
+/// A stopped copy of the inferior, forked by checkpoint for restart to go back to
+#[derive(Debug)]
+struct Checkpoint {
+    pid: Pid,
+    /// Original bytes of the breakpoints that were in the inferior's memory when it was copied
+    breakpoints: HashMap<u64, u8>,
 }
 
 /// This function calls ptrace with PTRACE_TRACEME to enable debugging on a process. You should use
 /// pre_exec with Command to call this in the child process.
 fn child_traceme() -> Result<(), std::io::Error> {
-    ptrace::traceme().or(Err(std::io::Error::new(
-        std::io::ErrorKind::Other,
-        "ptrace TRACEME failed",
-    )))
+    ptrace::traceme().or(Err(std::io::Error::other("ptrace TRACEME failed")))
 }
 
 #[derive(Debug)]
 pub struct Inferior {
-    child: Child,
+    /// Process started by new; None for a remote inferior
+    child: Option<Child>,
+    /// Connection to the gdbserver a remote inferior runs under, which registers, memory and
+    /// resuming go through instead of ptrace
+    remote: Option<Remote>,
+    /// Process being debugged: the child started, unless a fork has been followed into its child
+    pid: Pid,
     breakpoint_map: HashMap<u64, Breakpoint>,
+    /// Number of times the inferior has stopped at each breakpoint address. Kept apart from
+    /// breakpoint_map so that counts survive a breakpoint being disabled and enabled again.
+    hit_counts: HashMap<u64, usize>,
+    /// Addresses watched by each of the debug registers DR0-DR3
+    watch_slots: [Option<u64>; NUM_WATCH_SLOTS],
+    /// Breakpoint set by the debugger itself rather than the user, e.g. at a return address to
+    /// run until a call returns
+    temp_breakpoint: Option<u64>,
+    /// Difference between the addresses the executable was loaded at and the addresses in its
+    /// debugging information. Zero unless it is position-independent.
+    load_bias: u64,
+    /// Where the dynamic linker's library event function was loaded, with a breakpoint on it so
+    /// that the debugger hears about libraries being loaded. None for static executables.
+    library_event: Option<u64>,
+    follow_fork_mode: FollowForkMode,
+    /// Parent of a vfork whose child is being followed. The two share memory until the child
+    /// execs or exits, so the parent is kept stopped until then, when the breakpoints can be
+    /// taken out of its memory and it can be detached.
+    vfork_parent: Option<Pid>,
+    /// Process events the inferior stops at, for catchpoints
+    caught_events: Vec<EventKind>,
+    /// Whether to print each system call the inferior makes, like strace
+    trace_syscalls: bool,
+    /// System call the inferior is in, between the stops at its entry and return. Only known
+    /// while it runs with PTRACE_SYSCALL, for trace_syscalls or a syscall catchpoint.
+    syscall: Option<Syscall>,
+    /// Checkpoints taken, by number. They are killed along with the inferior.
+    checkpoints: Vec<Option<Checkpoint>>,
+    /// Log of the instructions executed since recording started, for reverse execution. While
+    /// recording, the inferior is single-stepped rather than left to run.
+    recording: Option<Recording>,
 }
 
 impl Inferior {
-    pub fn new(target: &str, args: &Vec<String>, breakpoints: &Vec<u64>) -> Option<Inferior> {
-        let mut cmd = Command::new(&target);
-        let cmd = cmd.args(args);
+    pub fn new(target: &str, run_args: &RunArgs, breakpoints: &[Option<u64>]) -> Option<Inferior> {
+        let mut cmd = Command::new(target);
+        let cmd = cmd.args(&run_args.args);
+        if let Some(path) = &run_args.stdin {
+            match std::fs::File::open(path) {
+                Ok(file) => cmd.stdin(Stdio::from(file)),
+                Err(err) => {
+                    println!("Cannot open {} for reading: {}", path, err);
+                    return None;
+                }
+            };
+        }
+        if let Some(path) = &run_args.stdout {
+            match std::fs::File::create(path) {
+                Ok(file) => cmd.stdout(Stdio::from(file)),
+                Err(err) => {
+                    println!("Cannot open {} for writing: {}", path, err);
+                    return None;
+                }
+            };
+        }
 
         unsafe { cmd.pre_exec(child_traceme) };
         let child = cmd.spawn().expect("couldn't create the child process");
 
-        let mut inferior = Inferior {
-            child,
-            breakpoint_map: HashMap::new(),
-        };
+        let pid = Pid::from_raw(child.id() as i32);
+        let mut inferior = Inferior::with_process(pid, Some(child), None);
 
         let status = inferior.wait(None).unwrap();
         match status {
-            Status::Stopped(sig, _) if sig == nix::sys::signal::SIGTRAP => (),
+            Status::Stopped(nix::sys::signal::SIGTRAP, _) => (),
             _ => return None,
         }
+        let options = ptrace::Options::PTRACE_O_TRACEFORK
+            | ptrace::Options::PTRACE_O_TRACEVFORK
+            | ptrace::Options::PTRACE_O_TRACEVFORKDONE
+            | ptrace::Options::PTRACE_O_TRACEEXEC
+            | ptrace::Options::PTRACE_O_TRACEEXIT
+            | ptrace::Options::PTRACE_O_TRACESYSGOOD;
+        ptrace::setoptions(inferior.pid(), options).ok()?;
+        // The executable has been mapped by the time exec stops
+        inferior.load_bias = find_load_bias(inferior.pid(), target).unwrap_or(0);
+        inferior.library_event = find_library_event(inferior.pid(), target);
+        if let Some(addr) = inferior.library_event {
+            inferior.add_breakpoint(addr);
+        }
+        inferior.add_initial_breakpoints(breakpoints);
+        Some(inferior)
+    }
+
+    /// Connects to gdbserver at address (host:port) to debug the process it has stopped, whose
+    /// executable target is a copy of. Shared libraries, watchpoints, checkpoints, threads and
+    /// process events aren't supported for a remote inferior.
+    pub fn connect(address: &str, target: &str, breakpoints: &[Option<u64>]) -> Option<Inferior> {
+        let remote = match Remote::connect(address) {
+            Ok(remote) => remote,
+            Err(err) => {
+                println!("{}: {}", address, err);
+                return None;
+            }
+        };
+        let load_bias = remote_load_bias(&remote, target).unwrap_or(0);
+        let mut inferior = Inferior::with_process(remote.pid(), None, Some(remote));
+        inferior.load_bias = load_bias;
+        inferior.add_initial_breakpoints(breakpoints);
+        Some(inferior)
+    }
+
+    fn with_process(pid: Pid, child: Option<Child>, remote: Option<Remote>) -> Inferior {
+        Inferior {
+            child,
+            remote,
+            pid,
+            breakpoint_map: HashMap::new(),
+            hit_counts: HashMap::new(),
+            watch_slots: [None; NUM_WATCH_SLOTS],
+            temp_breakpoint: None,
+            load_bias: 0,
+            library_event: None,
+            follow_fork_mode: FollowForkMode::default(),
+            vfork_parent: None,
+            caught_events: Vec::new(),
+            trace_syscalls: false,
+            syscall: None,
+            checkpoints: Vec::new(),
+            recording: None,
+        }
+    }
 
+    /// Sets the breakpoints a new inferior starts with, which are given as addresses in the
+    /// executable.
+    fn add_initial_breakpoints(&mut self, breakpoints: &[Option<u64>]) {
         for (idx, breakpoint) in breakpoints.iter().enumerate() {
-            match inferior.add_breakpoint(*breakpoint) {
+            let breakpoint = match breakpoint {
+                Some(breakpoint) => breakpoint,
+                None => continue,
+            };
+            let breakpoint = breakpoint + self.load_bias;
+            match self.add_breakpoint(breakpoint) {
                 Some(_) => println!("Set breakpoint {} at 0x{:#x}", idx, breakpoint),
                 None => println!(
                     "WARNING: Cannot set breakpoint {} at 0x{:#x}!",
@@ -65,7 +359,6 @@ impl Inferior {
                 ),
             }
         }
-        Some(inferior)
     }
 
     pub fn add_breakpoint(&mut self, breakpoint_addr: u64) -> Option<Breakpoint> {
@@ -88,40 +381,292 @@ impl Inferior {
         Some(breakpoint)
     }
 
-    pub fn kill(&mut self) -> () {
-        self.child.kill().expect("couldn't kill the process");
-        let status = self.child.wait().expect("failed to reap child");
-        println!("Killed inferior process {} with {}", self.pid(), status);
+    /// Removes the breakpoint at breakpoint_addr, writing back the original byte so that the trap
+    /// no longer fires.
+    pub fn remove_breakpoint(&mut self, breakpoint_addr: u64) -> Option<Breakpoint> {
+        let breakpoint = self.breakpoint_map.remove(&breakpoint_addr)?;
+        if let Err(error) = self.write_byte(breakpoint_addr, breakpoint.get_orig_byte()) {
+            println!("Error while removing breakpoint: {:?}", error);
+        }
+        Some(breakpoint)
     }
 
-    fn write_byte(&mut self, addr: u64, val: u8) -> Result<u8, nix::Error> {
+    /// Returns true if the user has a breakpoint at addr.
+    pub fn is_breakpoint(&self, addr: u64) -> bool {
+        self.breakpoint_map.contains_key(&addr)
+            && self.temp_breakpoint != Some(addr)
+            && self.library_event != Some(addr)
+    }
+
+    /// Returns true if addr is where the dynamic linker reports changes to the loaded libraries.
+    pub fn is_library_event(&self, addr: u64) -> bool {
+        self.library_event == Some(addr)
+    }
+
+    /// Sets a breakpoint at addr for the debugger's own use, unless the user already has one
+    /// there. Stops at it aren't counted as hits.
+    pub fn set_temp_breakpoint(&mut self, addr: u64) {
+        if !self.breakpoint_map.contains_key(&addr) {
+            self.add_breakpoint(addr);
+            self.temp_breakpoint = Some(addr);
+        }
+    }
+
+    /// Removes the breakpoint set by set_temp_breakpoint, if there is one.
+    pub fn clear_temp_breakpoint(&mut self) {
+        if let Some(addr) = self.temp_breakpoint.take() {
+            self.remove_breakpoint(addr);
+        }
+    }
+
+    /// Arms a hardware watchpoint on len bytes at addr, where len is 1, 2, 4 or 8 and addr is
+    /// aligned to it. Returns the debug register slot used.
+    pub fn set_watchpoint(
+        &mut self,
+        addr: u64,
+        len: usize,
+        kind: WatchKind,
+    ) -> Result<usize, String> {
+        if self.remote.is_some() {
+            return Err(String::from(
+                "hardware watchpoints aren't supported remotely",
+            ));
+        }
+        let slot = self
+            .watch_slots
+            .iter()
+            .position(|slot| slot.is_none())
+            .ok_or(format!(
+                "all {} hardware watchpoint registers are in use",
+                NUM_WATCH_SLOTS
+            ))?;
+        // x86 can't trap on reads alone, so read watchpoints trap on any access too
+        let rw_bits: u64 = match kind {
+            WatchKind::Write => 0b01,
+            WatchKind::Read | WatchKind::Access => 0b11,
+        };
+        let len_bits: u64 = match len {
+            1 => 0b00,
+            2 => 0b01,
+            4 => 0b11,
+            8 => 0b10,
+            _ => return Err(format!("can't watch {} bytes", len)),
+        };
+        if !addr.is_multiple_of(len as u64) {
+            return Err(format!("{:#x} isn't aligned to {} bytes", addr, len));
+        }
+        let mut dr7 = self
+            .read_debug_register(7)
+            .map_err(|err| format!("{:?}", err))?;
+        dr7 &= !(0b1111 << (16 + slot * 4));
+        dr7 |= ((len_bits << 2 | rw_bits) << (16 + slot * 4)) | (1 << (slot * 2));
+        self.write_debug_register(slot, addr)
+            .and_then(|_| self.write_debug_register(7, dr7))
+            .map_err(|err| format!("{:?}", err))?;
+        self.watch_slots[slot] = Some(addr);
+        Ok(slot)
+    }
+
+    /// Disarms the hardware watchpoint in the given debug register slot.
+    pub fn clear_watchpoint(&mut self, slot: usize) {
+        if self.watch_slots[slot].take().is_none() {
+            return;
+        }
+        if let Ok(dr7) = self.read_debug_register(7) {
+            let dr7 = dr7 & !(0b1111 << (16 + slot * 4)) & !(0b11 << (slot * 2));
+            let _ = self.write_debug_register(7, dr7);
+        }
+        let _ = self.write_debug_register(slot, 0);
+    }
+
+    /// Returns the slots of the watchpoints that stopped the inferior, if any did. DR6 is reset,
+    /// since the processor never clears it.
+    pub fn triggered_watchpoints(&mut self) -> Vec<usize> {
+        let dr6 = match self.read_debug_register(6) {
+            Ok(dr6) => dr6,
+            Err(_) => return Vec::new(),
+        };
+        if dr6 & 0b1111 != 0 {
+            let _ = self.write_debug_register(6, 0);
+        }
+        (0..NUM_WATCH_SLOTS)
+            .filter(|slot| dr6 & (1 << slot) != 0 && self.watch_slots[*slot].is_some())
+            .collect()
+    }
+
+    /// Reads a len-byte (1, 2, 4 or 8) value at addr, which must be aligned to len.
+    pub fn read_value(&self, addr: u64, len: usize) -> Result<u64, nix::Error> {
         let aligned_addr = align_addr_to_word(addr);
-        let byte_offset = addr - aligned_addr;
-        let word = ptrace::read(self.pid(), aligned_addr as ptrace::AddressType)? as u64;
-        let orig_byte = (word >> 8 * byte_offset) & 0xff;
-        let masked_word = word & !(0xff << 8 * byte_offset);
-        let updated_word = masked_word | ((val as u64) << 8 * byte_offset);
-        ptrace::write(
-            self.pid(),
-            aligned_addr as ptrace::AddressType,
-            updated_word as *mut std::ffi::c_void,
-        )?;
-        Ok(orig_byte as u8)
-    }
-
-    pub fn cont(&mut self) -> Result<Status, nix::Error> {
-        let mut regs = ptrace::getregs(self.pid()).unwrap();
-
-        let mut rip = regs.rip as u64;
-        rip -= 1;
-
-        if self.breakpoint_map.contains_key(&rip) {
-            let bp = self.breakpoint_map.get(&rip).unwrap().clone();
-            let _ = self.write_byte(bp.get_addr(), bp.get_orig_byte());
-            regs.rip -= 1;
-            let _ = ptrace::setregs(self.pid(), regs);
-            let _ = ptrace::step(self.pid(), None);
-            let status = self.wait(None).unwrap();
+        let word = self.read_word(aligned_addr)?;
+        let value = word >> (8 * (addr - aligned_addr));
+        if len >= 8 {
+            Ok(value)
+        } else {
+            Ok(value & ((1 << (8 * len)) - 1))
+        }
+    }
+
+    fn read_debug_register(&self, idx: usize) -> Result<u64, nix::Error> {
+        let value = unsafe {
+            libc::ptrace(
+                libc::PTRACE_PEEKUSER,
+                self.pid().as_raw(),
+                (DEBUGREG_OFFSET + idx * 8) as *mut libc::c_void,
+                std::ptr::null_mut::<libc::c_void>(),
+            )
+        };
+        Ok(nix::errno::Errno::result(value)? as u64)
+    }
+
+    fn write_debug_register(&self, idx: usize, value: u64) -> Result<(), nix::Error> {
+        let result = unsafe {
+            libc::ptrace(
+                libc::PTRACE_POKEUSER,
+                self.pid().as_raw(),
+                (DEBUGREG_OFFSET + idx * 8) as *mut libc::c_void,
+                value as *mut libc::c_void,
+            )
+        };
+        nix::errno::Errno::result(result)?;
+        Ok(())
+    }
+
+    pub fn kill(&mut self) {
+        if let Some(remote) = &self.remote {
+            remote.kill();
+            println!("Killed remote process {}", self.pid());
+            return;
+        }
+        self.release_vfork_parent("kill");
+        match kill_traced(self.pid()).expect("couldn't kill the process") {
+            WaitStatus::Signaled(_, sig, _) => println!(
+                "Killed inferior process {} with signal: {} ({})",
+                self.pid(),
+                sig as i32,
+                sig
+            ),
+            WaitStatus::Exited(_, code) => {
+                println!("Inferior process {} exited (status {})", self.pid(), code)
+            }
+            _ => (),
+        }
+        // The process started may have been left running by following a fork; if it has exited
+        // since, it needs reaping
+        if let Some(child) = self.child.as_mut() {
+            let _ = child.try_wait();
+        }
+    }
+
+    /// Takes a checkpoint: forks a copy of the inferior that stays stopped where the inferior is,
+    /// for restart to go back to. Returns the checkpoint's number and the copy's pid.
+    pub fn checkpoint(&mut self) -> Result<(usize, Pid), nix::Error> {
+        if self.remote.is_some() {
+            return Err(nix::Error::from(nix::errno::Errno::ENOSYS));
+        }
+        let pid = fork_stopped_copy(self.pid())?;
+        let breakpoints = self
+            .breakpoint_map
+            .iter()
+            .map(|(addr, bp)| (*addr, bp.get_orig_byte()))
+            .collect();
+        self.checkpoints.push(Some(Checkpoint { pid, breakpoints }));
+        Ok((self.checkpoints.len() - 1, pid))
+    }
+
+    /// Goes back to checkpoint idx: kills the process being debugged and carries on with a new
+    /// copy of the checkpoint, which is left as it was so that it can be restarted again. Returns
+    /// the new process, or None if there is no such checkpoint.
+    pub fn restart(&mut self, idx: usize) -> Result<Option<Pid>, nix::Error> {
+        let checkpoint = match self.checkpoints.get(idx) {
+            Some(Some(checkpoint)) => checkpoint,
+            _ => return Ok(None),
+        };
+        let copy = fork_stopped_copy(checkpoint.pid)?;
+        // The copy has the breakpoints there were when the checkpoint was taken, rather than the
+        // ones there are now
+        for (addr, orig_byte) in &checkpoint.breakpoints {
+            write_byte(copy, *addr, *orig_byte)?;
+        }
+        for addr in self.breakpoint_map.keys() {
+            write_byte(copy, *addr, 0xcc)?;
+        }
+        self.release_vfork_parent("restart");
+        let old = self.pid();
+        self.switch_process(copy)?;
+        kill_traced(old)?;
+        // What was recorded happened to the process that was killed
+        if let Some(recording) = self.recording.as_mut() {
+            recording.clear();
+        }
+        Ok(Some(copy))
+    }
+
+    /// Returns the number, pid and %rip of each checkpoint.
+    pub fn checkpoints(&self) -> Vec<(usize, Pid, u64)> {
+        self.checkpoints
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, checkpoint)| {
+                let pid = checkpoint.as_ref()?.pid;
+                Some((idx, pid, ptrace::getregs(pid).ok()?.rip))
+            })
+            .collect()
+    }
+
+    /// Deletes checkpoint idx, killing its process. Returns false if there is no such checkpoint.
+    pub fn delete_checkpoint(&mut self, idx: usize) -> bool {
+        let checkpoint = self.checkpoints.get_mut(idx).and_then(Option::take);
+        match checkpoint {
+            Some(checkpoint) => {
+                let _ = kill_traced(checkpoint.pid);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn write_byte(&mut self, addr: u64, val: u8) -> Result<u8, nix::Error> {
+        match &self.remote {
+            Some(remote) => {
+                let orig_byte = remote.read_memory(addr, 1).map_err(remote_error)?[0];
+                remote.write_memory(addr, &[val]).map_err(remote_error)?;
+                Ok(orig_byte)
+            }
+            None => write_byte(self.pid(), addr, val),
+        }
+    }
+
+    /// Reads the word at addr, which must be aligned.
+    fn read_word(&self, addr: u64) -> Result<u64, nix::Error> {
+        match &self.remote {
+            Some(remote) => {
+                let bytes = remote.read_memory(addr, 8).map_err(remote_error)?;
+                let mut word = [0_u8; 8];
+                word.copy_from_slice(&bytes);
+                Ok(u64::from_le_bytes(word))
+            }
+            None => Ok(ptrace::read(self.pid(), addr as ptrace::AddressType)? as u64),
+        }
+    }
+
+    /// Writes bytes into the inferior's memory at addr. Bytes under a breakpoint are kept as the
+    /// breakpoint's original byte, so that they take effect once the breakpoint is removed.
+    pub fn write_bytes(&mut self, addr: u64, bytes: &[u8]) -> Result<(), nix::Error> {
+        for (offset, byte) in bytes.iter().enumerate() {
+            let byte_addr = addr + offset as u64;
+            match self.breakpoint_map.get_mut(&byte_addr) {
+                Some(bp) => bp.set_orig_byte(*byte),
+                None => {
+                    self.write_byte(byte_addr, *byte)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Resumes the inferior until it stops, delivering signal to it if one is given.
+    pub fn cont(&mut self, signal: Option<signal::Signal>) -> Result<Status, nix::Error> {
+        if let Some(status) = self.step_over_breakpoint()? {
             let is_sigtrap = match status {
                 Status::Stopped(sig, _) => sig == nix::sys::signal::SIGTRAP,
                 _ => false,
@@ -129,63 +674,778 @@ impl Inferior {
             if !is_sigtrap {
                 return Ok(status);
             }
+        }
 
-            self.add_breakpoint(bp.get_addr());
+        match &self.remote {
+            Some(remote) => RUNNING_REMOTE.store(remote.as_raw_fd(), Ordering::SeqCst),
+            None => RUNNING_PID.store(self.pid().as_raw(), Ordering::SeqCst),
+        }
+        let result = self.resume(false, signal);
+        RUNNING_PID.store(0, Ordering::SeqCst);
+        RUNNING_REMOTE.store(-1, Ordering::SeqCst);
+        let status = result?;
+        if INTERRUPTED.swap(false, Ordering::SeqCst) {
+            if let Status::Stopped(..) = status {
+                self.absorb_interrupt()?;
+            }
         }
 
-        let _ = ptrace::cont(self.pid(), None);
-        let status = self.wait(None).unwrap();
+        // The trap instruction has been executed; move %rip back to the breakpoint address, so
+        // that the inferior is stopped at the start of the original instruction. A recorded
+        // inferior stops before the trap instead.
+        if let Status::Stopped(nix::sys::signal::SIGTRAP, rip) = status {
+            let trap_addr = (rip as u64).wrapping_sub(1);
+            if self.breakpoint_map.contains_key(&trap_addr) && self.recording.is_none() {
+                let mut regs = self.get_registers()?;
+                regs.rip = trap_addr;
+                self.set_registers(regs)?;
+                self.record_breakpoint_hit(trap_addr);
+                return Ok(Status::Stopped(
+                    nix::sys::signal::SIGTRAP,
+                    trap_addr as usize,
+                ));
+            }
+        }
+        Ok(status)
+    }
 
+    /// Called when the user has interrupted the inferior. The terminal sends the inferior its own
+    /// SIGINT for a Ctrl+C, so whichever of that and our SIGSTOP didn't stop it may still be
+    /// pending, and would stop it again as soon as it was resumed. Resuming it into those stops
+    /// now means the user only sees one.
+    fn absorb_interrupt(&mut self) -> Result<(), nix::Error> {
+        for sig in [signal::SIGINT, signal::SIGSTOP].iter() {
+            if self.signal_pending(*sig) {
+                self.resume(false, None)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns true if sig is pending for the inferior, and not blocked, so that it will be
+    /// delivered when the inferior next runs.
+    fn signal_pending(&self, sig: signal::Signal) -> bool {
+        if self.remote.is_some() {
+            return false;
+        }
+        let status = match std::fs::read_to_string(format!("/proc/{}/status", self.pid())) {
+            Ok(status) => status,
+            Err(_) => return false,
+        };
+        let mask = |field: &str| {
+            status
+                .lines()
+                .find(|line| line.starts_with(field))
+                .and_then(|line| u64::from_str_radix(line[field.len()..].trim(), 16).ok())
+                .unwrap_or(0)
+        };
+        let bit = 1 << (sig as i32 - 1);
+        (mask("SigPnd:") | mask("ShdPnd:")) & !mask("SigBlk:") & bit != 0
+    }
+
+    /// Executes a single instruction.
+    pub fn step(&mut self) -> Result<Status, nix::Error> {
+        let status = match self.step_over_breakpoint()? {
+            Some(status) => status,
+            None => self.resume(true, None)?,
+        };
+        if let Status::Stopped(nix::sys::signal::SIGTRAP, rip) = status {
+            self.record_breakpoint_hit(rip as u64);
+        }
         Ok(status)
     }
 
+    /// If the inferior is stopped at one of our breakpoints, executes the original instruction
+    /// there with a single step and puts the trap back. Returns the status after the step, or
+    /// None if there was no breakpoint to step over.
+    fn step_over_breakpoint(&mut self) -> Result<Option<Status>, nix::Error> {
+        let rip = self.get_registers()?.rip;
+        let bp = match self.breakpoint_map.get(&rip) {
+            Some(bp) => bp.clone(),
+            None => return Ok(None),
+        };
+        self.write_byte(bp.get_addr(), bp.get_orig_byte())?;
+        let status = self.resume(true, None)?;
+        if let Status::Stopped(..) = status {
+            self.add_breakpoint(bp.get_addr());
+        }
+        Ok(Some(status))
+    }
+
+    /// Resumes the inferior, for a single instruction if step is set, and waits for it to stop.
+    /// Forks and execs on the way are dealt with here: after a fork, deet detaches from the
+    /// process follow_fork_mode doesn't follow and carries on, while an exec stops the inferior
+    /// with a SIGTRAP, as it would without PTRACE_O_TRACEEXEC. Events in caught_events stop the
+    /// inferior with Status::Caught instead. System calls are traced here too, except while
+    /// recording, when the inferior only ever runs a single instruction at a time.
+    fn resume(&mut self, step: bool, signal: Option<signal::Signal>) -> Result<Status, nix::Error> {
+        if self.recording.is_some() {
+            if !step {
+                return self.resume_recording(signal);
+            }
+            self.record_instruction()?;
+        }
+        if let Some(remote) = &self.remote {
+            // gdbserver doesn't report forks, execs or system calls
+            let status = remote.resume(step, signal).map_err(remote_error)?;
+            return self.status_from(status);
+        }
+        let mut signal = signal;
+        loop {
+            let syscall_stops = !step && self.stops_at_syscalls();
+            if step {
+                ptrace::step(self.pid(), signal)?;
+            } else if syscall_stops {
+                resume_to_syscall(self.pid(), signal)?;
+            } else {
+                ptrace::cont(self.pid(), signal)?;
+            }
+            if !syscall_stops {
+                // The return from a system call is only reported when resuming with
+                // PTRACE_SYSCALL from its entry
+                self.syscall = None;
+            }
+            signal = None;
+            let event = match waitpid(self.pid(), None)? {
+                WaitStatus::PtraceEvent(_, _, event) => event,
+                WaitStatus::PtraceSyscall(_) => match self.syscall_stop()? {
+                    Some(status) => return Ok(status),
+                    None => continue,
+                },
+                status => {
+                    let status = self.status_from(status)?;
+                    if let Status::Exited(_) | Status::Signaled(_) = status {
+                        self.release_vfork_parent("exit");
+                        // exit and exit_group don't return
+                        match self.syscall.take() {
+                            Some(syscall) if self.trace_syscalls => {
+                                println!("{} = ?", syscall.call)
+                            }
+                            _ => (),
+                        }
+                    }
+                    return Ok(status);
+                }
+            };
+            if event == ptrace::Event::PTRACE_EVENT_FORK as i32
+                || event == ptrace::Event::PTRACE_EVENT_VFORK as i32
+            {
+                let vfork = event == ptrace::Event::PTRACE_EVENT_VFORK as i32;
+                let child = Pid::from_raw(ptrace::getevent(self.pid())? as i32);
+                // The new child starts out stopped with a SIGSTOP
+                waitpid(child, Some(WaitPidFlag::__WALL))?;
+                self.follow_fork(child, vfork)?;
+                let event = if vfork {
+                    ProcessEvent::Vfork(child)
+                } else {
+                    ProcessEvent::Fork(child)
+                };
+                if self.is_caught(&event) {
+                    return self.caught(event);
+                }
+            } else if event == ptrace::Event::PTRACE_EVENT_VFORK_DONE as i32 {
+                // The detached vfork child has exec'd or exited, so the parent has its memory
+                // to itself again
+                let addrs: Vec<u64> = self.breakpoint_map.keys().copied().collect();
+                for addr in addrs {
+                    self.write_byte(addr, 0xcc)?;
+                }
+            } else if event == ptrace::Event::PTRACE_EVENT_EXEC as i32 {
+                self.release_vfork_parent("exec");
+                // The breakpoints and the recording went with the old program's code
+                self.breakpoint_map.clear();
+                self.recording = None;
+                self.temp_breakpoint = None;
+                self.watch_slots = [None; NUM_WATCH_SLOTS];
+                let path = std::fs::read_link(format!("/proc/{}/exe", self.pid()))
+                    .map(|path| path.display().to_string())
+                    .unwrap_or_default();
+                println!("process {} is executing new program: {}", self.pid(), path);
+                let event = ProcessEvent::Exec(path);
+                if self.is_caught(&event) {
+                    return self.caught(event);
+                }
+                let rip = ptrace::getregs(self.pid())?.rip;
+                return Ok(Status::Stopped(nix::sys::signal::SIGTRAP, rip as usize));
+            } else if event == ptrace::Event::PTRACE_EVENT_EXIT as i32 {
+                let event = ProcessEvent::Exit(ptrace::getevent(self.pid())? as i32);
+                if self.is_caught(&event) {
+                    return self.caught(event);
+                }
+            }
+        }
+    }
+
+    /// Single-steps the inferior, recording each instruction, until it reaches a breakpoint,
+    /// triggers a watchpoint or stops for some other reason. It stops before a breakpoint's trap
+    /// rather than after it, as it would when let run.
+    fn resume_recording(&mut self, signal: Option<signal::Signal>) -> Result<Status, nix::Error> {
+        let mut signal = signal;
+        loop {
+            let rip = self.get_registers()?.rip;
+            if self.breakpoint_map.contains_key(&rip) {
+                self.record_breakpoint_hit(rip);
+                return Ok(Status::Stopped(nix::sys::signal::SIGTRAP, rip as usize));
+            }
+            let status = self.resume(true, signal.take())?;
+            match status {
+                // An exec stops recording, and the inferior with it
+                Status::Stopped(nix::sys::signal::SIGTRAP, _) if self.recording.is_some() => (),
+                status => return Ok(status),
+            }
+            // DR6 has a bit set for each watchpoint triggered. A remote inferior has none.
+            if self.remote.is_none() && self.read_debug_register(6)? & 0b1111 != 0 {
+                return Ok(status);
+            }
+        }
+    }
+
+    /// Logs the registers, and the memory the instruction at %rip may write, before the
+    /// instruction is executed.
+    fn record_instruction(&mut self) -> Result<(), nix::Error> {
+        let regs = self.get_registers()?;
+        // Code at the end of a mapping can be shorter than MAX_INSTRUCTION_LEN
+        let code = self
+            .read_bytes(regs.rip, MAX_INSTRUCTION_LEN)
+            .or_else(|_| self.read_bytes(regs.rip, 1))
+            .unwrap_or_default();
+        let instruction = Decoder::with_ip(64, &code, regs.rip, DecoderOptions::NONE).decode();
+        let memory = record::written_memory(&instruction, &regs)
+            .into_iter()
+            // Memory that can't be read, such as stack not mapped yet, has nothing to restore
+            .filter_map(|(addr, len)| Some((addr, self.read_bytes(addr, len).ok()?)))
+            .collect();
+        if let Some(recording) = self.recording.as_mut() {
+            recording.push(Entry { regs, memory });
+        }
+        Ok(())
+    }
+
+    /// Starts recording the instructions the inferior executes. Returns false if it already is.
+    pub fn start_recording(&mut self) -> bool {
+        if self.recording.is_some() {
+            return false;
+        }
+        self.recording = Some(Recording::default());
+        true
+    }
+
+    /// Stops recording, discarding the log. Returns false if the inferior wasn't being recorded.
+    pub fn stop_recording(&mut self) -> bool {
+        self.recording.take().is_some()
+    }
+
+    /// Returns how many instructions have been recorded, or None if recording hasn't started.
+    pub fn recorded_instructions(&self) -> Option<usize> {
+        self.recording.as_ref().map(Recording::len)
+    }
+
+    /// Undoes the last instruction recorded, putting back the registers and memory it changed.
+    /// Returns false if there is nothing recorded to undo.
+    pub fn reverse_step(&mut self) -> Result<bool, nix::Error> {
+        let entry = match self.recording.as_mut().and_then(Recording::pop) {
+            Some(entry) => entry,
+            None => return Ok(false),
+        };
+        for (addr, bytes) in entry.memory.iter().rev() {
+            self.write_bytes(*addr, bytes)?;
+        }
+        self.set_registers(entry.regs)?;
+        self.syscall = None;
+        Ok(true)
+    }
+
+    /// Returns the address of the last instruction recorded, which reverse_step would undo next.
+    pub fn last_recorded_pc(&self) -> Option<u64> {
+        Some(self.recording.as_ref()?.last()?.regs.rip)
+    }
+
+    /// Handles the inferior stopping at the entry to or return from a system call: prints the
+    /// call and its result once it has returned, if tracing, and returns the status to stop with
+    /// if a catchpoint catches it.
+    fn syscall_stop(&mut self) -> Result<Option<Status>, nix::Error> {
+        let regs = self.get_registers()?;
+        let event = match self.syscall.take() {
+            None => {
+                let syscall = Syscall::decode(self, &regs);
+                self.syscall = Some(syscall.clone());
+                ProcessEvent::SyscallEntry(syscall)
+            }
+            Some(syscall) => {
+                let value = regs.rax as i64;
+                if self.trace_syscalls {
+                    println!("{} = {}", syscall.call, syscall.format_result(value));
+                }
+                ProcessEvent::SyscallReturn(syscall, value)
+            }
+        };
+        if self.is_caught(&event) {
+            return Ok(Some(self.caught(event)?));
+        }
+        Ok(None)
+    }
+
+    fn is_caught(&self, event: &ProcessEvent) -> bool {
+        self.caught_events.iter().any(|kind| kind.catches(event))
+    }
+
+    /// Returns true if the inferior needs to stop at system calls, to trace or catch them.
+    fn stops_at_syscalls(&self) -> bool {
+        self.trace_syscalls
+            || self
+                .caught_events
+                .iter()
+                .any(|kind| matches!(kind, EventKind::Syscall(_)))
+    }
+
+    fn caught(&self, event: ProcessEvent) -> Result<Status, nix::Error> {
+        let rip = self.get_registers()?.rip;
+        Ok(Status::Caught(event, rip as usize))
+    }
+
+    /// Detaches from whichever side of a fork follow_fork_mode doesn't follow, taking the
+    /// breakpoints out of its memory first so that it doesn't trap on them. Both sides are
+    /// stopped.
+    fn follow_fork(&mut self, child: Pid, vfork: bool) -> Result<(), nix::Error> {
+        let parent = self.pid();
+        let kind = if vfork { "vfork" } else { "fork" };
+        if self.follow_fork_mode == FollowForkMode::Parent {
+            // A vfork child shares its parent's memory, so this takes the breakpoints out of the
+            // parent's too; they are put back at PTRACE_EVENT_VFORK_DONE
+            self.remove_breakpoints_from(child)?;
+            ptrace::detach(child, None)?;
+            println!("[Detaching after {} from child process {}]", kind, child);
+            return Ok(());
+        }
+        println!(
+            "[Attaching after process {} {} to child process {}]",
+            parent, kind, child
+        );
+        self.switch_process(child)?;
+        if vfork {
+            self.vfork_parent = Some(parent);
+        } else {
+            self.remove_breakpoints_from(parent)?;
+            ptrace::detach(parent, None)?;
+            println!("[Detaching after fork from parent process {}]", parent);
+        }
+        Ok(())
+    }
+
+    /// Makes pid, a copy of the process being debugged made by fork, the process being debugged.
+    /// Debug registers aren't inherited, so watchpoints are moved over to it.
+    fn switch_process(&mut self, pid: Pid) -> Result<(), nix::Error> {
+        let mut debug_registers = Vec::new();
+        for idx in (0..NUM_WATCH_SLOTS).chain(std::iter::once(7)) {
+            debug_registers.push((idx, self.read_debug_register(idx)?));
+        }
+        self.write_debug_register(7, 0)?;
+        self.pid = pid;
+        for (idx, value) in debug_registers {
+            self.write_debug_register(idx, value)?;
+        }
+        // A forked child returns from fork without a stop for it
+        self.syscall = None;
+        Ok(())
+    }
+
+    /// Detaches from the parent of a vfork whose child was followed, once the child has exec'd
+    /// or exited (or is being killed) and so no longer shares the parent's memory.
+    fn release_vfork_parent(&mut self, reason: &str) {
+        if let Some(parent) = self.vfork_parent.take() {
+            let _ = self.remove_breakpoints_from(parent);
+            let _ = ptrace::detach(parent, None);
+            println!(
+                "[Detaching vfork parent process {} after child {}]",
+                parent, reason
+            );
+        }
+    }
+
+    /// Writes the original bytes of the breakpoints back into the memory of pid, which is a copy
+    /// of the inferior made by fork.
+    fn remove_breakpoints_from(&self, pid: Pid) -> Result<(), nix::Error> {
+        for bp in self.breakpoint_map.values() {
+            write_byte(pid, bp.get_addr(), bp.get_orig_byte())?;
+        }
+        Ok(())
+    }
+
+    /// Sets which process events stop the inferior.
+    pub fn set_caught_events(&mut self, events: Vec<EventKind>) {
+        self.caught_events = events;
+    }
+
+    /// Sets whether to print the system calls the inferior makes.
+    pub fn set_trace_syscalls(&mut self, trace: bool) {
+        self.trace_syscalls = trace;
+    }
+
+    /// Sets which side of a fork deet keeps debugging.
+    pub fn set_follow_fork_mode(&mut self, mode: FollowForkMode) {
+        self.follow_fork_mode = mode;
+    }
+
+    /// Counts a stop at addr, if the user has a breakpoint there.
+    fn record_breakpoint_hit(&mut self, addr: u64) {
+        if self.is_breakpoint(addr) {
+            *self.hit_counts.entry(addr).or_insert(0) += 1;
+        }
+    }
+
+    /// Returns true if the instruction at addr is a call.
+    pub fn is_call_instruction(&self, addr: u64) -> Result<bool, nix::Error> {
+        let code = self.read_bytes(addr, 8)?;
+        // Skip operand size, address size, segment and REX prefixes
+        let opcode = code
+            .iter()
+            .position(|byte| !matches!(byte, 0x66 | 0x67 | 0x2e | 0x3e | 0xf2 | 0x40..=0x4f))
+            .unwrap_or(code.len() - 1);
+        Ok(match code[opcode] {
+            0xe8 => true,
+            // Indirect calls are 0xff with 2 (near) or 3 (far) in the ModR/M reg field
+            0xff => opcode + 1 < code.len() && matches!((code[opcode + 1] >> 3) & 0b111, 2 | 3),
+            _ => false,
+        })
+    }
+
+    /// Reads len bytes of the inferior's memory starting at addr, as they were before any of our
+    /// breakpoints were written over them.
+    pub fn read_bytes(&self, addr: u64, len: usize) -> Result<Vec<u8>, nix::Error> {
+        let mut bytes = match &self.remote {
+            Some(remote) => remote.read_memory(addr, len).map_err(remote_error)?,
+            None => {
+                let mut bytes = Vec::with_capacity(len);
+                let mut word_addr = align_addr_to_word(addr);
+                while word_addr < addr + len as u64 {
+                    let word = ptrace::read(self.pid(), word_addr as ptrace::AddressType)? as u64;
+                    bytes.extend_from_slice(&word.to_le_bytes());
+                    word_addr += size_of::<u64>() as u64;
+                }
+                let start = (addr - align_addr_to_word(addr)) as usize;
+                bytes[start..start + len].to_vec()
+            }
+        };
+        for (offset, byte) in bytes.iter_mut().enumerate() {
+            if let Some(bp) = self.breakpoint_map.get(&(addr + offset as u64)) {
+                *byte = bp.get_orig_byte();
+            }
+        }
+        Ok(bytes)
+    }
+
+    /// Disassembles the instruction at addr, in AT&T syntax like gdb. Returns the instruction and
+    /// its length in bytes.
+    pub fn disassemble(&self, addr: u64) -> Result<(String, usize), nix::Error> {
+        let code = self.read_bytes(addr, MAX_INSTRUCTION_LEN)?;
+        let mut decoder = Decoder::with_ip(64, &code, addr, DecoderOptions::NONE);
+        let instruction = decoder.decode();
+        if instruction.is_invalid() {
+            return Ok((String::from("(bad)"), 1));
+        }
+        let mut text = String::new();
+        GasFormatter::new().format(&instruction, &mut text);
+        Ok((text, instruction.len()))
+    }
+
+    /// Returns the path and load bias of each shared library the inferior has mapped, including
+    /// the dynamic linker.
+    pub fn libraries(&self, target: &str) -> Vec<(String, u64)> {
+        if self.remote.is_some() {
+            return Vec::new();
+        }
+        let executable = std::fs::canonicalize(target).ok();
+        library::mapped_objects(self.pid())
+            .into_iter()
+            .filter(|(path, _)| executable.as_deref() != Some(std::path::Path::new(path)))
+            .collect()
+    }
+
+    /// Returns how far the executable was moved from the addresses in its debugging information.
+    pub fn load_bias(&self) -> u64 {
+        self.load_bias
+    }
+
+    /// Returns the ids of the inferior's threads, from /proc/pid/task, with the main thread first.
+    /// Only the main thread of a remote inferior is known.
+    pub fn thread_ids(&self) -> Vec<Pid> {
+        if self.remote.is_some() {
+            return vec![self.pid()];
+        }
+        let mut ids: Vec<Pid> = match std::fs::read_dir(format!("/proc/{}/task", self.pid())) {
+            Ok(entries) => entries
+                .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+                .map(Pid::from_raw)
+                .collect(),
+            Err(_) => vec![self.pid()],
+        };
+        ids.sort_by_key(|tid| (*tid != self.pid(), tid.as_raw()));
+        ids
+    }
+
+    /// Returns the name of one of the inferior's threads, which is the program's name unless the
+    /// thread has set its own.
+    pub fn thread_name(&self, tid: Pid) -> String {
+        if self.remote.is_some() {
+            return String::new();
+        }
+        std::fs::read_to_string(format!("/proc/{}/task/{}/comm", self.pid(), tid))
+            .map(|name| name.trim_end().to_string())
+            .unwrap_or_default()
+    }
+
+    /// Stops one of the inferior's threads other than the main one by attaching to it, since only
+    /// the main thread is traced, and returns its registers. It stays stopped until detach_thread.
+    pub fn attach_thread(&self, tid: Pid) -> Result<libc::user_regs_struct, nix::Error> {
+        ptrace::attach(tid)?;
+        // Attaching sends the thread a SIGSTOP, which it stops to deliver
+        waitpid(tid, Some(WaitPidFlag::__WALL))?;
+        ptrace::getregs(tid)
+    }
+
+    /// Lets a thread stopped by attach_thread run again, discarding the SIGSTOP it stopped for.
+    pub fn detach_thread(&self, tid: Pid) {
+        let _ = ptrace::detach(tid, None);
+    }
+
+    /// Returns the inferior's registers.
+    pub fn get_registers(&self) -> Result<libc::user_regs_struct, nix::Error> {
+        match &self.remote {
+            Some(remote) => remote.get_registers().map_err(remote_error),
+            None => ptrace::getregs(self.pid()),
+        }
+    }
+
+    fn set_registers(&self, regs: libc::user_regs_struct) -> Result<(), nix::Error> {
+        match &self.remote {
+            Some(remote) => remote.set_registers(regs).map_err(remote_error),
+            None => ptrace::setregs(self.pid(), regs),
+        }
+    }
+
+    /// Returns the inferior's floating point and SSE registers, in the 512-byte layout of the
+    /// fxsave instruction.
+    pub fn get_fp_registers(&self) -> Result<[u8; FP_REGISTERS_LEN], nix::Error> {
+        if self.remote.is_some() {
+            return Err(nix::Error::from(nix::errno::Errno::ENOSYS));
+        }
+        let mut fp_registers = [0_u8; FP_REGISTERS_LEN];
+        let result = unsafe {
+            libc::ptrace(
+                libc::PTRACE_GETFPREGS,
+                self.pid().as_raw(),
+                std::ptr::null_mut::<libc::c_void>(),
+                fp_registers.as_mut_ptr() as *mut libc::c_void,
+            )
+        };
+        nix::errno::Errno::result(result)?;
+        Ok(fp_registers)
+    }
+
+    /// Reads a region of memory through /proc/pid/mem, which unlike read_bytes takes a single
+    /// system call however large the region is. Breakpoints read as the bytes they replaced.
+    pub fn read_region(&self, addr: u64, len: usize) -> std::io::Result<Vec<u8>> {
+        let mut bytes = match &self.remote {
+            Some(remote) => remote.read_memory(addr, len)?,
+            None => {
+                let mut mem = std::fs::File::open(format!("/proc/{}/mem", self.pid()))?;
+                std::io::Seek::seek(&mut mem, std::io::SeekFrom::Start(addr))?;
+                let mut bytes = vec![0_u8; len];
+                mem.read_exact(&mut bytes)?;
+                bytes
+            }
+        };
+        for (bp_addr, bp) in &self.breakpoint_map {
+            if *bp_addr >= addr && *bp_addr < addr + len as u64 {
+                bytes[(bp_addr - addr) as usize] = bp.get_orig_byte();
+            }
+        }
+        Ok(bytes)
+    }
+
+    /// Returns how many times the inferior has stopped at the breakpoint at breakpoint_addr.
+    pub fn get_hit_count(&self, breakpoint_addr: u64) -> usize {
+        self.hit_counts.get(&breakpoint_addr).copied().unwrap_or(0)
+    }
+
     /// Returns the pid of this inferior.
     pub fn pid(&self) -> Pid {
-        nix::unistd::Pid::from_raw(self.child.id() as i32)
+        self.pid
+    }
+
+    /// Returns true if the inferior is running under gdbserver rather than being traced by deet.
+    pub fn is_remote(&self) -> bool {
+        self.remote.is_some()
     }
 
     /// Calls waitpid on this inferior and returns a Status to indicate the state of the process
     /// after the waitpid call.
     pub fn wait(&self, options: Option<WaitPidFlag>) -> Result<Status, nix::Error> {
-        Ok(match waitpid(self.pid(), options)? {
+        self.status_from(waitpid(self.pid(), options)?)
+    }
+
+    /// Converts what waitpid returned for the inferior into a Status.
+    fn status_from(&self, status: WaitStatus) -> Result<Status, nix::Error> {
+        Ok(match status {
             WaitStatus::Exited(_pid, exit_code) => Status::Exited(exit_code),
             WaitStatus::Signaled(_pid, signal, _core_dumped) => Status::Signaled(signal),
             WaitStatus::Stopped(_pid, signal) => {
-                let regs = ptrace::getregs(self.pid())?;
+                let regs = self.get_registers()?;
                 Status::Stopped(signal, regs.rip as usize)
             }
             other => panic!("waitpid returned unexpected status: {:?}", other),
         })
     }
+}
 
-    pub fn print_backtrace(&self, dwarf_data: &DwarfData) -> Result<(), nix::Error> {
-        let regs = ptrace::getregs(self.pid()).unwrap();
+impl Drop for Inferior {
+    /// Kills the checkpoints, which would otherwise stay stopped once deet has finished with the
+    /// inferior.
+    fn drop(&mut self) {
+        for checkpoint in self.checkpoints.iter().flatten() {
+            let _ = kill_traced(checkpoint.pid);
+        }
+    }
+}
 
-        let mut rbp = regs.rbp as usize;
-        let mut rip = regs.rip as usize;
-        let mut rbp_plus_8: usize;
+/// Returns the load bias of a position-independent executable, read from where it is mapped in
+/// /proc/pid/maps. Other executables are loaded at the addresses they were linked at, so their
+/// bias is zero.
+fn find_load_bias(pid: Pid, target: &str) -> Option<u64> {
+    // e_type is ET_DYN (3) for position-independent executables
+    let mut header = [0_u8; 18];
+    std::fs::File::open(target)
+        .ok()?
+        .read_exact(&mut header)
+        .ok()?;
+    if u16::from_le_bytes([header[16], header[17]]) != 3 {
+        return Some(0);
+    }
+    let path = std::fs::canonicalize(target).ok()?;
+    library::mapped_objects(pid)
+        .into_iter()
+        .find(|(object, _)| std::path::Path::new(object) == path)
+        .map(|(_, load_bias)| load_bias)
+}
 
-        loop {
-            let line = dwarf_data.get_line_from_addr(rip).unwrap_or(Line {
-                file: String::from(""),
-                number: 0,
-                address: 0,
-            });
-            let function = dwarf_data
-                .get_function_from_addr(rip)
-                .unwrap_or(String::from("couldn't find the function"));
+/// Returns the load bias of a position-independent executable running under gdbserver, which is
+/// how far its entry point has moved from the one in its ELF header.
+fn remote_load_bias(remote: &Remote, target: &str) -> Option<u64> {
+    let mut header = [0_u8; 32];
+    std::fs::File::open(target)
+        .ok()?
+        .read_exact(&mut header)
+        .ok()?;
+    if u16::from_le_bytes([header[16], header[17]]) != 3 {
+        return Some(0);
+    }
+    let mut entry = [0_u8; 8];
+    entry.copy_from_slice(&header[24..32]);
+    Some(remote.entry_point().ok()? - u64::from_le_bytes(entry))
+}
 
-            println!("{} ({})", function, line);
-            if function == String::from("main") {
-                break;
+/// Returns where the dynamic linker's library event function is in a process stopped at exec,
+/// when the dynamic linker is the only object mapped besides the executable.
+fn find_library_event(pid: Pid, target: &str) -> Option<u64> {
+    let executable = std::fs::canonicalize(target).ok()?;
+    let (linker, load_bias) = library::mapped_objects(pid)
+        .into_iter()
+        .find(|(path, _)| std::path::Path::new(path) != executable)?;
+    let symbols = library::read_symbols(&linker);
+    Some(symbols.get(library::LIBRARY_EVENT_SYMBOL)? + load_bias)
+}
+
+/// Kills pid, which deet is tracing, and waits for it to die. Returns the status it died with,
+/// which is an exit rather than the SIGKILL if it was exiting already. A process that isn't deet's
+/// own child, such as a forked child being followed, can be waited for as a tracee.
+fn kill_traced(pid: Pid) -> Result<WaitStatus, nix::Error> {
+    signal::kill(pid, signal::SIGKILL)?;
+    loop {
+        match waitpid(pid, Some(WaitPidFlag::__WALL))? {
+            status @ WaitStatus::Signaled(..) | status @ WaitStatus::Exited(..) => {
+                return Ok(status)
+            }
+            // It may stop at PTRACE_EVENT_EXIT on the way out
+            _ => {
+                let _ = ptrace::cont(pid, None);
             }
-            rbp_plus_8 = rbp + 8;
-            rip = ptrace::read(self.pid(), rbp_plus_8 as ptrace::AddressType)? as usize;
-            rbp = ptrace::read(self.pid(), rbp as ptrace::AddressType)? as usize;
         }
+    }
+}
 
-        Ok(())
+/// Makes pid, which is stopped, fork, by having it make a fork system call in place of the
+/// instruction at its %rip. Returns the new process. Both are left stopped where pid was, with
+/// the same registers and memory.
+fn fork_stopped_copy(pid: Pid) -> Result<Pid, nix::Error> {
+    let regs = ptrace::getregs(pid)?;
+    // The syscall instruction is 0f 05
+    let orig_bytes = [
+        write_byte(pid, regs.rip, 0x0f)?,
+        write_byte(pid, regs.rip + 1, 0x05)?,
+    ];
+    let mut call_regs = regs;
+    call_regs.rax = libc::SYS_fork as u64;
+    // Keeps the kernel from restarting a system call the process was interrupted in instead
+    call_regs.orig_rax = u64::MAX;
+    ptrace::setregs(pid, call_regs)?;
+    ptrace::step(pid, None)?;
+    let mut copy = None;
+    // The fork stops at PTRACE_EVENT_FORK, then the step finishes after it
+    while let WaitStatus::PtraceEvent(_, _, event) = waitpid(pid, None)? {
+        if event == ptrace::Event::PTRACE_EVENT_FORK as i32 {
+            let child = Pid::from_raw(ptrace::getevent(pid)? as i32);
+            // The new child starts out stopped with a SIGSTOP
+            waitpid(child, Some(WaitPidFlag::__WALL))?;
+            copy = Some(child);
+        }
+        ptrace::step(pid, None)?;
+    }
+    let result = ptrace::getregs(pid)?.rax as i64;
+    for process in std::iter::once(pid).chain(copy) {
+        write_byte(process, regs.rip, orig_bytes[0])?;
+        write_byte(process, regs.rip + 1, orig_bytes[1])?;
+        ptrace::setregs(process, regs)?;
     }
+    copy.ok_or_else(|| nix::Error::from(nix::errno::Errno::from_i32(-result as i32)))
+}
+
+/// Resumes pid until it enters or returns from a system call, delivering signal to it if one is
+/// given. nix's ptrace::syscall can't deliver a signal, so this calls ptrace itself.
+fn resume_to_syscall(pid: Pid, signal: Option<signal::Signal>) -> Result<(), nix::Error> {
+    let signal = signal.map_or(0, |sig| sig as libc::c_long);
+    let result = unsafe {
+        libc::ptrace(
+            libc::PTRACE_SYSCALL,
+            pid.as_raw(),
+            std::ptr::null_mut::<libc::c_void>(),
+            signal,
+        )
+    };
+    nix::errno::Errno::result(result)?;
+    Ok(())
+}
+
+/// Writes a byte into the memory of pid, returning the byte that was there.
+fn write_byte(pid: Pid, addr: u64, val: u8) -> Result<u8, nix::Error> {
+    let aligned_addr = align_addr_to_word(addr);
+    let byte_offset = addr - aligned_addr;
+    let word = ptrace::read(pid, aligned_addr as ptrace::AddressType)? as u64;
+    let orig_byte = (word >> (8 * byte_offset)) & 0xff;
+    let masked_word = word & !(0xff << (8 * byte_offset));
+    let updated_word = masked_word | ((val as u64) << (8 * byte_offset));
+    ptrace::write(
+        pid,
+        aligned_addr as ptrace::AddressType,
+        updated_word as *mut std::ffi::c_void,
+    )?;
+    Ok(orig_byte as u8)
+}
+
+/// Converts an error talking to gdbserver into the nix::Error a failed ptrace call would give.
+fn remote_error(err: std::io::Error) -> nix::Error {
+    nix::Error::from(nix::errno::Errno::from_i32(
+        err.raw_os_error().unwrap_or(libc::EIO),
+    ))
 }
 
 fn align_addr_to_word(addr: u64) -> u64 {
@@ -0,0 +1,176 @@
+//! Shared libraries mapped into the inferior. The dynamic linker calls _dl_debug_state whenever
+//! it has loaded or unloaded libraries, so with a breakpoint there the debugger can find out
+//! which are mapped where, and read their debugging information.
+
+use crate::dwarf_data::{self, DwarfData};
+use nix::unistd::Pid;
+use object::{Object, SymbolKind};
+use std::collections::HashMap;
+
+/// Function the dynamic linker calls after changing the set of loaded libraries
+pub const LIBRARY_EVENT_SYMBOL: &str = "_dl_debug_state";
+
+pub struct Library {
+    pub path: String,
+    /// How far the library was moved from the addresses in its symbols and debugging information
+    pub load_bias: u64,
+    /// Debugging information, if the library has any
+    pub debug_data: Option<DwarfData>,
+    /// Addresses of the functions in the library's symbol tables, before relocation
+    symbols: HashMap<String, u64>,
+}
+
+impl Library {
+    pub fn load(path: &str, load_bias: u64) -> Library {
+        let debug_data = DwarfData::from_file(path).ok().map(|mut debug_data| {
+            debug_data.set_load_bias(load_bias as usize);
+            debug_data
+        });
+        Library {
+            path: path.to_string(),
+            load_bias,
+            debug_data,
+            symbols: read_symbols(path),
+        }
+    }
+
+    /// Returns where a function in the library was loaded, looking it up in the debugging
+    /// information first and then in the symbol tables, which stripped libraries still have.
+    pub fn function_address(&self, name: &str) -> Option<u64> {
+        self.debug_data
+            .as_ref()
+            .and_then(|debug_data| debug_data.get_addr_for_function(None, name))
+            .map(|addr| addr as u64)
+            .or_else(|| Some(self.symbols.get(name)? + self.load_bias))
+    }
+}
+
+/// Returns the addresses of the functions in an ELF file's symbol table and dynamic symbol table.
+/// Rust and C++ functions can be found by their demangled names too, with or without the
+/// parameter list of a C++ name.
+pub fn read_symbols(path: &str) -> HashMap<String, u64> {
+    let data = match std::fs::read(path) {
+        Ok(data) => data,
+        Err(_) => return HashMap::new(),
+    };
+    let file = match object::File::parse(&data) {
+        Ok(file) => file,
+        Err(_) => return HashMap::new(),
+    };
+    let mut symbols = HashMap::new();
+    for (_, symbol) in file.symbols().chain(file.dynamic_symbols()) {
+        let name = match symbol.name() {
+            Some(name) if symbol.kind() == SymbolKind::Text && symbol.address() != 0 => name,
+            _ => continue,
+        };
+        let demangled = dwarf_data::demangle(name);
+        if let Some(without_params) = demangled.split('(').next() {
+            symbols
+                .entry(without_params.to_string())
+                .or_insert(symbol.address());
+        }
+        symbols.entry(demangled).or_insert(symbol.address());
+        symbols.insert(name.to_string(), symbol.address());
+    }
+    symbols
+}
+
+/// A region of memory mapped into a process, as listed in /proc/pid/maps
+pub struct Mapping {
+    pub start: u64,
+    pub end: u64,
+    /// Permissions, such as r-xp for readable, not writable, executable and private
+    pub perms: String,
+    /// Where in the mapped file the region starts
+    pub offset: u64,
+    /// File mapped, or a name such as [stack] or [heap] for memory that isn't backed by a file.
+    /// Empty for anonymous memory.
+    pub path: String,
+}
+
+/// Returns the regions of memory mapped into a process, in order of address.
+pub fn read_mappings(pid: Pid) -> std::io::Result<Vec<Mapping>> {
+    let maps = std::fs::read_to_string(format!("/proc/{}/maps", pid))?;
+    Ok(maps.lines().filter_map(parse_mapping).collect())
+}
+
+fn parse_mapping(line: &str) -> Option<Mapping> {
+    // Each line is: start-end perms offset dev inode path
+    let mut fields = line.split_whitespace();
+    let (start, end) = fields.next()?.split_once('-')?;
+    let perms = fields.next()?.to_string();
+    let offset = u64::from_str_radix(fields.next()?, 16).ok()?;
+    let path = fields.skip(2).collect::<Vec<&str>>().join(" ");
+    Some(Mapping {
+        start: u64::from_str_radix(start, 16).ok()?,
+        end: u64::from_str_radix(end, 16).ok()?,
+        perms,
+        offset,
+        path,
+    })
+}
+
+/// Returns the path and load bias of each ELF file mapped into a process, in the order they are
+/// mapped.
+pub fn mapped_objects(pid: Pid) -> Vec<(String, u64)> {
+    let mut objects: Vec<(String, u64)> = Vec::new();
+    for mapping in read_mappings(pid).unwrap_or_default() {
+        if !mapping.path.starts_with('/') {
+            continue;
+        }
+        if objects.iter().any(|(path, _)| *path == mapping.path) || !is_elf(&mapping.path) {
+            continue;
+        }
+        // The first mapping of an object holds the start of the file
+        objects.push((mapping.path, mapping.start - mapping.offset));
+    }
+    objects
+}
+
+fn is_elf(path: &str) -> bool {
+    let mut magic = [0_u8; 4];
+    std::fs::File::open(path)
+        .and_then(|mut file| std::io::Read::read_exact(&mut file, &mut magic))
+        .is_ok()
+        && &magic == b"\x7fELF"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_mapping_of_a_file() {
+        let mapping = parse_mapping(
+            "7f1c2a000000-7f1c2a028000 r--p 00001000 08:01 1048602    /usr/lib/libc.so.6",
+        )
+        .unwrap();
+        assert_eq!(mapping.start, 0x7f1c2a000000);
+        assert_eq!(mapping.end, 0x7f1c2a028000);
+        assert_eq!(mapping.perms, "r--p");
+        assert_eq!(mapping.offset, 0x1000);
+        assert_eq!(mapping.path, "/usr/lib/libc.so.6");
+    }
+
+    #[test]
+    fn parses_mappings_without_a_file() {
+        let mapping = parse_mapping("7ffd1000-7ffd2000 rw-p 00000000 00:00 0 [stack]").unwrap();
+        assert_eq!(mapping.path, "[stack]");
+        let mapping = parse_mapping("55d0e000-55d0f000 rw-p 00000000 00:00 0").unwrap();
+        assert_eq!(mapping.path, "");
+    }
+
+    #[test]
+    fn keeps_spaces_in_paths() {
+        let mapping =
+            parse_mapping("400000-401000 r-xp 00000000 08:01 42 /tmp/my program").unwrap();
+        assert_eq!(mapping.path, "/tmp/my program");
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        assert!(parse_mapping("").is_none());
+        assert!(parse_mapping("400000 r-xp 00000000 08:01 42").is_none());
+        assert!(parse_mapping("zz-401000 r-xp 00000000 08:01 42").is_none());
+    }
+}
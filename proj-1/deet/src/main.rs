@@ -1,24 +1,113 @@
+mod coredump;
 mod debugger;
 mod debugger_command;
 mod inferior;
 mod dwarf_data;
+mod examine;
+mod expression;
 mod gimli_wrapper;
+mod library;
+mod record;
+mod remote;
+mod settings;
+mod style;
+mod syscall;
 
 use crate::debugger::Debugger;
 use nix::sys::signal::{signal, SigHandler, Signal};
 use std::env;
+use std::io::Read;
+use std::path::PathBuf;
 
 fn main() {
     let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        println!("Usage: {} <target program>", args[0]);
-        std::process::exit(1);
+    let options = match Options::parse(&args[1..]) {
+        Some(options) => options,
+        None => {
+            println!(
+                "Usage: {} [--batch] [-x <command file>] <target program>",
+                args[0]
+            );
+            std::process::exit(1);
+        }
+    };
+
+    // Ctrl+C stops the inferior if it is running, rather than killing the debugger
+    let handler = SigHandler::Handler(inferior::interrupt_inferior);
+    unsafe { signal(Signal::SIGINT, handler) }.expect("Error setting up SIGINT handling");
+
+    let mut debugger = Debugger::new(&options.target, options.batch);
+    if options.batch && options.scripts.is_empty() {
+        // Without command files, batch mode reads its commands from standard input
+        let mut commands = String::new();
+        if let Err(err) = std::io::stdin().read_to_string(&mut commands) {
+            println!("Could not read commands from standard input: {}", err);
+            std::process::exit(1);
+        }
+        debugger.queue_commands(&commands);
+    }
+    // Startup files run before the command files, and both before commands from standard
+    // input. Each file's commands are queued ahead of those already queued, so the last goes
+    // first.
+    let mut scripts = init_files();
+    scripts.extend_from_slice(&options.scripts);
+    for script in scripts.iter().rev() {
+        debugger.source(script);
     }
-    let target = &args[1];
+    // The commands the user has defined come before any that might use them
+    debugger.load_user_commands();
+    debugger.run();
+    if options.batch {
+        std::process::exit(debugger.exit_status());
+    }
+}
 
-    // Disable handling of ctrl+c in this process (so that ctrl+c only gets delivered to child
-    // processes)
-    unsafe { signal(Signal::SIGINT, SigHandler::SigIgn) }.expect("Error disabling SIGINT handling");
+/// What deet was asked to do on its command line
+struct Options {
+    target: String,
+    /// Command files given with -x, to run in order before prompting
+    scripts: Vec<String>,
+    /// Set by --batch, to run commands without prompting and exit with the inferior's status
+    batch: bool,
+}
 
-    Debugger::new(target).run();
+impl Options {
+    fn parse(args: &[String]) -> Option<Options> {
+        let mut target = None;
+        let mut scripts = Vec::new();
+        let mut batch = false;
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-x" => scripts.push(args.next()?.clone()),
+                "--batch" => batch = true,
+                _ if target.is_none() => target = Some(arg.clone()),
+                _ => return None,
+            }
+        }
+        Some(Options {
+            target: target?,
+            scripts,
+            batch,
+        })
+    }
+}
+
+/// Returns the startup files there are, in the order they run: ~/.deetinit, then .deetinit in
+/// the current directory, unless that is the same file.
+fn init_files() -> Vec<String> {
+    let mut files = Vec::new();
+    if let Ok(home) = env::var("HOME") {
+        files.push(format!("{}/.deetinit", home));
+    }
+    files.push(String::from(".deetinit"));
+    let mut found: Vec<(String, PathBuf)> = Vec::new();
+    for file in files {
+        if let Ok(path) = std::fs::canonicalize(&file) {
+            if !found.iter().any(|(_, other)| *other == path) {
+                found.push((file, path));
+            }
+        }
+    }
+    found.into_iter().map(|(file, _)| file).collect()
 }
@@ -0,0 +1,141 @@
+//! Process record, for reverse execution. While recording, the inferior is single-stepped, and
+//! before each instruction its registers are logged along with the memory the instruction may
+//! write, so that reverse_step can undo it by putting them back.
+//!
+//! Memory written by the kernel, such as a read system call's buffer, isn't logged, so going back
+//! over a system call doesn't undo what it did.
+
+use iced_x86::{Instruction, OpKind, Register};
+use std::collections::VecDeque;
+use std::fmt;
+
+/// Most instructions logged, as in gdb. The oldest are dropped to make room for new ones.
+pub const RECORD_LIMIT: usize = 200_000;
+
+/// How far below %rsp an instruction can write without a memory operand, as push and call do
+const STACK_WRITE_LEN: u64 = 16;
+
+/// What executing an instruction overwrote
+pub struct Entry {
+    /// Registers before the instruction, %rip being the instruction's address
+    pub regs: libc::user_regs_struct,
+    /// Address and previous contents of each region of memory the instruction may have written
+    pub memory: Vec<(u64, Vec<u8>)>,
+}
+
+#[derive(Default)]
+pub struct Recording {
+    entries: VecDeque<Entry>,
+}
+
+impl fmt::Debug for Recording {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Recording({} instructions)", self.entries.len())
+    }
+}
+
+impl Recording {
+    pub fn push(&mut self, entry: Entry) {
+        if self.entries.len() == RECORD_LIMIT {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry);
+    }
+
+    /// Removes the entry for the last instruction executed.
+    pub fn pop(&mut self) -> Option<Entry> {
+        self.entries.pop_back()
+    }
+
+    /// Returns the entry for the last instruction executed.
+    pub fn last(&self) -> Option<&Entry> {
+        self.entries.back()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+/// Returns the address and length of each region of memory instruction may write, if it is
+/// executed with regs. Every memory operand is included, since it is simpler to save one that is
+/// only read than to know which are written, along with the stack just below %rsp. Operands whose
+/// address uses a register other than a 64-bit general purpose one are left out.
+pub fn written_memory(
+    instruction: &Instruction,
+    regs: &libc::user_regs_struct,
+) -> Vec<(u64, usize)> {
+    let mut regions = vec![(regs.rsp - STACK_WRITE_LEN, STACK_WRITE_LEN as usize)];
+    let size = instruction.memory_size().size();
+    if size == 0 {
+        return regions;
+    }
+    // A single step runs one iteration of a repeated string instruction, which touches only the
+    // element at %rsi or %rdi. The direction flag just decides which way they move afterwards, so
+    // the element is there either way. Once %rcx is zero, no iterations are left to run.
+    let repeated = instruction.has_rep_prefix() || instruction.has_repne_prefix();
+    for operand in 0..instruction.op_count() {
+        let addr = match instruction.op_kind(operand) {
+            OpKind::Memory => match memory_operand_address(instruction, regs) {
+                Some(addr) => addr,
+                None => continue,
+            },
+            OpKind::MemorySegRSI | OpKind::MemoryESRDI if repeated && regs.rcx == 0 => continue,
+            OpKind::MemorySegRSI => regs.rsi,
+            OpKind::MemoryESRDI => regs.rdi,
+            _ => continue,
+        };
+        regions.push((addr, size));
+    }
+    regions
+}
+
+/// Works out the address of instruction's memory operand from regs.
+fn memory_operand_address(instruction: &Instruction, regs: &libc::user_regs_struct) -> Option<u64> {
+    let base = match instruction.memory_base() {
+        // iced gives the displacement of a %rip-relative operand as the address it refers to
+        Register::None | Register::RIP => 0,
+        base => register_value(regs, base)?,
+    };
+    let index = match instruction.memory_index() {
+        Register::None => 0,
+        index => register_value(regs, index)? * instruction.memory_index_scale() as u64,
+    };
+    let segment_base = match instruction.memory_segment() {
+        Register::FS => regs.fs_base,
+        Register::GS => regs.gs_base,
+        _ => 0,
+    };
+    Some(
+        segment_base
+            .wrapping_add(base)
+            .wrapping_add(index)
+            .wrapping_add(instruction.memory_displacement64()),
+    )
+}
+
+fn register_value(regs: &libc::user_regs_struct, register: Register) -> Option<u64> {
+    Some(match register {
+        Register::RAX => regs.rax,
+        Register::RBX => regs.rbx,
+        Register::RCX => regs.rcx,
+        Register::RDX => regs.rdx,
+        Register::RSI => regs.rsi,
+        Register::RDI => regs.rdi,
+        Register::RBP => regs.rbp,
+        Register::RSP => regs.rsp,
+        Register::R8 => regs.r8,
+        Register::R9 => regs.r9,
+        Register::R10 => regs.r10,
+        Register::R11 => regs.r11,
+        Register::R12 => regs.r12,
+        Register::R13 => regs.r13,
+        Register::R14 => regs.r14,
+        Register::R15 => regs.r15,
+        _ => return None,
+    })
+}
@@ -0,0 +1,493 @@
+//! Client for gdb's remote serial protocol, for debugging a process running under gdbserver on
+//! another machine or in a container. Registers and memory are read and written with packets
+//! sent over a TCP connection, and the process is resumed with them, instead of with ptrace.
+
+use nix::sys::signal::Signal;
+use nix::sys::wait::WaitStatus;
+use nix::unistd::Pid;
+use std::cell::RefCell;
+use std::io::{self, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+/// Most bytes of memory read or written with one packet. Twice this in hex fits easily in the
+/// packet buffer gdbserver advertises.
+const MAX_TRANSFER_LEN: usize = 1024;
+
+/// Byte that interrupts a running process, as Ctrl+C does
+pub const INTERRUPT: u8 = 0x03;
+
+/// Offset and size in the g packet of each of the x86-64 registers deet uses: %rax to %rip, then
+/// eflags and the segment registers, then orig_rax, fs_base and gs_base after the x87 and SSE
+/// registers. The last three are only there if gdbserver sends the Linux and segment features.
+const REGISTER_LAYOUT: [(usize, usize); 27] = [
+    (0, 8),
+    (8, 8),
+    (16, 8),
+    (24, 8),
+    (32, 8),
+    (40, 8),
+    (48, 8),
+    (56, 8),
+    (64, 8),
+    (72, 8),
+    (80, 8),
+    (88, 8),
+    (96, 8),
+    (104, 8),
+    (112, 8),
+    (120, 8),
+    (128, 8),
+    (136, 4),
+    (140, 4),
+    (144, 4),
+    (148, 4),
+    (152, 4),
+    (156, 4),
+    (160, 4),
+    (536, 8),
+    (544, 8),
+    (552, 8),
+];
+
+/// gdb's numbers for signals, which the protocol uses whatever the remote system's are
+const SIGNAL_NUMBERS: [(u8, Signal); 29] = [
+    (1, Signal::SIGHUP),
+    (2, Signal::SIGINT),
+    (3, Signal::SIGQUIT),
+    (4, Signal::SIGILL),
+    (5, Signal::SIGTRAP),
+    (6, Signal::SIGABRT),
+    (8, Signal::SIGFPE),
+    (9, Signal::SIGKILL),
+    (10, Signal::SIGBUS),
+    (11, Signal::SIGSEGV),
+    (12, Signal::SIGSYS),
+    (13, Signal::SIGPIPE),
+    (14, Signal::SIGALRM),
+    (15, Signal::SIGTERM),
+    (16, Signal::SIGURG),
+    (17, Signal::SIGSTOP),
+    (18, Signal::SIGTSTP),
+    (19, Signal::SIGCONT),
+    (20, Signal::SIGCHLD),
+    (21, Signal::SIGTTIN),
+    (22, Signal::SIGTTOU),
+    (23, Signal::SIGIO),
+    (24, Signal::SIGXCPU),
+    (25, Signal::SIGXFSZ),
+    (26, Signal::SIGVTALRM),
+    (27, Signal::SIGPROF),
+    (28, Signal::SIGWINCH),
+    (30, Signal::SIGUSR1),
+    (31, Signal::SIGUSR2),
+];
+
+/// Type of the auxiliary vector entry holding the program's entry point
+const AT_ENTRY: u64 = 9;
+
+/// A connection to gdbserver, which has a process stopped for debugging
+#[derive(Debug)]
+pub struct Remote {
+    stream: TcpStream,
+    /// The same connection, for reading replies
+    reader: RefCell<BufReader<TcpStream>>,
+    /// Process gdbserver is debugging
+    pid: Pid,
+}
+
+impl Remote {
+    /// Connects to gdbserver at address, given as host:port.
+    pub fn connect(address: &str) -> io::Result<Remote> {
+        let stream = TcpStream::connect(address)?;
+        stream.set_nodelay(true)?;
+        let mut remote = Remote {
+            reader: RefCell::new(BufReader::new(stream.try_clone()?)),
+            stream,
+            pid: Pid::from_raw(0),
+        };
+        remote.command("qSupported")?;
+        // The process is stopped already, at its first instruction unless gdbserver attached
+        remote.command("?")?;
+        // The current thread is the main one, whose id is the pid
+        let reply = remote.command("qC")?;
+        let thread = reply.trim_start_matches("QC").trim_start_matches('p');
+        let pid = thread.split('.').next().unwrap_or_default();
+        let pid = i32::from_str_radix(pid, 16).map_err(|_| protocol_error(&reply))?;
+        remote.pid = Pid::from_raw(pid);
+        Ok(remote)
+    }
+
+    /// Returns the pid of the process on the remote system.
+    pub fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    pub fn get_registers(&self) -> io::Result<libc::user_regs_struct> {
+        let bytes = from_hex(&self.command("g")?);
+        let mut regs: libc::user_regs_struct = unsafe { std::mem::zeroed() };
+        let mut fields = register_fields(&mut regs);
+        for (field, (offset, size)) in fields.iter_mut().zip(&REGISTER_LAYOUT) {
+            if let Some(value) = bytes.get(*offset..offset + size) {
+                let mut word = [0_u8; 8];
+                word[..*size].copy_from_slice(value);
+                **field = u64::from_le_bytes(word);
+            }
+        }
+        Ok(regs)
+    }
+
+    /// Sets the registers in regs. The g packet has to give every register, so the others are
+    /// written back as they are.
+    pub fn set_registers(&self, regs: libc::user_regs_struct) -> io::Result<()> {
+        let mut bytes = from_hex(&self.command("g")?);
+        let mut regs = regs;
+        for (field, (offset, size)) in register_fields(&mut regs).iter().zip(&REGISTER_LAYOUT) {
+            if let Some(value) = bytes.get_mut(*offset..offset + size) {
+                value.copy_from_slice(&field.to_le_bytes()[..*size]);
+            }
+        }
+        self.expect_ok(&format!("G{}", to_hex(&bytes)))
+    }
+
+    pub fn read_memory(&self, addr: u64, len: usize) -> io::Result<Vec<u8>> {
+        let mut bytes = Vec::with_capacity(len);
+        while bytes.len() < len {
+            let chunk_addr = addr + bytes.len() as u64;
+            let chunk_len = (len - bytes.len()).min(MAX_TRANSFER_LEN);
+            let reply = self.command(&format!("m{:x},{:x}", chunk_addr, chunk_len))?;
+            let chunk = from_hex(&reply);
+            // gdbserver stops short at memory it can't read
+            if chunk.is_empty() {
+                return Err(io::Error::from_raw_os_error(libc::EIO));
+            }
+            bytes.extend_from_slice(&chunk);
+        }
+        Ok(bytes)
+    }
+
+    pub fn write_memory(&self, addr: u64, bytes: &[u8]) -> io::Result<()> {
+        for (idx, chunk) in bytes.chunks(MAX_TRANSFER_LEN).enumerate() {
+            let chunk_addr = addr + (idx * MAX_TRANSFER_LEN) as u64;
+            let packet = format!("M{:x},{:x}:{}", chunk_addr, chunk.len(), to_hex(chunk));
+            self.expect_ok(&packet)?;
+        }
+        Ok(())
+    }
+
+    /// Resumes the process, for a single instruction if step is set, delivering signal to it if
+    /// one is given, and waits for it to stop. Returns what happened as waitpid would have.
+    pub fn resume(&self, step: bool, signal: Option<Signal>) -> io::Result<WaitStatus> {
+        let packet = match (step, signal.and_then(gdb_signal_number)) {
+            (false, None) => String::from("c"),
+            (true, None) => String::from("s"),
+            (false, Some(number)) => format!("C{:02x}", number),
+            (true, Some(number)) => format!("S{:02x}", number),
+        };
+        self.send_packet(&packet)?;
+        loop {
+            let reply = String::from_utf8_lossy(&self.receive_packet()?).into_owned();
+            // The signal or exit code is the two hex digits after the letter
+            let value = |reply: &str| {
+                reply
+                    .get(1..3)
+                    .and_then(|value| u8::from_str_radix(value, 16).ok())
+                    .ok_or_else(|| protocol_error(reply))
+            };
+            return Ok(match reply.chars().next() {
+                Some('T') | Some('S') => WaitStatus::Stopped(self.pid, signal_from(value(&reply)?)),
+                Some('W') => WaitStatus::Exited(self.pid, value(&reply)? as i32),
+                Some('X') => WaitStatus::Signaled(self.pid, signal_from(value(&reply)?), false),
+                // Output from the process, when gdbserver is asked to forward it
+                Some('O') => {
+                    print!("{}", String::from_utf8_lossy(&from_hex(&reply[1..])));
+                    continue;
+                }
+                _ => return Err(protocol_error(&reply)),
+            });
+        }
+    }
+
+    /// Kills the process. gdbserver doesn't reply, since it exits along with it.
+    pub fn kill(&self) {
+        let _ = self.send_packet("k");
+    }
+
+    /// Returns the program's entry point, from its auxiliary vector, for working out where a
+    /// position-independent executable was loaded.
+    pub fn entry_point(&self) -> io::Result<u64> {
+        let mut auxv = Vec::new();
+        loop {
+            let packet = format!("qXfer:auxv:read::{:x},{:x}", auxv.len(), MAX_TRANSFER_LEN);
+            let reply = self.request(&packet)?;
+            match reply.split_first() {
+                Some((b'm', data)) => auxv.extend_from_slice(data),
+                Some((b'l', data)) => {
+                    auxv.extend_from_slice(data);
+                    break;
+                }
+                _ => return Err(protocol_error(&String::from_utf8_lossy(&reply))),
+            }
+        }
+        auxv.chunks_exact(16)
+            .map(|entry| {
+                let word = |bytes: &[u8]| {
+                    let mut word = [0_u8; 8];
+                    word.copy_from_slice(bytes);
+                    u64::from_le_bytes(word)
+                };
+                (word(&entry[..8]), word(&entry[8..]))
+            })
+            .find(|(kind, _)| *kind == AT_ENTRY)
+            .map(|(_, value)| value)
+            .ok_or_else(|| protocol_error("no AT_ENTRY in the auxiliary vector"))
+    }
+
+    /// Sends packet and returns the reply, failing if it is an error reply.
+    fn command(&self, packet: &str) -> io::Result<String> {
+        let reply = String::from_utf8_lossy(&self.request(packet)?).into_owned();
+        if reply.len() == 3 && reply.starts_with('E') {
+            return Err(io::Error::other(format!("remote error {}", reply)));
+        }
+        Ok(reply)
+    }
+
+    /// Sends a command that replies OK when it succeeds.
+    fn expect_ok(&self, packet: &str) -> io::Result<()> {
+        match self.command(packet)?.as_str() {
+            "OK" => Ok(()),
+            reply => Err(protocol_error(reply)),
+        }
+    }
+
+    fn request(&self, packet: &str) -> io::Result<Vec<u8>> {
+        self.send_packet(packet)?;
+        self.receive_packet()
+    }
+
+    /// Sends $packet#checksum, again until gdbserver acknowledges it with a +.
+    fn send_packet(&self, packet: &str) -> io::Result<()> {
+        let checksum = packet
+            .bytes()
+            .fold(0_u8, |sum, byte| sum.wrapping_add(byte));
+        let framed = format!("${}#{:02x}", packet, checksum);
+        loop {
+            (&self.stream).write_all(framed.as_bytes())?;
+            loop {
+                match self.read_byte()? {
+                    b'+' => return Ok(()),
+                    b'-' => break,
+                    _ => continue,
+                }
+            }
+        }
+    }
+
+    /// Reads a packet, acknowledging it, and returns its data with escapes and run-length
+    /// encoding undone.
+    fn receive_packet(&self) -> io::Result<Vec<u8>> {
+        loop {
+            while self.read_byte()? != b'$' {}
+            let mut raw = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    b'#' => break,
+                    byte => raw.push(byte),
+                }
+            }
+            let checksum = [self.read_byte()?, self.read_byte()?];
+            let checksum = u8::from_str_radix(&String::from_utf8_lossy(&checksum), 16).ok();
+            let sum = raw.iter().fold(0_u8, |sum, byte| sum.wrapping_add(*byte));
+            if checksum != Some(sum) {
+                (&self.stream).write_all(b"-")?;
+                continue;
+            }
+            (&self.stream).write_all(b"+")?;
+            let mut data = Vec::with_capacity(raw.len());
+            let mut bytes = raw.into_iter();
+            while let Some(byte) = bytes.next() {
+                match byte {
+                    b'}' => data.push(bytes.next().unwrap_or_default() ^ 0x20),
+                    // *n repeats the byte before it n - 29 more times
+                    b'*' => {
+                        let count = bytes.next().unwrap_or(29).saturating_sub(29);
+                        let last = data.last().copied().unwrap_or_default();
+                        data.extend(std::iter::repeat_n(last, count as usize));
+                    }
+                    byte => data.push(byte),
+                }
+            }
+            return Ok(data);
+        }
+    }
+
+    fn read_byte(&self) -> io::Result<u8> {
+        let mut byte = [0_u8];
+        self.reader.borrow_mut().read_exact(&mut byte)?;
+        Ok(byte[0])
+    }
+}
+
+impl AsRawFd for Remote {
+    fn as_raw_fd(&self) -> RawFd {
+        self.stream.as_raw_fd()
+    }
+}
+
+/// Returns the fields of regs in the order of REGISTER_LAYOUT.
+fn register_fields(regs: &mut libc::user_regs_struct) -> [&mut u64; 27] {
+    [
+        &mut regs.rax,
+        &mut regs.rbx,
+        &mut regs.rcx,
+        &mut regs.rdx,
+        &mut regs.rsi,
+        &mut regs.rdi,
+        &mut regs.rbp,
+        &mut regs.rsp,
+        &mut regs.r8,
+        &mut regs.r9,
+        &mut regs.r10,
+        &mut regs.r11,
+        &mut regs.r12,
+        &mut regs.r13,
+        &mut regs.r14,
+        &mut regs.r15,
+        &mut regs.rip,
+        &mut regs.eflags,
+        &mut regs.cs,
+        &mut regs.ss,
+        &mut regs.ds,
+        &mut regs.es,
+        &mut regs.fs,
+        &mut regs.gs,
+        &mut regs.orig_rax,
+        &mut regs.fs_base,
+        &mut regs.gs_base,
+    ]
+}
+
+fn gdb_signal_number(signal: Signal) -> Option<u8> {
+    SIGNAL_NUMBERS
+        .iter()
+        .find(|(_, sig)| *sig == signal)
+        .map(|(number, _)| *number)
+}
+
+/// Returns the signal gdb numbers number. Signals deet has no name for, such as real-time ones,
+/// are taken as a SIGSTOP.
+fn signal_from(number: u8) -> Signal {
+    SIGNAL_NUMBERS
+        .iter()
+        .find(|(n, _)| *n == number)
+        .map_or(Signal::SIGSTOP, |(_, sig)| *sig)
+}
+
+fn protocol_error(reply: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("unexpected reply from the remote: {}", reply),
+    )
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Decodes hex, taking registers gdbserver can't read, sent as xx, to be zero.
+fn from_hex(text: &str) -> Vec<u8> {
+    text.as_bytes()
+        .chunks_exact(2)
+        .map(|pair| u8::from_str_radix(&String::from_utf8_lossy(pair), 16).unwrap_or(0))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    /// Returns a Remote whose gdbserver end has already sent sent, along with that end.
+    fn remote_receiving(sent: &[u8]) -> (Remote, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+        server.write_all(sent).unwrap();
+        let remote = Remote {
+            reader: RefCell::new(BufReader::new(stream.try_clone().unwrap())),
+            stream,
+            pid: Pid::from_raw(1),
+        };
+        (remote, server)
+    }
+
+    /// Frames data as a packet with its checksum.
+    fn packet(data: &[u8]) -> Vec<u8> {
+        let checksum = data.iter().fold(0_u8, |sum, byte| sum.wrapping_add(*byte));
+        let mut packet = vec![b'$'];
+        packet.extend_from_slice(data);
+        packet.extend_from_slice(format!("#{:02x}", checksum).as_bytes());
+        packet
+    }
+
+    /// Returns the acknowledgements the client has sent so far.
+    fn acks(server: &mut TcpStream, count: usize) -> Vec<u8> {
+        let mut acks = vec![0; count];
+        server.read_exact(&mut acks).unwrap();
+        acks
+    }
+
+    #[test]
+    fn receives_and_acknowledges_a_packet() {
+        let (remote, mut server) = remote_receiving(&packet(b"OK"));
+        assert_eq!(remote.receive_packet().unwrap(), b"OK");
+        assert_eq!(acks(&mut server, 1), b"+");
+    }
+
+    #[test]
+    fn skips_bytes_before_the_packet() {
+        let mut sent = b"+junk".to_vec();
+        sent.extend(packet(b"S05"));
+        let (remote, _server) = remote_receiving(&sent);
+        assert_eq!(remote.receive_packet().unwrap(), b"S05");
+    }
+
+    #[test]
+    fn undoes_escapes_and_run_length_encoding() {
+        // }] is an escaped }, and 0* (space is 32) is four zeros
+        let (remote, _server) = remote_receiving(&packet(b"}]a0* b"));
+        assert_eq!(remote.receive_packet().unwrap(), b"}a0000b");
+    }
+
+    #[test]
+    fn asks_again_for_a_packet_with_a_bad_checksum() {
+        let mut sent = b"$OK#00".to_vec();
+        sent.extend(packet(b"OK"));
+        let (remote, mut server) = remote_receiving(&sent);
+        assert_eq!(remote.receive_packet().unwrap(), b"OK");
+        assert_eq!(acks(&mut server, 2), b"-+");
+    }
+
+    #[test]
+    fn error_replies_are_errors() {
+        let mut sent = b"+".to_vec();
+        sent.extend(packet(b"E01"));
+        let (remote, mut server) = remote_receiving(&sent);
+        let err = remote.command("m0,8").unwrap_err();
+        assert_eq!(err.to_string(), "remote error E01");
+        let request = packet(b"m0,8");
+        let mut received = vec![0; request.len()];
+        server.read_exact(&mut received).unwrap();
+        assert_eq!(received, request);
+    }
+
+    #[test]
+    fn decodes_hex_and_signals() {
+        assert_eq!(from_hex("00ff1a"), vec![0x00, 0xff, 0x1a]);
+        assert_eq!(from_hex("xxxx10"), vec![0, 0, 0x10]);
+        assert_eq!(to_hex(&[0x00, 0xab]), "00ab");
+        assert_eq!(signal_from(11), Signal::SIGSEGV);
+        assert_eq!(signal_from(64), Signal::SIGSTOP);
+        assert_eq!(gdb_signal_number(Signal::SIGCHLD), Some(20));
+    }
+}
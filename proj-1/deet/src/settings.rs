@@ -0,0 +1,203 @@
+//! Settings the user can change with set and look at with show
+
+use crate::examine::as_signed;
+use crate::inferior::FollowForkMode;
+use crate::style::{self, Style};
+
+/// Number of source lines list prints unless changed with set listsize, as in gdb
+const DEFAULT_LIST_SIZE: usize = 10;
+
+/// Names of the settings, in the order show lists them
+pub const NAMES: [&str; 5] = [
+    "confirm",
+    "follow-fork-mode",
+    "listsize",
+    "output-radix",
+    "style",
+];
+
+pub struct Settings {
+    /// Whether to ask before doing something that kills the inferior, such as quitting
+    pub confirm: bool,
+    /// Which process to keep debugging when the inferior forks
+    pub follow_fork_mode: FollowForkMode,
+    /// Number of source lines list prints
+    pub list_size: usize,
+    /// Base print shows integers in: 8, 10 or 16
+    pub output_radix: u32,
+    /// Whether output is colored
+    pub style: bool,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            confirm: true,
+            follow_fork_mode: FollowForkMode::default(),
+            list_size: DEFAULT_LIST_SIZE,
+            output_radix: 10,
+            style: style::supported(),
+        }
+    }
+}
+
+impl Settings {
+    /// Changes the setting called name to the value given by the words in value. Returns a usage
+    /// message if the value isn't valid. name must be one of NAMES.
+    pub fn set(&mut self, name: &str, value: &[String]) -> Result<(), String> {
+        let value = value.first().map(|s| s.as_str());
+        match name {
+            "confirm" => {
+                self.confirm = match value {
+                    Some("on") | None => true,
+                    Some("off") => false,
+                    _ => return Err(String::from("Usage: set confirm on|off")),
+                }
+            }
+            "follow-fork-mode" => {
+                self.follow_fork_mode = match value {
+                    Some("parent") => FollowForkMode::Parent,
+                    Some("child") => FollowForkMode::Child,
+                    _ => return Err(String::from("Usage: set follow-fork-mode parent|child")),
+                }
+            }
+            "listsize" => match value.map(|value| value.parse::<usize>()) {
+                Some(Ok(size)) if size > 0 => self.list_size = size,
+                _ => return Err(String::from("Usage: set listsize <number of lines>")),
+            },
+            "output-radix" => match value.map(|value| value.parse::<u32>()) {
+                Some(Ok(radix)) if radix == 8 || radix == 10 || radix == 16 => {
+                    self.output_radix = radix
+                }
+                _ => return Err(String::from("Usage: set output-radix 8|10|16")),
+            },
+            "style" => {
+                self.style = match value {
+                    Some("on") | None => true,
+                    Some("off") => false,
+                    _ => return Err(String::from("Usage: set style on|off")),
+                }
+            }
+            _ => panic!("no setting called {}", name),
+        }
+        Ok(())
+    }
+
+    /// Returns show's description of the setting called name, or None if there is no such
+    /// setting.
+    pub fn show(&self, name: &str) -> Option<String> {
+        Some(match name {
+            "confirm" => format!(
+                "Whether to confirm potentially dangerous operations is {}.",
+                if self.confirm { "on" } else { "off" }
+            ),
+            "follow-fork-mode" => format!(
+                "Debugger response to a program call of fork or vfork is \"{}\".",
+                self.follow_fork_mode
+            ),
+            "listsize" => format!(
+                "Number of source lines deet will list by default is {}.",
+                self.list_size
+            ),
+            "output-radix" => format!(
+                "Default output radix for printing of values is {}.",
+                self.output_radix
+            ),
+            "style" => format!(
+                "CLI output styling is {}.",
+                if self.style { "enabled" } else { "disabled" }
+            ),
+            _ => return None,
+        })
+    }
+
+    /// Returns text in style, or as it is if styling is off.
+    pub fn paint(&self, style: Style, text: &str) -> String {
+        if self.style {
+            style.paint(text)
+        } else {
+            text.to_string()
+        }
+    }
+
+    /// Formats a len-byte integer, whose bits are in value, in the output radix. It is shown as
+    /// signed if signed is set and the radix is 10; octal and hex show its bits.
+    pub fn format_integer(&self, value: u64, len: usize, signed: bool) -> String {
+        let bits = if len >= 8 {
+            value
+        } else {
+            value & ((1 << (8 * len)) - 1)
+        };
+        match self.output_radix {
+            16 => format!("{:#x}", bits),
+            8 if bits == 0 => String::from("0"),
+            8 => format!("0{:o}", bits),
+            _ if signed => format!("{}", as_signed(bits, len.min(8))),
+            _ => format!("{}", bits),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(text: &str) -> Vec<String> {
+        text.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn sets_each_setting() {
+        let mut settings = Settings::default();
+        settings.set("confirm", &words("off")).unwrap();
+        assert!(!settings.confirm);
+        settings.set("confirm", &words("")).unwrap();
+        assert!(settings.confirm);
+        settings.set("follow-fork-mode", &words("child")).unwrap();
+        assert_eq!(settings.follow_fork_mode, FollowForkMode::Child);
+        settings.set("listsize", &words("25")).unwrap();
+        assert_eq!(settings.list_size, 25);
+        settings.set("output-radix", &words("16")).unwrap();
+        assert_eq!(settings.output_radix, 16);
+        settings.set("style", &words("off")).unwrap();
+        assert!(!settings.style);
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        let mut settings = Settings::default();
+        assert_eq!(
+            settings.set("confirm", &words("maybe")),
+            Err(String::from("Usage: set confirm on|off"))
+        );
+        assert_eq!(
+            settings.set("follow-fork-mode", &words("")),
+            Err(String::from("Usage: set follow-fork-mode parent|child"))
+        );
+        assert!(settings.set("listsize", &words("0")).is_err());
+        assert!(settings.set("output-radix", &words("2")).is_err());
+        assert_eq!(settings.list_size, DEFAULT_LIST_SIZE);
+        assert_eq!(settings.output_radix, 10);
+    }
+
+    #[test]
+    fn every_setting_can_be_shown() {
+        let settings = Settings::default();
+        for name in NAMES.iter() {
+            assert!(settings.show(name).is_some(), "{} can't be shown", name);
+        }
+        assert_eq!(settings.show("height"), None);
+    }
+
+    #[test]
+    fn formats_integers_in_the_output_radix() {
+        let mut settings = Settings::default();
+        assert_eq!(settings.format_integer(0xff, 1, true), "-1");
+        assert_eq!(settings.format_integer(0xff, 1, false), "255");
+        settings.set("output-radix", &words("16")).unwrap();
+        assert_eq!(settings.format_integer(0x1ff, 1, true), "0xff");
+        settings.set("output-radix", &words("8")).unwrap();
+        assert_eq!(settings.format_integer(8, 4, true), "010");
+        assert_eq!(settings.format_integer(0, 4, true), "0");
+    }
+}
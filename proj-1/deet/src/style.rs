@@ -0,0 +1,39 @@
+//! Colors for deet's output, which set style off turns off. Function names, file names and
+//! addresses are colored as in gdb, while the prompt, stop banners and the line the inferior is
+//! stopped at are in bold.
+
+/// What a piece of output is, which decides how it looks
+#[derive(Clone, Copy, Debug)]
+pub enum Style {
+    Function,
+    File,
+    Address,
+    Prompt,
+    /// Why the inferior stopped
+    Banner,
+    /// The line the inferior is stopped at, in source listings
+    CurrentLine,
+    /// Line numbers in source listings
+    LineNumber,
+}
+
+impl Style {
+    /// Returns text wrapped in the ANSI escape codes for this style.
+    pub fn paint(self, text: &str) -> String {
+        let code = match self {
+            Style::Function => "33",
+            Style::File => "32",
+            Style::Address => "34",
+            Style::Prompt | Style::Banner | Style::CurrentLine => "1",
+            Style::LineNumber => "2",
+        };
+        format!("\x1b[{}m{}\x1b[0m", code, text)
+    }
+}
+
+/// Returns true if standard output is a terminal that can show colors, which is when styling
+/// starts out on.
+pub fn supported() -> bool {
+    let is_terminal = unsafe { libc::isatty(libc::STDOUT_FILENO) } == 1;
+    is_terminal && std::env::var("TERM").is_ok_and(|term| term != "dumb")
+}
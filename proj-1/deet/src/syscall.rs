@@ -0,0 +1,518 @@
+//! x86-64 Linux system calls, for catch syscall and trace: their names, and how to show a call
+//! and its result the way strace does, e.g. write(1, "hello\n", 6) = 6.
+
+use crate::examine;
+use crate::inferior::Inferior;
+
+/// Longest string argument shown before it is cut off with "..."
+const MAX_STRING_LEN: usize = 32;
+
+const WORD_LEN: u64 = std::mem::size_of::<u64>() as u64;
+
+/// Number of the first system call in NEWER_SYSCALLS. The numbers between the end of SYSCALLS and
+/// this were never used on x86-64.
+const NEWER_SYSCALLS_START: u64 = 424;
+
+/// System calls that return addresses, which are shown in hex
+const ADDRESS_RESULTS: [&str; 4] = ["brk", "mmap", "mremap", "shmat"];
+
+/// Name and arguments of each system call, by number. Each argument is shown according to its
+/// letter: d (int), l (long), u (unsigned decimal), o (octal), x (hex, for addresses and flags),
+/// s (string) or b (buffer whose length is the next argument).
+const SYSCALLS: [(&str, &str); 335] = [
+    ("read", "dxu"),
+    ("write", "dbu"),
+    ("open", "sxo"),
+    ("close", "d"),
+    ("stat", "sx"),
+    ("fstat", "dx"),
+    ("lstat", "sx"),
+    ("poll", "xud"),
+    ("lseek", "dld"),
+    ("mmap", "xuxxdx"),
+    ("mprotect", "xux"),
+    ("munmap", "xu"),
+    ("brk", "x"),
+    ("rt_sigaction", "dxxu"),
+    ("rt_sigprocmask", "dxxu"),
+    ("rt_sigreturn", ""),
+    ("ioctl", "dxx"),
+    ("pread64", "dxul"),
+    ("pwrite64", "dbul"),
+    ("readv", "dxd"),
+    ("writev", "dxd"),
+    ("access", "so"),
+    ("pipe", "x"),
+    ("select", "dxxxx"),
+    ("sched_yield", ""),
+    ("mremap", "xuuxx"),
+    ("msync", "xux"),
+    ("mincore", "xux"),
+    ("madvise", "xud"),
+    ("shmget", "dux"),
+    ("shmat", "dxx"),
+    ("shmctl", "ddx"),
+    ("dup", "d"),
+    ("dup2", "dd"),
+    ("pause", ""),
+    ("nanosleep", "xx"),
+    ("getitimer", "dx"),
+    ("alarm", "u"),
+    ("setitimer", "dxx"),
+    ("getpid", ""),
+    ("sendfile", "ddxu"),
+    ("socket", "ddd"),
+    ("connect", "dxu"),
+    ("accept", "dxx"),
+    ("sendto", "dbuxxu"),
+    ("recvfrom", "dxuxxx"),
+    ("sendmsg", "dxx"),
+    ("recvmsg", "dxx"),
+    ("shutdown", "dd"),
+    ("bind", "dxu"),
+    ("listen", "dd"),
+    ("getsockname", "dxx"),
+    ("getpeername", "dxx"),
+    ("socketpair", "dddx"),
+    ("setsockopt", "dddxu"),
+    ("getsockopt", "dddxx"),
+    ("clone", "xxxxx"),
+    ("fork", ""),
+    ("vfork", ""),
+    ("execve", "sxx"),
+    ("exit", "d"),
+    ("wait4", "dxxx"),
+    ("kill", "dd"),
+    ("uname", "x"),
+    ("semget", "ddx"),
+    ("semop", "dxu"),
+    ("semctl", "dddx"),
+    ("shmdt", "x"),
+    ("msgget", "dx"),
+    ("msgsnd", "dxux"),
+    ("msgrcv", "dxudx"),
+    ("msgctl", "ddx"),
+    ("fcntl", "ddx"),
+    ("flock", "dd"),
+    ("fsync", "d"),
+    ("fdatasync", "d"),
+    ("truncate", "sl"),
+    ("ftruncate", "dl"),
+    ("getdents", "dxu"),
+    ("getcwd", "xu"),
+    ("chdir", "s"),
+    ("fchdir", "d"),
+    ("rename", "ss"),
+    ("mkdir", "so"),
+    ("rmdir", "s"),
+    ("creat", "so"),
+    ("link", "ss"),
+    ("unlink", "s"),
+    ("symlink", "ss"),
+    ("readlink", "sxu"),
+    ("chmod", "so"),
+    ("fchmod", "do"),
+    ("chown", "sdd"),
+    ("fchown", "ddd"),
+    ("lchown", "sdd"),
+    ("umask", "o"),
+    ("gettimeofday", "xx"),
+    ("getrlimit", "dx"),
+    ("getrusage", "dx"),
+    ("sysinfo", "x"),
+    ("times", "x"),
+    ("ptrace", "ddxx"),
+    ("getuid", ""),
+    ("syslog", "dxd"),
+    ("getgid", ""),
+    ("setuid", "d"),
+    ("setgid", "d"),
+    ("geteuid", ""),
+    ("getegid", ""),
+    ("setpgid", "dd"),
+    ("getppid", ""),
+    ("getpgrp", ""),
+    ("setsid", ""),
+    ("setreuid", "dd"),
+    ("setregid", "dd"),
+    ("getgroups", "dx"),
+    ("setgroups", "dx"),
+    ("setresuid", "ddd"),
+    ("getresuid", "xxx"),
+    ("setresgid", "ddd"),
+    ("getresgid", "xxx"),
+    ("getpgid", "d"),
+    ("setfsuid", "d"),
+    ("setfsgid", "d"),
+    ("getsid", "d"),
+    ("capget", "xx"),
+    ("capset", "xx"),
+    ("rt_sigpending", "xu"),
+    ("rt_sigtimedwait", "xxxu"),
+    ("rt_sigqueueinfo", "ddx"),
+    ("rt_sigsuspend", "xu"),
+    ("sigaltstack", "xx"),
+    ("utime", "sx"),
+    ("mknod", "sox"),
+    ("uselib", "s"),
+    ("personality", "x"),
+    ("ustat", "xx"),
+    ("statfs", "sx"),
+    ("fstatfs", "dx"),
+    ("sysfs", "dxx"),
+    ("getpriority", "dd"),
+    ("setpriority", "ddd"),
+    ("sched_setparam", "dx"),
+    ("sched_getparam", "dx"),
+    ("sched_setscheduler", "ddx"),
+    ("sched_getscheduler", "d"),
+    ("sched_get_priority_max", "d"),
+    ("sched_get_priority_min", "d"),
+    ("sched_rr_get_interval", "dx"),
+    ("mlock", "xu"),
+    ("munlock", "xu"),
+    ("mlockall", "x"),
+    ("munlockall", ""),
+    ("vhangup", ""),
+    ("modify_ldt", "dxu"),
+    ("pivot_root", "ss"),
+    ("_sysctl", "x"),
+    ("prctl", "dxxxx"),
+    ("arch_prctl", "dx"),
+    ("adjtimex", "x"),
+    ("setrlimit", "dx"),
+    ("chroot", "s"),
+    ("sync", ""),
+    ("acct", "s"),
+    ("settimeofday", "xx"),
+    ("mount", "sssxx"),
+    ("umount2", "sx"),
+    ("swapon", "sx"),
+    ("swapoff", "s"),
+    ("reboot", "xxxx"),
+    ("sethostname", "bu"),
+    ("setdomainname", "bu"),
+    ("iopl", "d"),
+    ("ioperm", "xud"),
+    ("create_module", "su"),
+    ("init_module", "xus"),
+    ("delete_module", "sx"),
+    ("get_kernel_syms", "x"),
+    ("query_module", "sdxux"),
+    ("quotactl", "xsdx"),
+    ("nfsservctl", "dxx"),
+    ("getpmsg", "xxxxx"),
+    ("putpmsg", "xxxxx"),
+    ("afs_syscall", "xxxxx"),
+    ("tuxcall", "xxx"),
+    ("security", "xxx"),
+    ("gettid", ""),
+    ("readahead", "dlu"),
+    ("setxattr", "ssxux"),
+    ("lsetxattr", "ssxux"),
+    ("fsetxattr", "dsxux"),
+    ("getxattr", "ssxu"),
+    ("lgetxattr", "ssxu"),
+    ("fgetxattr", "dsxu"),
+    ("listxattr", "sxu"),
+    ("llistxattr", "sxu"),
+    ("flistxattr", "dxu"),
+    ("removexattr", "ss"),
+    ("lremovexattr", "ss"),
+    ("fremovexattr", "ds"),
+    ("tkill", "dd"),
+    ("time", "x"),
+    ("futex", "xddxxd"),
+    ("sched_setaffinity", "dux"),
+    ("sched_getaffinity", "dux"),
+    ("set_thread_area", "x"),
+    ("io_setup", "ux"),
+    ("io_destroy", "x"),
+    ("io_getevents", "xddxx"),
+    ("io_submit", "xdx"),
+    ("io_cancel", "xxx"),
+    ("get_thread_area", "x"),
+    ("lookup_dcookie", "xxu"),
+    ("epoll_create", "d"),
+    ("epoll_ctl_old", "xxxx"),
+    ("epoll_wait_old", "xxx"),
+    ("remap_file_pages", "xuxux"),
+    ("getdents64", "dxu"),
+    ("set_tid_address", "x"),
+    ("restart_syscall", ""),
+    ("semtimedop", "dxux"),
+    ("fadvise64", "dlld"),
+    ("timer_create", "dxx"),
+    ("timer_settime", "dxxx"),
+    ("timer_gettime", "dx"),
+    ("timer_getoverrun", "d"),
+    ("timer_delete", "d"),
+    ("clock_settime", "dx"),
+    ("clock_gettime", "dx"),
+    ("clock_getres", "dx"),
+    ("clock_nanosleep", "dxxx"),
+    ("exit_group", "d"),
+    ("epoll_wait", "dxdd"),
+    ("epoll_ctl", "dddx"),
+    ("tgkill", "ddd"),
+    ("utimes", "sx"),
+    ("vserver", "xxxxx"),
+    ("mbind", "xudxux"),
+    ("set_mempolicy", "dxu"),
+    ("get_mempolicy", "xxuxx"),
+    ("mq_open", "sxox"),
+    ("mq_unlink", "s"),
+    ("mq_timedsend", "dbuux"),
+    ("mq_timedreceive", "dxuxx"),
+    ("mq_notify", "dx"),
+    ("mq_getsetattr", "dxx"),
+    ("kexec_load", "xuxx"),
+    ("waitid", "ddxxx"),
+    ("add_key", "ssxud"),
+    ("request_key", "sssd"),
+    ("keyctl", "dxxxx"),
+    ("ioprio_set", "ddd"),
+    ("ioprio_get", "dd"),
+    ("inotify_init", ""),
+    ("inotify_add_watch", "dsx"),
+    ("inotify_rm_watch", "dd"),
+    ("migrate_pages", "duxx"),
+    ("openat", "dsxo"),
+    ("mkdirat", "dso"),
+    ("mknodat", "dsox"),
+    ("fchownat", "dsddx"),
+    ("futimesat", "dsx"),
+    ("newfstatat", "dsxx"),
+    ("unlinkat", "dsx"),
+    ("renameat", "dsds"),
+    ("linkat", "dsdsx"),
+    ("symlinkat", "sds"),
+    ("readlinkat", "dsxu"),
+    ("fchmodat", "dso"),
+    ("faccessat", "dso"),
+    ("pselect6", "dxxxxx"),
+    ("ppoll", "xuxxu"),
+    ("unshare", "x"),
+    ("set_robust_list", "xu"),
+    ("get_robust_list", "dxx"),
+    ("splice", "dxdxux"),
+    ("tee", "ddux"),
+    ("sync_file_range", "dllx"),
+    ("vmsplice", "dxux"),
+    ("move_pages", "duxxxx"),
+    ("utimensat", "dsxx"),
+    ("epoll_pwait", "dxddxu"),
+    ("signalfd", "dxu"),
+    ("timerfd_create", "dx"),
+    ("eventfd", "u"),
+    ("fallocate", "dxll"),
+    ("timerfd_settime", "dxxx"),
+    ("timerfd_gettime", "dx"),
+    ("accept4", "dxxx"),
+    ("signalfd4", "dxux"),
+    ("eventfd2", "ux"),
+    ("epoll_create1", "x"),
+    ("dup3", "ddx"),
+    ("pipe2", "xx"),
+    ("inotify_init1", "x"),
+    ("preadv", "dxduu"),
+    ("pwritev", "dxduu"),
+    ("rt_tgsigqueueinfo", "dddx"),
+    ("perf_event_open", "xdddx"),
+    ("recvmmsg", "dxuxx"),
+    ("fanotify_init", "xx"),
+    ("fanotify_mark", "dxxds"),
+    ("prlimit64", "ddxx"),
+    ("name_to_handle_at", "dsxxx"),
+    ("open_by_handle_at", "dxx"),
+    ("clock_adjtime", "dx"),
+    ("syncfs", "d"),
+    ("sendmmsg", "dxux"),
+    ("setns", "dx"),
+    ("getcpu", "xxx"),
+    ("process_vm_readv", "dxuxux"),
+    ("process_vm_writev", "dxuxux"),
+    ("kcmp", "dddxx"),
+    ("finit_module", "dsx"),
+    ("sched_setattr", "dxx"),
+    ("sched_getattr", "dxux"),
+    ("renameat2", "dsdsx"),
+    ("seccomp", "dxx"),
+    ("getrandom", "xux"),
+    ("memfd_create", "sx"),
+    ("kexec_file_load", "ddusx"),
+    ("bpf", "dxu"),
+    ("execveat", "dsxxx"),
+    ("userfaultfd", "x"),
+    ("membarrier", "dx"),
+    ("mlock2", "xux"),
+    ("copy_file_range", "dxdxux"),
+    ("preadv2", "dxduux"),
+    ("pwritev2", "dxduux"),
+    ("pkey_mprotect", "xuxd"),
+    ("pkey_alloc", "xx"),
+    ("pkey_free", "d"),
+    ("statx", "dsxxx"),
+    ("io_pgetevents", "xddxxx"),
+    ("rseq", "xuxx"),
+];
+
+/// Name and arguments of the system calls numbered from NEWER_SYSCALLS_START
+const NEWER_SYSCALLS: [(&str, &str); 28] = [
+    ("pidfd_send_signal", "ddxx"),
+    ("io_uring_setup", "ux"),
+    ("io_uring_enter", "duuxxu"),
+    ("io_uring_register", "duxu"),
+    ("open_tree", "dsx"),
+    ("move_mount", "dsdsx"),
+    ("fsopen", "sx"),
+    ("fsconfig", "dusxd"),
+    ("fsmount", "dxx"),
+    ("fspick", "dsx"),
+    ("pidfd_open", "dx"),
+    ("clone3", "xu"),
+    ("close_range", "uux"),
+    ("openat2", "dsxu"),
+    ("pidfd_getfd", "ddx"),
+    ("faccessat2", "dsox"),
+    ("process_madvise", "dxudx"),
+    ("epoll_pwait2", "dxdxxu"),
+    ("mount_setattr", "dsxxu"),
+    ("quotactl_fd", "dxdx"),
+    ("landlock_create_ruleset", "xux"),
+    ("landlock_add_rule", "ddxx"),
+    ("landlock_restrict_self", "dx"),
+    ("memfd_secret", "x"),
+    ("process_mrelease", "dx"),
+    ("futex_waitv", "xuxxd"),
+    ("set_mempolicy_home_node", "xuux"),
+    ("cachestat", "dxxx"),
+];
+
+/// A system call the inferior has entered
+#[derive(Clone, Debug)]
+pub struct Syscall {
+    pub number: u64,
+    /// The call as strace shows it, decoded on entry while the memory its arguments point to
+    /// still holds what was passed
+    pub call: String,
+}
+
+impl Syscall {
+    /// Decodes the system call the inferior is entering, from its registers at the entry stop.
+    pub fn decode(inferior: &Inferior, regs: &libc::user_regs_struct) -> Syscall {
+        let number = regs.orig_rax;
+        let values = [regs.rdi, regs.rsi, regs.rdx, regs.r10, regs.r8, regs.r9];
+        let kinds = signature(number).map_or("xxxxxx", |(_, kinds)| kinds);
+        let args: Vec<String> = kinds
+            .chars()
+            .zip(&values)
+            .enumerate()
+            .map(|(idx, (kind, value))| {
+                let len = values.get(idx + 1).copied().unwrap_or(0);
+                format_arg(inferior, kind, *value, len)
+            })
+            .collect();
+        Syscall {
+            number,
+            call: format!("{}({})", name(number), args.join(", ")),
+        }
+    }
+
+    pub fn name(&self) -> String {
+        name(self.number)
+    }
+
+    /// Shows what the system call returned: the error for a failure, as strace does.
+    pub fn format_result(&self, value: i64) -> String {
+        if (-4095..0).contains(&value) {
+            let errno = nix::errno::Errno::from_i32(-value as i32);
+            return format!("-1 {:?} ({})", errno, errno.desc());
+        }
+        if ADDRESS_RESULTS.contains(&self.name().as_str()) {
+            format!("{:#x}", value)
+        } else {
+            value.to_string()
+        }
+    }
+}
+
+/// Returns the name of a system call, or syscall_N for a number there is none for.
+pub fn name(number: u64) -> String {
+    match signature(number) {
+        Some((name, _)) => name.to_string(),
+        None => format!("syscall_{}", number),
+    }
+}
+
+/// Returns the number of the system call with the given name.
+pub fn number(name: &str) -> Option<u64> {
+    if let Some(number) = SYSCALLS.iter().position(|(other, _)| *other == name) {
+        return Some(number as u64);
+    }
+    let number = NEWER_SYSCALLS
+        .iter()
+        .position(|(other, _)| *other == name)?;
+    Some(NEWER_SYSCALLS_START + number as u64)
+}
+
+fn signature(number: u64) -> Option<(&'static str, &'static str)> {
+    if number < NEWER_SYSCALLS_START {
+        SYSCALLS.get(number as usize).copied()
+    } else {
+        NEWER_SYSCALLS
+            .get((number - NEWER_SYSCALLS_START) as usize)
+            .copied()
+    }
+}
+
+/// Formats an argument of the given kind. len is the next argument, which is the length of a
+/// buffer.
+fn format_arg(inferior: &Inferior, kind: char, value: u64, len: u64) -> String {
+    let bytes = match kind {
+        'd' => return (value as i32).to_string(),
+        'l' => return (value as i64).to_string(),
+        'u' => return value.to_string(),
+        'o' if value == 0 => return String::from("0"),
+        'o' => return format!("0{:o}", value),
+        's' if value != 0 => read_string(inferior, value, None),
+        'b' if value != 0 => read_string(inferior, value, Some(len as usize)),
+        _ if value == 0 => return String::from("NULL"),
+        _ => return format!("{:#x}", value),
+    };
+    match bytes {
+        Some((bytes, truncated)) if truncated => format!("{}...", examine::quote_string(&bytes)),
+        Some((bytes, _)) => examine::quote_string(&bytes),
+        None => format!("{:#x}", value),
+    }
+}
+
+/// Reads up to MAX_STRING_LEN bytes of a string in the inferior's memory: a null-terminated one,
+/// or one of len bytes if len is given. Returns the bytes and whether there were more, or None if
+/// the string can't be read at all.
+fn read_string(inferior: &Inferior, addr: u64, len: Option<usize>) -> Option<(Vec<u8>, bool)> {
+    let limit = len.unwrap_or(usize::MAX).min(MAX_STRING_LEN + 1);
+    let mut bytes = Vec::new();
+    // An aligned word at a time, so as not to read into unmapped memory past the end of the
+    // string
+    while bytes.len() < limit {
+        let word_addr = addr + bytes.len() as u64;
+        let word_len = WORD_LEN - (word_addr % WORD_LEN);
+        let word = match inferior.read_bytes(word_addr, word_len as usize) {
+            Ok(word) => word,
+            Err(_) if bytes.is_empty() => return None,
+            Err(_) => break,
+        };
+        if len.is_none() {
+            if let Some(end) = word.iter().position(|byte| *byte == 0) {
+                bytes.extend_from_slice(&word[..end]);
+                break;
+            }
+        }
+        bytes.extend_from_slice(&word);
+    }
+    bytes.truncate(limit);
+    let truncated = bytes.len() > MAX_STRING_LEN;
+    bytes.truncate(MAX_STRING_LEN);
+    Some((bytes, truncated))
+}
@@ -0,0 +1,166 @@
+use crate::{request, response, status, ProxyState};
+use std::sync::atomic::Ordering;
+use tokio::net::TcpStream;
+
+/// Serves admin API requests on a connection until the client hangs up or sends something we
+/// can't parse. The admin API is served on its own listener (--admin-bind), so that it is never
+/// reachable through the public port.
+pub async fn handle_connection(mut stream: TcpStream, state: &ProxyState) {
+    loop {
+        let request =
+            match request::read_from_stream(&mut stream, &request::Limits::default()).await {
+                Ok(request) => request,
+                Err(request::Error::IncompleteRequest(0)) => return,
+                Err(error) => {
+                    tracing::debug!("Error reading admin API request: {:?}", error);
+                    return;
+                }
+            };
+        let response = handle_request(&request, state).await;
+        tracing::info!(
+            "Admin API: {} -> {}",
+            request::format_request_line(&request),
+            response::format_response_line(&response)
+        );
+        if let Err(error) = response::write_to_stream(&response, &mut stream).await {
+            tracing::warn!("Failed to send admin API response: {}", error);
+            return;
+        }
+    }
+}
+
+/// Dispatches a single admin API request. Supported endpoints:
+///
+/// * `GET /canary`: returns the current canary percentage
+/// * `PUT /canary?percent=N`: sets the canary percentage for new connections
+/// * `GET /status`: the JSON status report served at /__balancebeam/status, along with the
+///   addresses of the upstreams
+/// * `GET /metrics`: upstream latency histograms and traffic counters in Prometheus text format
+/// * `GET /traffic`: requests, responses by status class and bytes per upstream, as JSON
+/// * `GET /drain`: the upstreams being drained, their in-flight requests, and whether they are
+///   drained (safe to take down)
+/// * `PUT /drain?upstream=ADDR`: stops routing new requests to an upstream
+/// * `DELETE /drain?upstream=ADDR`: routes requests to an upstream drained with PUT again
+async fn handle_request(
+    request: &http::Request<Vec<u8>>,
+    state: &ProxyState,
+) -> http::Response<Vec<u8>> {
+    match (request.method(), request.uri().path()) {
+        (&http::Method::GET, "/canary") => text_response(
+            http::StatusCode::OK,
+            format!("{}\n", state.canary_percent.load(Ordering::Relaxed)),
+        ),
+        (&http::Method::PUT, "/canary") => {
+            let percent = query_param(request, "percent").and_then(|p| p.parse::<usize>().ok());
+            match percent {
+                Some(percent) if percent <= 100 => {
+                    state.canary_percent.store(percent, Ordering::Relaxed);
+                    tracing::info!("Canary percentage set to {}", percent);
+                    text_response(http::StatusCode::OK, format!("{}\n", percent))
+                }
+                _ => text_response(
+                    http::StatusCode::BAD_REQUEST,
+                    "expected ?percent=N with N between 0 and 100\n".to_string(),
+                ),
+            }
+        }
+        (_, "/canary") => response::make_http_error(http::StatusCode::METHOD_NOT_ALLOWED),
+        (&http::Method::GET, "/status") => status::make_status_response(state, true).await,
+        (_, "/status") => response::make_http_error(http::StatusCode::METHOD_NOT_ALLOWED),
+        (&http::Method::GET, "/metrics") => text_response(
+            http::StatusCode::OK,
+            state.latency.render_prometheus().await + &state.traffic.render_prometheus().await,
+        ),
+        (_, "/metrics") => response::make_http_error(http::StatusCode::METHOD_NOT_ALLOWED),
+        (&http::Method::GET, "/traffic") => {
+            let mut response =
+                text_response(http::StatusCode::OK, state.traffic.render_json().await);
+            response.headers_mut().insert(
+                http::header::CONTENT_TYPE,
+                http::HeaderValue::from_static("application/json"),
+            );
+            response
+        }
+        (_, "/traffic") => response::make_http_error(http::StatusCode::METHOD_NOT_ALLOWED),
+        (&http::Method::GET, "/drain") => drain_response(state),
+        (&http::Method::PUT | &http::Method::DELETE, "/drain") => {
+            let Some(upstream) = query_param(request, "upstream") else {
+                return text_response(
+                    http::StatusCode::BAD_REQUEST,
+                    "expected ?upstream=ADDR\n".to_string(),
+                );
+            };
+            if request.method() == http::Method::DELETE {
+                if state.drains.stop(upstream) {
+                    tracing::info!("No longer draining upstream {}", upstream);
+                }
+            } else {
+                if !state
+                    .upstream_addresses
+                    .read()
+                    .unwrap()
+                    .contains_key(upstream)
+                {
+                    return text_response(
+                        http::StatusCode::NOT_FOUND,
+                        format!("unknown upstream {}\n", upstream),
+                    );
+                }
+                if state.drains.start(upstream) {
+                    tracing::info!(
+                        "Draining upstream {} ({} requests in flight)",
+                        upstream,
+                        state.in_flight(upstream)
+                    );
+                }
+            }
+            drain_response(state)
+        }
+        (_, "/drain") => response::make_http_error(http::StatusCode::METHOD_NOT_ALLOWED),
+        _ => response::make_http_error(http::StatusCode::NOT_FOUND),
+    }
+}
+
+/// Reports every upstream being drained as JSON, with the requests still in flight there. Once
+/// none are left, the upstream is reported as drained and can be taken down.
+fn drain_response(state: &ProxyState) -> http::Response<Vec<u8>> {
+    let mut body = String::from("{\"upstreams\":[");
+    for (idx, upstream) in state.drains.draining().iter().enumerate() {
+        if idx > 0 {
+            body.push(',');
+        }
+        let in_flight = state.in_flight(upstream);
+        body += &format!(
+            "{{\"address\":{},\"in_flight\":{},\"drained\":{}}}",
+            status::json_string(upstream),
+            in_flight,
+            in_flight == 0
+        );
+    }
+    body.push_str("]}\n");
+    let mut response = text_response(http::StatusCode::OK, body);
+    response.headers_mut().insert(
+        http::header::CONTENT_TYPE,
+        http::HeaderValue::from_static("application/json"),
+    );
+    response
+}
+
+/// Returns the value of a query string parameter, if present.
+fn query_param<'a>(request: &'a http::Request<Vec<u8>>, name: &str) -> Option<&'a str> {
+    request.uri().query()?.split('&').find_map(|pair| {
+        let (key, value) = pair.split_once('=')?;
+        (key == name).then_some(value)
+    })
+}
+
+fn text_response(status: http::StatusCode, body: String) -> http::Response<Vec<u8>> {
+    let body = body.into_bytes();
+    http::Response::builder()
+        .status(status)
+        .header("Content-Type", "text/plain")
+        .header("Content-Length", body.len().to_string())
+        .version(http::Version::HTTP_11)
+        .body(body)
+        .unwrap()
+}
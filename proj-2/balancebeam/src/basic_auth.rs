@@ -0,0 +1,291 @@
+use base64::Engine;
+use md5::{Digest, Md5};
+use sha1::Sha1;
+use std::collections::HashMap;
+use std::fmt;
+
+/// Salt that passwords of unknown users are hashed with, so that they are turned away no faster
+/// than users with a wrong password
+const DUMMY_SALT: &str = "balancer";
+
+/// Users allowed through balancebeam, loaded from the htpasswd-style file passed with --htpasswd.
+/// Each line is `user:hash`; blank lines and lines starting with `#` are ignored. Supported hash
+/// formats are the ones `htpasswd` produces with `-m` (the default, `$apr1$` MD5) and `-s`
+/// (`{SHA}` SHA-1), plus plain text (`-p`). bcrypt hashes (`-B`) are not supported.
+#[derive(Debug)]
+pub struct Htpasswd {
+    users: HashMap<String, PasswordHash>,
+}
+
+#[derive(Debug)]
+enum PasswordHash {
+    Apr1 { salt: String, hash: String },
+    Sha1(Vec<u8>),
+    Plain(String),
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(std::io::Error),
+    /// A line that couldn't be parsed; contains the line number and a description of the problem
+    Format(usize, &'static str),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "{}", err),
+            Error::Format(line, message) => write!(f, "line {}: {}", line, message),
+        }
+    }
+}
+
+impl Htpasswd {
+    pub fn from_file(path: &str) -> Result<Htpasswd, Error> {
+        Htpasswd::parse(&std::fs::read_to_string(path).map_err(Error::Io)?)
+    }
+
+    pub fn parse(contents: &str) -> Result<Htpasswd, Error> {
+        let mut users = HashMap::new();
+        for (idx, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (user, hash) = line
+                .split_once(':')
+                .ok_or(Error::Format(idx + 1, "expected user:hash"))?;
+            let hash = if let Some(rest) = hash.strip_prefix("$apr1$") {
+                let (salt, hash) = rest
+                    .split_once('$')
+                    .ok_or(Error::Format(idx + 1, "malformed $apr1$ hash"))?;
+                PasswordHash::Apr1 {
+                    salt: salt.to_string(),
+                    hash: hash.to_string(),
+                }
+            } else if let Some(digest) = hash.strip_prefix("{SHA}") {
+                let digest = base64::engine::general_purpose::STANDARD
+                    .decode(digest)
+                    .map_err(|_| Error::Format(idx + 1, "malformed {SHA} hash"))?;
+                PasswordHash::Sha1(digest)
+            } else if hash.starts_with("$2") {
+                return Err(Error::Format(idx + 1, "bcrypt hashes are not supported"));
+            } else if hash.starts_with('$') {
+                return Err(Error::Format(idx + 1, "unsupported hash format"));
+            } else {
+                PasswordHash::Plain(hash.to_string())
+            };
+            users.insert(user.to_string(), hash);
+        }
+        Ok(Htpasswd { users })
+    }
+
+    /// Checks the request's Authorization header. Returns the authenticated user name, or None if
+    /// the header is missing or the credentials are wrong.
+    pub fn authenticate(&self, request: &http::Request<Vec<u8>>) -> Option<String> {
+        let header = request
+            .headers()
+            .get(http::header::AUTHORIZATION)?
+            .to_str()
+            .ok()?;
+        let (scheme, credentials) = header.trim().split_once(' ')?;
+        if !scheme.eq_ignore_ascii_case("basic") {
+            return None;
+        }
+        let credentials = base64::engine::general_purpose::STANDARD
+            .decode(credentials.trim())
+            .ok()?;
+        let credentials = String::from_utf8(credentials).ok()?;
+        let (user, password) = credentials.split_once(':')?;
+        let verified = match self.users.get(user) {
+            Some(PasswordHash::Apr1 { salt, hash }) => {
+                constant_time_eq(apr1(password, salt).as_bytes(), hash.as_bytes())
+            }
+            Some(PasswordHash::Sha1(digest)) => {
+                constant_time_eq(&Sha1::digest(password.as_bytes()), digest)
+            }
+            Some(PasswordHash::Plain(expected)) => {
+                constant_time_eq(password.as_bytes(), expected.as_bytes())
+            }
+            None => {
+                // Response timing shouldn't reveal which users exist
+                std::hint::black_box(apr1(password, DUMMY_SALT));
+                false
+            }
+        };
+        verified.then(|| user.to_string())
+    }
+}
+
+/// Builds the 401 response sent to clients that didn't authenticate.
+pub fn challenge(mut response: http::Response<Vec<u8>>, realm: &str) -> http::Response<Vec<u8>> {
+    let value = format!(
+        "Basic realm=\"{}\", charset=\"UTF-8\"",
+        realm.replace('"', "")
+    );
+    response.headers_mut().insert(
+        http::header::WWW_AUTHENTICATE,
+        http::HeaderValue::from_str(&value).unwrap(),
+    );
+    response
+}
+
+/// Compares two byte strings without returning early, so that response timing doesn't reveal how
+/// much of a password was right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Apache's MD5-based crypt variant, as produced by `htpasswd -m`. Returns the encoded hash (the
+/// part after `$apr1$salt$`).
+fn apr1(password: &str, salt: &str) -> String {
+    let password = password.as_bytes();
+    let salt = &salt.as_bytes()[..salt.len().min(8)];
+
+    let alternate = Md5::digest([password, salt, password].concat());
+    let mut input = [password, b"$apr1$", salt].concat();
+    for chunk in (0..password.len()).step_by(16) {
+        input.extend_from_slice(&alternate[..(password.len() - chunk).min(16)]);
+    }
+    let mut len = password.len();
+    while len > 0 {
+        input.push(if len & 1 != 0 { 0 } else { password[0] });
+        len >>= 1;
+    }
+    let mut digest = Md5::digest(&input);
+
+    for round in 0..1000 {
+        let mut input = Vec::new();
+        if round & 1 != 0 {
+            input.extend_from_slice(password);
+        } else {
+            input.extend_from_slice(&digest);
+        }
+        if round % 3 != 0 {
+            input.extend_from_slice(salt);
+        }
+        if round % 7 != 0 {
+            input.extend_from_slice(password);
+        }
+        if round & 1 != 0 {
+            input.extend_from_slice(&digest);
+        } else {
+            input.extend_from_slice(password);
+        }
+        digest = Md5::digest(&input);
+    }
+
+    const ALPHABET: &[u8] = b"./0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+    let mut encoded = String::new();
+    let mut push = |mut value: u32, chars: usize| {
+        for _ in 0..chars {
+            encoded.push(ALPHABET[(value & 0x3f) as usize] as char);
+            value >>= 6;
+        }
+    };
+    for (a, b, c) in [(0, 6, 12), (1, 7, 13), (2, 8, 14), (3, 9, 15), (4, 10, 5)] {
+        push(
+            (digest[a] as u32) << 16 | (digest[b] as u32) << 8 | digest[c] as u32,
+            4,
+        );
+    }
+    push(digest[11] as u32, 2);
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(digest: &[u8]) -> String {
+        digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    fn request(user: &str, password: &str) -> http::Request<Vec<u8>> {
+        let credentials =
+            base64::engine::general_purpose::STANDARD.encode(format!("{}:{}", user, password));
+        http::Request::builder()
+            .header("Authorization", format!("Basic {}", credentials))
+            .body(Vec::new())
+            .unwrap()
+    }
+
+    #[test]
+    fn md5_matches_rfc_1321() {
+        for (message, digest) in [
+            ("", "d41d8cd98f00b204e9800998ecf8427e"),
+            ("a", "0cc175b9c0f1b6a831c399e269772661"),
+            ("abc", "900150983cd24fb0d6963f7d28e17f72"),
+            ("message digest", "f96b697d7cb7938d525a2f31aaf161d0"),
+            (
+                "abcdefghijklmnopqrstuvwxyz",
+                "c3fcd3d76192e4007dfb496cca67e13b",
+            ),
+        ] {
+            assert_eq!(hex(&Md5::digest(message)), digest, "{:?}", message);
+        }
+    }
+
+    #[test]
+    fn sha1_matches_rfc_3174() {
+        for (message, digest) in [
+            ("abc", "a9993e364706816aba3e25717850c26c9cd0d89d"),
+            (
+                "abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq",
+                "84983e441c3bd26ebaae4aa1f95129e5e54670f1",
+            ),
+        ] {
+            assert_eq!(hex(&Sha1::digest(message)), digest, "{:?}", message);
+        }
+    }
+
+    #[test]
+    fn apr1_matches_htpasswd() {
+        assert_eq!(apr1("password", "r31....."), "ARC3pREO82RIm0aQ2zszC0");
+        assert_eq!(apr1("correct horse", "saltsalt"), "EGVZDNN6gOqijy.tv9axG/");
+        assert_eq!(apr1("x", "ab"), "eIePjsejfBGR8ITtu2z0U1");
+        assert_eq!(
+            apr1("a password longer than sixteen bytes", "abcdefgh"),
+            "KxnTlry9zkkkUhSc6GrVg/"
+        );
+    }
+
+    #[test]
+    fn authenticates_each_hash_format() {
+        let htpasswd = Htpasswd::parse(
+            "# comment\n\
+             \n\
+             apr:$apr1$r31.....$ARC3pREO82RIm0aQ2zszC0\n\
+             sha:{SHA}W6ph5Mm5Pz8GgiULbPgzG37mj9g=\n\
+             plain:password\n",
+        )
+        .unwrap();
+        for user in ["apr", "sha", "plain"] {
+            assert_eq!(
+                htpasswd.authenticate(&request(user, "password")),
+                Some(user.to_string())
+            );
+            assert_eq!(htpasswd.authenticate(&request(user, "Password")), None);
+        }
+        assert_eq!(htpasswd.authenticate(&request("nobody", "password")), None);
+    }
+
+    #[test]
+    fn rejects_unsupported_hashes() {
+        assert!(matches!(
+            Htpasswd::parse("user:$2y$05$abcdefghijklmnopqrstuv"),
+            Err(Error::Format(1, "bcrypt hashes are not supported"))
+        ));
+        assert!(matches!(
+            Htpasswd::parse("ok:plain\nuser:$6$salt$hash"),
+            Err(Error::Format(2, "unsupported hash format"))
+        ));
+        assert!(matches!(
+            Htpasswd::parse("no colon"),
+            Err(Error::Format(1, "expected user:hash"))
+        ));
+    }
+}
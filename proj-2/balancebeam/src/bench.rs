@@ -0,0 +1,217 @@
+use crate::{request, response};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+
+/// Options for `balancebeam bench`
+#[derive(clap::Args, Debug)]
+pub struct Options {
+    /// "Address (host:port) to send requests to, e.g. a running balancebeam"
+    target: String,
+    /// "Path to request"
+    #[arg(long, default_value = "/")]
+    path: String,
+    /// "Method to request the path with"
+    #[arg(long, default_value = "GET")]
+    method: String,
+    /// "Number of connections sending requests at the same time"
+    #[arg(short, long, default_value = "10")]
+    concurrency: usize,
+    /// "Total number of requests to send"
+    #[arg(short = 'n', long, default_value = "1000")]
+    requests: usize,
+    /// "Stop after this long even if not all requests were sent (e.g. 30s or 2m, 0 = no limit)"
+    #[arg(long, default_value = "0s", value_parser = crate::parse_duration)]
+    duration: Duration,
+    /// "Open a new connection for every request instead of reusing connections"
+    #[arg(long)]
+    no_keep_alive: bool,
+}
+
+/// What one worker observed
+#[derive(Default)]
+struct Results {
+    /// Time taken by every request that got a response
+    latencies: Vec<Duration>,
+    /// Number of responses with each status code
+    statuses: BTreeMap<u16, usize>,
+    /// Requests that failed without a response (connect errors, broken connections)
+    errors: usize,
+    /// Response bytes read, not counting headers
+    body_bytes: usize,
+}
+
+/// Sends the configured load at the target and prints a summary of throughput and latencies.
+/// Each of the `concurrency` workers sends requests one after another over its own connection,
+/// reconnecting after errors, until `requests` have been sent between them or time is up.
+pub async fn run(options: Options) -> Result<(), String> {
+    let method = http::Method::from_bytes(options.method.as_bytes())
+        .map_err(|_| format!("invalid method {}", options.method))?;
+    if options.concurrency == 0 {
+        return Err("concurrency must be at least 1".to_string());
+    }
+    let path = if options.path.starts_with('/') {
+        options.path.clone()
+    } else {
+        format!("/{}", options.path)
+    };
+    let request = http::Request::builder()
+        .method(method)
+        .uri(path)
+        .header("Host", &options.target)
+        .header(
+            "Connection",
+            if options.no_keep_alive {
+                "close"
+            } else {
+                "keep-alive"
+            },
+        )
+        .version(http::Version::HTTP_11)
+        .body(Vec::new())
+        .map_err(|err| format!("invalid request: {}", err))?;
+
+    println!(
+        "Sending {} {} requests to {} over {} connections",
+        options.requests, options.method, options.target, options.concurrency
+    );
+    let request = Arc::new(request);
+    let remaining = Arc::new(AtomicUsize::new(options.requests));
+    let deadline = (!options.duration.is_zero()).then(|| Instant::now() + options.duration);
+    let start = Instant::now();
+    let workers: Vec<_> = (0..options.concurrency)
+        .map(|_| {
+            tokio::spawn(worker(
+                options.target.clone(),
+                Arc::clone(&request),
+                Arc::clone(&remaining),
+                deadline,
+                !options.no_keep_alive,
+            ))
+        })
+        .collect();
+    let mut results = Results::default();
+    for worker in workers {
+        let worker_results = worker
+            .await
+            .map_err(|err| format!("worker failed: {}", err))?;
+        results.latencies.extend(worker_results.latencies);
+        for (status, count) in worker_results.statuses {
+            *results.statuses.entry(status).or_insert(0) += count;
+        }
+        results.errors += worker_results.errors;
+        results.body_bytes += worker_results.body_bytes;
+    }
+    print!("{}", report(&mut results, start.elapsed()));
+    Ok(())
+}
+
+/// Sends requests until the shared request count runs out or the deadline passes.
+async fn worker(
+    target: String,
+    request: Arc<http::Request<Vec<u8>>>,
+    remaining: Arc<AtomicUsize>,
+    deadline: Option<Instant>,
+    keep_alive: bool,
+) -> Results {
+    let mut results = Results::default();
+    let mut conn: Option<TcpStream> = None;
+    while deadline.is_none_or(|deadline| Instant::now() < deadline)
+        && remaining
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+            .is_ok()
+    {
+        let request_start = Instant::now();
+        let mut stream = match conn.take() {
+            Some(stream) => stream,
+            None => match TcpStream::connect(&target).await {
+                Ok(stream) => {
+                    // Requests are written in small pieces; don't let Nagle's algorithm hold them
+                    // back waiting for ACKs, or the numbers measure that instead
+                    stream.set_nodelay(true).ok();
+                    stream
+                }
+                Err(_) => {
+                    results.errors += 1;
+                    continue;
+                }
+            },
+        };
+        if request::write_to_stream(&request, &mut stream)
+            .await
+            .is_err()
+        {
+            results.errors += 1;
+            continue;
+        }
+        match response::read_from_stream(&mut stream, request.method(), response::MAX_BODY_SIZE)
+            .await
+        {
+            Ok(response) => {
+                results.latencies.push(request_start.elapsed());
+                *results
+                    .statuses
+                    .entry(response.status().as_u16())
+                    .or_insert(0) += 1;
+                results.body_bytes += response.body().len();
+                let closing = response
+                    .headers()
+                    .get("connection")
+                    .is_some_and(|value| value.as_bytes().eq_ignore_ascii_case(b"close"));
+                if keep_alive && !closing {
+                    conn = Some(stream);
+                }
+            }
+            Err(_) => results.errors += 1,
+        }
+    }
+    results
+}
+
+/// Returns the latency below which `percentile` percent of the (sorted) latencies fall.
+fn percentile(sorted: &[Duration], percentile: f64) -> Duration {
+    let rank = (sorted.len() as f64 * percentile / 100.0).ceil().max(1.0) as usize;
+    sorted[rank.min(sorted.len()) - 1]
+}
+
+fn report(results: &mut Results, elapsed: Duration) -> String {
+    let completed = results.latencies.len();
+    let mut report = format!(
+        "\nCompleted {} requests in {:.2}s ({} failed)\n",
+        completed,
+        elapsed.as_secs_f64(),
+        results.errors
+    );
+    report += &format!(
+        "Throughput: {:.1} requests/s, {:.1} KB/s\n",
+        completed as f64 / elapsed.as_secs_f64(),
+        results.body_bytes as f64 / 1024.0 / elapsed.as_secs_f64()
+    );
+    if !results.statuses.is_empty() {
+        let statuses: Vec<String> = results
+            .statuses
+            .iter()
+            .map(|(status, count)| format!("{}: {}", status, count))
+            .collect();
+        report += &format!("Status codes: {}\n", statuses.join(", "));
+    }
+    if completed > 0 {
+        results.latencies.sort();
+        let mean = results.latencies.iter().sum::<Duration>() / completed as u32;
+        report += &format!("Latency: mean {:.2}ms", mean.as_secs_f64() * 1000.0);
+        for p in [50.0, 90.0, 99.0] {
+            report += &format!(
+                ", p{} {:.2}ms",
+                p,
+                percentile(&results.latencies, p).as_secs_f64() * 1000.0
+            );
+        }
+        report += &format!(
+            ", max {:.2}ms\n",
+            results.latencies[completed - 1].as_secs_f64() * 1000.0
+        );
+    }
+    report
+}
@@ -0,0 +1,76 @@
+// Shared with balancebeam by path, so that balancebeam doesn't depend on the test server's library.
+// Only parse_duration_ms is used here.
+#[allow(dead_code)]
+#[path = "../duration.rs"]
+mod duration;
+
+use balancebeam_testserver::{Behavior, Failure, TestServer};
+use clap::Parser;
+use duration::parse_duration_ms;
+use std::time::Duration;
+
+/// Command-line options for the test server
+#[derive(Parser, Debug)]
+#[command(
+    about = "HTTP server with configurable responses, latency and failures, for testing balancebeam"
+)]
+struct CmdOptions {
+    /// "IP/port to bind to"
+    #[arg(short, long, default_value = "127.0.0.1:8080")]
+    bind: String,
+    /// "Status code of every response"
+    #[arg(long, default_value = "200")]
+    status: u16,
+    /// "Body of every response (default: echo the request back)"
+    #[arg(long)]
+    body: Option<String>,
+    /// "Header added to every response, given as NAME: VALUE"
+    #[arg(long, value_parser = parse_header)]
+    header: Vec<(String, String)>,
+    /// "How long to wait before responding, e.g. 250ms or 2s (plain numbers are milliseconds)"
+    #[arg(long, default_value = "0ms", value_parser = parse_duration_ms)]
+    latency: Duration,
+    /// "Fail requests instead of responding: close, hang or truncate"
+    #[arg(long)]
+    fail: Option<Failure>,
+    /// "With --fail, only fail every nth request"
+    #[arg(long, default_value = "1")]
+    fail_every: usize,
+}
+
+fn parse_header(s: &str) -> Result<(String, String), String> {
+    let (name, value) = s
+        .split_once(':')
+        .ok_or_else(|| format!("expected NAME: VALUE, got {}", s))?;
+    Ok((name.trim().to_string(), value.trim().to_string()))
+}
+
+#[tokio::main]
+async fn main() {
+    if std::env::var("RUST_LOG").is_err() {
+        std::env::set_var("RUST_LOG", "info");
+    }
+    pretty_env_logger::init();
+
+    let options = CmdOptions::parse();
+    let behavior = Behavior {
+        status: options.status,
+        body: options.body,
+        headers: options.header,
+        latency: options.latency,
+        failure: options.fail,
+        fail_every: options.fail_every,
+    };
+    let server = match TestServer::start(&options.bind, behavior) {
+        Ok(server) => server,
+        Err(err) => {
+            log::error!("{}", err);
+            std::process::exit(1);
+        }
+    };
+    log::info!("Listening for requests on {}", server.address);
+
+    tokio::signal::ctrl_c().await.ok();
+    let requests = server.stop().await;
+    log::info!("Served {} requests, exiting", requests);
+}
@@ -0,0 +1,180 @@
+use http::header::{self, HeaderMap, HeaderName, HeaderValue};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Longest chunk-size or trailer line we are willing to buffer
+const MAX_LINE_SIZE: usize = 8000;
+
+/// Trailer fields sent after a chunked body. They are kept in the extensions of the request or
+/// response they came with, so that they can be sent on after the body.
+#[derive(Clone, Debug, Default)]
+pub struct Trailers(pub HeaderMap);
+
+#[derive(Debug)]
+pub enum Error {
+    /// The connection was closed before the whole body was read
+    Incomplete,
+    /// The chunked data is malformed
+    Malformed,
+    /// The decoded body is bigger than the limit passed to read_body
+    TooLarge,
+    /// Encountered an I/O error when reading from the TcpStream
+    Io(std::io::Error),
+}
+
+/// Returns true if a message's body is sent with chunked transfer coding.
+pub fn is_chunked(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::TRANSFER_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .last()
+        .is_some_and(|coding| coding.trim().eq_ignore_ascii_case("chunked"))
+}
+
+/// Returns true if a field may not be sent as a trailer, because it is needed before the body
+/// (framing, routing, authentication) or only describes the connection.
+fn is_forbidden_trailer(name: &HeaderName) -> bool {
+    matches!(
+        name.as_str(),
+        "content-length"
+            | "transfer-encoding"
+            | "trailer"
+            | "host"
+            | "authorization"
+            | "content-encoding"
+            | "content-type"
+            | "content-range"
+            | "te"
+            | "connection"
+            | "keep-alive"
+            | "upgrade"
+    )
+}
+
+/// Reads from the stream until `buffer` holds at least `needed` bytes.
+async fn fill_buffer(
+    stream: &mut TcpStream,
+    buffer: &mut Vec<u8>,
+    needed: usize,
+) -> Result<(), Error> {
+    while buffer.len() < needed {
+        let mut chunk = [0_u8; 512];
+        let bytes_read = stream.read(&mut chunk).await.map_err(Error::Io)?;
+        if bytes_read == 0 {
+            return Err(Error::Incomplete);
+        }
+        buffer.extend_from_slice(&chunk[..bytes_read]);
+    }
+    Ok(())
+}
+
+/// Reads a CRLF-terminated line starting at `start` in the buffer, reading more from the stream
+/// as needed. Returns the line (without the CRLF) and the position just after it.
+async fn read_line(
+    stream: &mut TcpStream,
+    buffer: &mut Vec<u8>,
+    start: usize,
+) -> Result<(Vec<u8>, usize), Error> {
+    loop {
+        if let Some(end) = buffer[start..].windows(2).position(|w| w == b"\r\n") {
+            return Ok((buffer[start..start + end].to_vec(), start + end + 2));
+        }
+        if buffer.len() - start > MAX_LINE_SIZE {
+            return Err(Error::Malformed);
+        }
+        let needed = buffer.len() + 1;
+        fill_buffer(stream, buffer, needed).await?;
+    }
+}
+
+/// Reads a body sent with chunked transfer coding. `buffer` holds whatever was already read past
+/// the headers. Returns the decoded body and the trailer fields that followed it; trailer fields
+/// that aren't allowed in trailers are dropped.
+pub async fn read_body(
+    stream: &mut TcpStream,
+    mut buffer: Vec<u8>,
+    max_body_size: usize,
+) -> Result<(Vec<u8>, HeaderMap), Error> {
+    let mut body = Vec::new();
+    let mut pos = 0;
+    loop {
+        let (size_line, after) = read_line(stream, &mut buffer, pos).await?;
+        // Chunk extensions (after a ';') carry nothing we need
+        let size_line = String::from_utf8_lossy(&size_line);
+        let size_field = size_line.split(';').next().unwrap_or("").trim();
+        let size = usize::from_str_radix(size_field, 16).map_err(|_| Error::Malformed)?;
+        pos = after;
+        if size == 0 {
+            break;
+        }
+        if body.len().saturating_add(size) > max_body_size {
+            return Err(Error::TooLarge);
+        }
+        fill_buffer(stream, &mut buffer, pos + size + 2).await?;
+        if &buffer[pos + size..pos + size + 2] != b"\r\n" {
+            return Err(Error::Malformed);
+        }
+        body.extend_from_slice(&buffer[pos..pos + size]);
+        pos += size + 2;
+    }
+
+    // The trailer section is a list of fields ending with an empty line
+    let mut trailers = HeaderMap::new();
+    loop {
+        let (line, after) = read_line(stream, &mut buffer, pos).await?;
+        pos = after;
+        if line.is_empty() {
+            break;
+        }
+        let colon = line
+            .iter()
+            .position(|b| *b == b':')
+            .ok_or(Error::Malformed)?;
+        let name = HeaderName::from_bytes(&line[..colon]).map_err(|_| Error::Malformed)?;
+        let value = HeaderValue::from_bytes(line[colon + 1..].trim_ascii())
+            .map_err(|_| Error::Malformed)?;
+        if !is_forbidden_trailer(&name) {
+            trailers.append(name, value);
+        }
+    }
+    Ok((body, trailers))
+}
+
+/// Sets up a message's headers for a chunked body followed by the given trailers: the body
+/// length is left to the chunked coding, and the Trailer header announces the trailer fields.
+pub fn frame_with_trailers(headers: &mut HeaderMap, trailers: &HeaderMap) {
+    headers.remove(header::CONTENT_LENGTH);
+    headers.insert(
+        header::TRANSFER_ENCODING,
+        HeaderValue::from_static("chunked"),
+    );
+    let names: Vec<&str> = trailers.keys().map(|name| name.as_str()).collect();
+    if let Ok(value) = HeaderValue::from_str(&names.join(", ")) {
+        headers.insert(header::TRAILER, value);
+    }
+}
+
+/// Writes a body with chunked transfer coding, as a single chunk followed by the trailers.
+pub async fn write_body(
+    stream: &mut TcpStream,
+    body: &[u8],
+    trailers: &HeaderMap,
+) -> Result<(), std::io::Error> {
+    let mut encoded = Vec::with_capacity(body.len() + 64);
+    if !body.is_empty() {
+        encoded.extend_from_slice(format!("{:x}\r\n", body.len()).as_bytes());
+        encoded.extend_from_slice(body);
+        encoded.extend_from_slice(b"\r\n");
+    }
+    encoded.extend_from_slice(b"0\r\n");
+    for (name, value) in trailers {
+        encoded.extend_from_slice(name.as_str().as_bytes());
+        encoded.extend_from_slice(b": ");
+        encoded.extend_from_slice(value.as_bytes());
+        encoded.extend_from_slice(b"\r\n");
+    }
+    encoded.extend_from_slice(b"\r\n");
+    stream.write_all(&encoded).await
+}
@@ -0,0 +1,496 @@
+use crate::cors::CorsPolicy;
+use std::fmt;
+use std::fs;
+
+/// Routing configuration loaded from the file passed with --config. The file is line-based:
+///
+/// ```text
+/// # Comments start with a hash
+/// [route /api]
+/// request-header set X-Env prod
+/// request-header remove X-Internal-Token
+/// response-header add Cache-Control no-store
+/// security-headers on
+/// security-header frame-options SAMEORIGIN
+/// ```
+///
+/// Each `[route PREFIX]` line starts a new route, and the directives that follow apply to requests
+/// whose path starts with PREFIX at a segment boundary (`/api` covers `/api/users` but not
+/// `/apikeys`). Directives before the first route header belong to the route `/`. A request only
+/// gets the directives of the route with the longest matching prefix.
+///
+/// `security-headers on` adds a default set of security headers (see SecurityHeaders) to the
+/// route's responses. `security-header NAME VALUE` changes one of them (and turns the set on), and
+/// `security-header NAME off` leaves it out. NAME is one of hsts, content-type-options,
+/// frame-options or referrer-policy.
+///
+/// Any cors-* directive enables CORS handling for the route (see cors.rs):
+///
+/// * `cors-origin ORIGIN`: allows cross-origin requests from ORIGIN (may be repeated; `*` allows
+///   any origin)
+/// * `cors-methods LIST`: methods allowed in preflight responses (default `GET, HEAD, POST`)
+/// * `cors-headers LIST`: request headers allowed in preflight responses
+/// * `cors-max-age SECONDS`: how long browsers may cache preflight responses
+/// * `cors-credentials on|off`: whether to allow credentialed requests
+///
+/// `rate-limit N` gives the route its own limit of N requests per client per minute, counted
+/// separately from other routes, in place of --max-requests-per-minute. `rate-limit 0` exempts the
+/// route from rate limiting.
+///
+/// `allow-methods LIST` only lets requests with one of the listed methods (comma-separated)
+/// through to the route's upstreams, and `deny-methods LIST` blocks the listed methods. Other
+/// requests get a 405 with an Allow header, without any upstream being contacted.
+#[derive(Debug, Default)]
+pub struct Config {
+    pub routes: Vec<Route>,
+}
+
+#[derive(Debug)]
+pub struct Route {
+    /// Path prefix this route applies to
+    pub prefix: String,
+    /// Rewrites applied to requests before they are forwarded upstream
+    pub request_headers: Vec<HeaderRule>,
+    /// Rewrites applied to responses before they are sent back to the client
+    pub response_headers: Vec<HeaderRule>,
+    /// Security headers added to responses, if enabled for this route
+    pub security_headers: Option<SecurityHeaders>,
+    /// CORS handling for this route, if enabled
+    pub cors: Option<CorsPolicy>,
+    /// Requests per client per minute allowed on this route, overriding the global limit
+    pub rate_limit: Option<usize>,
+    /// If set, only requests with one of these methods are allowed
+    pub allowed_methods: Option<Vec<http::Method>>,
+    /// Methods that are never allowed
+    pub denied_methods: Vec<http::Method>,
+}
+
+/// Methods listed in the Allow header of 405 responses for routes that only deny some methods
+const STANDARD_METHODS: [http::Method; 7] = [
+    http::Method::GET,
+    http::Method::HEAD,
+    http::Method::POST,
+    http::Method::PUT,
+    http::Method::DELETE,
+    http::Method::PATCH,
+    http::Method::OPTIONS,
+];
+
+/// Security headers added to responses that don't already carry them. A header set to None is
+/// left out.
+#[derive(Debug)]
+pub struct SecurityHeaders {
+    pub hsts: Option<http::HeaderValue>,
+    pub content_type_options: Option<http::HeaderValue>,
+    pub frame_options: Option<http::HeaderValue>,
+    pub referrer_policy: Option<http::HeaderValue>,
+}
+
+impl Default for SecurityHeaders {
+    fn default() -> SecurityHeaders {
+        SecurityHeaders {
+            hsts: Some(http::HeaderValue::from_static(
+                "max-age=31536000; includeSubDomains",
+            )),
+            content_type_options: Some(http::HeaderValue::from_static("nosniff")),
+            frame_options: Some(http::HeaderValue::from_static("DENY")),
+            referrer_policy: Some(http::HeaderValue::from_static(
+                "strict-origin-when-cross-origin",
+            )),
+        }
+    }
+}
+
+impl SecurityHeaders {
+    pub fn apply(&self, headers: &mut http::HeaderMap) {
+        let all = [
+            (http::header::STRICT_TRANSPORT_SECURITY, &self.hsts),
+            (
+                http::header::X_CONTENT_TYPE_OPTIONS,
+                &self.content_type_options,
+            ),
+            (http::header::X_FRAME_OPTIONS, &self.frame_options),
+            (http::header::REFERRER_POLICY, &self.referrer_policy),
+        ];
+        for (name, value) in all {
+            if let Some(value) = value {
+                if !headers.contains_key(&name) {
+                    headers.insert(name, value.clone());
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum HeaderRule {
+    /// Appends a header, keeping any existing values
+    Add(http::HeaderName, http::HeaderValue),
+    /// Replaces all existing values of a header
+    Set(http::HeaderName, http::HeaderValue),
+    /// Removes a header entirely
+    Remove(http::HeaderName),
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// The config file couldn't be read
+    Io(std::io::Error),
+    /// The config file contains something we don't understand. Contains the (1-based) line number
+    /// and a description of the problem
+    Parse(usize, String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "{}", err),
+            Error::Parse(line, message) => write!(f, "line {}: {}", line, message),
+        }
+    }
+}
+
+impl Route {
+    fn new(prefix: &str) -> Route {
+        Route {
+            prefix: prefix.to_string(),
+            request_headers: Vec::new(),
+            response_headers: Vec::new(),
+            security_headers: None,
+            cors: None,
+            rate_limit: None,
+            allowed_methods: None,
+            denied_methods: Vec::new(),
+        }
+    }
+
+    /// Returns true if requests with this method may be passed on to the route's upstreams.
+    pub fn method_allowed(&self, method: &http::Method) -> bool {
+        self.allowed_methods
+            .as_ref()
+            .is_none_or(|allowed| allowed.contains(method))
+            && !self.denied_methods.contains(method)
+    }
+
+    /// Returns the value of the Allow header sent with 405 responses for this route.
+    pub fn allow_header(&self) -> http::HeaderValue {
+        let methods: Vec<&str> = self
+            .allowed_methods
+            .as_ref()
+            .map_or(&STANDARD_METHODS[..], |allowed| &allowed[..])
+            .iter()
+            .filter(|method| !self.denied_methods.contains(method))
+            .map(|method| method.as_str())
+            .collect();
+        http::HeaderValue::from_str(&methods.join(", ")).unwrap()
+    }
+}
+
+impl Config {
+    pub fn from_file(path: &str) -> Result<Config, Error> {
+        Config::parse(&fs::read_to_string(path).map_err(Error::Io)?)
+    }
+
+    pub fn parse(contents: &str) -> Result<Config, Error> {
+        let mut routes = vec![Route::new("/")];
+        let mut current = 0;
+        for (idx, line) in contents.lines().enumerate() {
+            let line_number = idx + 1;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            if let Some(header) = line.strip_prefix('[') {
+                let prefix = header
+                    .strip_suffix(']')
+                    .and_then(|header| header.trim().strip_prefix("route "))
+                    .map(str::trim)
+                    .filter(|prefix| prefix.starts_with('/'))
+                    .ok_or_else(|| {
+                        Error::Parse(line_number, "expected [route /PREFIX]".to_string())
+                    })?;
+                // A prefix may appear more than once; later sections add to the earlier one
+                current = match routes.iter().position(|route| route.prefix == prefix) {
+                    Some(idx) => idx,
+                    None => {
+                        routes.push(Route::new(prefix));
+                        routes.len() - 1
+                    }
+                };
+                continue;
+            }
+
+            let route = &mut routes[current];
+            let (directive, args) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let args = args.trim();
+            match directive {
+                "request-header" => route
+                    .request_headers
+                    .push(parse_header_rule(line_number, args)?),
+                "response-header" => route
+                    .response_headers
+                    .push(parse_header_rule(line_number, args)?),
+                "security-headers" => match args {
+                    "on" => {
+                        route.security_headers.get_or_insert_with(Default::default);
+                    }
+                    "off" => route.security_headers = None,
+                    _ => return Err(Error::Parse(line_number, "expected on or off".to_string())),
+                },
+                "security-header" => parse_security_header(
+                    line_number,
+                    args,
+                    route.security_headers.get_or_insert_with(Default::default),
+                )?,
+                "rate-limit" => {
+                    route.rate_limit = Some(args.parse().map_err(|_| {
+                        Error::Parse(line_number, "expected a number of requests".to_string())
+                    })?)
+                }
+                "allow-methods" => {
+                    route.allowed_methods = Some(parse_methods(line_number, args)?);
+                }
+                "deny-methods" => route
+                    .denied_methods
+                    .extend(parse_methods(line_number, args)?),
+                _ if directive.starts_with("cors-") => parse_cors_directive(
+                    line_number,
+                    directive,
+                    args,
+                    route.cors.get_or_insert_with(Default::default),
+                )?,
+                _ => {
+                    return Err(Error::Parse(
+                        line_number,
+                        format!("unknown directive {:?}", directive),
+                    ))
+                }
+            }
+        }
+        Ok(Config { routes })
+    }
+
+    /// Returns the route with the longest prefix matching the given request path. A prefix only
+    /// matches whole path segments, so /api matches /api, /api/users and /api?page=2 but not
+    /// /apikeys. The path is normalized first, so that //api, /./api, /%61pi and /x/../api can't
+    /// be used to get around the route's settings.
+    pub fn route_for(&self, path: &str) -> Option<&Route> {
+        let path = normalize_path(path);
+        self.routes
+            .iter()
+            .filter(|route| prefix_matches(&route.prefix, &path))
+            .max_by_key(|route| route.prefix.len())
+    }
+}
+
+/// Returns a path as the upstream would resolve it: percent-escapes of unreserved characters are
+/// decoded, runs of slashes are collapsed, and . and .. segments are removed. A query string is
+/// left as it is.
+fn normalize_path(path: &str) -> String {
+    let (path, query) = match path.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (path, None),
+    };
+    let decoded = decode_unreserved(path);
+    let mut segments: Vec<&str> = Vec::new();
+    for segment in decoded.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            segment => segments.push(segment),
+        }
+    }
+    let mut normalized = format!("/{}", segments.join("/"));
+    // /api/ and /api are different prefixes, so a trailing slash is kept
+    let last = decoded.rsplit('/').next().unwrap_or("");
+    if !segments.is_empty() && matches!(last, "" | "." | "..") {
+        normalized.push('/');
+    }
+    if let Some(query) = query {
+        normalized.push('?');
+        normalized.push_str(query);
+    }
+    normalized
+}
+
+/// Decodes percent-escapes of unreserved characters (RFC 3986 section 2.3), which mean the same
+/// as the characters themselves. Other escapes, such as %2F, are left alone.
+fn decode_unreserved(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut idx = 0;
+    while idx < bytes.len() {
+        let escaped = bytes
+            .get(idx + 1..idx + 3)
+            .filter(|_| bytes[idx] == b'%')
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok())
+            .filter(|byte| byte.is_ascii_alphanumeric() || b"-._~".contains(byte));
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                idx += 3;
+            }
+            None => {
+                decoded.push(bytes[idx]);
+                idx += 1;
+            }
+        }
+    }
+    // Only ASCII bytes were decoded, so the path is still valid UTF-8
+    String::from_utf8(decoded).unwrap()
+}
+
+/// Returns whether path starts with prefix at a segment boundary: the prefix ends in a slash, or
+/// is followed in the path by a slash, a query string or nothing.
+fn prefix_matches(prefix: &str, path: &str) -> bool {
+    match path.strip_prefix(prefix) {
+        Some(rest) => {
+            prefix.ends_with('/')
+                || rest.is_empty()
+                || rest.starts_with('/')
+                || rest.starts_with('?')
+        }
+        None => false,
+    }
+}
+
+/// Parses the arguments of a request-header/response-header directive: `add NAME VALUE`,
+/// `set NAME VALUE` or `remove NAME`. The value is the rest of the line and may contain spaces.
+fn parse_header_rule(line_number: usize, args: &str) -> Result<HeaderRule, Error> {
+    let parse_error = |message: &str| Error::Parse(line_number, message.to_string());
+    let mut parts = args.splitn(3, char::is_whitespace);
+    let action = parts.next().unwrap_or("");
+    let name = parts
+        .next()
+        .ok_or_else(|| parse_error("missing header name"))?;
+    let name = http::HeaderName::from_bytes(name.as_bytes())
+        .map_err(|_| parse_error("invalid header name"))?;
+    let value = parts.next().map(str::trim);
+    let parse_value = |value: Option<&str>| match value {
+        Some(value) if !value.is_empty() => {
+            http::HeaderValue::from_str(value).map_err(|_| parse_error("invalid header value"))
+        }
+        _ => Err(parse_error("missing header value")),
+    };
+    match action {
+        "add" => Ok(HeaderRule::Add(name, parse_value(value)?)),
+        "set" => Ok(HeaderRule::Set(name, parse_value(value)?)),
+        "remove" => Ok(HeaderRule::Remove(name)),
+        _ => Err(parse_error("expected add, set or remove")),
+    }
+}
+
+/// Parses a comma-separated list of methods, as used by allow-methods and deny-methods. Methods
+/// are case-sensitive, so they are uppercased to match what clients send.
+fn parse_methods(line_number: usize, args: &str) -> Result<Vec<http::Method>, Error> {
+    let methods = args
+        .split(',')
+        .map(str::trim)
+        .filter(|method| !method.is_empty())
+        .map(|method| {
+            http::Method::from_bytes(method.to_ascii_uppercase().as_bytes())
+                .map_err(|_| Error::Parse(line_number, format!("invalid method {:?}", method)))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if methods.is_empty() {
+        return Err(Error::Parse(
+            line_number,
+            "expected a list of methods".to_string(),
+        ));
+    }
+    Ok(methods)
+}
+
+/// Parses the arguments of a security-header directive (`NAME VALUE` or `NAME off`) into
+/// `security_headers`.
+fn parse_security_header(
+    line_number: usize,
+    args: &str,
+    security_headers: &mut SecurityHeaders,
+) -> Result<(), Error> {
+    let parse_error = |message: &str| Error::Parse(line_number, message.to_string());
+    let (name, value) = args
+        .split_once(char::is_whitespace)
+        .ok_or_else(|| parse_error("expected security-header NAME VALUE"))?;
+    let value = match value.trim() {
+        "off" => None,
+        value => Some(
+            http::HeaderValue::from_str(value).map_err(|_| parse_error("invalid header value"))?,
+        ),
+    };
+    match name {
+        "hsts" => security_headers.hsts = value,
+        "content-type-options" => security_headers.content_type_options = value,
+        "frame-options" => security_headers.frame_options = value,
+        "referrer-policy" => security_headers.referrer_policy = value,
+        _ => {
+            return Err(parse_error(
+                "expected hsts, content-type-options, frame-options or referrer-policy",
+            ))
+        }
+    }
+    Ok(())
+}
+
+/// Parses a cors-* directive into `cors`.
+fn parse_cors_directive(
+    line_number: usize,
+    directive: &str,
+    args: &str,
+    cors: &mut CorsPolicy,
+) -> Result<(), Error> {
+    let parse_error = |message: &str| Error::Parse(line_number, message.to_string());
+    if args.is_empty() {
+        return Err(parse_error("missing value"));
+    }
+    let header_value = |value: &str| {
+        http::HeaderValue::from_str(value)
+            .map(|_| value.to_string())
+            .map_err(|_| parse_error("invalid header value"))
+    };
+    match directive {
+        "cors-origin" => cors.allowed_origins.push(header_value(args)?),
+        "cors-methods" => cors.allowed_methods = header_value(args)?,
+        "cors-headers" => cors.allowed_headers = Some(header_value(args)?),
+        "cors-max-age" => {
+            cors.max_age = Some(
+                args.parse()
+                    .map_err(|_| parse_error("expected a number of seconds"))?,
+            )
+        }
+        "cors-credentials" => {
+            cors.allow_credentials = match args {
+                "on" => true,
+                "off" => false,
+                _ => return Err(parse_error("expected on or off")),
+            }
+        }
+        _ => {
+            return Err(Error::Parse(
+                line_number,
+                format!("unknown directive {:?}", directive),
+            ))
+        }
+    }
+    Ok(())
+}
+
+/// Applies header rewrite rules, in order, to a set of request or response headers.
+pub fn apply_header_rules(rules: &[HeaderRule], headers: &mut http::HeaderMap) {
+    for rule in rules {
+        match rule {
+            HeaderRule::Add(name, value) => {
+                headers.append(name.clone(), value.clone());
+            }
+            HeaderRule::Set(name, value) => {
+                headers.insert(name.clone(), value.clone());
+            }
+            HeaderRule::Remove(name) => {
+                headers.remove(name);
+            }
+        }
+    }
+}
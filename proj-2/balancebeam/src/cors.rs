@@ -0,0 +1,123 @@
+/// CORS policy for a route, configured with cors-* directives in the config file (see config.rs).
+/// balancebeam answers preflight requests itself and adds Access-Control-* headers to proxied
+/// responses, so upstreams don't need to know about CORS at all.
+#[derive(Debug)]
+pub struct CorsPolicy {
+    /// Origins allowed to make cross-origin requests ("*" allows any origin)
+    pub allowed_origins: Vec<String>,
+    /// Value of Access-Control-Allow-Methods in preflight responses
+    pub allowed_methods: String,
+    /// Value of Access-Control-Allow-Headers in preflight responses, if any
+    pub allowed_headers: Option<String>,
+    /// How long browsers may cache preflight responses (in seconds)
+    pub max_age: Option<u64>,
+    /// Whether to send Access-Control-Allow-Credentials
+    pub allow_credentials: bool,
+}
+
+impl Default for CorsPolicy {
+    fn default() -> CorsPolicy {
+        CorsPolicy {
+            allowed_origins: Vec::new(),
+            allowed_methods: "GET, HEAD, POST".to_string(),
+            allowed_headers: None,
+            max_age: None,
+            allow_credentials: false,
+        }
+    }
+}
+
+/// Returns the request's Origin header, if it has one.
+pub fn origin(request: &http::Request<Vec<u8>>) -> Option<&str> {
+    request
+        .headers()
+        .get(http::header::ORIGIN)
+        .and_then(|origin| origin.to_str().ok())
+}
+
+/// Returns true for CORS preflight requests, which are OPTIONS requests carrying an Origin and an
+/// Access-Control-Request-Method header.
+pub fn is_preflight(request: &http::Request<Vec<u8>>) -> bool {
+    request.method() == http::Method::OPTIONS
+        && origin(request).is_some()
+        && request
+            .headers()
+            .contains_key(http::header::ACCESS_CONTROL_REQUEST_METHOD)
+}
+
+impl CorsPolicy {
+    fn allows(&self, origin: &str) -> bool {
+        self.allowed_origins
+            .iter()
+            .any(|allowed| allowed == "*" || allowed.eq_ignore_ascii_case(origin))
+    }
+
+    /// Value to send in Access-Control-Allow-Origin. A wildcard can't be combined with
+    /// credentials, so in that case the origin is echoed back instead.
+    fn allow_origin_value(&self, origin: &str) -> String {
+        let wildcard = self.allowed_origins.iter().any(|allowed| allowed == "*");
+        if wildcard && !self.allow_credentials {
+            "*".to_string()
+        } else {
+            origin.to_string()
+        }
+    }
+
+    /// Builds the response to a preflight request: a 204 with the policy's headers if the origin
+    /// is allowed, a 403 otherwise.
+    pub fn preflight_response(&self, origin: &str) -> http::Response<Vec<u8>> {
+        if !self.allows(origin) {
+            return crate::response::make_http_error(http::StatusCode::FORBIDDEN);
+        }
+        let mut response = http::Response::builder()
+            .status(http::StatusCode::NO_CONTENT)
+            .header(
+                http::header::ACCESS_CONTROL_ALLOW_METHODS,
+                &self.allowed_methods,
+            )
+            .version(http::Version::HTTP_11)
+            .body(Vec::new())
+            .unwrap();
+        let headers = response.headers_mut();
+        if let Some(allowed_headers) = &self.allowed_headers {
+            headers.insert(
+                http::header::ACCESS_CONTROL_ALLOW_HEADERS,
+                http::HeaderValue::from_str(allowed_headers).unwrap(),
+            );
+        }
+        if let Some(max_age) = self.max_age {
+            headers.insert(
+                http::header::ACCESS_CONTROL_MAX_AGE,
+                http::HeaderValue::from(max_age),
+            );
+        }
+        self.apply(origin, headers);
+        response
+    }
+
+    /// Adds Access-Control-Allow-Origin (and -Credentials) to a response if the origin is allowed.
+    pub fn apply(&self, origin: &str, headers: &mut http::HeaderMap) {
+        if !self.allows(origin) {
+            return;
+        }
+        let Ok(allow_origin) = http::HeaderValue::from_str(&self.allow_origin_value(origin)) else {
+            return;
+        };
+        headers.insert(http::header::ACCESS_CONTROL_ALLOW_ORIGIN, allow_origin);
+        if self.allow_credentials {
+            headers.insert(
+                http::header::ACCESS_CONTROL_ALLOW_CREDENTIALS,
+                http::HeaderValue::from_static("true"),
+            );
+        }
+        // The response depends on the Origin header, so caches must keep them apart
+        let vary = match headers.get(http::header::VARY) {
+            Some(existing) => [existing.as_bytes(), b", Origin"].concat(),
+            None => b"Origin".to_vec(),
+        };
+        headers.insert(
+            http::header::VARY,
+            http::HeaderValue::from_bytes(&vary).unwrap(),
+        );
+    }
+}
@@ -0,0 +1,589 @@
+use crate::{request, response};
+use rand::Rng;
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+
+/// How long a single DNS query may take
+const DNS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long Consul may hold a blocking query open before answering that nothing changed
+pub const CONSUL_WAIT: Duration = Duration::from_secs(60);
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const TYPE_SRV: u16 = 33;
+
+/// A place that upstreams are discovered from, given with --discover
+#[derive(Clone, Debug)]
+pub enum Source {
+    /// DNS SRV records for `name`, queried from `nameserver` (HOST:PORT) or else the first
+    /// nameserver in /etc/resolv.conf
+    Srv {
+        name: String,
+        nameserver: Option<String>,
+    },
+    /// Healthy instances of a service in a Consul catalog, watched through the HTTP API at
+    /// `address` (HOST:PORT)
+    Consul { address: String, service: String },
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Srv { name, .. } => write!(f, "srv:{}", name),
+            Source::Consul { address, service } => write!(f, "consul:{}/{}", address, service),
+        }
+    }
+}
+
+/// Parses a --discover value: srv:NAME[@NAMESERVER:PORT] or consul:HOST:PORT/SERVICE.
+pub fn parse_source(value: &str) -> Result<Source, String> {
+    if let Some(rest) = value.strip_prefix("srv:") {
+        let (name, nameserver) = match rest.split_once('@') {
+            Some((name, nameserver)) => (name, Some(nameserver.to_string())),
+            None => (rest, None),
+        };
+        if name.is_empty() {
+            return Err("expected srv:NAME[@NAMESERVER:PORT]".to_string());
+        }
+        Ok(Source::Srv {
+            name: name.trim_end_matches('.').to_string(),
+            nameserver,
+        })
+    } else if let Some(rest) = value.strip_prefix("consul:") {
+        match rest.split_once('/') {
+            Some((address, service)) if !address.is_empty() && !service.is_empty() => {
+                Ok(Source::Consul {
+                    address: address.to_string(),
+                    service: service.to_string(),
+                })
+            }
+            _ => Err("expected consul:HOST:PORT/SERVICE".to_string()),
+        }
+    } else {
+        Err(format!(
+            "unknown discovery source {:?} (expected srv:... or consul:...)",
+            value
+        ))
+    }
+}
+
+/// Fetches the current members of a source. `index` is only used by Consul sources; see
+/// consul_service.
+pub async fn lookup(source: &Source, index: Option<u64>) -> io::Result<(Vec<String>, Option<u64>)> {
+    match source {
+        Source::Srv { name, nameserver } => {
+            Ok((lookup_srv(name, nameserver.as_deref()).await?, None))
+        }
+        Source::Consul { address, service } => consul_service(address, service, index).await,
+    }
+}
+
+/// Looks up the SRV records for `name` and returns the addresses of their targets, sorted. Only
+/// the records with the lowest priority value are used, as RFC 2782 requires; weights are ignored,
+/// since balancebeam balances across all members itself.
+pub async fn lookup_srv(name: &str, nameserver: Option<&str>) -> io::Result<Vec<String>> {
+    let nameserver = match nameserver {
+        Some(nameserver) => nameserver.to_string(),
+        None => system_nameserver()?,
+    };
+    let response = tokio::time::timeout(DNS_TIMEOUT, query(&nameserver, name))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "DNS query timed out"))??;
+    let message = parse_message(&response)?;
+
+    let Some(priority) = message.records.iter().map(|srv| srv.priority).min() else {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no SRV records for {}", name),
+        ));
+    };
+    let mut members = Vec::new();
+    for srv in message
+        .records
+        .iter()
+        .filter(|srv| srv.priority == priority)
+    {
+        match message.addresses.get(&srv.target.to_ascii_lowercase()) {
+            // The nameserver usually includes the targets' addresses, saving another lookup
+            Some(addresses) => members.extend(
+                addresses
+                    .iter()
+                    .map(|ip| SocketAddr::new(*ip, srv.port).to_string()),
+            ),
+            None => match tokio::net::lookup_host((srv.target.as_str(), srv.port)).await {
+                Ok(addresses) => members.extend(addresses.map(|addr| addr.to_string())),
+                Err(err) => tracing::warn!("Could not resolve SRV target {}: {}", srv.target, err),
+            },
+        }
+    }
+    members.sort();
+    members.dedup();
+    Ok(members)
+}
+
+/// Returns the first nameserver listed in /etc/resolv.conf.
+fn system_nameserver() -> io::Result<String> {
+    let resolv_conf = std::fs::read_to_string("/etc/resolv.conf")?;
+    resolv_conf
+        .lines()
+        .find_map(|line| {
+            let mut fields = line.split_whitespace();
+            (fields.next() == Some("nameserver")).then(|| fields.next())?
+        })
+        .map(|ip| match ip.parse::<IpAddr>() {
+            Ok(ip) => SocketAddr::new(ip, 53).to_string(),
+            Err(_) => format!("{}:53", ip),
+        })
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no nameserver in resolv.conf"))
+}
+
+/// Sends an SRV query over UDP, retrying over TCP if the answer didn't fit in a datagram.
+async fn query(nameserver: &str, name: &str) -> io::Result<Vec<u8>> {
+    let id: u16 = rand::thread_rng().gen();
+    let mut packet = Vec::new();
+    packet.extend_from_slice(&id.to_be_bytes());
+    // Recursion desired, one question
+    packet.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid DNS name {:?}", name),
+            ));
+        }
+        packet.push(label.len() as u8);
+        packet.extend_from_slice(label.as_bytes());
+    }
+    packet.push(0);
+    packet.extend_from_slice(&TYPE_SRV.to_be_bytes());
+    packet.extend_from_slice(&1_u16.to_be_bytes());
+
+    let bind_addr = if nameserver.starts_with('[') {
+        "[::]:0"
+    } else {
+        "0.0.0.0:0"
+    };
+    let socket = UdpSocket::bind(bind_addr).await?;
+    socket.connect(nameserver).await?;
+    socket.send(&packet).await?;
+    let mut buffer = vec![0_u8; 4096];
+    let response = loop {
+        let len = socket.recv(&mut buffer).await?;
+        // Ignore stray datagrams that aren't answers to our query
+        if len >= 12 && buffer[0..2] == id.to_be_bytes() {
+            break buffer[..len].to_vec();
+        }
+    };
+    if response[2] & 0x02 == 0 {
+        return Ok(response);
+    }
+
+    tracing::debug!("DNS response for {} was truncated, retrying over TCP", name);
+    let mut stream = TcpStream::connect(nameserver).await?;
+    let mut framed = (packet.len() as u16).to_be_bytes().to_vec();
+    framed.extend_from_slice(&packet);
+    stream.write_all(&framed).await?;
+    let len = stream.read_u16().await? as usize;
+    let mut response = vec![0_u8; len];
+    stream.read_exact(&mut response).await?;
+    Ok(response)
+}
+
+struct SrvRecord {
+    priority: u16,
+    port: u16,
+    target: String,
+}
+
+/// The parts of a DNS response that SRV discovery needs
+struct Message {
+    records: Vec<SrvRecord>,
+    /// A and AAAA records, keyed by lowercased name
+    addresses: HashMap<String, Vec<IpAddr>>,
+}
+
+fn parse_message(data: &[u8]) -> io::Result<Message> {
+    let malformed = || io::Error::new(io::ErrorKind::InvalidData, "malformed DNS response");
+    let u16_at = |pos: usize| -> io::Result<u16> {
+        data.get(pos..pos + 2)
+            .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+            .ok_or_else(malformed)
+    };
+    if data.len() < 12 {
+        return Err(malformed());
+    }
+    match data[3] & 0x0f {
+        0 => {}
+        3 => return Err(io::Error::new(io::ErrorKind::NotFound, "no such domain")),
+        rcode => return Err(io::Error::other(format!("DNS error (rcode {})", rcode))),
+    }
+    let questions = u16_at(4)?;
+    let records = u16_at(6)? as usize + u16_at(8)? as usize + u16_at(10)? as usize;
+
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = read_name(data, pos)?.1 + 4;
+    }
+    let mut message = Message {
+        records: Vec::new(),
+        addresses: HashMap::new(),
+    };
+    // Answer, authority and additional records are all handled alike
+    for _ in 0..records {
+        let (name, next) = read_name(data, pos)?;
+        let record_type = u16_at(next)?;
+        let len = u16_at(next + 8)? as usize;
+        let start = next + 10;
+        let rdata = data.get(start..start + len).ok_or_else(malformed)?;
+        match record_type {
+            TYPE_SRV if len >= 7 => message.records.push(SrvRecord {
+                priority: u16_at(start)?,
+                port: u16_at(start + 4)?,
+                target: read_name(data, start + 6)?.0,
+            }),
+            TYPE_A if len == 4 => {
+                let ip = Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3]);
+                message
+                    .addresses
+                    .entry(name.to_ascii_lowercase())
+                    .or_default()
+                    .push(IpAddr::V4(ip));
+            }
+            TYPE_AAAA if len == 16 => {
+                let octets: [u8; 16] = rdata.try_into().unwrap();
+                message
+                    .addresses
+                    .entry(name.to_ascii_lowercase())
+                    .or_default()
+                    .push(IpAddr::V6(Ipv6Addr::from(octets)));
+            }
+            _ => {}
+        }
+        pos = start + len;
+    }
+    Ok(message)
+}
+
+/// Reads a possibly compressed domain name at `pos`. Returns the name (without a trailing dot)
+/// and the offset just past it.
+fn read_name(data: &[u8], mut pos: usize) -> io::Result<(String, usize)> {
+    let malformed = || io::Error::new(io::ErrorKind::InvalidData, "malformed DNS name");
+    let mut labels = Vec::new();
+    let mut end = None;
+    // Bounds the number of compression pointers followed, so a loop can't hang us
+    for _ in 0..128 {
+        let len = *data.get(pos).ok_or_else(malformed)? as usize;
+        if len == 0 {
+            return Ok((labels.join("."), end.unwrap_or(pos + 1)));
+        }
+        if len & 0xc0 == 0xc0 {
+            let low = *data.get(pos + 1).ok_or_else(malformed)? as usize;
+            end.get_or_insert(pos + 2);
+            pos = ((len & 0x3f) << 8) | low;
+            continue;
+        }
+        let label = data.get(pos + 1..pos + 1 + len).ok_or_else(malformed)?;
+        labels.push(String::from_utf8_lossy(label).into_owned());
+        pos += 1 + len;
+    }
+    Err(malformed())
+}
+
+/// Fetches the healthy instances of `service` from the Consul agent at `address`. If `index` is
+/// given, this is a blocking query: Consul holds the request until the service changes or
+/// CONSUL_WAIT passes. Returns the instances' addresses, sorted, and the index to pass to the next
+/// query.
+pub async fn consul_service(
+    address: &str,
+    service: &str,
+    index: Option<u64>,
+) -> io::Result<(Vec<String>, Option<u64>)> {
+    let mut path = format!("/v1/health/service/{}?passing", service);
+    if let Some(index) = index {
+        path += &format!("&index={}&wait={}s", index, CONSUL_WAIT.as_secs());
+    }
+    let mut request = http::Request::builder()
+        .method(http::Method::GET)
+        .uri(path)
+        .header("Host", address)
+        .header("Connection", "close")
+        .version(http::Version::HTTP_11);
+    if let Ok(token) = std::env::var("CONSUL_HTTP_TOKEN") {
+        request = request.header("X-Consul-Token", token);
+    }
+    let request = request
+        .body(Vec::new())
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err.to_string()))?;
+
+    let mut stream = TcpStream::connect(address).await?;
+    request::write_to_stream(&request, &mut stream).await?;
+    let response =
+        response::read_from_stream(&mut stream, request.method(), response::MAX_BODY_SIZE)
+            .await
+            .map_err(|err| io::Error::other(format!("{:?}", err)))?;
+    if !response.status().is_success() {
+        return Err(io::Error::other(format!(
+            "Consul returned {}",
+            response.status()
+        )));
+    }
+    let next_index = response
+        .headers()
+        .get("x-consul-index")
+        .and_then(|value| value.to_str().ok()?.parse::<u64>().ok());
+
+    let body = String::from_utf8_lossy(response.body());
+    let entries = json::parse(&body)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, format!("{}", err)))?;
+    let mut members = Vec::new();
+    for entry in entries.as_array().unwrap_or(&[]) {
+        let service = entry.get("Service");
+        let Some(port) = service
+            .and_then(|service| service.get("Port"))
+            .and_then(json::Value::as_u16)
+        else {
+            continue;
+        };
+        // Services registered without an address are reachable at their node's address
+        let host = service
+            .and_then(|service| service.get("Address"))
+            .and_then(json::Value::as_str)
+            .filter(|host| !host.is_empty())
+            .or_else(|| entry.get("Node")?.get("Address")?.as_str());
+        match host.map(|host| (host, host.parse::<IpAddr>())) {
+            Some((_, Ok(ip))) => members.push(SocketAddr::new(ip, port).to_string()),
+            Some((host, Err(_))) => members.push(format!("{}:{}", host, port)),
+            None => {}
+        }
+    }
+    members.sort();
+    members.dedup();
+    Ok((members, next_index))
+}
+
+/// Just enough of a JSON parser to read Consul API responses
+mod json {
+    use std::fmt;
+
+    #[derive(Debug)]
+    pub enum Value {
+        /// null, true and false, which nothing we read needs to tell apart
+        Other,
+        Number(f64),
+        String(String),
+        Array(Vec<Value>),
+        Object(Vec<(String, Value)>),
+    }
+
+    impl Value {
+        pub fn get(&self, key: &str) -> Option<&Value> {
+            match self {
+                Value::Object(entries) => entries.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+                _ => None,
+            }
+        }
+
+        pub fn as_array(&self) -> Option<&[Value]> {
+            match self {
+                Value::Array(items) => Some(items),
+                _ => None,
+            }
+        }
+
+        pub fn as_str(&self) -> Option<&str> {
+            match self {
+                Value::String(value) => Some(value),
+                _ => None,
+            }
+        }
+
+        pub fn as_u16(&self) -> Option<u16> {
+            match self {
+                Value::Number(value) if value.fract() == 0.0 && (1.0..=65535.0).contains(value) => {
+                    Some(*value as u16)
+                }
+                _ => None,
+            }
+        }
+    }
+
+    #[derive(Debug)]
+    pub struct Error(usize, &'static str);
+
+    impl fmt::Display for Error {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "invalid JSON at offset {}: {}", self.0, self.1)
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<Value, Error> {
+        let mut parser = Parser {
+            text: text.as_bytes(),
+            pos: 0,
+        };
+        let value = parser.value(0)?;
+        parser.whitespace();
+        if parser.pos != parser.text.len() {
+            return Err(parser.error("trailing characters"));
+        }
+        Ok(value)
+    }
+
+    struct Parser<'a> {
+        text: &'a [u8],
+        pos: usize,
+    }
+
+    impl Parser<'_> {
+        fn error(&self, message: &'static str) -> Error {
+            Error(self.pos, message)
+        }
+
+        fn whitespace(&mut self) {
+            while self
+                .text
+                .get(self.pos)
+                .is_some_and(|c| c.is_ascii_whitespace())
+            {
+                self.pos += 1;
+            }
+        }
+
+        fn expect(&mut self, literal: &str) -> Result<(), Error> {
+            if self.text[self.pos..].starts_with(literal.as_bytes()) {
+                self.pos += literal.len();
+                Ok(())
+            } else {
+                Err(self.error("unexpected character"))
+            }
+        }
+
+        fn value(&mut self, depth: usize) -> Result<Value, Error> {
+            if depth > 64 {
+                return Err(self.error("nested too deeply"));
+            }
+            self.whitespace();
+            match self.text.get(self.pos) {
+                Some(b'n') => self.expect("null").map(|_| Value::Other),
+                Some(b't') => self.expect("true").map(|_| Value::Other),
+                Some(b'f') => self.expect("false").map(|_| Value::Other),
+                Some(b'"') => self.string().map(Value::String),
+                Some(b'[') => {
+                    self.pos += 1;
+                    let mut items = Vec::new();
+                    self.whitespace();
+                    if self.text.get(self.pos) == Some(&b']') {
+                        self.pos += 1;
+                        return Ok(Value::Array(items));
+                    }
+                    loop {
+                        items.push(self.value(depth + 1)?);
+                        self.whitespace();
+                        match self.text.get(self.pos) {
+                            Some(b',') => self.pos += 1,
+                            Some(b']') => {
+                                self.pos += 1;
+                                return Ok(Value::Array(items));
+                            }
+                            _ => return Err(self.error("expected , or ]")),
+                        }
+                    }
+                }
+                Some(b'{') => {
+                    self.pos += 1;
+                    let mut entries = Vec::new();
+                    self.whitespace();
+                    if self.text.get(self.pos) == Some(&b'}') {
+                        self.pos += 1;
+                        return Ok(Value::Object(entries));
+                    }
+                    loop {
+                        self.whitespace();
+                        if self.text.get(self.pos) != Some(&b'"') {
+                            return Err(self.error("expected a key"));
+                        }
+                        let key = self.string()?;
+                        self.whitespace();
+                        self.expect(":")?;
+                        entries.push((key, self.value(depth + 1)?));
+                        self.whitespace();
+                        match self.text.get(self.pos) {
+                            Some(b',') => self.pos += 1,
+                            Some(b'}') => {
+                                self.pos += 1;
+                                return Ok(Value::Object(entries));
+                            }
+                            _ => return Err(self.error("expected , or }")),
+                        }
+                    }
+                }
+                Some(b'-' | b'0'..=b'9') => {
+                    let start = self.pos;
+                    while self.text.get(self.pos).is_some_and(|c| {
+                        matches!(c, b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9')
+                    }) {
+                        self.pos += 1;
+                    }
+                    std::str::from_utf8(&self.text[start..self.pos])
+                        .ok()
+                        .and_then(|number| number.parse::<f64>().ok())
+                        .map(Value::Number)
+                        .ok_or(Error(start, "invalid number"))
+                }
+                _ => Err(self.error("expected a value")),
+            }
+        }
+
+        fn string(&mut self) -> Result<String, Error> {
+            // Skip the opening quote
+            self.pos += 1;
+            let mut bytes = Vec::new();
+            loop {
+                let c = *self
+                    .text
+                    .get(self.pos)
+                    .ok_or(self.error("unterminated string"))?;
+                self.pos += 1;
+                match c {
+                    b'"' => return Ok(String::from_utf8_lossy(&bytes).into_owned()),
+                    b'\\' => {
+                        let escape = *self
+                            .text
+                            .get(self.pos)
+                            .ok_or(self.error("unterminated string"))?;
+                        self.pos += 1;
+                        match escape {
+                            b'"' | b'\\' | b'/' => bytes.push(escape),
+                            b'b' => bytes.push(0x08),
+                            b'f' => bytes.push(0x0c),
+                            b'n' => bytes.push(b'\n'),
+                            b'r' => bytes.push(b'\r'),
+                            b't' => bytes.push(b'\t'),
+                            b'u' => {
+                                let hex = self
+                                    .text
+                                    .get(self.pos..self.pos + 4)
+                                    .and_then(|hex| std::str::from_utf8(hex).ok())
+                                    .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                                    .ok_or(self.error("invalid \\u escape"))?;
+                                self.pos += 4;
+                                // Surrogate pairs are replaced rather than decoded; nothing we
+                                // read from Consul needs them
+                                let c = char::from_u32(hex).unwrap_or('\u{fffd}');
+                                bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+                            }
+                            _ => return Err(self.error("invalid escape")),
+                        }
+                    }
+                    _ => bytes.push(c),
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,26 @@
+use std::io;
+use std::net::SocketAddr;
+
+/// Returns true if an upstream is given as HOST:PORT with a hostname rather than an IP address,
+/// and so has to be resolved to find its members.
+pub fn is_hostname(address: &str) -> bool {
+    address.parse::<SocketAddr>().is_err()
+}
+
+/// Resolves a HOST:PORT upstream to the addresses it currently points to. The addresses are
+/// sorted and deduplicated so that successive lookups can be compared.
+pub async fn resolve(address: &str) -> io::Result<Vec<String>> {
+    let mut members: Vec<String> = tokio::net::lookup_host(address)
+        .await?
+        .map(|addr| addr.to_string())
+        .collect();
+    members.sort();
+    members.dedup();
+    if members.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} did not resolve to any addresses", address),
+        ));
+    }
+    Ok(members)
+}
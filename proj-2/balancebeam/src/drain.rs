@@ -0,0 +1,68 @@
+use std::collections::HashSet;
+use std::sync::RwLock;
+
+/// Upstreams being drained: no new requests are routed to them, but requests already in flight
+/// there are left to finish, after which the upstream can be taken down without failing anyone.
+/// Upstreams are drained through the admin API, or by listing them in --drain-file, which is
+/// reread on SIGUSR1. The two are kept apart, so that rereading the file doesn't undo drains
+/// started through the admin API.
+#[derive(Default)]
+pub struct Drains {
+    /// Upstreams drained through the admin API
+    admin: RwLock<HashSet<String>>,
+    /// Upstreams listed in the drain file when it was last read
+    file: RwLock<HashSet<String>>,
+}
+
+impl Drains {
+    pub fn is_draining(&self, upstream: &str) -> bool {
+        self.admin.read().unwrap().contains(upstream)
+            || self.file.read().unwrap().contains(upstream)
+    }
+
+    /// Starts draining an upstream. Returns false if it was already being drained through the
+    /// admin API.
+    pub fn start(&self, upstream: &str) -> bool {
+        self.admin.write().unwrap().insert(upstream.to_string())
+    }
+
+    /// Stops draining an upstream drained through the admin API. An upstream listed in the drain
+    /// file stays drained until it is removed from the file.
+    pub fn stop(&self, upstream: &str) -> bool {
+        self.admin.write().unwrap().remove(upstream)
+    }
+
+    /// Returns every upstream being drained, sorted.
+    pub fn draining(&self) -> Vec<String> {
+        let mut upstreams: Vec<String> = self
+            .admin
+            .read()
+            .unwrap()
+            .union(&self.file.read().unwrap())
+            .cloned()
+            .collect();
+        upstreams.sort();
+        upstreams
+    }
+
+    /// Replaces the upstreams drained through the drain file with the ones listed in it now.
+    /// Returns the number of upstreams listed.
+    pub fn load_file(&self, path: &str) -> Result<usize, String> {
+        let upstreams = read_file(path)?;
+        let count = upstreams.len();
+        *self.file.write().unwrap() = upstreams;
+        Ok(count)
+    }
+}
+
+/// Reads a drain file: one upstream address per line. Blank lines and lines starting with # are
+/// ignored.
+fn read_file(path: &str) -> Result<HashSet<String>, String> {
+    let contents = std::fs::read_to_string(path).map_err(|err| err.to_string())?;
+    Ok(contents
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| line.to_string())
+        .collect())
+}
@@ -0,0 +1,38 @@
+//! Parsing of durations given on the command line, shared by balancebeam and the test server
+
+use std::time::Duration;
+
+/// Parses a duration such as 500ms, 2s, 1.5m or 1h. Numbers without a unit are seconds.
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    parse_duration_with_default_unit(value, "s")
+}
+
+/// Parses a duration like parse_duration, except that numbers without a unit are milliseconds.
+/// Used for options that were given in milliseconds before units were accepted.
+pub fn parse_duration_ms(value: &str) -> Result<Duration, String> {
+    parse_duration_with_default_unit(value, "ms")
+}
+
+fn parse_duration_with_default_unit(value: &str, default_unit: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number = number
+        .parse::<f64>()
+        .map_err(|_| format!("invalid duration {:?}", value))?;
+    let unit = match unit.trim() {
+        "" => default_unit,
+        unit => unit,
+    };
+    let seconds_per_unit = match unit.to_ascii_lowercase().as_str() {
+        "ms" => 0.001,
+        "s" | "sec" | "secs" => 1.0,
+        "m" | "min" | "mins" => 60.0,
+        "h" | "hr" | "hrs" => 3600.0,
+        _ => return Err(format!("unknown duration unit {:?}", unit)),
+    };
+    Duration::try_from_secs_f64(number * seconds_per_unit)
+        .map_err(|_| format!("duration {:?} is too large", value))
+}
@@ -0,0 +1,117 @@
+use crate::response;
+use std::collections::HashMap;
+use std::fs;
+
+/// File extensions and content types error pages can be rendered in, in order of preference when
+/// a client accepts several equally
+const FORMATS: [(&str, &str); 2] = [("html", "text/html"), ("json", "application/json")];
+
+/// Templates for the error responses balancebeam generates itself (502, 429, 400, ...), loaded
+/// from the directory passed with --error-page-dir. A template named `502.html` or `502.json` is
+/// used for that status; `error.html` and `error.json` are used for any status without a more
+/// specific template. In templates, `{{status}}` is replaced with the numeric status code and
+/// `{{reason}}` with its reason phrase.
+///
+/// Which format is sent depends on the request's Accept header. If the client doesn't accept any
+/// of the formats there are templates for, the plain-text response from
+/// `response::make_http_error` is sent instead.
+#[derive(Debug, Default)]
+pub struct ErrorPages {
+    /// Template contents keyed by (status code or "error", file extension)
+    templates: HashMap<(String, String), String>,
+}
+
+impl ErrorPages {
+    pub fn from_dir(dir: &str) -> Result<ErrorPages, std::io::Error> {
+        let mut templates = HashMap::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let (Some(stem), Some(extension)) = (
+                path.file_stem().and_then(|stem| stem.to_str()),
+                path.extension().and_then(|extension| extension.to_str()),
+            ) else {
+                continue;
+            };
+            let is_known_format = FORMATS.iter().any(|(ext, _)| *ext == extension);
+            let is_known_name = stem == "error" || stem.parse::<http::StatusCode>().is_ok();
+            if is_known_format && is_known_name {
+                tracing::debug!("Loaded error page template {}", path.display());
+                templates.insert(
+                    (stem.to_string(), extension.to_string()),
+                    fs::read_to_string(&path)?,
+                );
+            }
+        }
+        Ok(ErrorPages { templates })
+    }
+
+    /// Builds the error response for a status, picking a template based on the request's Accept
+    /// header (if we have a request at all).
+    pub fn render(
+        &self,
+        status: http::StatusCode,
+        accept: Option<&str>,
+    ) -> http::Response<Vec<u8>> {
+        let accept = accept.unwrap_or("*/*");
+        let mut best: Option<(f32, &str, &String)> = None;
+        for (extension, content_type) in FORMATS {
+            let Some(template) = self
+                .templates
+                .get(&(status.as_str().to_string(), extension.to_string()))
+                .or_else(|| {
+                    self.templates
+                        .get(&("error".to_string(), extension.to_string()))
+                })
+            else {
+                continue;
+            };
+            let quality = accept_quality(accept, content_type);
+            if quality > 0.0 && best.is_none_or(|(best_quality, ..)| quality > best_quality) {
+                best = Some((quality, content_type, template));
+            }
+        }
+
+        let Some((_, content_type, template)) = best else {
+            return response::make_http_error(status);
+        };
+        let body = template
+            .replace("{{status}}", status.as_str())
+            .replace("{{reason}}", status.canonical_reason().unwrap_or(""))
+            .into_bytes();
+        http::Response::builder()
+            .status(status)
+            .header("Content-Type", content_type)
+            .header("Content-Length", body.len().to_string())
+            .version(http::Version::HTTP_11)
+            .body(body)
+            .unwrap()
+    }
+}
+
+/// Returns the quality value (0 to 1) an Accept header assigns to a content type, taking the most
+/// specific matching media range: `type/subtype` beats `type/*`, which beats `*/*`.
+fn accept_quality(accept: &str, content_type: &str) -> f32 {
+    let (main_type, _) = content_type.split_once('/').unwrap_or((content_type, ""));
+    let mut best: Option<(u8, f32)> = None;
+    for media_range in accept.split(',') {
+        let mut params = media_range.split(';');
+        let range = params.next().unwrap_or("").trim().to_ascii_lowercase();
+        let specificity = if range == content_type {
+            2
+        } else if range == format!("{}/*", main_type) {
+            1
+        } else if range == "*/*" {
+            0
+        } else {
+            continue;
+        };
+        let quality = params
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .find_map(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        if best.is_none_or(|(best_specificity, _)| specificity > best_specificity) {
+            best = Some((specificity, quality));
+        }
+    }
+    best.map_or(0.0, |(_, quality)| quality)
+}
@@ -0,0 +1,126 @@
+use ipnet::IpNet;
+use std::net::IpAddr;
+
+/// What to do with the X-Forwarded-For header sent upstream
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+pub enum ForwardedForMode {
+    /// Append the peer's address to whatever chain the request arrived with
+    Append,
+    /// Send only the real client address (as determined from trusted proxies)
+    Replace,
+    /// Don't send X-Forwarded-For at all
+    Strip,
+}
+
+/// Parses a --trusted-proxies entry. A bare address is treated as a single-host network.
+pub fn parse_cidr(value: &str) -> Result<IpNet, String> {
+    value
+        .parse::<IpNet>()
+        .or_else(|_| value.parse::<IpAddr>().map(IpNet::from))
+        .map_err(|_| format!("expected an IP address or CIDR, got {:?}", value))
+}
+
+pub fn is_trusted(addr: &IpAddr, trusted_proxies: &[IpNet]) -> bool {
+    trusted_proxies.iter().any(|net| net.contains(addr))
+}
+
+/// Determines the address of the client that originated a request. If the peer we're talking to
+/// is a trusted proxy, the X-Forwarded-For chain is walked from the right, skipping trusted
+/// proxies, and the first untrusted address is the client. Otherwise the header could have been
+/// forged by the peer, and the peer itself is the client.
+pub fn client_ip(
+    request: &http::Request<Vec<u8>>,
+    peer_ip: IpAddr,
+    trusted_proxies: &[IpNet],
+) -> IpAddr {
+    if !is_trusted(&peer_ip, trusted_proxies) {
+        return peer_ip;
+    }
+    let chain: Vec<IpAddr> = request
+        .headers()
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map_while(|addr| addr.trim().parse::<IpAddr>().ok())
+        .collect();
+    let mut client = peer_ip;
+    for addr in chain.into_iter().rev() {
+        client = addr;
+        if !is_trusted(&addr, trusted_proxies) {
+            break;
+        }
+    }
+    client
+}
+
+/// Rewrites X-Forwarded-For according to `mode`, and sets X-Forwarded-Proto, X-Forwarded-Host and
+/// X-Forwarded-Port to describe the request as the client made it, so that upstreams can build
+/// absolute URLs that work from the outside. Forwarding headers set by a trusted proxy in front of
+/// us are kept; anything else the peer sent is replaced.
+pub fn set_forwarding_headers(
+    request: &mut http::Request<Vec<u8>>,
+    peer_ip: IpAddr,
+    client_ip: IpAddr,
+    trusted_proxies: &[IpNet],
+    mode: ForwardedForMode,
+    local_port: Option<u16>,
+    client_host: Option<&str>,
+) {
+    match mode {
+        ForwardedForMode::Append => {
+            crate::request::extend_header_value(request, "x-forwarded-for", &peer_ip.to_string())
+        }
+        ForwardedForMode::Replace => {
+            let value = http::HeaderValue::from_str(&client_ip.to_string()).unwrap();
+            request.headers_mut().insert("x-forwarded-for", value);
+        }
+        ForwardedForMode::Strip => {
+            request.headers_mut().remove("x-forwarded-for");
+        }
+    }
+
+    let headers = request.headers_mut();
+    let peer_trusted = is_trusted(&peer_ip, trusted_proxies);
+    let keep = |headers: &http::HeaderMap, name: &str| peer_trusted && headers.contains_key(name);
+    if !keep(headers, "x-forwarded-proto") {
+        // balancebeam doesn't terminate TLS, so clients always talk plain HTTP to us
+        headers.insert("x-forwarded-proto", http::HeaderValue::from_static("http"));
+    }
+    if !keep(headers, "x-forwarded-host") {
+        match client_host.and_then(|host| http::HeaderValue::from_str(host).ok()) {
+            Some(host) => {
+                headers.insert("x-forwarded-host", host);
+            }
+            None => {
+                headers.remove("x-forwarded-host");
+            }
+        }
+    }
+    if !keep(headers, "x-forwarded-port") {
+        match local_port {
+            Some(port) => {
+                headers.insert("x-forwarded-port", http::HeaderValue::from(port));
+            }
+            None => {
+                headers.remove("x-forwarded-port");
+            }
+        }
+    }
+}
+
+/// Appends balancebeam to the Via header of a forwarded request or response, as RFC 7230 section
+/// 5.7.1 requires of proxies. The protocol version is that of the message we received.
+pub fn append_via(headers: &mut http::HeaderMap, version: http::Version) {
+    let protocol = match version {
+        http::Version::HTTP_09 => "0.9",
+        http::Version::HTTP_10 => "1.0",
+        _ => "1.1",
+    };
+    let via = format!("{} balancebeam", protocol);
+    let value = match headers.get("via") {
+        Some(existing) => [existing.as_bytes(), b", ", via.as_bytes()].concat(),
+        None => via.into_bytes(),
+    };
+    headers.insert("via", http::HeaderValue::from_bytes(&value).unwrap());
+}
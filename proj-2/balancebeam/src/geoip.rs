@@ -0,0 +1,263 @@
+use std::fmt;
+use std::net::IpAddr;
+
+/// Marks the start of the metadata section at the end of an MMDB file
+const METADATA_MARKER: &[u8] = b"\xab\xcd\xefMaxMind.com";
+
+/// A MaxMind DB (.mmdb) file, such as GeoLite2-Country, used to look up the country of client
+/// addresses. Only as much of the format as country lookups need is implemented; see
+/// https://maxmind.github.io/MaxMind-DB/ for the specification.
+pub struct GeoIpDb {
+    data: Vec<u8>,
+    node_count: usize,
+    record_size: usize,
+    ip_version: u64,
+    /// Node to start IPv4 lookups from in an IPv6 tree (the node reached after 96 zero bits)
+    ipv4_start: usize,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(std::io::Error),
+    /// The file isn't a valid MMDB file; contains a description of the problem
+    Format(&'static str),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "{}", err),
+            Error::Format(message) => write!(f, "invalid MaxMind DB: {}", message),
+        }
+    }
+}
+
+/// The subset of MMDB data types we decode
+#[derive(Debug)]
+enum Value {
+    String(String),
+    Uint(u128),
+    Map(Vec<(String, Value)>),
+    /// Types country lookups never need (arrays, doubles, bytes, ...)
+    Other,
+}
+
+impl Value {
+    fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Map(entries) => entries.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    fn as_uint(&self) -> Option<u128> {
+        match self {
+            Value::Uint(value) => Some(*value),
+            _ => None,
+        }
+    }
+}
+
+impl GeoIpDb {
+    pub fn open(path: &str) -> Result<GeoIpDb, Error> {
+        GeoIpDb::from_bytes(std::fs::read(path).map_err(Error::Io)?)
+    }
+
+    pub fn from_bytes(data: Vec<u8>) -> Result<GeoIpDb, Error> {
+        let metadata_start = data
+            .windows(METADATA_MARKER.len())
+            .rposition(|window| window == METADATA_MARKER)
+            .ok_or(Error::Format("metadata marker not found"))?
+            + METADATA_MARKER.len();
+        let (metadata, _) = decode(&data, metadata_start, metadata_start)?;
+        let field = |name| {
+            metadata
+                .get(name)
+                .and_then(Value::as_uint)
+                .ok_or(Error::Format("missing metadata field"))
+        };
+        let node_count = field("node_count")? as usize;
+        let record_size = field("record_size")? as usize;
+        let ip_version = field("ip_version")? as u64;
+        if ![24, 28, 32].contains(&record_size) {
+            return Err(Error::Format("unsupported record size"));
+        }
+        if node_count * record_size / 4 > metadata_start {
+            return Err(Error::Format("search tree is larger than the file"));
+        }
+
+        let mut db = GeoIpDb {
+            data,
+            node_count,
+            record_size,
+            ip_version,
+            ipv4_start: 0,
+        };
+        if ip_version == 6 {
+            let mut node = 0;
+            for _ in 0..96 {
+                if node >= node_count {
+                    break;
+                }
+                node = db.read_record(node, 0);
+            }
+            db.ipv4_start = node;
+        }
+        Ok(db)
+    }
+
+    /// Returns the ISO 3166 country code of an address, if the database knows it.
+    pub fn country(&self, addr: IpAddr) -> Option<String> {
+        let record = self.lookup(addr)?;
+        ["country", "registered_country"]
+            .iter()
+            .find_map(|key| record.get(key)?.get("iso_code")?.as_str())
+            .map(|code| code.to_string())
+    }
+
+    fn lookup(&self, addr: IpAddr) -> Option<Value> {
+        let (bits, mut node): (Vec<u8>, usize) = match addr {
+            IpAddr::V4(v4) if self.ip_version == 6 => (v4.octets().to_vec(), self.ipv4_start),
+            IpAddr::V4(v4) => (v4.octets().to_vec(), 0),
+            IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+                Some(v4) => return self.lookup(IpAddr::V4(v4)),
+                None if self.ip_version == 4 => return None,
+                None => (v6.octets().to_vec(), 0),
+            },
+        };
+        for bit in 0..bits.len() * 8 {
+            if node >= self.node_count {
+                break;
+            }
+            let direction = (bits[bit / 8] >> (7 - bit % 8)) & 1;
+            node = self.read_record(node, direction as usize);
+        }
+        if node <= self.node_count {
+            // node_count itself means "no data"
+            return None;
+        }
+        let data_section = self.node_count * self.record_size / 4 + 16;
+        let offset = data_section + (node - self.node_count - 16);
+        decode(&self.data, offset, data_section)
+            .ok()
+            .map(|(value, _)| value)
+    }
+
+    /// Reads the left (0) or right (1) record of a search tree node.
+    fn read_record(&self, node: usize, direction: usize) -> usize {
+        let node_bytes = self.record_size / 4;
+        let b = &self.data[node * node_bytes..(node + 1) * node_bytes];
+        let be = |bytes: &[u8]| bytes.iter().fold(0, |acc, b| (acc << 8) | *b as usize);
+        match (self.record_size, direction) {
+            (24, 0) => be(&b[0..3]),
+            (24, _) => be(&b[3..6]),
+            (28, 0) => ((b[3] as usize & 0xf0) << 20) | be(&b[0..3]),
+            (28, _) => ((b[3] as usize & 0x0f) << 24) | be(&b[4..7]),
+            (_, 0) => be(&b[0..4]),
+            (_, _) => be(&b[4..8]),
+        }
+    }
+}
+
+/// Decodes the data field at `offset`. Pointers are resolved relative to `section_start`. Returns
+/// the value and the offset just past the field.
+fn decode(data: &[u8], offset: usize, section_start: usize) -> Result<(Value, usize), Error> {
+    let truncated = Error::Format("truncated data field");
+    let byte = |idx: usize| {
+        data.get(idx)
+            .copied()
+            .ok_or(Error::Format("truncated data field"))
+    };
+    let be = |start: usize, len: usize| -> Result<u128, Error> {
+        let bytes = data
+            .get(start..start + len)
+            .ok_or(Error::Format("truncated data field"))?;
+        Ok(bytes.iter().fold(0, |acc, b| (acc << 8) | *b as u128))
+    };
+
+    let control = byte(offset)?;
+    let mut pos = offset + 1;
+    let mut data_type = control >> 5;
+    if data_type == 1 {
+        // Pointer: the size bits encode the pointer itself
+        let size = ((control >> 3) & 0x3) as usize;
+        let low = (control & 0x7) as u128;
+        let target = match size {
+            0 => (low << 8) | be(pos, 1)?,
+            1 => ((low << 16) | be(pos, 2)?) + 2048,
+            2 => ((low << 24) | be(pos, 3)?) + 526336,
+            _ => be(pos, 4)?,
+        } as usize;
+        let (value, _) = decode(data, section_start + target, section_start)?;
+        return Ok((value, pos + size + 1));
+    }
+    if data_type == 0 {
+        data_type = 7 + byte(pos)?;
+        pos += 1;
+    }
+    let mut size = (control & 0x1f) as usize;
+    match size {
+        29 => {
+            size = 29 + be(pos, 1)? as usize;
+            pos += 1;
+        }
+        30 => {
+            size = 285 + be(pos, 2)? as usize;
+            pos += 2;
+        }
+        31 => {
+            size = 65821 + be(pos, 3)? as usize;
+            pos += 3;
+        }
+        _ => {}
+    }
+
+    match data_type {
+        // UTF-8 string
+        2 => {
+            let bytes = data.get(pos..pos + size).ok_or(truncated)?;
+            Ok((
+                Value::String(String::from_utf8_lossy(bytes).into_owned()),
+                pos + size,
+            ))
+        }
+        // Unsigned integers of various widths
+        5 | 6 | 9 | 10 => Ok((Value::Uint(be(pos, size)?), pos + size)),
+        // Map
+        7 => {
+            let mut entries = Vec::with_capacity(size);
+            for _ in 0..size {
+                let (key, next) = decode(data, pos, section_start)?;
+                let (value, next) = decode(data, next, section_start)?;
+                let Value::String(key) = key else {
+                    return Err(Error::Format("map key is not a string"));
+                };
+                entries.push((key, value));
+                pos = next;
+            }
+            Ok((Value::Map(entries), pos))
+        }
+        // Array: the items still have to be decoded to find where the array ends
+        11 => {
+            for _ in 0..size {
+                pos = decode(data, pos, section_start)?.1;
+            }
+            Ok((Value::Other, pos))
+        }
+        // Double and float have fixed sizes
+        3 => Ok((Value::Other, pos + 8)),
+        15 => Ok((Value::Other, pos + 4)),
+        // Booleans keep their value in the size bits
+        14 => Ok((Value::Other, pos)),
+        // Bytes, int32, data cache container, end marker
+        4 | 8 | 12 | 13 => Ok((Value::Other, pos + size)),
+        _ => Err(Error::Format("unknown data type")),
+    }
+}
@@ -0,0 +1,413 @@
+use std::fmt;
+
+/// Lengths and distances of DEFLATE back-references: the base value for each code, and how many
+/// extra bits follow it
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+/// Order in which the code length code lengths of a dynamic block are sent
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+#[derive(Debug)]
+pub enum Error {
+    /// The data isn't valid gzip; contains a description of the problem
+    Format(&'static str),
+    /// The decompressed data is bigger than the allowed size
+    TooLarge,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Format(message) => write!(f, "invalid gzip data: {}", message),
+            Error::TooLarge => write!(f, "decompressed data is too large"),
+        }
+    }
+}
+
+/// If the request body is gzip-encoded (the last coding in its Content-Encoding header is gzip),
+/// decompresses it in place and updates Content-Encoding and Content-Length to match. Requests
+/// with other encodings are left alone.
+pub fn decompress_request(
+    request: &mut http::Request<Vec<u8>>,
+    max_size: usize,
+) -> Result<(), Error> {
+    let Some(encoding) = request
+        .headers()
+        .get(http::header::CONTENT_ENCODING)
+        .and_then(|value| value.to_str().ok())
+    else {
+        return Ok(());
+    };
+    let mut codings: Vec<&str> = encoding
+        .split(',')
+        .map(str::trim)
+        .filter(|coding| !coding.is_empty())
+        .collect();
+    // Codings are listed in the order they were applied, so gzip has to be the last one
+    if !codings.last().is_some_and(|coding| {
+        coding.eq_ignore_ascii_case("gzip") || coding.eq_ignore_ascii_case("x-gzip")
+    }) {
+        return Ok(());
+    }
+    codings.pop();
+    let remaining = codings.join(", ");
+
+    let body = decompress(request.body(), max_size)?;
+    let headers = request.headers_mut();
+    if remaining.is_empty() {
+        headers.remove(http::header::CONTENT_ENCODING);
+    } else {
+        headers.insert(
+            http::header::CONTENT_ENCODING,
+            http::HeaderValue::from_str(&remaining).unwrap(),
+        );
+    }
+    headers.insert(http::header::CONTENT_LENGTH, body.len().into());
+    *request.body_mut() = body;
+    Ok(())
+}
+
+/// Decompresses gzip data (RFC 1952), which may consist of several concatenated members. Fails
+/// with Error::TooLarge as soon as the output grows past `max_size`, so that a small compressed
+/// body can't make us allocate an arbitrary amount of memory.
+pub fn decompress(data: &[u8], max_size: usize) -> Result<Vec<u8>, Error> {
+    if data.is_empty() {
+        return Err(Error::Format("no data"));
+    }
+    let mut output = Vec::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        let member_start = output.len();
+        pos = skip_header(data, pos)?;
+        let mut bits = BitReader::new(data, pos);
+        inflate(&mut bits, &mut output, max_size)?;
+        pos = bits.byte_position();
+
+        let trailer = data
+            .get(pos..pos + 8)
+            .ok_or(Error::Format("truncated trailer"))?;
+        let crc = u32::from_le_bytes(trailer[0..4].try_into().unwrap());
+        let size = u32::from_le_bytes(trailer[4..8].try_into().unwrap());
+        let member = &output[member_start..];
+        if crc32(member) != crc {
+            return Err(Error::Format("CRC mismatch"));
+        }
+        if member.len() as u32 != size {
+            return Err(Error::Format("size mismatch"));
+        }
+        pos += 8;
+    }
+    Ok(output)
+}
+
+/// Checks the gzip member header at `pos` and returns the offset of the compressed data after it.
+fn skip_header(data: &[u8], pos: usize) -> Result<usize, Error> {
+    const FHCRC: u8 = 0x02;
+    const FEXTRA: u8 = 0x04;
+    const FNAME: u8 = 0x08;
+    const FCOMMENT: u8 = 0x10;
+
+    let header = data
+        .get(pos..pos + 10)
+        .ok_or(Error::Format("truncated header"))?;
+    if header[0..2] != [0x1f, 0x8b] {
+        return Err(Error::Format("bad magic number"));
+    }
+    if header[2] != 8 {
+        return Err(Error::Format("unknown compression method"));
+    }
+    let flags = header[3];
+    let mut pos = pos + 10;
+    if flags & FEXTRA != 0 {
+        let len = data
+            .get(pos..pos + 2)
+            .ok_or(Error::Format("truncated header"))?;
+        pos += 2 + u16::from_le_bytes([len[0], len[1]]) as usize;
+    }
+    for field in [FNAME, FCOMMENT] {
+        if flags & field != 0 {
+            // Zero-terminated strings
+            let len = data
+                .get(pos..)
+                .and_then(|rest| rest.iter().position(|b| *b == 0))
+                .ok_or(Error::Format("truncated header"))?;
+            pos += len + 1;
+        }
+    }
+    if flags & FHCRC != 0 {
+        pos += 2;
+    }
+    if pos > data.len() {
+        return Err(Error::Format("truncated header"));
+    }
+    Ok(pos)
+}
+
+/// Reads the bits of a DEFLATE stream, least significant bit first.
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    bit: u32,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8], pos: usize) -> BitReader<'a> {
+        BitReader { data, pos, bit: 0 }
+    }
+
+    fn bits(&mut self, count: u32) -> Result<u32, Error> {
+        let mut value = 0;
+        for i in 0..count {
+            let byte = *self
+                .data
+                .get(self.pos)
+                .ok_or(Error::Format("truncated compressed data"))?;
+            value |= (((byte >> self.bit) & 1) as u32) << i;
+            self.bit += 1;
+            if self.bit == 8 {
+                self.bit = 0;
+                self.pos += 1;
+            }
+        }
+        Ok(value)
+    }
+
+    /// Skips to the next byte boundary.
+    fn align(&mut self) {
+        if self.bit != 0 {
+            self.bit = 0;
+            self.pos += 1;
+        }
+    }
+
+    fn byte_position(&self) -> usize {
+        self.pos + (self.bit != 0) as usize
+    }
+}
+
+/// A canonical Huffman code, stored as the number of codes of each length and the symbols in code
+/// order.
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Result<Huffman, Error> {
+        let mut counts = [0_u16; 16];
+        for &len in lengths {
+            counts[len as usize] += 1;
+        }
+        counts[0] = 0;
+        // Make sure no length has more codes than it can hold
+        let mut left: i32 = 1;
+        for &count in &counts[1..] {
+            left = left * 2 - count as i32;
+            if left < 0 {
+                return Err(Error::Format("over-subscribed Huffman code"));
+            }
+        }
+        let mut offsets = [0_u16; 16];
+        for len in 1..15 {
+            offsets[len + 1] = offsets[len] + counts[len];
+        }
+        let mut symbols = vec![0; lengths.len()];
+        for (symbol, &len) in lengths.iter().enumerate() {
+            if len != 0 {
+                symbols[offsets[len as usize] as usize] = symbol as u16;
+                offsets[len as usize] += 1;
+            }
+        }
+        Ok(Huffman { counts, symbols })
+    }
+
+    fn decode(&self, bits: &mut BitReader) -> Result<u16, Error> {
+        // Codes are packed most significant bit first, so they are read one bit at a time
+        let mut code: i32 = 0;
+        let mut first: i32 = 0;
+        let mut index: i32 = 0;
+        for &count in &self.counts[1..] {
+            code |= bits.bits(1)? as i32;
+            let count = count as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(Error::Format("invalid Huffman code"))
+    }
+}
+
+/// Decompresses a DEFLATE stream (RFC 1951), appending it to `output`.
+fn inflate(bits: &mut BitReader, output: &mut Vec<u8>, max_size: usize) -> Result<(), Error> {
+    loop {
+        let last = bits.bits(1)? == 1;
+        match bits.bits(2)? {
+            0 => {
+                bits.align();
+                let header = bits
+                    .data
+                    .get(bits.pos..bits.pos + 4)
+                    .ok_or(Error::Format("truncated stored block"))?;
+                let len = u16::from_le_bytes([header[0], header[1]]);
+                let nlen = u16::from_le_bytes([header[2], header[3]]);
+                if len != !nlen {
+                    return Err(Error::Format("corrupt stored block length"));
+                }
+                let start = bits.pos + 4;
+                let block = bits
+                    .data
+                    .get(start..start + len as usize)
+                    .ok_or(Error::Format("truncated stored block"))?;
+                if output.len() + block.len() > max_size {
+                    return Err(Error::TooLarge);
+                }
+                output.extend_from_slice(block);
+                bits.pos = start + len as usize;
+            }
+            1 => {
+                let mut lengths = [0_u8; 288];
+                lengths[..144].fill(8);
+                lengths[144..256].fill(9);
+                lengths[256..280].fill(7);
+                lengths[280..].fill(8);
+                let literals = Huffman::new(&lengths)?;
+                let distances = Huffman::new(&[5; 30])?;
+                inflate_block(bits, output, max_size, &literals, &distances)?;
+            }
+            2 => {
+                let (literals, distances) = read_dynamic_codes(bits)?;
+                inflate_block(bits, output, max_size, &literals, &distances)?;
+            }
+            _ => return Err(Error::Format("invalid block type")),
+        }
+        if last {
+            return Ok(());
+        }
+    }
+}
+
+/// Reads the literal/length and distance codes at the start of a dynamic Huffman block.
+fn read_dynamic_codes(bits: &mut BitReader) -> Result<(Huffman, Huffman), Error> {
+    let num_literals = bits.bits(5)? as usize + 257;
+    let num_distances = bits.bits(5)? as usize + 1;
+    let num_code_lengths = bits.bits(4)? as usize + 4;
+    if num_literals > 286 || num_distances > 30 {
+        return Err(Error::Format("too many codes in dynamic block"));
+    }
+
+    let mut code_length_lengths = [0_u8; 19];
+    for &symbol in &CODE_LENGTH_ORDER[..num_code_lengths] {
+        code_length_lengths[symbol] = bits.bits(3)? as u8;
+    }
+    let code_lengths = Huffman::new(&code_length_lengths)?;
+
+    let mut lengths = Vec::with_capacity(num_literals + num_distances);
+    while lengths.len() < num_literals + num_distances {
+        let (value, repeat) = match code_lengths.decode(bits)? {
+            symbol @ 0..=15 => (symbol as u8, 1),
+            16 => {
+                let previous = *lengths
+                    .last()
+                    .ok_or(Error::Format("repeated length with no previous length"))?;
+                (previous, 3 + bits.bits(2)?)
+            }
+            17 => (0, 3 + bits.bits(3)?),
+            _ => (0, 11 + bits.bits(7)?),
+        };
+        for _ in 0..repeat {
+            lengths.push(value);
+        }
+    }
+    if lengths.len() > num_literals + num_distances {
+        return Err(Error::Format("too many code lengths in dynamic block"));
+    }
+    if lengths[256] == 0 {
+        return Err(Error::Format("dynamic block has no end-of-block code"));
+    }
+    Ok((
+        Huffman::new(&lengths[..num_literals])?,
+        Huffman::new(&lengths[num_literals..])?,
+    ))
+}
+
+/// Decodes the symbols of a Huffman-coded block until its end-of-block code.
+fn inflate_block(
+    bits: &mut BitReader,
+    output: &mut Vec<u8>,
+    max_size: usize,
+    literals: &Huffman,
+    distances: &Huffman,
+) -> Result<(), Error> {
+    loop {
+        let symbol = literals.decode(bits)? as usize;
+        if symbol < 256 {
+            if output.len() >= max_size {
+                return Err(Error::TooLarge);
+            }
+            output.push(symbol as u8);
+        } else if symbol == 256 {
+            return Ok(());
+        } else {
+            let code = symbol - 257;
+            if code >= LENGTH_BASE.len() {
+                return Err(Error::Format("invalid length code"));
+            }
+            let length =
+                LENGTH_BASE[code] as usize + bits.bits(LENGTH_EXTRA[code] as u32)? as usize;
+            let code = distances.decode(bits)? as usize;
+            if code >= DISTANCE_BASE.len() {
+                return Err(Error::Format("invalid distance code"));
+            }
+            let distance =
+                DISTANCE_BASE[code] as usize + bits.bits(DISTANCE_EXTRA[code] as u32)? as usize;
+            if distance > output.len() {
+                return Err(Error::Format("distance too far back"));
+            }
+            if output.len() + length > max_size {
+                return Err(Error::TooLarge);
+            }
+            // The copy may overlap the bytes it is producing, so it goes one byte at a time
+            let start = output.len() - distance;
+            for i in 0..length {
+                output.push(output[start + i]);
+            }
+        }
+    }
+}
+
+/// CRC-32 as used by gzip (the IEEE polynomial, bit-reversed).
+fn crc32(data: &[u8]) -> u32 {
+    let mut table = [0_u32; 256];
+    for (i, entry) in table.iter_mut().enumerate() {
+        let mut value = i as u32;
+        for _ in 0..8 {
+            value = if value & 1 != 0 {
+                0xedb88320 ^ (value >> 1)
+            } else {
+                value >> 1
+            };
+        }
+        *entry = value;
+    }
+    !data.iter().fold(!0_u32, |crc, byte| {
+        table[((crc ^ *byte as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
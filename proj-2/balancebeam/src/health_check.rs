@@ -0,0 +1,45 @@
+use crate::{request, response};
+use tokio::net::TcpStream;
+
+#[derive(Debug)]
+#[allow(dead_code, clippy::enum_variant_names)]
+pub enum Error {
+    /// Encountered an I/O error when connecting to or writing to the upstream
+    ConnectionError(std::io::Error),
+    /// The upstream did not send back a valid HTTP response
+    ResponseError(response::Error),
+}
+
+/// Sends a single active health check request to an upstream over a fresh connection, and returns
+/// the upstream's response. The request goes through the same request/response code that is used
+/// for proxied traffic, so a passing check means the upstream can actually talk to us.
+pub async fn send_request(
+    upstream: &str,
+    method: &http::Method,
+    path: &str,
+) -> Result<http::Response<Vec<u8>>, Error> {
+    let mut stream = TcpStream::connect(upstream)
+        .await
+        .map_err(Error::ConnectionError)?;
+
+    let path = if path.starts_with('/') {
+        path.to_string()
+    } else {
+        format!("/{}", path)
+    };
+    let request = http::Request::builder()
+        .method(method)
+        .uri(path)
+        .header("Host", upstream)
+        .header("Connection", "close")
+        .version(http::Version::HTTP_11)
+        .body(Vec::new())
+        .unwrap();
+    request::write_to_stream(&request, &mut stream)
+        .await
+        .map_err(Error::ConnectionError)?;
+
+    response::read_from_stream(&mut stream, request.method(), response::MAX_BODY_SIZE)
+        .await
+        .map_err(Error::ResponseError)
+}
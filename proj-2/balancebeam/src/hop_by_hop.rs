@@ -0,0 +1,55 @@
+use http::header::{self, HeaderMap, HeaderName};
+
+/// Headers that only describe the connection a message arrived on, rather than the message itself
+/// (RFC 7230 section 6.1). A proxy must not pass them on, since its connections to the client and
+/// to the upstream are managed separately. balancebeam doesn't handle protocol upgrades, so
+/// Upgrade is dropped too.
+const HOP_BY_HOP_HEADERS: [HeaderName; 8] = [
+    header::CONNECTION,
+    HeaderName::from_static("keep-alive"),
+    HeaderName::from_static("proxy-connection"),
+    header::PROXY_AUTHENTICATE,
+    header::PROXY_AUTHORIZATION,
+    header::TE,
+    header::TRAILER,
+    header::UPGRADE,
+];
+
+/// Returns the comma-separated options in a message's Connection headers, lowercased.
+fn connection_options(headers: &HeaderMap) -> Vec<String> {
+    headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|option| option.trim().to_ascii_lowercase())
+        .filter(|option| !option.is_empty())
+        .collect()
+}
+
+/// Returns true if the connection a message arrived on stays open after it (RFC 7230 section
+/// 6.3): HTTP/1.1 connections are persistent unless the sender asks for them to be closed, while
+/// HTTP/1.0 connections are closed unless the sender asks for them to be kept alive.
+pub fn keeps_alive(headers: &HeaderMap, version: http::Version) -> bool {
+    let options = connection_options(headers);
+    if options.iter().any(|option| option == "close") {
+        return false;
+    }
+    version >= http::Version::HTTP_11 || options.iter().any(|option| option == "keep-alive")
+}
+
+/// Removes hop-by-hop headers from a message before it is forwarded: the standard ones, and any
+/// others the sender listed in its Connection header (other than Content-Length and Host).
+pub fn strip(headers: &mut HeaderMap) {
+    for option in connection_options(headers) {
+        if let Ok(name) = HeaderName::from_bytes(option.as_bytes()) {
+            // Framing and routing can't be left to the other end's discretion
+            if name != header::CONTENT_LENGTH && name != header::HOST {
+                headers.remove(name);
+            }
+        }
+    }
+    for name in HOP_BY_HOP_HEADERS.iter() {
+        headers.remove(name);
+    }
+}
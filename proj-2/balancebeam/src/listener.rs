@@ -0,0 +1,97 @@
+use socket2::{Domain, SockRef, Socket, Type};
+use std::net::ToSocketAddrs;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+
+/// Environment variable through which an old balancebeam process hands its listening socket to
+/// the process replacing it (see `spawn_successor`)
+const INHERITED_FD_VAR: &str = "BALANCEBEAM_LISTEN_FD";
+
+/// First file descriptor passed by systemd socket activation (SD_LISTEN_FDS_START)
+const SYSTEMD_FIRST_FD: RawFd = 3;
+
+/// Where the main listener came from, for logging
+pub enum Source {
+    /// Passed by systemd socket activation
+    Systemd,
+    /// Inherited from the balancebeam process we are replacing
+    Inherited,
+    /// Bound by us
+    Bound,
+}
+
+/// Opens the socket balancebeam accepts client connections on. In order of preference, this is:
+///
+/// * a socket inherited from an older balancebeam process during an upgrade,
+/// * the first socket passed by systemd (LISTEN_FDS/LISTEN_PID), or
+/// * a fresh socket bound to `bind`. With `reuse_port`, the socket is bound with SO_REUSEPORT, so
+///   that a new balancebeam can be started on the same address before the old one is stopped.
+pub fn open(bind: &str, reuse_port: bool) -> std::io::Result<(tokio::net::TcpListener, Source)> {
+    let (listener, source) = if let Some(fd) = inherited_fd() {
+        (
+            unsafe { std::net::TcpListener::from_raw_fd(fd) },
+            Source::Inherited,
+        )
+    } else if let Some(fd) = systemd_fd() {
+        (
+            unsafe { std::net::TcpListener::from_raw_fd(fd) },
+            Source::Systemd,
+        )
+    } else {
+        (bind_socket(bind, reuse_port)?, Source::Bound)
+    };
+    // Neither variable should leak into processes we spawn later
+    std::env::remove_var(INHERITED_FD_VAR);
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_PID");
+    listener.set_nonblocking(true)?;
+    Ok((tokio::net::TcpListener::from_std(listener)?, source))
+}
+
+fn inherited_fd() -> Option<RawFd> {
+    std::env::var(INHERITED_FD_VAR).ok()?.parse().ok()
+}
+
+fn systemd_fd() -> Option<RawFd> {
+    let pid: u32 = std::env::var("LISTEN_PID").ok()?.parse().ok()?;
+    let fds: u32 = std::env::var("LISTEN_FDS").ok()?.parse().ok()?;
+    (pid == std::process::id() && fds >= 1).then_some(SYSTEMD_FIRST_FD)
+}
+
+fn bind_socket(bind: &str, reuse_port: bool) -> std::io::Result<std::net::TcpListener> {
+    // Resolve names like localhost:1100, as TcpListener::bind does, and take the first address
+    let addr = bind.to_socket_addrs()?.next().ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("{} doesn't resolve to any address", bind),
+        )
+    })?;
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    socket.set_reuse_address(true)?;
+    if reuse_port {
+        socket.set_reuse_port(true)?;
+    }
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    Ok(socket.into())
+}
+
+/// Starts a new copy of the running balancebeam binary (which may have been replaced on disk since
+/// we started) with the same arguments, handing it our listening socket. Once this returns, the
+/// new process owns the listener and the caller should stop accepting connections and drain.
+pub fn spawn_successor(listener: &tokio::net::TcpListener) -> std::io::Result<u32> {
+    // Go through argv[0] rather than current_exe(): if the binary was replaced, /proc/self/exe
+    // points at the deleted old one
+    let mut args = std::env::args_os();
+    let program = args
+        .next()
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "missing program name"))?;
+    let socket = SockRef::from(listener);
+    socket.set_cloexec(false)?;
+    let child = std::process::Command::new(program)
+        .args(args)
+        .env(INHERITED_FD_VAR, listener.as_raw_fd().to_string())
+        .spawn();
+    // Whether or not the spawn worked, nothing else we start should inherit the socket
+    socket.set_cloexec(true)?;
+    Ok(child?.id())
+}
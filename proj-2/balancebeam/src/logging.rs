@@ -0,0 +1,246 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt::{self, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Level, Metadata, Subscriber};
+use tracing_core::span::Current;
+
+/// A minimal tracing subscriber that writes events through the `log` crate (and therefore through
+/// pretty_env_logger and RUST_LOG filtering), prefixing each message with the fields of the spans
+/// it happened in, outermost first:
+///
+/// ```text
+///  INFO  balancebeam > [client=127.0.0.1 request=7 upstream=127.0.0.1:8000] Retrying request
+/// ```
+///
+/// Spans are tracked per thread, so futures must be wrapped with `Instrument::instrument` rather
+/// than holding a span guard across an await.
+struct LogSubscriber {
+    next_id: AtomicU64,
+    spans: Mutex<HashMap<u64, SpanData>>,
+}
+
+struct SpanData {
+    metadata: &'static Metadata<'static>,
+    /// Field names and formatted values, in the order they were first recorded
+    fields: Vec<(&'static str, String)>,
+    parent: Option<u64>,
+    /// Number of handles to this span (and of child spans) that are still alive
+    ref_count: usize,
+}
+
+thread_local! {
+    /// Spans entered on this thread, innermost last
+    static CURRENT_SPANS: RefCell<Vec<u64>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Installs the subscriber. Must be called after the `log` backend has been initialized.
+pub fn init() {
+    let subscriber = LogSubscriber {
+        next_id: AtomicU64::new(1),
+        spans: Mutex::new(HashMap::new()),
+    };
+    tracing::subscriber::set_global_default(subscriber)
+        .expect("A tracing subscriber was already installed");
+}
+
+fn to_log_level(level: &Level) -> log::Level {
+    match *level {
+        Level::ERROR => log::Level::Error,
+        Level::WARN => log::Level::Warn,
+        Level::INFO => log::Level::Info,
+        Level::DEBUG => log::Level::Debug,
+        Level::TRACE => log::Level::Trace,
+    }
+}
+
+/// Collects formatted fields, keeping an event's message separately
+#[derive(Default)]
+struct FieldVisitor {
+    message: String,
+    fields: Vec<(&'static str, String)>,
+}
+
+impl Visit for FieldVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message.push_str(value);
+        } else {
+            self.fields.push((field.name(), value.to_string()));
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            write!(self.message, "{:?}", value).unwrap();
+        } else {
+            self.fields.push((field.name(), format!("{:?}", value)));
+        }
+    }
+}
+
+fn format_fields(fields: &[(&'static str, String)]) -> String {
+    fields
+        .iter()
+        .map(|(name, value)| format!("{}={}", name, value))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+impl LogSubscriber {
+    /// Formats the fields of the current span and all of its parents
+    fn context(&self) -> String {
+        let Some(current) = CURRENT_SPANS.with(|spans| spans.borrow().last().copied()) else {
+            return String::new();
+        };
+        let spans = self.spans.lock().unwrap();
+        let mut chain = Vec::new();
+        let mut next = Some(current);
+        while let Some(id) = next {
+            let Some(span) = spans.get(&id) else {
+                break;
+            };
+            if !span.fields.is_empty() {
+                chain.push(format_fields(&span.fields));
+            }
+            next = span.parent;
+        }
+        if chain.is_empty() {
+            return String::new();
+        }
+        chain.reverse();
+        format!("[{}] ", chain.join(" "))
+    }
+}
+
+impl Subscriber for LogSubscriber {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        // Spans are always tracked so that the events inside them can be annotated
+        metadata.is_span()
+            || log::logger().enabled(
+                &log::Metadata::builder()
+                    .level(to_log_level(metadata.level()))
+                    .target(metadata.target())
+                    .build(),
+            )
+    }
+
+    fn new_span(&self, attrs: &Attributes<'_>) -> Id {
+        let mut visitor = FieldVisitor::default();
+        attrs.record(&mut visitor);
+        let parent = if attrs.is_contextual() {
+            CURRENT_SPANS.with(|spans| spans.borrow().last().copied())
+        } else {
+            attrs.parent().map(Id::into_u64)
+        };
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let mut spans = self.spans.lock().unwrap();
+        // A child keeps its parent alive, so that its context can still be printed
+        if let Some(parent) = parent.and_then(|parent| spans.get_mut(&parent)) {
+            parent.ref_count += 1;
+        }
+        spans.insert(
+            id,
+            SpanData {
+                metadata: attrs.metadata(),
+                fields: visitor.fields,
+                parent,
+                ref_count: 1,
+            },
+        );
+        Id::from_u64(id)
+    }
+
+    fn record(&self, span: &Id, values: &Record<'_>) {
+        let mut visitor = FieldVisitor::default();
+        values.record(&mut visitor);
+        if let Some(span) = self.spans.lock().unwrap().get_mut(&span.into_u64()) {
+            for (name, value) in visitor.fields {
+                match span
+                    .fields
+                    .iter_mut()
+                    .find(|(existing, _)| *existing == name)
+                {
+                    Some(field) => field.1 = value,
+                    None => span.fields.push((name, value)),
+                }
+            }
+        }
+    }
+
+    fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+    fn event(&self, event: &Event<'_>) {
+        let mut visitor = FieldVisitor::default();
+        event.record(&mut visitor);
+        let metadata = event.metadata();
+        log::logger().log(
+            &log::Record::builder()
+                .level(to_log_level(metadata.level()))
+                .target(metadata.target())
+                .module_path(metadata.module_path())
+                .file(metadata.file())
+                .line(metadata.line())
+                .args(format_args!(
+                    "{}{}{}",
+                    self.context(),
+                    visitor.message,
+                    visitor
+                        .fields
+                        .iter()
+                        .map(|(name, value)| format!(" {}={}", name, value))
+                        .collect::<String>()
+                ))
+                .build(),
+        );
+    }
+
+    fn enter(&self, span: &Id) {
+        CURRENT_SPANS.with(|spans| spans.borrow_mut().push(span.into_u64()));
+    }
+
+    fn exit(&self, span: &Id) {
+        CURRENT_SPANS.with(|spans| {
+            let mut spans = spans.borrow_mut();
+            if let Some(pos) = spans.iter().rposition(|id| *id == span.into_u64()) {
+                spans.remove(pos);
+            }
+        });
+    }
+
+    fn current_span(&self) -> Current {
+        let current = CURRENT_SPANS.with(|spans| spans.borrow().last().copied());
+        match current.and_then(|id| Some((id, self.spans.lock().unwrap().get(&id)?.metadata))) {
+            Some((id, metadata)) => Current::new(Id::from_u64(id), metadata),
+            None => Current::none(),
+        }
+    }
+
+    fn clone_span(&self, span: &Id) -> Id {
+        if let Some(span) = self.spans.lock().unwrap().get_mut(&span.into_u64()) {
+            span.ref_count += 1;
+        }
+        span.clone()
+    }
+
+    fn try_close(&self, span: Id) -> bool {
+        let mut spans = self.spans.lock().unwrap();
+        let mut next = Some(span.into_u64());
+        let mut closed = false;
+        while let Some(id) = next {
+            let Some(data) = spans.get_mut(&id) else {
+                break;
+            };
+            data.ref_count -= 1;
+            if data.ref_count > 0 {
+                break;
+            }
+            next = spans.remove(&id).and_then(|data| data.parent);
+            closed = closed || id == span.into_u64();
+        }
+        closed
+    }
+}
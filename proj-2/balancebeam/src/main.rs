@@ -1,13 +1,48 @@
+mod admin;
+mod basic_auth;
+mod bench;
+mod chunked;
+mod config;
+mod cors;
+mod discovery;
+mod dns;
+mod drain;
+mod duration;
+mod error_pages;
+mod forwarded;
+mod geoip;
+mod gzip;
+mod health_check;
+mod hop_by_hop;
+mod listener;
+mod logging;
+mod metrics;
+mod otlp;
+mod plugin;
+mod redis;
 mod request;
 mod response;
+mod retry;
+mod status;
+mod trace_context;
 
 use clap::Parser;
-use std::collections::HashMap;
-use std::fmt;
+use duration::{parse_duration, parse_duration_ms};
+use futures_util::future::join_all;
+use rand::Rng;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tokio::net::{TcpListener, TcpStream};
+use tokio::signal::unix::{signal, SignalKind};
 use tokio::sync::Mutex;
+use tracing::Instrument;
+
+/// ID given to the next request balancebeam reads, used to correlate log lines
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(1);
 
 /// Contains information parsed from the command-line invocation of balancebeam. The Clap macros
 /// provide a fancy way to automatically construct a command-line argument parser. #[derive(Parser, Debug)]
@@ -17,18 +52,405 @@ struct CmdOptions {
     /// "IP/port to bind to"
     #[arg(short, long, default_value = "0.0.0.0:1100")]
     bind: String,
+    /// "Bind with SO_REUSEPORT, so that a new instance can be started on the same address before
+    /// this one is stopped"
+    #[arg(long)]
+    reuse_port: bool,
+    /// "After SIGTERM or SIGUSR2, how long to wait for open connections to finish before exiting
+    /// (e.g. 500ms, 30s or 2m; plain numbers are seconds)"
+    #[arg(long, default_value = "30s", value_parser = parse_duration)]
+    shutdown_timeout: Duration,
+    /// "Maximum size of a request's request line and headers (in bytes); larger requests get a 431"
+    #[arg(long, default_value = "8000")]
+    max_header_size: usize,
+    /// "Maximum number of headers in a request; requests with more get a 431"
+    #[arg(long, default_value = "32")]
+    max_header_count: usize,
+    /// "Maximum size of a request body, and of an upstream response body buffered for a client
+    /// (in bytes, or with a unit such as 512KB, 10MB or 1GiB); larger requests get a 413 and larger
+    /// responses a 502"
+    #[arg(long, default_value = "10MB", value_parser = parse_size)]
+    max_body_size: usize,
+    /// "Decompress gzip-encoded request bodies before forwarding them, for upstreams that can't
+    /// handle Content-Encoding (decompressed bodies are also subject to --max-body-size)"
+    #[arg(long)]
+    decompress_requests: bool,
+    /// "Close client connections that haven't sent a complete request for this long (0 = never)"
+    #[arg(long, default_value = "60s", value_parser = parse_duration)]
+    client_idle_timeout: Duration,
+    /// "Close client connections after this many requests, sending Connection: close with the last
+    /// response, so that long-lived clients get rebalanced across upstreams (0 = unlimited)"
+    #[arg(long, default_value = "0")]
+    max_requests_per_connection: usize,
     /// "Upstream host to forward requests to"
     #[arg(short, long)]
     upstream: Vec<String>,
-    /// "Perform active health checks on this interval (in seconds)"
-    #[arg(long, default_value = "2")]
-    active_health_check_interval: usize,
+    /// "How often upstreams given as hostnames are re-resolved, so that pool members are added and
+    /// removed as their DNS records change (0 = resolve only at startup)"
+    #[arg(long, default_value = "30s", value_parser = parse_duration)]
+    dns_refresh_interval: Duration,
+    /// "Discover upstreams from DNS SRV records (srv:NAME[@NAMESERVER:PORT], re-queried every
+    /// --dns-refresh-interval) or from the healthy instances of a Consul service
+    /// (consul:HOST:PORT/SERVICE, watched for changes)"
+    #[arg(long, value_parser = discovery::parse_source)]
+    discover: Vec<discovery::Source>,
+    /// "Lower-priority group of upstreams, used only when no higher-priority upstream is healthy,
+    /// given as PRIORITY=HOST,HOST,... (--upstream hosts have priority 0)"
+    #[arg(long, value_parser = parse_upstream_group)]
+    upstream_group: Vec<(u32, Vec<String>)>,
+    /// "Canary upstream that receives --canary-percent of new connections"
+    #[arg(long)]
+    canary_upstream: Vec<String>,
+    /// "Percentage of new connections to route to canary upstreams (adjustable at runtime via the
+    /// admin API)"
+    #[arg(long, default_value = "0", value_parser = clap::value_parser!(u8).range(0..=100))]
+    canary_percent: u8,
+    /// "Request header whose value decides canary assignment, so that a given client consistently
+    /// lands on the same side (random if unset or missing)"
+    #[arg(long)]
+    canary_header: Option<String>,
+    /// "Path to a config file with per-route rules (see config.rs for the format)"
+    #[arg(long)]
+    config: Option<String>,
+    /// "Directory with templates (e.g. 502.html, error.json) for errors generated by balancebeam"
+    #[arg(long)]
+    error_page_dir: Option<String>,
+    /// "MaxMind DB file (e.g. GeoLite2-Country.mmdb) used to look up client countries, which are
+    /// passed upstream in X-Geo-Country"
+    #[arg(long)]
+    geoip_db: Option<String>,
+    /// "Only accept clients from these countries (ISO codes, comma-separated); clients whose
+    /// country is unknown are rejected too. Requires --geoip-db"
+    #[arg(long, value_delimiter = ',')]
+    geo_allow: Vec<String>,
+    /// "Reject clients from these countries (ISO codes, comma-separated). Requires --geoip-db"
+    #[arg(long, value_delimiter = ',')]
+    geo_deny: Vec<String>,
+    /// "Upstreams dedicated to clients from some countries, given as CC,CC,...=HOST,HOST,...
+    /// Requires --geoip-db"
+    #[arg(long, value_parser = parse_geo_pool)]
+    geo_pool: Vec<(Vec<String>, Vec<String>)>,
+    /// "htpasswd file with the users allowed through; if set, clients must authenticate with HTTP
+    /// Basic auth"
+    #[arg(long)]
+    htpasswd: Option<String>,
+    /// "Realm sent to clients in Basic auth challenges"
+    #[arg(long, default_value = "balancebeam")]
+    auth_realm: String,
+    /// "Script in balancebeam's plugin language with on_request/on_response hooks that can inspect
+    /// and modify requests and responses or answer requests itself (may be given several times;
+    /// hooks run in order)"
+    #[arg(long)]
+    plugin: Vec<String>,
+    /// "IP/port to serve the admin API on (disabled if unset)"
+    #[arg(long)]
+    admin_bind: Option<String>,
+    /// "File listing upstreams to drain, one address per line; drained upstreams get no new
+    /// requests, but requests in flight there finish. Reread on SIGUSR1"
+    #[arg(long)]
+    drain_file: Option<String>,
+    /// "Perform active health checks on this interval"
+    #[arg(long, default_value = "2s", value_parser = parse_duration)]
+    active_health_check_interval: Duration,
     /// "Path to send request to for active health checks"
     #[arg(long, default_value = "/")]
     active_health_check_path: String,
+    /// "HTTP method to use for active health checks"
+    #[arg(long, default_value = "GET")]
+    active_health_check_method: String,
+    /// "HTTP status codes that count as a passing active health check (comma-separated)"
+    #[arg(long, value_delimiter = ',', default_value = "200")]
+    active_health_check_status: Vec<u16>,
+    /// "Substring the active health check response body must contain to pass"
+    #[arg(long)]
+    active_health_check_body: Option<String>,
+    /// "Time to wait for an active health check response before marking the upstream unavailable"
+    #[arg(long, default_value = "1s", value_parser = parse_duration)]
+    active_health_check_timeout: Duration,
+    /// "Maximum random delay added to each active health check interval, so that multiple
+    /// balancebeam instances don't probe upstreams in lockstep (plain numbers are milliseconds)"
+    #[arg(long, default_value = "0ms", value_parser = parse_duration_ms)]
+    active_health_check_jitter: Duration,
     /// "Maximum number of requests to accept per IP per minute (0 = unlimited)"
     #[arg(long, default_value = "0")]
     max_requests_per_minute: usize,
+    /// "Keep rate limiting counts in Redis (redis://[:PASSWORD@]HOST[:PORT][/DB]) so that several
+    /// balancebeam instances enforce one shared limit, instead of in memory"
+    #[arg(long)]
+    rate_limit_backend: Option<String>,
+    /// "Clients (IP or CIDR, comma-separated) that are never rate limited, such as health
+    /// checkers and internal services"
+    #[arg(long, value_delimiter = ',', value_parser = forwarded::parse_cidr)]
+    rate_limit_exempt: Vec<ipnet::IpNet>,
+    /// "What requests are counted by for rate limiting: the client IP, the client IP and the local
+    /// port it connected to, or the value of --rate-limit-header"
+    #[arg(long, value_enum, default_value = "ip")]
+    rate_limit_key: RateLimitKey,
+    /// "Request header identifying clients when --rate-limit-key is header (e.g. X-Api-Key). Each
+    /// client IP gets a limit per value, so the header should be set by a trusted proxy in front of
+    /// balancebeam: clients that can set it themselves get a fresh limit with every new value"
+    #[arg(long)]
+    rate_limit_header: Option<http::HeaderName>,
+    /// "Maximum number of client connections served at once; further connections wait in the
+    /// listen backlog until one closes (0 = unlimited)"
+    #[arg(long, default_value = "0")]
+    max_connections: usize,
+    /// "Maximum number of connections a single client IP may have open at once; further
+    /// connections get a 429 and are closed (0 = unlimited)"
+    #[arg(long, default_value = "0")]
+    max_connections_per_client: usize,
+    /// "Maximum number of requests in flight to any one upstream at a time; once an upstream is at
+    /// the limit, requests go to other upstreams, or get a 503 if all are saturated (0 = unlimited)"
+    #[arg(long, default_value = "0")]
+    max_upstream_concurrency: usize,
+    /// "How long to wait for a connection to an upstream to be established"
+    #[arg(long, default_value = "5s", value_parser = parse_duration)]
+    upstream_connect_timeout: Duration,
+    /// "How long to wait for an upstream to accept a request"
+    #[arg(long, default_value = "60s", value_parser = parse_duration)]
+    upstream_write_timeout: Duration,
+    /// "How long to wait for an upstream's complete response"
+    #[arg(long, default_value = "60s", value_parser = parse_duration)]
+    upstream_read_timeout: Duration,
+    /// "Maximum number of times to retry an idempotent request on another upstream"
+    #[arg(long, default_value = "2")]
+    max_retries: usize,
+    /// "Retries (of requests on another upstream, and of connections after one fails) may add at
+    /// most this percentage on top of the requests being handled (0 = unlimited)"
+    #[arg(long, default_value = "20")]
+    retry_budget_percent: usize,
+    /// "After an upstream refuses a connection, it is left alone for this long before it is tried
+    /// again, doubling with every further failure (plain numbers are milliseconds, 0 = no backoff)"
+    #[arg(long, default_value = "100ms", value_parser = parse_duration_ms)]
+    connect_backoff_base: Duration,
+    /// "Longest an upstream is left alone after failed connects (plain numbers are milliseconds)"
+    #[arg(long, default_value = "10s", value_parser = parse_duration_ms)]
+    connect_backoff_max: Duration,
+    /// "Number of proxy-observed failures within the passive window before an upstream is marked
+    /// unavailable"
+    #[arg(long, default_value = "3")]
+    passive_unhealthy_threshold: usize,
+    /// "Window over which passive health check failures are counted"
+    #[arg(long, default_value = "10s", value_parser = parse_duration)]
+    passive_window: Duration,
+    /// "Eject upstreams whose error rate is more than this many times the pool average
+    /// (0 = disabled)"
+    #[arg(long, default_value = "0")]
+    outlier_ratio: f64,
+    /// "Minimum number of requests an upstream must have served in an outlier detection interval
+    /// before it can be ejected"
+    #[arg(long, default_value = "5")]
+    outlier_min_requests: usize,
+    /// "Evaluate upstream error rates for outlier detection on this interval"
+    #[arg(long, default_value = "10s", value_parser = parse_duration)]
+    outlier_interval: Duration,
+    /// "How long an ejected outlier stays out of rotation"
+    #[arg(long, default_value = "30s", value_parser = parse_duration)]
+    outlier_cooldown: Duration,
+    /// "Recompute upstream weights from their recent latency and error rate on this interval, so
+    /// that traffic shifts away from slow or failing upstreams (0 = plain round-robin)"
+    #[arg(long, default_value = "0s", value_parser = parse_duration)]
+    dynamic_weight_interval: Duration,
+    /// "Lowest weight a dynamically weighted upstream can get, relative to the best one, so that
+    /// slow upstreams keep getting enough traffic to notice when they recover (0 to 1)"
+    #[arg(long, default_value = "0.1")]
+    dynamic_weight_min: f64,
+    /// "Log a summary of upstream latencies and traffic on this interval (0 = disabled)"
+    #[arg(long, default_value = "60s", value_parser = parse_duration)]
+    latency_log_interval: Duration,
+    /// "Proxies (IP or CIDR, comma-separated) whose X-Forwarded-For headers are believed when
+    /// determining the client's address"
+    #[arg(long, value_delimiter = ',', value_parser = forwarded::parse_cidr)]
+    trusted_proxies: Vec<ipnet::IpNet>,
+    /// "How to pass X-Forwarded-For upstream: append the peer's address, replace it with the real
+    /// client address, or strip it"
+    #[arg(long, value_enum, default_value = "append")]
+    forwarded_for: forwarded::ForwardedForMode,
+    /// "Replace the Server header of upstream responses with this value, so that the upstream's
+    /// software doesn't leak to clients"
+    #[arg(long)]
+    server_header: Option<http::HeaderValue>,
+    /// "OpenTelemetry collector (HOST:PORT) to export request spans to over OTLP/HTTP (disabled if
+    /// unset)"
+    #[arg(long)]
+    otlp_endpoint: Option<String>,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+/// Things balancebeam can do instead of running the load balancer
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    /// Send concurrent request load at a server and report throughput and latency percentiles
+    Bench(bench::Options),
+}
+
+/// Parses a size such as 4096, 512KB or 10MiB into a number of bytes. KB, MB and GB are powers of
+/// 1000, KiB, MiB and GiB powers of 1024; units are case-insensitive and the B may be left off.
+fn parse_size(value: &str) -> Result<usize, String> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number = number
+        .parse::<usize>()
+        .map_err(|_| format!("invalid size {:?}", value))?;
+    let multiplier: usize = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" => 1000,
+        "m" | "mb" => 1000 * 1000,
+        "g" | "gb" => 1000 * 1000 * 1000,
+        "ki" | "kib" => 1 << 10,
+        "mi" | "mib" => 1 << 20,
+        "gi" | "gib" => 1 << 30,
+        _ => return Err(format!("unknown size unit {:?}", unit.trim())),
+    };
+    number
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("size {:?} is too large", value))
+}
+
+/// Parses a --geo-pool value of the form CC,CC,...=HOST,HOST,...
+fn parse_geo_pool(value: &str) -> Result<(Vec<String>, Vec<String>), String> {
+    let (countries, upstreams) = value
+        .split_once('=')
+        .ok_or_else(|| "expected CC,CC,...=HOST,HOST,...".to_string())?;
+    let split = |list: &str| -> Vec<String> {
+        list.split(',')
+            .filter(|item| !item.is_empty())
+            .map(|item| item.to_string())
+            .collect()
+    };
+    let countries: Vec<String> = split(countries)
+        .iter()
+        .map(|country| country.to_ascii_uppercase())
+        .collect();
+    let upstreams = split(upstreams);
+    if countries.is_empty() || upstreams.is_empty() {
+        return Err("geo pool must contain at least one country and one upstream".to_string());
+    }
+    Ok((countries, upstreams))
+}
+
+/// Parses an --upstream-group value of the form PRIORITY=HOST,HOST,...
+fn parse_upstream_group(value: &str) -> Result<(u32, Vec<String>), String> {
+    let (priority, upstreams) = value
+        .split_once('=')
+        .ok_or_else(|| "expected PRIORITY=HOST,HOST,...".to_string())?;
+    let priority = priority
+        .parse::<u32>()
+        .map_err(|err| format!("invalid priority {:?}: {}", priority, err))?;
+    let upstreams: Vec<String> = upstreams
+        .split(',')
+        .filter(|upstream| !upstream.is_empty())
+        .map(|upstream| upstream.to_string())
+        .collect();
+    if upstreams.is_empty() {
+        return Err("upstream group must contain at least one upstream".to_string());
+    }
+    Ok((priority, upstreams))
+}
+
+/// Health information the proxy keeps about each upstream
+struct UpstreamHealth {
+    /// Priority of the group this upstream belongs to. Traffic only goes to the lowest-numbered
+    /// priority that has routable upstreams.
+    priority: u32,
+    /// Whether this is a canary upstream, which only gets canary_percent of connections
+    canary: bool,
+    /// If non-empty, this upstream belongs to a geo pool and serves clients from these countries
+    countries: Vec<String>,
+    /// Whether requests are currently being routed to this upstream. This is kept out of `stats`
+    /// so that health checks can flip it without waiting on connection setup.
+    available: AtomicBool,
+    /// Everything that changes as traffic is served. Each upstream has its own lock, so that
+    /// recording a result for one upstream never waits on another.
+    stats: std::sync::Mutex<UpstreamStats>,
+}
+
+/// What the proxy has observed about an upstream's traffic
+struct UpstreamStats {
+    /// Times of recent proxy-observed failures (failed connects, broken upstream connections),
+    /// oldest first
+    recent_failures: VecDeque<Instant>,
+    /// Requests sent to this upstream in the current outlier detection interval
+    requests: usize,
+    /// Requests in the current outlier detection interval that failed or got a 5xx response
+    errors: usize,
+    /// If set, this upstream was ejected by outlier detection and is kept out of rotation until
+    /// this time
+    ejected_until: Option<Instant>,
+    /// Number of connects to this upstream that have failed in a row
+    connect_failures: u32,
+    /// If set, connects to this upstream failed recently, and it isn't tried again until this time
+    backoff_until: Option<Instant>,
+    /// Share of traffic this upstream gets relative to the others, if dynamic weights are enabled
+    weight: f64,
+    /// Traffic served since weights were last adjusted
+    weight_stats: WeightStats,
+}
+
+/// What an upstream's weight is computed from
+#[derive(Default)]
+struct WeightStats {
+    /// Requests sent to the upstream, including failed ones
+    attempts: usize,
+    /// Attempts that failed or got a 5xx response
+    errors: usize,
+    /// Responses received, and the total time they took
+    responses: usize,
+    latency: Duration,
+}
+
+impl UpstreamHealth {
+    fn new(priority: u32, canary: bool, countries: Vec<String>) -> UpstreamHealth {
+        UpstreamHealth {
+            priority,
+            canary,
+            countries,
+            available: AtomicBool::new(true),
+            stats: std::sync::Mutex::new(UpstreamStats {
+                recent_failures: VecDeque::new(),
+                requests: 0,
+                errors: 0,
+                ejected_until: None,
+                connect_failures: 0,
+                backoff_until: None,
+                weight: 1.0,
+                weight_stats: WeightStats::default(),
+            }),
+        }
+    }
+
+    fn is_available(&self) -> bool {
+        self.available.load(Ordering::SeqCst)
+    }
+
+    fn stats(&self) -> std::sync::MutexGuard<'_, UpstreamStats> {
+        self.stats.lock().unwrap()
+    }
+
+    /// Returns true if requests can be routed to this upstream right now
+    fn is_routable(&self) -> bool {
+        self.is_available() && !self.stats().is_ejected()
+    }
+}
+
+impl UpstreamStats {
+    fn is_ejected(&self) -> bool {
+        self.ejected_until
+            .is_some_and(|ejected_until| Instant::now() < ejected_until)
+    }
+
+    fn is_backing_off(&self) -> bool {
+        self.backoff_until
+            .is_some_and(|backoff_until| Instant::now() < backoff_until)
+    }
+
+    fn error_rate(&self) -> f64 {
+        self.errors as f64 / self.requests as f64
+    }
 }
 
 /// Contains information about the state of balancebeam (e.g. what servers we are currently proxying
@@ -39,27 +461,331 @@ struct CmdOptions {
 struct ProxyState {
     /// How frequently we check whether upstream servers are alive (Milestone 4)
     #[allow(dead_code)]
-    active_health_check_interval: usize,
+    active_health_check_interval: Duration,
     /// Where we should send requests when doing active health checks (Milestone 4)
     #[allow(dead_code)]
     active_health_check_path: String,
+    /// Method used for active health check requests
+    active_health_check_method: http::Method,
+    /// Status codes that count as a passing active health check
+    active_health_check_statuses: Vec<u16>,
+    /// If set, active health check responses must contain this in their body to pass
+    active_health_check_body: Option<String>,
+    /// How long a single active health check may take before it counts as a failure
+    active_health_check_timeout: Duration,
+    /// Upper bound on the random delay added to each active health check interval
+    active_health_check_jitter: Duration,
     /// Maximum number of requests an individual IP can make in a minute (Milestone 5)
     #[allow(dead_code)]
     max_requests_per_minute: usize,
-    /// Addresses of servers that we are proxying to
-    upstream_addresses: Arc<Mutex<HashMap<String, bool>>>,
+    /// Maximum number of times a failed GET/HEAD request is retried on a different upstream
+    max_retries: usize,
+    /// How often upstream weights are recomputed; if None, upstreams are picked round-robin
+    dynamic_weight_interval: Option<Duration>,
+    /// Lowest weight an upstream can be given
+    dynamic_weight_min: f64,
+    /// Limits how many retries can be made, relative to the number of requests
+    retry_budget: Arc<retry::RetryBudget>,
+    /// Initial and maximum time an upstream is left alone after a failed connect
+    connect_backoff_base: Duration,
+    connect_backoff_max: Duration,
+    /// Maximum number of requests in flight to each upstream (0 = unlimited)
+    max_upstream_concurrency: usize,
+    /// Limits on the size of requests read from clients
+    request_limits: request::Limits,
+    /// Whether gzip-encoded request bodies are decompressed before being forwarded
+    decompress_requests: bool,
+    /// How long a client connection may wait for its next request before it is closed
+    client_idle_timeout: Option<Duration>,
+    /// How many requests a client connection may send before it is closed
+    max_requests_per_connection: Option<usize>,
+    /// Timeouts for connecting to upstreams, sending them requests and reading their responses
+    upstream_connect_timeout: Duration,
+    upstream_write_timeout: Duration,
+    upstream_read_timeout: Duration,
+    /// Number of requests currently in flight to each upstream. Entries are created on first use,
+    /// since upstreams resolved from DNS can join the pool at any time.
+    in_flight: Arc<std::sync::RwLock<HashMap<String, Arc<AtomicUsize>>>>,
+    /// Upstreams that get no new requests, so that they can be taken down once idle
+    drains: Arc<drain::Drains>,
+    /// Maximum number of open connections per client IP (0 = unlimited)
+    max_connections_per_client: usize,
+    /// Number of open connections from each client IP that has any
+    client_connections: Arc<std::sync::Mutex<HashMap<IpAddr, usize>>>,
+    /// How many failures within passive_window it takes to mark an upstream unavailable
+    passive_unhealthy_threshold: usize,
+    /// Window over which passive health check failures are counted
+    passive_window: Duration,
+    /// Upstreams with an error rate above this multiple of the pool average are ejected
+    /// (0 = outlier detection disabled)
+    outlier_ratio: f64,
+    /// Minimum number of requests before an upstream's error rate is considered meaningful
+    outlier_min_requests: usize,
+    /// How often error rates are evaluated
+    outlier_interval: Duration,
+    /// How long an ejected upstream stays out of rotation
+    outlier_cooldown: Duration,
+    /// Addresses of servers that we are proxying to, along with their health. The write lock is
+    /// only taken when upstreams join or leave the pool; everything else shares the read lock.
+    upstream_addresses: Arc<std::sync::RwLock<HashMap<String, UpstreamHealth>>>,
+    /// Percentage of new connections that are routed to canary upstreams
+    canary_percent: Arc<AtomicUsize>,
+    /// If set, canary assignment is derived from this request header instead of chosen randomly
+    canary_header: Option<String>,
+    /// Per-route rules loaded from the config file
+    config: Arc<config::Config>,
+    /// Templates for errors generated by balancebeam itself
+    error_pages: Arc<error_pages::ErrorPages>,
+    /// Latency histograms for requests proxied to each upstream
+    latency: Arc<metrics::LatencyMetrics>,
+    /// Request, status class and byte counts for each upstream
+    traffic: Arc<metrics::TrafficMetrics>,
+    /// Proxies allowed to tell us the client's address through X-Forwarded-For
+    trusted_proxies: Vec<ipnet::IpNet>,
+    /// Clients that bypass rate limiting
+    rate_limit_exempt: Vec<ipnet::IpNet>,
+    /// What identifies a client for rate limiting
+    rate_limit_key: RateLimitKey,
+    /// Header identifying clients when rate_limit_key is Header
+    rate_limit_header: Option<http::HeaderName>,
+    /// How X-Forwarded-For is passed upstream
+    forwarded_for: forwarded::ForwardedForMode,
+    /// Database used to look up the country of clients
+    geoip: Option<Arc<geoip::GeoIpDb>>,
+    /// If non-empty, only clients from these countries are accepted
+    geo_allow: Vec<String>,
+    /// Clients from these countries are rejected
+    geo_deny: Vec<String>,
+    /// If set, clients must authenticate as one of these users
+    htpasswd: Option<Arc<basic_auth::Htpasswd>>,
+    /// Realm sent in WWW-Authenticate challenges
+    auth_realm: String,
+    /// Scripts whose hooks are run on every proxied request and response, in order
+    plugins: Arc<Vec<plugin::Plugin>>,
+    /// If set, replaces the Server header of upstream responses
+    server_header: Option<http::HeaderValue>,
+    /// If set, a span for every sampled request is exported to an OpenTelemetry collector
+    otlp: Option<Arc<otlp::Exporter>>,
     /// Counter to keep track of the next upstream server to pick
-    next_connection: Arc<Mutex<usize>>,
+    next_connection: Arc<AtomicUsize>,
+    /// Number of client connections currently being served
+    active_connections: Arc<AtomicUsize>,
+    /// When this balancebeam process started
+    started: Instant,
+
+    rate_limiter_service: Arc<RateLimiterService>,
+}
+
+/// A request slot on an upstream, held while a request is in flight there
+struct InFlight(Arc<AtomicUsize>);
 
-    rate_limiter_service: Arc<Mutex<RateLimiterService>>,
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// One of a client's open connections, counted against max_connections_per_client while held
+struct ClientSlot<'a> {
+    connections: &'a std::sync::Mutex<HashMap<IpAddr, usize>>,
+    client: IpAddr,
+}
+
+impl Drop for ClientSlot<'_> {
+    fn drop(&mut self) {
+        let mut connections = self.connections.lock().unwrap();
+        if let Some(count) = connections.get_mut(&self.client) {
+            *count -= 1;
+            if *count == 0 {
+                connections.remove(&self.client);
+            }
+        }
+    }
 }
 
 impl ProxyState {
-    pub async fn get_connection_index(&self, count: usize) -> usize {
-        let mut next_connection_idx = self.next_connection.lock().await;
-        *next_connection_idx += 1;
-        *next_connection_idx %= count;
-        *next_connection_idx
+    pub fn get_connection_index(&self, count: usize) -> usize {
+        // The counter is allowed to wrap around; only its value modulo count matters
+        self.next_connection
+            .fetch_add(1, Ordering::Relaxed)
+            .wrapping_add(1)
+            % count
+    }
+
+    /// Returns true if the upstream already has max_upstream_concurrency requests in flight.
+    fn is_saturated(&self, upstream: &str) -> bool {
+        self.max_upstream_concurrency > 0
+            && self
+                .in_flight
+                .read()
+                .unwrap()
+                .get(upstream)
+                .is_some_and(|count| count.load(Ordering::SeqCst) >= self.max_upstream_concurrency)
+    }
+
+    /// Returns the number of requests in flight to an upstream.
+    fn in_flight(&self, upstream: &str) -> usize {
+        self.in_flight
+            .read()
+            .unwrap()
+            .get(upstream)
+            .map_or(0, |count| count.load(Ordering::SeqCst))
+    }
+
+    /// Counts a new connection from a client, or returns None if the client already has
+    /// max_connections_per_client connections open.
+    fn acquire_client_slot(&self, client: IpAddr) -> Option<ClientSlot<'_>> {
+        let mut connections = self.client_connections.lock().unwrap();
+        let count = connections.entry(client).or_insert(0);
+        if self.max_connections_per_client > 0 && *count >= self.max_connections_per_client {
+            return None;
+        }
+        *count += 1;
+        Some(ClientSlot {
+            connections: &self.client_connections,
+            client,
+        })
+    }
+
+    /// Takes a request slot on an upstream, or returns None if it is saturated.
+    fn acquire_slot(&self, upstream: &str) -> Option<InFlight> {
+        let existing = self.in_flight.read().unwrap().get(upstream).cloned();
+        let count = match existing {
+            Some(count) => count,
+            None => Arc::clone(
+                self.in_flight
+                    .write()
+                    .unwrap()
+                    .entry(upstream.to_string())
+                    .or_default(),
+            ),
+        };
+        count
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |in_flight| {
+                (self.max_upstream_concurrency == 0 || in_flight < self.max_upstream_concurrency)
+                    .then_some(in_flight + 1)
+            })
+            .ok()
+            .map(|_| InFlight(count))
+    }
+
+    /// Records a failure the proxy observed while talking to an upstream. Once
+    /// passive_unhealthy_threshold failures have been seen within passive_window, the upstream is
+    /// marked as unavailable until an active health check succeeds again.
+    pub fn record_failure(&self, upstream: &str) {
+        let upstream_addresses = self.upstream_addresses.read().unwrap();
+        let Some(health) = upstream_addresses.get(upstream) else {
+            return;
+        };
+        let mut stats = health.stats();
+        stats.requests += 1;
+        stats.errors += 1;
+        stats.weight_stats.attempts += 1;
+        stats.weight_stats.errors += 1;
+        let now = Instant::now();
+        stats.recent_failures.push_back(now);
+        while let Some(oldest) = stats.recent_failures.front() {
+            if now.duration_since(*oldest) <= self.passive_window {
+                break;
+            }
+            stats.recent_failures.pop_front();
+        }
+        if stats.recent_failures.len() >= self.passive_unhealthy_threshold
+            && health.available.swap(false, Ordering::SeqCst)
+        {
+            tracing::warn!(
+                "Upstream {} failed {} times in the last {:?}; marking it unavailable",
+                upstream,
+                stats.recent_failures.len(),
+                self.passive_window
+            );
+        }
+    }
+
+    /// Records a failed connect to an upstream, and leaves the upstream alone for a while before
+    /// it is tried again, so that an upstream that is down or flapping isn't hammered by
+    /// reconnects. The wait grows with every connect that fails in a row.
+    pub fn record_connect_failure(&self, upstream: &str) {
+        if self.connect_backoff_base.is_zero() {
+            return;
+        }
+        let upstream_addresses = self.upstream_addresses.read().unwrap();
+        let Some(health) = upstream_addresses.get(upstream) else {
+            return;
+        };
+        let mut stats = health.stats();
+        stats.connect_failures = stats.connect_failures.saturating_add(1);
+        let delay = retry::backoff_delay(
+            stats.connect_failures,
+            self.connect_backoff_base,
+            self.connect_backoff_max,
+        );
+        tracing::debug!(
+            "Not connecting to upstream {} again for {:?} ({} failures in a row)",
+            upstream,
+            delay,
+            stats.connect_failures
+        );
+        stats.backoff_until = Some(Instant::now() + delay);
+    }
+
+    /// Records the status of a response an upstream sent back and how long it took, for outlier
+    /// detection and dynamic weights.
+    pub fn record_response(&self, upstream: &str, status: http::StatusCode, elapsed: Duration) {
+        if let Some(health) = self.upstream_addresses.read().unwrap().get(upstream) {
+            let mut stats = health.stats();
+            stats.requests += 1;
+            stats.weight_stats.attempts += 1;
+            stats.weight_stats.responses += 1;
+            stats.weight_stats.latency += elapsed;
+            if status.is_server_error() {
+                stats.errors += 1;
+                stats.weight_stats.errors += 1;
+            }
+        }
+    }
+}
+
+/// What identifies a client for rate limiting
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum)]
+enum RateLimitKey {
+    /// The client's IP address, so every connection from an address shares one limit
+    Ip,
+    /// The client's IP address and the local port it connected to, giving each listener port a
+    /// separate limit
+    IpPort,
+    /// The client's IP address together with the value of the --rate-limit-header request header
+    /// (e.g. an API key)
+    Header,
+}
+
+/// Where a client stands against its rate limit after a request was counted
+struct RateLimitStatus {
+    /// Whether the request exceeded the limit and must be rejected
+    limited: bool,
+    limit: usize,
+    /// Requests the client may still make in the current window
+    remaining: usize,
+    /// Seconds until the current window ends and the count starts over
+    reset: u64,
+}
+
+impl RateLimitStatus {
+    /// Adds X-RateLimit-Limit, X-RateLimit-Remaining and X-RateLimit-Reset to a response, so that
+    /// clients can pace themselves, plus Retry-After if the request was rejected.
+    fn apply(&self, headers: &mut http::HeaderMap) {
+        headers.insert("x-ratelimit-limit", http::HeaderValue::from(self.limit));
+        headers.insert(
+            "x-ratelimit-remaining",
+            http::HeaderValue::from(self.remaining),
+        );
+        headers.insert("x-ratelimit-reset", http::HeaderValue::from(self.reset));
+        if self.limited {
+            headers.insert(
+                http::header::RETRY_AFTER,
+                http::HeaderValue::from(self.reset),
+            );
+        }
     }
 }
 
@@ -67,6 +793,10 @@ struct RateLimiterService {
     max_requests_per_minute: usize,
 
     client_request_count_map: Arc<Mutex<HashMap<String, HashMap<u64, usize>>>>,
+
+    /// If set, counts are kept in Redis so that all balancebeam instances using it share a limit.
+    /// The local map is only used as a fallback while Redis is unreachable.
+    redis: Option<redis::RedisClient>,
 }
 
 impl RateLimiterService {
@@ -80,28 +810,95 @@ impl RateLimiterService {
         now / std::time::Duration::from_secs(60).as_secs()
     }
 
-    pub async fn should_rate_limit(&mut self, client: &String, port: &String) -> bool {
-        if self.max_requests_per_minute == 0 {
-            return false;
+    /// Counts a request against the client's limit. Requests to a route with a rate-limit of its
+    /// own are counted in a separate bucket for that route, against its limit. Returns None if
+    /// the request isn't rate limited at all.
+    pub async fn should_rate_limit(
+        &self,
+        client: &str,
+        route: Option<&config::Route>,
+    ) -> Option<RateLimitStatus> {
+        let scope = route.and_then(|route| Some((route.prefix.as_str(), route.rate_limit?)));
+        let limit = scope.map_or(self.max_requests_per_minute, |(_, limit)| limit);
+        if limit == 0 {
+            return None;
+        };
+        let key = match scope {
+            Some((prefix, _)) => format!("{} {}", client, prefix),
+            None => client.to_string(),
         };
         let window = self.get_current_window();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let reset = 60 - now % 60;
+
+        if let Some(redis) = &self.redis {
+            let redis_key = format!("balancebeam:ratelimit:{}:{}", key, window);
+            match redis.incr_with_expiry(&redis_key, 60).await {
+                Ok(count) => {
+                    // Unlike local counts, Redis counts include rejected requests
+                    let count = count.max(0) as usize;
+                    return Some(RateLimitStatus {
+                        limited: count > limit,
+                        limit,
+                        remaining: limit.saturating_sub(count),
+                        reset,
+                    });
+                }
+                Err(err) => {
+                    tracing::warn!("Rate limiting with local counts, Redis failed: {}", err);
+                }
+            }
+        }
 
         let mut state = self.client_request_count_map.lock().await;
-        let key = format!("{}{}", client.clone(), port.clone());
         let bucket_count_for_client = state.entry(key.clone()).or_default();
         let count = bucket_count_for_client.entry(window).or_insert(0);
 
-        log::info!("For {} the count is {} in window {}", key, count, window);
-        if *count < self.max_requests_per_minute {
+        tracing::info!("For {} the count is {} in window {}", key, count, window);
+        let limited = *count >= limit;
+        if !limited {
             *count += 1;
-            false
-        } else {
-            true
         }
+        Some(RateLimitStatus {
+            limited,
+            limit,
+            remaining: limit - *count,
+            reset,
+        })
     }
 
-    pub async fn reset_counts(&mut self) {
-        self.client_request_count_map.lock().await.clear();
+    /// Returns how many clients the local map holds counts for.
+    pub async fn tracked_clients(&self) -> usize {
+        self.client_request_count_map.lock().await.len()
+    }
+
+    /// Returns how long it is until the current window ends.
+    fn until_next_window(&self) -> Duration {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        Duration::from_secs(60 - now % 60)
+    }
+
+    /// Drops counts for windows that have ended, and then clients with no counts left, so that
+    /// the map only holds clients seen in the current window.
+    pub async fn prune(&self) {
+        let window = self.get_current_window();
+        let mut state = self.client_request_count_map.lock().await;
+        let clients_before = state.len();
+        for buckets in state.values_mut() {
+            buckets.retain(|bucket_window, _| *bucket_window >= window);
+        }
+        state.retain(|_, buckets| !buckets.is_empty());
+        tracing::debug!(
+            "Pruned rate limiter state: {} of {} clients left",
+            state.len(),
+            clients_before
+        );
     }
 }
 
@@ -110,50 +907,292 @@ async fn main() {
     // Initialize the logging library. You can print log messages using the `log` macros:
     // https://docs.rs/log/0.4.8/log/ You are welcome to continue using print! statements; this
     // just looks a little prettier.
-    if let Err(_) = std::env::var("RUST_LOG") {
+    if std::env::var("RUST_LOG").is_err() {
         std::env::set_var("RUST_LOG", "debug");
     }
     pretty_env_logger::init();
+    logging::init();
 
     // Parse the command line arguments passed to this program
     let options = CmdOptions::parse();
-    if options.upstream.len() < 1 {
-        log::error!("At least one upstream server must be specified using the --upstream option.");
+    if let Some(Command::Bench(bench_options)) = options.command {
+        if let Err(err) = bench::run(bench_options).await {
+            tracing::error!("Benchmark failed: {}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+    let mut upstream_groups: Vec<(u32, Vec<String>, bool, Vec<String>)> = options
+        .upstream_group
+        .iter()
+        .map(|(priority, upstreams)| (*priority, upstreams.clone(), false, Vec::new()))
+        .collect();
+    upstream_groups.push((0, options.upstream.clone(), false, Vec::new()));
+    upstream_groups.push((0, options.canary_upstream.clone(), true, Vec::new()));
+    for (countries, upstreams) in &options.geo_pool {
+        upstream_groups.push((0, upstreams.clone(), false, countries.clone()));
+    }
+    let mut upstream_address_map: HashMap<String, UpstreamHealth> = HashMap::new();
+    for (priority, upstreams, canary, countries) in upstream_groups {
+        for address in upstreams {
+            if upstream_address_map.contains_key(&address) {
+                tracing::error!("Upstream {} was specified more than once", address);
+                std::process::exit(1);
+            }
+            upstream_address_map.insert(
+                address,
+                UpstreamHealth::new(priority, canary, countries.clone()),
+            );
+        }
+    }
+    if upstream_address_map.is_empty() && options.discover.is_empty() {
+        tracing::error!(
+            "At least one upstream server must be specified using the --upstream or --discover \
+            option."
+        );
         std::process::exit(1);
     }
 
+    // Upstreams given as hostnames are replaced by the addresses they resolve to, so that every
+    // member of a DNS round-robin pool is balanced and health checked on its own. A hostname that
+    // can't be resolved yet stays in the pool as is until it can.
+    let mut resolved_upstreams: HashMap<String, ResolvedUpstream> = HashMap::new();
+    let hostnames: Vec<String> = upstream_address_map
+        .keys()
+        .filter(|address| dns::is_hostname(address))
+        .cloned()
+        .collect();
+    for hostname in hostnames {
+        let health = &upstream_address_map[&hostname];
+        let mut resolved = ResolvedUpstream {
+            priority: health.priority,
+            canary: health.canary,
+            countries: health.countries.clone(),
+            members: vec![hostname.clone()],
+        };
+        match dns::resolve(&hostname).await {
+            Ok(addresses) => {
+                resolved.update(&hostname, addresses, &mut upstream_address_map);
+            }
+            Err(err) => tracing::warn!("Could not resolve upstream {}: {}", hostname, err),
+        }
+        resolved_upstreams.insert(hostname, resolved);
+    }
+
+    // Discovered upstreams are looked up once before we start listening, so that there is
+    // something to route to straight away, and then kept up to date in the background
+    let mut discovered_upstreams = Vec::new();
+    for source in &options.discover {
+        let mut discovered = ResolvedUpstream {
+            priority: 0,
+            canary: false,
+            countries: Vec::new(),
+            members: Vec::new(),
+        };
+        let mut index = None;
+        match discovery::lookup(source, None).await {
+            Ok((addresses, next_index)) => {
+                discovered.update(&source.to_string(), addresses, &mut upstream_address_map);
+                index = next_index;
+            }
+            Err(err) => tracing::warn!("Could not discover upstreams from {}: {}", source, err),
+        }
+        discovered_upstreams.push((source.clone(), discovered, index));
+    }
+
     // Start listening for connections
-    let listener = match TcpListener::bind(&options.bind).await {
-        Ok(listener) => listener,
+    let listener = match listener::open(&options.bind, options.reuse_port) {
+        Ok((listener, source)) => {
+            let address = listener
+                .local_addr()
+                .map_or_else(|_| options.bind.clone(), |addr| addr.to_string());
+            match source {
+                listener::Source::Systemd => {
+                    tracing::info!("Listening on {} (from systemd)", address)
+                }
+                listener::Source::Inherited => {
+                    tracing::info!("Listening on {} (inherited from previous process)", address)
+                }
+                listener::Source::Bound => tracing::info!("Listening for requests on {}", address),
+            }
+            listener
+        }
         Err(err) => {
-            log::error!("Could not bind to {}: {}", options.bind, err);
+            tracing::error!("Could not bind to {}: {}", options.bind, err);
             std::process::exit(1);
         }
     };
-    log::info!("Listening for requests on {}", options.bind);
 
-    let upstream_address_map = move || -> HashMap<String, bool> {
-        let mut map = HashMap::new();
-        for address in options.upstream {
-            map.insert(address, true);
+    let active_health_check_method =
+        match http::Method::from_bytes(options.active_health_check_method.as_bytes()) {
+            Ok(method) => method,
+            Err(_) => {
+                tracing::error!(
+                    "Invalid active health check method {}",
+                    options.active_health_check_method
+                );
+                std::process::exit(1);
+            }
+        };
+
+    let upstream_addresses = Arc::new(std::sync::RwLock::new(upstream_address_map));
+
+    let config = match &options.config {
+        Some(path) => match config::Config::from_file(path) {
+            Ok(config) => config,
+            Err(err) => {
+                tracing::error!("Could not load config file {}: {}", path, err);
+                std::process::exit(1);
+            }
+        },
+        None => config::Config::default(),
+    };
+
+    let geo_filtering = !options.geo_allow.is_empty()
+        || !options.geo_deny.is_empty()
+        || !options.geo_pool.is_empty();
+    let geoip = match &options.geoip_db {
+        Some(path) => match geoip::GeoIpDb::open(path) {
+            Ok(db) => Some(Arc::new(db)),
+            Err(err) => {
+                tracing::error!("Could not load GeoIP database {}: {}", path, err);
+                std::process::exit(1);
+            }
+        },
+        None if geo_filtering => {
+            tracing::error!("--geo-allow, --geo-deny and --geo-pool require --geoip-db");
+            std::process::exit(1);
         }
-        map
-    }();
+        None => None,
+    };
 
-    let upstream_addresses = Arc::new(Mutex::new(upstream_address_map));
+    let htpasswd = match &options.htpasswd {
+        Some(path) => match basic_auth::Htpasswd::from_file(path) {
+            Ok(htpasswd) => Some(Arc::new(htpasswd)),
+            Err(err) => {
+                tracing::error!("Could not load htpasswd file {}: {}", path, err);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
 
-    let rate_limiter_service = Arc::new(Mutex::new(RateLimiterService {
+    let mut plugins = Vec::new();
+    for path in &options.plugin {
+        match plugin::Plugin::from_file(path) {
+            Ok(plugin) => plugins.push(plugin),
+            Err(err) => {
+                tracing::error!("Could not load plugin {}: {}", path, err);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let drains = drain::Drains::default();
+    if let Some(path) = &options.drain_file {
+        if let Err(err) = drains.load_file(path) {
+            tracing::error!("Could not read drain file {}: {}", path, err);
+            std::process::exit(1);
+        }
+    }
+
+    let error_pages = match &options.error_page_dir {
+        Some(dir) => match error_pages::ErrorPages::from_dir(dir) {
+            Ok(error_pages) => error_pages,
+            Err(err) => {
+                tracing::error!("Could not load error pages from {}: {}", dir, err);
+                std::process::exit(1);
+            }
+        },
+        None => error_pages::ErrorPages::default(),
+    };
+
+    if options.rate_limit_key == RateLimitKey::Header && options.rate_limit_header.is_none() {
+        tracing::error!("--rate-limit-key header requires --rate-limit-header");
+        std::process::exit(1);
+    }
+    let redis = match &options.rate_limit_backend {
+        Some(url) => match redis::RedisClient::from_url(url) {
+            Ok(client) => Some(client),
+            Err(err) => {
+                tracing::error!("Invalid --rate-limit-backend: {}", err);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+    let rate_limiter_service = Arc::new(RateLimiterService {
         max_requests_per_minute: options.max_requests_per_minute,
         client_request_count_map: Arc::new(Mutex::new(HashMap::new())),
-    }));
+        redis,
+    });
 
     // Handle incoming connections
     let state = Arc::new(ProxyState {
         upstream_addresses,
         active_health_check_interval: options.active_health_check_interval,
         active_health_check_path: options.active_health_check_path,
+        active_health_check_method,
+        active_health_check_statuses: options.active_health_check_status,
+        active_health_check_body: options.active_health_check_body,
+        active_health_check_timeout: options.active_health_check_timeout,
+        active_health_check_jitter: options.active_health_check_jitter,
         max_requests_per_minute: options.max_requests_per_minute,
-        next_connection: Arc::new(Mutex::new(0)),
+        max_retries: options.max_retries,
+        dynamic_weight_interval: (!options.dynamic_weight_interval.is_zero())
+            .then_some(options.dynamic_weight_interval),
+        dynamic_weight_min: options.dynamic_weight_min.clamp(0.01, 1.0),
+        retry_budget: Arc::new(retry::RetryBudget::new(options.retry_budget_percent)),
+        connect_backoff_base: options.connect_backoff_base,
+        connect_backoff_max: options.connect_backoff_max,
+        max_upstream_concurrency: options.max_upstream_concurrency,
+        in_flight: Arc::new(std::sync::RwLock::new(HashMap::new())),
+        drains: Arc::new(drains),
+        upstream_connect_timeout: options.upstream_connect_timeout,
+        upstream_write_timeout: options.upstream_write_timeout,
+        upstream_read_timeout: options.upstream_read_timeout,
+        request_limits: request::Limits {
+            max_headers_size: options.max_header_size,
+            max_num_headers: options.max_header_count,
+            max_body_size: options.max_body_size,
+        },
+        decompress_requests: options.decompress_requests,
+        client_idle_timeout: (!options.client_idle_timeout.is_zero())
+            .then_some(options.client_idle_timeout),
+        max_requests_per_connection: (options.max_requests_per_connection > 0)
+            .then_some(options.max_requests_per_connection),
+        max_connections_per_client: options.max_connections_per_client,
+        client_connections: Arc::new(std::sync::Mutex::new(HashMap::new())),
+        passive_unhealthy_threshold: options.passive_unhealthy_threshold.max(1),
+        passive_window: options.passive_window,
+        outlier_ratio: options.outlier_ratio,
+        outlier_min_requests: options.outlier_min_requests.max(1),
+        outlier_interval: options.outlier_interval,
+        outlier_cooldown: options.outlier_cooldown,
+        canary_percent: Arc::new(AtomicUsize::new(options.canary_percent as usize)),
+        canary_header: options.canary_header,
+        config: Arc::new(config),
+        error_pages: Arc::new(error_pages),
+        latency: Arc::new(metrics::LatencyMetrics::default()),
+        traffic: Arc::new(metrics::TrafficMetrics::default()),
+        trusted_proxies: options.trusted_proxies,
+        rate_limit_exempt: options.rate_limit_exempt,
+        rate_limit_key: options.rate_limit_key,
+        rate_limit_header: options.rate_limit_header,
+        forwarded_for: options.forwarded_for,
+        geoip,
+        geo_allow: uppercase(options.geo_allow),
+        geo_deny: uppercase(options.geo_deny),
+        htpasswd,
+        auth_realm: options.auth_realm,
+        plugins: Arc::new(plugins),
+        server_header: options.server_header,
+        otlp: options
+            .otlp_endpoint
+            .map(|endpoint| Arc::new(otlp::Exporter::start(endpoint))),
+        next_connection: Arc::new(AtomicUsize::new(0)),
+        active_connections: Arc::new(AtomicUsize::new(0)),
+        started: Instant::now(),
         rate_limiter_service,
     });
     //let state_mutex = Arc::new(Mutex::new(state));
@@ -163,193 +1202,1559 @@ async fn main() {
     let health_state_clone = Arc::clone(&state);
     tokio::spawn(async move {
         loop {
-            tokio::time::sleep(std::time::Duration::from_secs(
-                health_state_clone.active_health_check_interval as u64,
-            ))
-            .await;
+            tokio::time::sleep(health_check_delay(&health_state_clone)).await;
             perform_health_check(&health_state_clone).await;
         }
     });
 
+    if state.outlier_ratio > 0.0 {
+        let outlier_state_clone = Arc::clone(&state);
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(outlier_state_clone.outlier_interval).await;
+                detect_outliers(&outlier_state_clone).await;
+            }
+        });
+    }
+
+    if !options.dns_refresh_interval.is_zero() && !resolved_upstreams.is_empty() {
+        let dns_state_clone = Arc::clone(&state);
+        let interval = options.dns_refresh_interval;
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                refresh_dns(&dns_state_clone, &mut resolved_upstreams).await;
+            }
+        });
+    }
+
+    for (source, discovered, index) in discovered_upstreams {
+        if matches!(source, discovery::Source::Srv { .. }) && options.dns_refresh_interval.is_zero()
+        {
+            continue;
+        }
+        let discovery_state_clone = Arc::clone(&state);
+        let interval = options.dns_refresh_interval;
+        tokio::spawn(watch_discovery(
+            discovery_state_clone,
+            source,
+            discovered,
+            index,
+            interval,
+        ));
+    }
+
+    // Expired rate limiting windows would otherwise pile up for every client ever seen
+    let route_rate_limits = state
+        .config
+        .routes
+        .iter()
+        .any(|route| route.rate_limit.is_some_and(|limit| limit > 0));
+    if options.max_requests_per_minute > 0 || route_rate_limits {
+        let rate_limit_state_clone = Arc::clone(&state);
+        tokio::spawn(async move {
+            loop {
+                // Prune just after each window ends, when its counts have all become stale
+                let rate_limiter = &rate_limit_state_clone.rate_limiter_service;
+                tokio::time::sleep(rate_limiter.until_next_window()).await;
+                rate_limiter.prune().await;
+            }
+        });
+    }
+
+    if let Some(interval) = state.dynamic_weight_interval {
+        let weight_state_clone = Arc::clone(&state);
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                adjust_weights(&weight_state_clone).await;
+            }
+        });
+    }
+
+    if !options.latency_log_interval.is_zero() {
+        let latency_state_clone = Arc::clone(&state);
+        let interval = options.latency_log_interval;
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                latency_state_clone.latency.log_summary().await;
+                latency_state_clone.traffic.log_summary().await;
+            }
+        });
+    }
+
+    let mut admin_task = match &options.admin_bind {
+        Some(admin_bind) => match serve_admin_api(admin_bind, &state).await {
+            Ok(task) => Some(task),
+            Err(err) => {
+                tracing::error!("Could not bind admin API to {}: {}", admin_bind, err);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    let connection_limit = (options.max_connections > 0)
+        .then(|| Arc::new(tokio::sync::Semaphore::new(options.max_connections)));
+    let mut sigterm = signal(SignalKind::terminate()).expect("Could not install SIGTERM handler");
+    let mut sigusr1 =
+        signal(SignalKind::user_defined1()).expect("Could not install SIGUSR1 handler");
+    let mut sigusr2 =
+        signal(SignalKind::user_defined2()).expect("Could not install SIGUSR2 handler");
     loop {
-        if let Ok((stream, _)) = listener.accept().await {
-            let state = Arc::clone(&state);
-            tokio::spawn(async move {
-                //state.rate_limiter_service.lock().await.reset_counts().await;
-                handle_connection(stream, state).await;
-            });
+        tokio::select! {
+            accepted = accept_connection(&listener, connection_limit.as_ref()) => {
+                if let Ok((stream, client_addr, permit)) = accepted {
+                    let state = Arc::clone(&state);
+                    state.active_connections.fetch_add(1, Ordering::SeqCst);
+                    let connection_span =
+                        tracing::info_span!("connection", client = %client_addr);
+                    tokio::spawn(
+                        async move {
+                            handle_connection(stream, Arc::clone(&state)).await;
+                            state.active_connections.fetch_sub(1, Ordering::SeqCst);
+                            drop(permit);
+                        }
+                        .instrument(connection_span),
+                    );
+                }
+            }
+            _ = sigterm.recv() => {
+                tracing::info!("Received SIGTERM, no longer accepting connections");
+                break;
+            }
+            _ = sigusr1.recv() => {
+                match &options.drain_file {
+                    Some(path) => match state.drains.load_file(path) {
+                        Ok(count) => tracing::info!(
+                            "Received SIGUSR1, reread drain file {} ({} upstreams)",
+                            path,
+                            count
+                        ),
+                        // Keep draining what the file listed before, rather than suddenly
+                        // sending traffic to upstreams that may be on their way down
+                        Err(err) => tracing::error!("Could not reread drain file {}: {}", path, err),
+                    },
+                    None => tracing::warn!("Received SIGUSR1, but no --drain-file was given"),
+                }
+            }
+            _ = sigusr2.recv() => {
+                // The admin listener isn't handed over, so release its port for the new process
+                if let Some(task) = admin_task.take() {
+                    task.abort();
+                    let _ = task.await;
+                }
+                match listener::spawn_successor(&listener) {
+                    Ok(pid) => {
+                        tracing::info!("Received SIGUSR2, handed listener to new process {}", pid);
+                        break;
+                    }
+                    Err(err) => {
+                        tracing::error!("Could not start new process: {}", err);
+                        if let Some(admin_bind) = &options.admin_bind {
+                            admin_task = serve_admin_api(admin_bind, &state).await.ok();
+                        }
+                    }
+                }
+            }
         }
     }
+
+    drop(listener);
+    drain_connections(&state, options.shutdown_timeout).await;
 }
 
-async fn perform_health_check(state: &Arc<ProxyState>) {
-    let mut upstream_addresses = state.upstream_addresses.lock().await;
-    let client = reqwest::Client::new();
-    for (upstream, available) in upstream_addresses.iter_mut() {
-        let request_path = state.active_health_check_path.clone();
-        let response = client
-            .get(&format!("http://{}/{}", upstream, request_path))
-            .header("Host", upstream)
-            .send()
-            .await
-            .ok();
+fn uppercase(countries: Vec<String>) -> Vec<String> {
+    countries
+        .into_iter()
+        .map(|country| country.to_ascii_uppercase())
+        .collect()
+}
+
+/// Accepts the next client connection. If --max-connections is set, a connection slot is taken
+/// first, so that while all slots are in use new connections wait in the kernel's listen backlog
+/// instead of each getting a task.
+async fn accept_connection(
+    listener: &TcpListener,
+    limit: Option<&Arc<tokio::sync::Semaphore>>,
+) -> std::io::Result<(
+    TcpStream,
+    std::net::SocketAddr,
+    Option<tokio::sync::OwnedSemaphorePermit>,
+)> {
+    let permit = match limit {
+        Some(limit) => {
+            if limit.available_permits() == 0 {
+                tracing::info!("At the connection limit, waiting for a connection to close");
+            }
+            Some(Arc::clone(limit).acquire_owned().await.unwrap())
+        }
+        None => None,
+    };
+    let (stream, client_addr) = listener.accept().await?;
+    Ok((stream, client_addr, permit))
+}
+
+/// Binds the admin API listener and spawns a task serving it. Aborting the returned task closes
+/// the listener.
+async fn serve_admin_api(
+    admin_bind: &str,
+    state: &Arc<ProxyState>,
+) -> std::io::Result<tokio::task::JoinHandle<()>> {
+    let admin_listener = TcpListener::bind(admin_bind).await?;
+    tracing::info!("Serving admin API on {}", admin_bind);
+    let admin_state = Arc::clone(state);
+    Ok(tokio::spawn(async move {
+        loop {
+            if let Ok((stream, client_addr)) = admin_listener.accept().await {
+                let state = Arc::clone(&admin_state);
+                let admin_span = tracing::info_span!("admin", client = %client_addr);
+                tokio::spawn(
+                    async move {
+                        admin::handle_connection(stream, &state).await;
+                    }
+                    .instrument(admin_span),
+                );
+            }
+        }
+    }))
+}
 
-        if response.is_some() {
-            *available = response.unwrap().status().as_u16() == 200;
-            log::info!("Upstream {:?} is available: {:?}", *upstream, *available);
+/// Waits for in-flight client connections to finish, giving up after `timeout`.
+async fn drain_connections(state: &ProxyState, timeout: Duration) {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let remaining = state.active_connections.load(Ordering::SeqCst);
+        if remaining == 0 {
+            tracing::info!("All connections closed, exiting");
+            return;
         }
+        if Instant::now() >= deadline {
+            tracing::warn!("Exiting with {} connections still open", remaining);
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
     }
 }
 
-async fn connect_to_upstream(state: Arc<ProxyState>) -> Result<Vec<TcpStream>, std::io::Error> {
-    let mut upstream_addresses = state.upstream_addresses.lock().await;
-    //let upstream_idx = rng.gen_range(0..state.upstream_addresses.len());
-    //let upstream_ip = &state.upstream_addresses[upstream_idx];
-    let mut stream: Option<TcpStream>;
-    let mut streams: Vec<TcpStream> = Vec::new();
-    for (upstream_ip, available) in upstream_addresses.iter_mut() {
-        if *available {
-            stream = match TcpStream::connect(upstream_ip).await {
-                Ok(tcp_stream) => Some(tcp_stream),
-                Err(err) => {
-                    log::warn!("Failed to connect to upstream {}: {}", upstream_ip, err);
-                    *available = false;
-                    None
+/// An upstream given as a hostname or a --discover source, along with the pool members it
+/// currently resolves to
+struct ResolvedUpstream {
+    /// Settings that members inherit from the hostname's --upstream/--upstream-group/... option
+    priority: u32,
+    canary: bool,
+    countries: Vec<String>,
+    /// Addresses this hostname or source added to the upstream map
+    members: Vec<String>,
+}
+
+impl ResolvedUpstream {
+    /// Brings the upstream map in line with a new set of addresses for this upstream: addresses
+    /// that appeared are added as fresh upstreams, and members that disappeared are removed.
+    /// Addresses that are already in the map for some other reason are left to their owner.
+    fn update(
+        &mut self,
+        source: &str,
+        addresses: Vec<String>,
+        upstream_addresses: &mut HashMap<String, UpstreamHealth>,
+    ) {
+        let mut members = Vec::new();
+        for address in addresses.iter() {
+            if self.members.contains(address) {
+                members.push(address.clone());
+            } else if !upstream_addresses.contains_key(address) {
+                tracing::info!("Adding upstream {} (from {})", address, source);
+                upstream_addresses.insert(
+                    address.clone(),
+                    UpstreamHealth::new(self.priority, self.canary, self.countries.clone()),
+                );
+                members.push(address.clone());
+            }
+        }
+        for member in &self.members {
+            if !addresses.contains(member) {
+                tracing::info!("Removing upstream {} (no longer in {})", member, source);
+                upstream_addresses.remove(member);
+            }
+        }
+        self.members = members;
+    }
+}
+
+/// Re-resolves every upstream given as a hostname and updates the pool to match. If a lookup
+/// fails, the hostname keeps its current members rather than dropping out of the pool.
+async fn refresh_dns(
+    state: &ProxyState,
+    resolved_upstreams: &mut HashMap<String, ResolvedUpstream>,
+) {
+    for (hostname, resolved) in resolved_upstreams.iter_mut() {
+        match dns::resolve(hostname).await {
+            Ok(addresses) if addresses == resolved.members => {}
+            Ok(addresses) => {
+                let mut upstream_addresses = state.upstream_addresses.write().unwrap();
+                resolved.update(hostname, addresses, &mut upstream_addresses);
+            }
+            Err(err) => tracing::warn!(
+                "Could not re-resolve upstream {}, keeping its current members: {}",
+                hostname,
+                err
+            ),
+        }
+    }
+}
+
+/// Keeps the members of a --discover source up to date. SRV records are re-queried every
+/// `interval`; Consul is asked with blocking queries, which return as soon as the service changes.
+/// After an error, the source keeps its current members and is retried a little later.
+async fn watch_discovery(
+    state: Arc<ProxyState>,
+    source: discovery::Source,
+    mut discovered: ResolvedUpstream,
+    mut index: Option<u64>,
+    interval: Duration,
+) {
+    let blocking = matches!(source, discovery::Source::Consul { .. });
+    let name = source.to_string();
+    loop {
+        if !blocking {
+            tokio::time::sleep(interval).await;
+        }
+        let lookup = discovery::lookup(&source, Some(index.unwrap_or(0)));
+        let result = match tokio::time::timeout(discovery::CONSUL_WAIT * 2, lookup).await {
+            Ok(result) => result,
+            Err(_) => Err(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                "lookup timed out",
+            )),
+        };
+        match result {
+            Ok((addresses, next_index)) => {
+                // Consul indexes only ever grow; if one goes backwards, the watch starts over
+                index = next_index.map(|next| if next < index.unwrap_or(0) { 0 } else { next });
+                let mut upstream_addresses = state.upstream_addresses.write().unwrap();
+                discovered.update(&name, addresses, &mut upstream_addresses);
+            }
+            Err(err) => {
+                tracing::warn!(
+                    "Could not discover upstreams from {}, keeping its current members: {}",
+                    name,
+                    err
+                );
+                if blocking {
+                    tokio::time::sleep(Duration::from_secs(5)).await;
                 }
+            }
+        }
+    }
+}
+
+/// Compares each upstream's error rate over the last outlier detection interval against the pool
+/// average, and ejects upstreams that are doing much worse than their peers for outlier_cooldown.
+/// This catches upstreams that pass health checks but fail real traffic. The counters are reset
+/// afterwards so that every interval is judged on its own.
+async fn detect_outliers(state: &ProxyState) {
+    let upstream_addresses = state.upstream_addresses.read().unwrap();
+    let total_requests: usize = upstream_addresses
+        .values()
+        .map(|h| h.stats().requests)
+        .sum();
+    let total_errors: usize = upstream_addresses.values().map(|h| h.stats().errors).sum();
+    if total_requests > 0 && total_errors > 0 {
+        let average_error_rate = total_errors as f64 / total_requests as f64;
+        let mut routable = upstream_addresses
+            .values()
+            .filter(|health| health.is_routable())
+            .count();
+        for (upstream, health) in upstream_addresses.iter() {
+            if !health.is_routable() {
+                continue;
+            }
+            let mut stats = health.stats();
+            if stats.requests < state.outlier_min_requests
+                || stats.error_rate() <= average_error_rate * state.outlier_ratio
+            {
+                continue;
+            }
+            // Never eject the last upstream we could send traffic to
+            if routable <= 1 {
+                tracing::warn!(
+                    "Upstream {} is an outlier but is the last routable upstream; keeping it",
+                    upstream
+                );
+                continue;
+            }
+            tracing::warn!(
+                "Ejecting upstream {} for {:?}: error rate {:.2} vs. pool average {:.2}",
+                upstream,
+                state.outlier_cooldown,
+                stats.error_rate(),
+                average_error_rate
+            );
+            stats.ejected_until = Some(Instant::now() + state.outlier_cooldown);
+            routable -= 1;
+        }
+    }
+    for health in upstream_addresses.values() {
+        let mut stats = health.stats();
+        stats.requests = 0;
+        stats.errors = 0;
+    }
+}
+
+/// Upstreams that served fewer requests than this since the last weight adjustment keep their
+/// weight, since a handful of requests says little about how an upstream is doing
+const WEIGHT_MIN_SAMPLES: usize = 5;
+
+/// How much errors count against an upstream's weight: an upstream whose requests all fail costs
+/// this many times more than its response times alone suggest
+const WEIGHT_ERROR_PENALTY: f64 = 10.0;
+
+/// Recomputes upstream weights from what each upstream served since the last adjustment, so that
+/// traffic shifts away from slow or failing upstreams, and back once they recover. An upstream's
+/// cost is its mean response time, inflated by its error rate; the cheapest upstream gets weight 1
+/// and the others proportionally less, but never less than dynamic_weight_min, so that they keep
+/// getting enough traffic to notice a recovery. Weights only move halfway to their new value each
+/// time, which keeps traffic from swinging back and forth between upstreams.
+async fn adjust_weights(state: &ProxyState) {
+    let upstream_addresses = state.upstream_addresses.read().unwrap();
+    let costs: HashMap<String, f64> = upstream_addresses
+        .iter()
+        .filter_map(|(upstream, health)| {
+            let stats = &health.stats().weight_stats;
+            if stats.attempts < WEIGHT_MIN_SAMPLES {
+                return None;
+            }
+            let error_rate = stats.errors as f64 / stats.attempts as f64;
+            // Upstreams that never answered are as bad as they can be
+            let mean_latency_ms = if stats.responses > 0 {
+                (stats.latency.as_secs_f64() * 1000.0 / stats.responses as f64).max(1.0)
+            } else {
+                f64::INFINITY
             };
-            if stream.is_some() {
-                log::info!("{:?}", stream);
-                streams.push(stream.unwrap());
-            };
+            let cost = mean_latency_ms * (1.0 + WEIGHT_ERROR_PENALTY * error_rate);
+            Some((upstream.clone(), cost))
+        })
+        .collect();
+    let best_cost = costs.values().copied().fold(f64::INFINITY, f64::min);
+    for (upstream, health) in upstream_addresses.iter() {
+        let mut stats = health.stats();
+        stats.weight_stats = WeightStats::default();
+        let Some(cost) = costs.get(upstream) else {
+            continue;
+        };
+        let target = if best_cost.is_finite() {
+            (best_cost / cost).max(state.dynamic_weight_min)
+        } else {
+            1.0
+        };
+        let weight = (stats.weight + target) / 2.0;
+        if (weight - stats.weight).abs() >= 0.05 {
+            tracing::info!(
+                "Upstream {} weight {:.2} -> {:.2} (cost {:.1}ms, best {:.1}ms)",
+                upstream,
+                stats.weight,
+                weight,
+                cost,
+                best_cost
+            );
         }
+        stats.weight = weight;
     }
+}
 
-    if streams.is_empty() {
-        return Err(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            "couldn't connect to any upstream server",
-        ));
+/// Picks an index into `weights` at random, with probability proportional to its weight.
+fn weighted_index(weights: &[f64]) -> usize {
+    let total: f64 = weights.iter().sum();
+    let mut point = rand::thread_rng().gen_range(0.0..total);
+    for (idx, weight) in weights.iter().enumerate() {
+        if point < *weight {
+            return idx;
+        }
+        point -= weight;
+    }
+    weights.len() - 1
+}
+
+/// Returns how long to wait before the next active health check sweep: the configured interval
+/// plus a random amount of jitter.
+fn health_check_delay(state: &ProxyState) -> Duration {
+    let interval = state.active_health_check_interval;
+    let max_jitter = state.active_health_check_jitter.as_millis() as u64;
+    if max_jitter == 0 {
+        return interval;
+    }
+    interval + Duration::from_millis(rand::thread_rng().gen_range(0..=max_jitter))
+}
+
+/// Runs an active health check against every upstream concurrently, then applies the results.
+/// The upstream map is only read-locked to take a snapshot of the upstreams and to record results,
+/// so request routing is never blocked on a health check sweep.
+async fn perform_health_check(state: &Arc<ProxyState>) {
+    let upstreams: Vec<String> = state
+        .upstream_addresses
+        .read()
+        .unwrap()
+        .keys()
+        .cloned()
+        .collect();
+    let results = join_all(upstreams.iter().map(|upstream| {
+        check_upstream(state, upstream)
+            .instrument(tracing::info_span!("health_check", upstream = %upstream))
+    }))
+    .await;
+
+    let upstream_addresses = state.upstream_addresses.read().unwrap();
+    for (upstream, passed) in upstreams.iter().zip(results) {
+        let Some(health) = upstream_addresses.get(upstream) else {
+            continue;
+        };
+        if passed {
+            // A passing active check re-admits the upstream with a clean slate
+            health.stats().recent_failures.clear();
+        }
+        health.available.store(passed, Ordering::SeqCst);
+        tracing::info!("Upstream {:?} is available: {:?}", upstream, passed);
+    }
+}
+
+/// Sends an active health check to a single upstream. Returns whether it passed: an upstream that
+/// refuses the connection, can't be reached or times out fails, as does a bad response.
+async fn check_upstream(state: &ProxyState, upstream: &str) -> bool {
+    let check = health_check::send_request(
+        upstream,
+        &state.active_health_check_method,
+        &state.active_health_check_path,
+    );
+    match tokio::time::timeout(state.active_health_check_timeout, check).await {
+        Ok(Ok(response)) => health_check_passed(state, &response),
+        Ok(Err(error)) => {
+            tracing::warn!(
+                "Active health check of upstream {} failed: {:?}",
+                upstream,
+                error
+            );
+            false
+        }
+        Err(_) => {
+            tracing::warn!(
+                "Active health check of upstream {} timed out after {:?}",
+                upstream,
+                state.active_health_check_timeout
+            );
+            false
+        }
+    }
+}
+
+/// Decides whether an active health check response means the upstream is healthy: the status
+/// must be one of the accepted statuses, and if a body substring is configured, the body must
+/// contain it.
+fn health_check_passed(state: &ProxyState, response: &http::Response<Vec<u8>>) -> bool {
+    if !state
+        .active_health_check_statuses
+        .contains(&response.status().as_u16())
+    {
+        return false;
+    }
+    match &state.active_health_check_body {
+        Some(expected) => String::from_utf8_lossy(response.body()).contains(expected.as_str()),
+        None => true,
+    }
+}
+
+/// Decides whether a new client connection should be routed to the canary upstreams, based on its
+/// first request.
+fn choose_canary(state: &ProxyState, request: &http::Request<Vec<u8>>) -> bool {
+    let percent = state.canary_percent.load(Ordering::Relaxed);
+    if percent == 0 {
+        return false;
+    }
+    let header_value = state
+        .canary_header
+        .as_ref()
+        .and_then(|name| request.headers().get(name.as_str()));
+    let bucket = match header_value {
+        Some(value) => {
+            let mut hasher = std::collections::hash_map::DefaultHasher::new();
+            value.as_bytes().hash(&mut hasher);
+            (hasher.finish() % 100) as usize
+        }
+        None => rand::thread_rng().gen_range(0..100),
+    };
+    bucket < percent
+}
+
+/// Picks an upstream using round-robin (or at random by weight, if dynamic weights are enabled)
+/// over the highest-priority group of upstreams currently believed to be healthy, and opens a
+/// connection to it. Canary upstreams are only picked if
+/// `canary` is set (and stable upstreams only if it isn't), unless no upstream on the requested
+/// side is routable. If the connection fails, the failure is recorded for the passive
+/// health checks and another upstream is tried, until either a connection succeeds or no healthy
+/// upstreams remain. Upstreams listed in `exclude`, and upstreams backing off after failed
+/// connects, are never picked. Trying another upstream after a failed connect counts against the
+/// retry budget.
+///
+/// The upstream map is only read-locked while taking a snapshot of the healthy upstreams and while
+/// recording a failure, never while connecting.
+async fn connect_to_upstream(
+    state: &ProxyState,
+    exclude: &[String],
+    canary: bool,
+    country: Option<&str>,
+) -> Result<(TcpStream, String), std::io::Error> {
+    let mut tried = exclude.to_vec();
+    let mut timed_out = false;
+    loop {
+        let mut available_upstreams: Vec<(String, bool, f64)> = {
+            let upstream_addresses = state.upstream_addresses.read().unwrap();
+            let mut candidates: Vec<(&String, &UpstreamHealth)> = upstream_addresses
+                .iter()
+                .filter(|(upstream, health)| {
+                    health.is_routable()
+                        && !health.stats().is_backing_off()
+                        && !state.drains.is_draining(upstream)
+                        && !tried.contains(upstream)
+                })
+                .collect();
+            if candidates
+                .iter()
+                .all(|(upstream, _)| state.is_saturated(upstream))
+                && !candidates.is_empty()
+            {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::ResourceBusy,
+                    "all upstream servers are saturated",
+                ));
+            }
+            candidates.retain(|(upstream, _)| !state.is_saturated(upstream));
+            if candidates.iter().any(|(_, health)| health.canary == canary) {
+                candidates.retain(|(_, health)| health.canary == canary);
+            }
+            // Clients from a country with a geo pool go to that pool while it has routable
+            // upstreams; everyone else stays off the geo pools unless nothing else is left
+            let in_geo_pool = |health: &UpstreamHealth| {
+                country.is_some_and(|country| health.countries.iter().any(|c| c == country))
+            };
+            if candidates.iter().any(|(_, health)| in_geo_pool(health)) {
+                candidates.retain(|(_, health)| in_geo_pool(health));
+            } else if candidates
+                .iter()
+                .any(|(_, health)| health.countries.is_empty())
+            {
+                candidates.retain(|(_, health)| health.countries.is_empty());
+            }
+            // Only fail over to a lower-priority group once every upstream in the groups above
+            // it is unhealthy or has already failed us
+            let best_priority = candidates.iter().map(|(_, health)| health.priority).min();
+            candidates
+                .into_iter()
+                .filter(|(_, health)| Some(health.priority) == best_priority)
+                .map(|(upstream, health)| {
+                    let stats = health.stats();
+                    (upstream.clone(), stats.connect_failures > 0, stats.weight)
+                })
+                .collect()
+        };
+        if available_upstreams.is_empty() {
+            // Nothing was even worth trying: every upstream is down, backing off or excluded
+            if tried.len() == exclude.len() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::AddrNotAvailable,
+                    "no upstream server is available",
+                ));
+            }
+            return Err(std::io::Error::new(
+                if timed_out {
+                    std::io::ErrorKind::TimedOut
+                } else {
+                    std::io::ErrorKind::Other
+                },
+                "couldn't connect to any upstream server",
+            ));
+        }
+        if tried.len() > exclude.len() && !state.retry_budget.withdraw() {
+            tracing::warn!("Retry budget exhausted, not trying another upstream");
+            return Err(std::io::Error::new(
+                if timed_out {
+                    std::io::ErrorKind::TimedOut
+                } else {
+                    std::io::ErrorKind::Other
+                },
+                "retry budget exhausted",
+            ));
+        }
+        // HashMap iteration order is arbitrary, so sort to keep round-robin order stable
+        available_upstreams.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let idx = if state.dynamic_weight_interval.is_some() {
+            let weights: Vec<f64> = available_upstreams.iter().map(|(.., w)| *w).collect();
+            weighted_index(&weights)
+        } else {
+            state.get_connection_index(available_upstreams.len())
+        };
+        let (upstream_ip, recently_failed, _) = &available_upstreams[idx];
+        let connect_start = Instant::now();
+        let connect = TcpStream::connect(upstream_ip);
+        let connected = tokio::time::timeout(state.upstream_connect_timeout, connect)
+            .await
+            .unwrap_or_else(|_| {
+                Err(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    "connection timed out",
+                ))
+            });
+        match connected {
+            Ok(stream) => {
+                if *recently_failed {
+                    if let Some(health) = state.upstream_addresses.read().unwrap().get(upstream_ip)
+                    {
+                        let mut stats = health.stats();
+                        stats.connect_failures = 0;
+                        stats.backoff_until = None;
+                    }
+                }
+                state
+                    .latency
+                    .record(
+                        upstream_ip,
+                        metrics::Phase::Connect,
+                        connect_start.elapsed(),
+                    )
+                    .await;
+                return Ok((stream, upstream_ip.clone()));
+            }
+            Err(err) => {
+                tracing::warn!("Failed to connect to upstream {}: {}", upstream_ip, err);
+                timed_out = err.kind() == std::io::ErrorKind::TimedOut;
+                state.record_failure(upstream_ip);
+                state.record_connect_failure(upstream_ip);
+                tried.push(upstream_ip.clone());
+            }
+        }
     }
+}
 
-    Ok(streams)
+/// Finishes a client connection that may still have unread request data in it. Closing such a
+/// socket straight away makes the kernel reset the connection, and the client might never see the
+/// response we just sent, so stop writing and discard input (for a little while) first.
+async fn drain_and_close(stream: &mut TcpStream) {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let _ = stream.shutdown().await;
+    let drain = async {
+        let mut buffer = [0_u8; 4096];
+        while matches!(stream.read(&mut buffer).await, Ok(read) if read > 0) {}
+    };
+    let _ = tokio::time::timeout(Duration::from_secs(1), drain).await;
 }
 
 async fn send_response(client_conn: &mut TcpStream, response: &http::Response<Vec<u8>>) {
     let client_ip = client_conn.peer_addr().unwrap().ip().to_string();
-    log::info!(
+    tracing::info!(
         "{} <- {}",
         client_ip,
-        response::format_response_line(&response)
+        response::format_response_line(response)
     );
-    if let Err(error) = response::write_to_stream(&response, client_conn).await {
-        log::warn!("Failed to send response to client: {}", error);
-        return;
+    if let Err(error) = response::write_to_stream(response, client_conn).await {
+        tracing::warn!("Failed to send response to client: {}", error);
     };
 }
 
-async fn handle_connection(mut client_conn: TcpStream, state: Arc<ProxyState>) {
-    let client_ip = client_conn.peer_addr().unwrap().ip().to_string();
-    log::info!("Connection received from {}", client_ip);
-
-    // Open a connection to a random destination server
-    let mut upstream_conns = match connect_to_upstream(state.clone()).await {
-        Ok(stream) => stream,
-        Err(_error) => {
-            let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
-            send_response(&mut client_conn, &response).await;
-            return;
+/// Builds an error response generated by balancebeam itself, using the configured error page
+/// templates and the request's Accept header.
+fn make_error(
+    state: &ProxyState,
+    status: http::StatusCode,
+    request: &http::Request<Vec<u8>>,
+) -> http::Response<Vec<u8>> {
+    let accept = request
+        .headers()
+        .get("accept")
+        .and_then(|accept| accept.to_str().ok());
+    state.error_pages.render(status, accept)
+}
+
+/// A client connection, along with the upstream connection its requests are being proxied to
+struct ClientConnection {
+    stream: TcpStream,
+    /// Address of the client (or proxy) on the other end of `stream`
+    peer_ip: IpAddr,
+    /// The upstream connection is opened once the first request arrives, so that the balancing
+    /// strategy can take the request into account (e.g. for canary assignment). The client
+    /// connection then stays pinned to that upstream unless it fails.
+    upstream: Option<(TcpStream, String)>,
+    /// Whether this connection was assigned to the canary upstreams
+    canary: bool,
+    /// How many more requests may be read after the current one, if the connection is limited
+    requests_left: Option<usize>,
+    /// Whether the connection is closed after the current request, because the client asked for
+    /// that or (as an HTTP/1.0 client) didn't ask for it to be kept alive
+    closing: bool,
+    /// Whether the current request came from an HTTP/1.0 client, which needs to be told explicitly
+    /// that the connection is kept alive
+    http10: bool,
+    /// Whether the current request is a HEAD request, whose response has no body but describes
+    /// the body a GET would have had
+    head: bool,
+}
+
+impl ClientConnection {
+    /// Sends a response to the client. If the request being answered is the last one the
+    /// connection may send, the response tells the client that the connection is being closed.
+    ///
+    /// Bodies are sent with a Content-Length, since they are fully buffered by now; that way every
+    /// client, including HTTP/1.0 ones, can tell where a response ends without waiting for the
+    /// connection to close. The exception is a response with trailers going to an HTTP/1.1
+    /// client, which is sent chunked so that the trailers can follow the body.
+    async fn send_response(&mut self, response: &mut http::Response<Vec<u8>>) {
+        let status = response.status();
+        let has_body = !(self.head
+            || status.is_informational()
+            || status == http::StatusCode::NO_CONTENT
+            || status == http::StatusCode::NOT_MODIFIED);
+        let body_len = response.body().len();
+        let trailers = response
+            .extensions_mut()
+            .remove::<chunked::Trailers>()
+            .filter(|_| has_body && !self.http10);
+        let headers = response.headers_mut();
+        headers.remove(http::header::TRANSFER_ENCODING);
+        headers.remove(http::header::TRAILER);
+        if let Some(chunked::Trailers(trailers)) = &trailers {
+            chunked::frame_with_trailers(headers, trailers);
+        } else if has_body {
+            headers.insert(http::header::CONTENT_LENGTH, body_len.into());
         }
-    };
-    let mut upstream_ips = Vec::new();
-    for upstream_conn in upstream_conns.iter() {
-        upstream_ips.push(upstream_conn.peer_addr().unwrap().ip().to_string());
+        if self.closing || self.requests_left == Some(0) {
+            headers.insert(
+                http::header::CONNECTION,
+                http::HeaderValue::from_static("close"),
+            );
+        } else if self.http10 {
+            headers.insert(
+                http::header::CONNECTION,
+                http::HeaderValue::from_static("keep-alive"),
+            );
+        }
+        if let Some(trailers) = trailers {
+            response.extensions_mut().insert(trailers);
+        }
+        send_response(&mut self.stream, response).await;
     }
-    //log::info!("Working upstreams: {:?} {:?}", upstream_ips, upstream_conns);
+}
+
+async fn handle_connection(client_conn: TcpStream, state: Arc<ProxyState>) {
+    let peer_ip = client_conn.peer_addr().unwrap().ip();
+    tracing::info!("Connection received from {}", peer_ip);
+    let Some(_client_slot) = state.acquire_client_slot(peer_ip) else {
+        tracing::info!("Refusing connection from {}: too many connections", peer_ip);
+        let mut client_conn = client_conn;
+        // Read the client's first request before answering: closing the socket with unread data
+        // in it would reset the connection, and the client would never see the 429
+        let _ = tokio::time::timeout(
+            Duration::from_secs(1),
+            request::read_from_stream(&mut client_conn, &state.request_limits),
+        )
+        .await;
+        let mut response = state
+            .error_pages
+            .render(http::StatusCode::TOO_MANY_REQUESTS, None);
+        response.headers_mut().insert(
+            http::header::CONNECTION,
+            http::HeaderValue::from_static("close"),
+        );
+        send_response(&mut client_conn, &response).await;
+        return;
+    };
+    let mut conn = ClientConnection {
+        stream: client_conn,
+        peer_ip,
+        upstream: None,
+        canary: false,
+        requests_left: state.max_requests_per_connection,
+        closing: false,
+        http10: false,
+        head: false,
+    };
+
     // The client may now send us one or more requests. Keep trying to read requests until the
     // client hangs up or we get an error.
     loop {
-        let state = Arc::clone(&state);
-        let idx = state.get_connection_index(upstream_ips.len()).await;
-        //log::info!("routing to: {:?}", idx);
-        // Read a request from the client
-        let mut request = match request::read_from_stream(&mut client_conn).await {
+        // Read a request from the client, giving up on clients that go quiet
+        let read = request::read_from_stream(&mut conn.stream, &state.request_limits);
+        let read_result = match state.client_idle_timeout {
+            Some(idle_timeout) => match tokio::time::timeout(idle_timeout, read).await {
+                Ok(result) => result,
+                Err(_) => {
+                    tracing::debug!("Client was idle for {:?}, closing connection", idle_timeout);
+                    return;
+                }
+            },
+            None => read.await,
+        };
+        let request = match read_result {
             Ok(request) => request,
             // Handle case where client closed connection and is no longer sending requests
             Err(request::Error::IncompleteRequest(0)) => {
-                log::debug!("Client finished sending requests. Shutting down connection");
+                tracing::debug!("Client finished sending requests. Shutting down connection");
                 return;
             }
             // Handle I/O error in reading from the client
             Err(request::Error::ConnectionError(io_err)) => {
-                log::info!("Error reading request from client stream: {}", io_err);
+                tracing::info!("Error reading request from client stream: {}", io_err);
                 return;
             }
             Err(error) => {
-                log::debug!("Error parsing request: {:?}", error);
-                let response = response::make_http_error(match error {
-                    request::Error::IncompleteRequest(_)
-                    | request::Error::MalformedRequest(_)
-                    | request::Error::InvalidContentLength
-                    | request::Error::ContentLengthMismatch => http::StatusCode::BAD_REQUEST,
-                    request::Error::RequestBodyTooLarge => http::StatusCode::PAYLOAD_TOO_LARGE,
-                    request::Error::ConnectionError(_) => http::StatusCode::SERVICE_UNAVAILABLE,
-                });
-                send_response(&mut client_conn, &response).await;
+                tracing::debug!("Error parsing request: {:?}", error);
+                let response = state.error_pages.render(
+                    match error {
+                        request::Error::IncompleteRequest(_)
+                        | request::Error::MalformedRequest(_)
+                        | request::Error::InvalidContentLength
+                        | request::Error::ContentLengthMismatch
+                        | request::Error::MalformedChunkedBody => http::StatusCode::BAD_REQUEST,
+                        request::Error::RequestBodyTooLarge => http::StatusCode::PAYLOAD_TOO_LARGE,
+                        request::Error::HeadersTooLarge => {
+                            http::StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE
+                        }
+                        request::Error::ConnectionError(_) => http::StatusCode::SERVICE_UNAVAILABLE,
+                    },
+                    None,
+                );
+                send_response(&mut conn.stream, &response).await;
+                if matches!(
+                    error,
+                    request::Error::HeadersTooLarge | request::Error::RequestBodyTooLarge
+                ) {
+                    // The rest of the oversized request is still unread, so the connection can't
+                    // be used for another request
+                    drain_and_close(&mut conn.stream).await;
+                    return;
+                }
                 continue;
             }
         };
-        log::info!(
-            "{} -> {}: {}",
-            client_ip,
-            upstream_ips[idx],
-            request::format_request_line(&request)
+
+        // Everything logged while handling this request is tagged with its ID and trace ID, and
+        // with the upstream once one has been picked
+        let trace = trace_context::TraceContext::from_request(&request);
+        let request_span = tracing::info_span!(
+            "request",
+            request = NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed),
+            trace_id = %trace.trace_id,
+            forwarded_for = tracing::field::Empty,
+            upstream = tracing::field::Empty,
         );
+        let client_ip = forwarded::client_ip(&request, peer_ip, &state.trusted_proxies);
+        if client_ip != peer_ip {
+            request_span.record("forwarded_for", tracing::field::display(client_ip));
+        }
+        let method = request.method().to_string();
+        let path = request.uri().path().to_string();
+        let start = SystemTime::now();
+        conn.requests_left = conn.requests_left.map(|left| left.saturating_sub(1));
+        conn.closing = !hop_by_hop::keeps_alive(request.headers(), request.version());
+        conn.http10 = request.version() == http::Version::HTTP_10;
+        conn.head = request.method() == http::Method::HEAD;
+        let outcome = handle_request(request, &trace, client_ip, &mut conn, &state)
+            .instrument(request_span)
+            .await;
 
-        // Add X-Forwarded-For header so that the upstream server knows the client's IP address.
-        // (We're the ones connecting directly to the upstream server, so without this header, the
-        // upstream server will only know our IP, not the client's.)
-        request::extend_header_value(&mut request, "x-forwarded-for", &client_ip);
+        if let Some(exporter) = state.otlp.as_ref().filter(|_| trace.is_sampled()) {
+            exporter.export(otlp::Span {
+                trace_id: trace.trace_id,
+                span_id: trace.span_id,
+                parent_span_id: trace.parent_span_id,
+                method,
+                path,
+                status: outcome.status,
+                upstream: conn
+                    .upstream
+                    .as_ref()
+                    .map(|(_, upstream_ip)| upstream_ip.clone()),
+                start,
+                end: SystemTime::now(),
+            });
+        }
+        if !outcome.keep_open {
+            return;
+        }
+        if conn.closing {
+            tracing::debug!("Client asked for the connection to be closed");
+            return;
+        }
+        if conn.requests_left == Some(0) {
+            tracing::debug!("Client reached the maximum number of requests. Closing connection");
+            return;
+        }
+    }
+}
+
+/// How a request read by handle_connection was dealt with
+struct RequestOutcome {
+    /// Status of the response sent to the client
+    status: http::StatusCode,
+    /// Whether to keep reading requests from the client connection
+    keep_open: bool,
+}
+
+impl RequestOutcome {
+    fn new(response: &http::Response<Vec<u8>>, keep_open: bool) -> RequestOutcome {
+        RequestOutcome {
+            status: response.status(),
+            keep_open,
+        }
+    }
+}
+
+/// Proxies a single request read from a client connection.
+async fn handle_request(
+    mut request: http::Request<Vec<u8>>,
+    trace: &trace_context::TraceContext,
+    client_ip: IpAddr,
+    conn: &mut ClientConnection,
+    state: &ProxyState,
+) -> RequestOutcome {
+    // Unauthenticated clients are turned away before any upstream sees their request
+    if let Some(htpasswd) = &state.htpasswd {
+        if htpasswd.authenticate(&request).is_none() {
+            let mut response = basic_auth::challenge(
+                make_error(state, http::StatusCode::UNAUTHORIZED, &request),
+                &state.auth_realm,
+            );
+            conn.send_response(&mut response).await;
+            return RequestOutcome::new(&response, true);
+        }
+    }
 
-        let mut rate_limiter_service = state.rate_limiter_service.lock().await;
-        let port = client_conn.local_addr().unwrap().port().to_string();
-        if rate_limiter_service
-            .should_rate_limit(&client_ip, &port)
+    // The status route is answered by balancebeam itself, even if there's no upstream to talk to
+    if request.uri().path() == status::STATUS_PATH {
+        let mut response = status::make_status_response(state, false).await;
+        conn.send_response(&mut response).await;
+        return RequestOutcome::new(&response, true);
+    }
+
+    // The client's connection options apply to its connection to us, not to ours to the upstream,
+    // which stays open for the client's next request
+    hop_by_hop::strip(request.headers_mut());
+    // We pass trailers on, so the upstream may send them (TE is hop-by-hop, so it is listed in
+    // Connection)
+    request.headers_mut().insert(
+        http::header::CONNECTION,
+        http::HeaderValue::from_static("keep-alive, te"),
+    );
+    request
+        .headers_mut()
+        .insert(http::header::TE, http::HeaderValue::from_static("trailers"));
+
+    // Remember the host the client asked for, before any header rewriting happens, so that
+    // redirects from the upstream can be pointed back at it
+    let client_host = request
+        .headers()
+        .get("host")
+        .and_then(|host| host.to_str().ok())
+        .map(|host| host.to_string());
+
+    // Add X-Forwarded-For header so that the upstream server knows the client's IP address.
+    // (We're the ones connecting directly to the upstream server, so without this header, the
+    // upstream server will only know our IP, not the client's.)
+    forwarded::set_forwarding_headers(
+        &mut request,
+        conn.peer_ip,
+        client_ip,
+        &state.trusted_proxies,
+        state.forwarded_for,
+        conn.stream.local_addr().ok().map(|addr| addr.port()),
+        client_host.as_deref(),
+    );
+    let request_version = request.version();
+    forwarded::append_via(request.headers_mut(), request_version);
+    // Whatever version the client speaks, we speak HTTP/1.1 to the upstream
+    *request.version_mut() = http::Version::HTTP_11;
+
+    // Look up the client's country, so that it can be enforced and passed upstream. The header is
+    // always set or removed, so that clients can't supply their own.
+    let country = state.geoip.as_ref().and_then(|db| db.country(client_ip));
+    if state.geoip.is_some() {
+        match country
+            .as_deref()
+            .and_then(|country| http::HeaderValue::from_str(country).ok())
+        {
+            Some(country) => {
+                request.headers_mut().insert("x-geo-country", country);
+            }
+            None => {
+                request.headers_mut().remove("x-geo-country");
+            }
+        }
+    }
+    if !geo_allowed(state, country.as_deref()) {
+        tracing::info!("Rejecting client from country {:?}", country);
+        let mut response = make_error(state, http::StatusCode::FORBIDDEN, &request);
+        conn.send_response(&mut response).await;
+        return RequestOutcome::new(&response, true);
+    }
+    trace.apply(&mut request);
+
+    let route = state.config.route_for(request.uri().path());
+    if let Some(route) = route {
+        config::apply_header_rules(&route.request_headers, request.headers_mut());
+    }
+
+    let exempt = state
+        .rate_limit_exempt
+        .iter()
+        .any(|net| net.contains(&client_ip));
+    let rate_limit = if exempt {
+        None
+    } else {
+        state
+            .rate_limiter_service
+            .should_rate_limit(&rate_limit_key(state, &request, client_ip, conn), route)
             .await
+    };
+    if let Some(rate_limit) = rate_limit.as_ref().filter(|rate_limit| rate_limit.limited) {
+        let mut response = make_error(state, http::StatusCode::TOO_MANY_REQUESTS, &request);
+        rate_limit.apply(response.headers_mut());
+        conn.send_response(&mut response).await;
+        return RequestOutcome::new(&response, true);
+    }
+
+    // CORS preflights for routes with a CORS policy are answered here; upstreams never see them
+    if let Some((cors, origin)) = route
+        .and_then(|route| route.cors.as_ref())
+        .zip(cors::origin(&request))
+        .filter(|_| cors::is_preflight(&request))
+    {
+        let mut response = cors.preflight_response(origin);
+        conn.send_response(&mut response).await;
+        return RequestOutcome::new(&response, true);
+    }
+
+    // Methods the route doesn't allow are turned away before any upstream sees them
+    if let Some(route) = route.filter(|route| !route.method_allowed(request.method())) {
+        tracing::info!(
+            "Rejecting {} request to {}: method not allowed",
+            request.method(),
+            request.uri().path()
+        );
+        let mut response = make_error(state, http::StatusCode::METHOD_NOT_ALLOWED, &request);
+        response
+            .headers_mut()
+            .insert(http::header::ALLOW, route.allow_header());
+        conn.send_response(&mut response).await;
+        return RequestOutcome::new(&response, true);
+    }
+
+    if state.decompress_requests {
+        if let Err(error) =
+            gzip::decompress_request(&mut request, state.request_limits.max_body_size)
         {
-            let response = response::make_http_error(http::StatusCode::TOO_MANY_REQUESTS);
-            //log::info!("{:?}", response);
-            if let Err(error) = response::write_to_stream(&response, &mut client_conn).await {
-                log::warn!("Failed to send response to client: {}", error);
-                return;
+            tracing::debug!("Failed to decompress request body: {}", error);
+            let status = match error {
+                gzip::Error::TooLarge => http::StatusCode::PAYLOAD_TOO_LARGE,
+                gzip::Error::Format(_) => http::StatusCode::BAD_REQUEST,
             };
-            continue;
+            let mut response = make_error(state, status, &request);
+            conn.send_response(&mut response).await;
+            return RequestOutcome::new(&response, true);
+        }
+    }
+
+    // Plugins get the last word on the request, and may answer it themselves
+    for plugin in state.plugins.iter() {
+        let mut response = match plugin.on_request(&mut request, client_ip) {
+            Ok(None) => continue,
+            Ok(Some(response)) => response,
+            Err(error) => {
+                tracing::warn!("Plugin {} failed in on_request: {}", plugin.name(), error);
+                make_error(state, http::StatusCode::INTERNAL_SERVER_ERROR, &request)
+            }
+        };
+        conn.send_response(&mut response).await;
+        return RequestOutcome::new(&response, true);
+    }
+
+    // Trailers can only follow a chunked body, so a request that came with them goes out chunked
+    if let Some(chunked::Trailers(trailers)) = request.extensions().get::<chunked::Trailers>() {
+        let trailers = trailers.clone();
+        chunked::frame_with_trailers(request.headers_mut(), &trailers);
+    }
+
+    state.retry_budget.deposit();
+
+    // A pinned upstream that is at its concurrency limit or being drained is given up for
+    // another one
+    if conn.upstream.as_ref().is_some_and(|(_, upstream)| {
+        state.is_saturated(upstream) || state.drains.is_draining(upstream)
+    }) {
+        conn.upstream = None;
+    }
+
+    // Open a connection to the upstream selected by the balancing strategy
+    if conn.upstream.is_none() {
+        conn.canary = choose_canary(state, &request);
+        match connect_to_upstream(state, &[], conn.canary, country.as_deref()).await {
+            Ok(upstream) => conn.upstream = Some(upstream),
+            Err(error) => {
+                let mut response = connect_error_response(state, &error, &request);
+                conn.send_response(&mut response).await;
+                return RequestOutcome::new(
+                    &response,
+                    response.status() == http::StatusCode::SERVICE_UNAVAILABLE,
+                );
+            }
+        }
+    }
+    let (upstream_conn, upstream_ip) = conn.upstream.as_mut().unwrap();
+    let Some(mut slot) = state.acquire_slot(upstream_ip) else {
+        // Another request took the last slot since the upstream was chosen
+        let mut response = make_error(state, http::StatusCode::SERVICE_UNAVAILABLE, &request);
+        conn.send_response(&mut response).await;
+        return RequestOutcome::new(&response, true);
+    };
+    tracing::Span::current().record("upstream", upstream_ip.as_str());
+    tracing::info!(
+        "{} -> {}: {}",
+        client_ip,
+        upstream_ip,
+        request::format_request_line(&request)
+    );
+
+    // Forward the request to the server. If the upstream fails us, idempotent requests are
+    // retried on a different upstream (up to max_retries times, and as long as the retry budget
+    // allows) before giving up with a 502.
+    let mut failed_upstreams = Vec::new();
+    let mut response = loop {
+        let attempt_start = Instant::now();
+        let error = match proxy_request(state, &request, upstream_conn, upstream_ip).await {
+            Ok(response) => {
+                state.record_response(upstream_ip, response.status(), attempt_start.elapsed());
+                state
+                    .traffic
+                    .record_response(
+                        upstream_ip,
+                        response.status(),
+                        request.body().len(),
+                        response.body().len(),
+                    )
+                    .await;
+                break response;
+            }
+            // The upstream is working fine, and would send the same response again if retried
+            Err(ProxyError::ResponseTooLarge) => {
+                state
+                    .traffic
+                    .record_failure(upstream_ip, request.body().len())
+                    .await;
+                let mut response = make_error(state, http::StatusCode::BAD_GATEWAY, &request);
+                conn.send_response(&mut response).await;
+                return RequestOutcome::new(&response, false);
+            }
+            Err(error) => error,
+        };
+        state.record_failure(upstream_ip);
+        state
+            .traffic
+            .record_failure(upstream_ip, request.body().len())
+            .await;
+        failed_upstreams.push(upstream_ip.clone());
+        // A timed-out upstream may still be working on the request, and waiting out the timeout
+        // again elsewhere would leave the client hanging twice as long, so timeouts aren't retried
+        if error == ProxyError::TimedOut {
+            let mut response = make_error(state, http::StatusCode::GATEWAY_TIMEOUT, &request);
+            conn.send_response(&mut response).await;
+            return RequestOutcome::new(&response, false);
+        }
+        if !is_idempotent(request.method())
+            || failed_upstreams.len() > state.max_retries
+            || !state.retry_budget.withdraw()
+        {
+            let mut response = make_error(state, http::StatusCode::BAD_GATEWAY, &request);
+            conn.send_response(&mut response).await;
+            return RequestOutcome::new(&response, false);
+        }
+        match connect_to_upstream(state, &failed_upstreams, conn.canary, country.as_deref()).await {
+            Ok((stream, ip)) => {
+                tracing::info!("Retrying request on upstream {}", ip);
+                let Some(retry_slot) = state.acquire_slot(&ip) else {
+                    let mut response =
+                        make_error(state, http::StatusCode::SERVICE_UNAVAILABLE, &request);
+                    conn.send_response(&mut response).await;
+                    return RequestOutcome::new(&response, false);
+                };
+                slot = retry_slot;
+                *upstream_conn = stream;
+                *upstream_ip = ip;
+                tracing::Span::current().record("upstream", upstream_ip.as_str());
+            }
+            Err(error) => {
+                let mut response = connect_error_response(state, &error, &request);
+                conn.send_response(&mut response).await;
+                // The client can try again on this connection later, once it has an upstream
+                let keep_open = response.status() == http::StatusCode::SERVICE_UNAVAILABLE;
+                if keep_open {
+                    conn.upstream = None;
+                }
+                return RequestOutcome::new(&response, keep_open);
+            }
+        }
+    };
+    drop(slot);
+    // An upstream that is closing its end can't take the client's next request
+    let upstream_closing = !hop_by_hop::keeps_alive(response.headers(), response.version());
+    hop_by_hop::strip(response.headers_mut());
+    rewrite_location(&mut response, upstream_ip, client_host.as_deref());
+    let response_version = response.version();
+    forwarded::append_via(response.headers_mut(), response_version);
+    *response.version_mut() = http::Version::HTTP_11;
+    if let Some(server_header) = &state.server_header {
+        response
+            .headers_mut()
+            .insert(http::header::SERVER, server_header.clone());
+    }
+    if let Some(route) = route {
+        config::apply_header_rules(&route.response_headers, response.headers_mut());
+        if let Some(security_headers) = &route.security_headers {
+            security_headers.apply(response.headers_mut());
+        }
+        if let Some((cors, origin)) = route.cors.as_ref().zip(cors::origin(&request)) {
+            cors.apply(origin, response.headers_mut());
         }
-        // Forward the request to the server
-        if let Err(error) = request::write_to_stream(&request, &mut upstream_conns[idx]).await {
-            log::error!(
+    }
+    if let Some(rate_limit) = &rate_limit {
+        rate_limit.apply(response.headers_mut());
+    }
+    for plugin in state.plugins.iter() {
+        if let Err(error) = plugin.on_response(&request, &mut response, client_ip) {
+            tracing::warn!("Plugin {} failed in on_response: {}", plugin.name(), error);
+            response = make_error(state, http::StatusCode::INTERNAL_SERVER_ERROR, &request);
+            break;
+        }
+    }
+
+    if upstream_closing {
+        conn.upstream = None;
+    }
+
+    // Forward the response to the client
+    conn.send_response(&mut response).await;
+    tracing::debug!("Forwarded response to client");
+    RequestOutcome::new(&response, true)
+}
+
+/// Identifies the client a request is counted against for rate limiting, according to
+/// --rate-limit-key.
+fn rate_limit_key(
+    state: &ProxyState,
+    request: &http::Request<Vec<u8>>,
+    client_ip: IpAddr,
+    conn: &ClientConnection,
+) -> String {
+    match state.rate_limit_key {
+        RateLimitKey::Ip => client_ip.to_string(),
+        RateLimitKey::IpPort => {
+            let port = conn.stream.local_addr().map_or(0, |addr| addr.port());
+            std::net::SocketAddr::new(client_ip, port).to_string()
+        }
+        RateLimitKey::Header => {
+            // Clients that don't send the header are still limited, by address. Values are counted
+            // per address too, so a client can't use up the limit of a value another one sent.
+            let value = state
+                .rate_limit_header
+                .as_ref()
+                .and_then(|name| request.headers().get(name))
+                .and_then(|value| value.to_str().ok());
+            match value {
+                Some(value) => format!("{} header {}", client_ip, value),
+                None => client_ip.to_string(),
+            }
+        }
+    }
+}
+
+/// Status to answer with when no upstream connection could be made: 503 if every upstream was
+/// merely saturated or none was available at all, 504 if the last attempt timed out, 502 if they
+/// failed.
+fn connect_error_status(error: &std::io::Error) -> http::StatusCode {
+    match error.kind() {
+        std::io::ErrorKind::ResourceBusy | std::io::ErrorKind::AddrNotAvailable => {
+            http::StatusCode::SERVICE_UNAVAILABLE
+        }
+        std::io::ErrorKind::TimedOut => http::StatusCode::GATEWAY_TIMEOUT,
+        _ => http::StatusCode::BAD_GATEWAY,
+    }
+}
+
+/// Builds the error response for a failed connect_to_upstream. If no upstream was available, the
+/// client is told to come back after the next active health check, which is the soonest an
+/// upstream could be back.
+fn connect_error_response(
+    state: &ProxyState,
+    error: &std::io::Error,
+    request: &http::Request<Vec<u8>>,
+) -> http::Response<Vec<u8>> {
+    let mut response = make_error(state, connect_error_status(error), request);
+    if error.kind() == std::io::ErrorKind::AddrNotAvailable {
+        response.headers_mut().insert(
+            http::header::RETRY_AFTER,
+            http::HeaderValue::from(
+                (state.active_health_check_interval.as_secs_f64().ceil() as u64).max(1),
+            ),
+        );
+    }
+    response
+}
+
+/// Applies --geo-allow and --geo-deny to a client's country.
+fn geo_allowed(state: &ProxyState, country: Option<&str>) -> bool {
+    if !state.geo_allow.is_empty()
+        && !country.is_some_and(|country| state.geo_allow.iter().any(|c| c == country))
+    {
+        return false;
+    }
+    !country.is_some_and(|country| state.geo_deny.iter().any(|c| c == country))
+}
+
+/// If an upstream redirects to its own (internal) address, rewrites the Location header to point
+/// at the host the client used instead, so that the backend address doesn't leak and the redirect
+/// actually works for the client.
+fn rewrite_location(
+    response: &mut http::Response<Vec<u8>>,
+    upstream_ip: &str,
+    client_host: Option<&str>,
+) {
+    if !response.status().is_redirection() {
+        return;
+    }
+    let (Some(client_host), Some(location)) = (
+        client_host,
+        response
+            .headers()
+            .get("location")
+            .and_then(|location| location.to_str().ok())
+            .and_then(|location| location.parse::<http::Uri>().ok()),
+    ) else {
+        return;
+    };
+    // Relative redirects are already relative to whatever host the client used
+    let Some(authority) = location.authority() else {
+        return;
+    };
+    let Ok(upstream) = upstream_ip.parse::<http::uri::Authority>() else {
+        return;
+    };
+    let default_port = if location.scheme_str() == Some("https") {
+        443
+    } else {
+        80
+    };
+    if !authority.host().eq_ignore_ascii_case(upstream.host())
+        || authority.port_u16().unwrap_or(default_port)
+            != upstream.port_u16().unwrap_or(default_port)
+    {
+        return;
+    }
+
+    let path_and_query = location
+        .path_and_query()
+        .map(|path_and_query| path_and_query.as_str())
+        .unwrap_or("/");
+    let rewritten = format!("http://{}{}", client_host, path_and_query);
+    tracing::debug!("Rewriting Location {} to {}", location, rewritten);
+    if let Ok(value) = http::HeaderValue::from_str(&rewritten) {
+        response.headers_mut().insert("location", value);
+    }
+}
+
+/// Returns true if a request with this method can safely be sent more than once.
+fn is_idempotent(method: &http::Method) -> bool {
+    method == http::Method::GET || method == http::Method::HEAD
+}
+
+/// Why proxying a request to an upstream failed
+#[derive(Debug, PartialEq)]
+enum ProxyError {
+    /// The upstream connection broke, or the upstream sent something we couldn't parse
+    Failed,
+    /// The upstream didn't take the request or send a response within the configured timeouts
+    TimedOut,
+    /// The upstream's response body is bigger than --max-body-size
+    ResponseTooLarge,
+}
+
+/// Sends a request to an upstream and reads back its response. Failures are logged here; the
+/// caller decides whether the request can be retried elsewhere.
+async fn proxy_request(
+    state: &ProxyState,
+    request: &http::Request<Vec<u8>>,
+    upstream_conn: &mut TcpStream,
+    upstream_ip: &str,
+) -> Result<http::Response<Vec<u8>>, ProxyError> {
+    let start = Instant::now();
+    let write = request::write_to_stream(request, upstream_conn);
+    match tokio::time::timeout(state.upstream_write_timeout, write).await {
+        Ok(Ok(())) => {}
+        Ok(Err(error)) => {
+            tracing::error!(
                 "Failed to send request to upstream {}: {}",
-                upstream_ips[idx],
+                upstream_ip,
                 error
             );
-            let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
-            send_response(&mut client_conn, &response).await;
-            return;
+            return Err(ProxyError::Failed);
+        }
+        Err(_) => {
+            tracing::error!("Timed out sending request to upstream {}", upstream_ip);
+            return Err(ProxyError::TimedOut);
         }
-        log::debug!("Forwarded request to server");
+    }
+    tracing::debug!("Forwarded request to server");
 
-        // Read the server's response
-        let response =
-            match response::read_from_stream(&mut upstream_conns[idx], request.method()).await {
-                Ok(response) => response,
-                Err(error) => {
-                    log::error!("Error reading response from server: {:?}", error);
-                    let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
-                    send_response(&mut client_conn, &response).await;
-                    return;
-                }
-            };
-        // Forward the response to the client
-        send_response(&mut client_conn, &response).await;
-        log::debug!("Forwarded response to client");
+    let read = async {
+        // Wait for the response to start arriving before parsing it, so that time to first byte
+        // can be told apart from time spent reading a large response
+        if upstream_conn.readable().await.is_ok() {
+            state
+                .latency
+                .record(upstream_ip, metrics::Phase::FirstByte, start.elapsed())
+                .await;
+        }
+        response::read_from_stream(
+            upstream_conn,
+            request.method(),
+            state.request_limits.max_body_size,
+        )
+        .await
+    };
+    match tokio::time::timeout(state.upstream_read_timeout, read).await {
+        Ok(Ok(response)) => {
+            state
+                .latency
+                .record(upstream_ip, metrics::Phase::Total, start.elapsed())
+                .await;
+            Ok(response)
+        }
+        Ok(Err(response::Error::ResponseBodyTooLarge)) => {
+            tracing::warn!(
+                "Response from upstream {} is larger than the maximum body size",
+                upstream_ip
+            );
+            Err(ProxyError::ResponseTooLarge)
+        }
+        Ok(Err(error)) => {
+            tracing::error!(
+                "Error reading response from upstream {}: {:?}",
+                upstream_ip,
+                error
+            );
+            Err(ProxyError::Failed)
+        }
+        Err(_) => {
+            tracing::error!(
+                "Timed out waiting for response from upstream {}",
+                upstream_ip
+            );
+            Err(ProxyError::TimedOut)
+        }
     }
 }
@@ -0,0 +1,414 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::time::Duration;
+use tokio::sync::Mutex;
+
+/// Upper bounds of the latency histogram buckets, in milliseconds. Anything slower than the last
+/// bound lands in an overflow (+Inf) bucket.
+const BUCKET_BOUNDS_MS: [u64; 11] = [5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10000];
+
+/// The parts of a proxied request that are timed
+#[derive(Clone, Copy, Debug)]
+pub enum Phase {
+    /// Opening the TCP connection to the upstream
+    Connect,
+    /// From sending the request until the first byte of the response arrives
+    FirstByte,
+    /// From sending the request until the whole response has been read
+    Total,
+}
+
+impl Phase {
+    const ALL: [Phase; 3] = [Phase::Connect, Phase::FirstByte, Phase::Total];
+
+    fn name(self) -> &'static str {
+        match self {
+            Phase::Connect => "connect",
+            Phase::FirstByte => "first_byte",
+            Phase::Total => "total",
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+struct Histogram {
+    /// Number of observations per bucket; the last entry is the overflow bucket
+    buckets: [u64; BUCKET_BOUNDS_MS.len() + 1],
+    count: u64,
+    sum: Duration,
+}
+
+impl Histogram {
+    fn observe(&mut self, elapsed: Duration) {
+        let ms = elapsed.as_millis();
+        let bucket = BUCKET_BOUNDS_MS
+            .iter()
+            .position(|bound| ms <= *bound as u128)
+            .unwrap_or(BUCKET_BOUNDS_MS.len());
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.sum += elapsed;
+    }
+
+    /// Returns the upper bound (in ms) of the bucket containing the given percentile, or None if
+    /// it is in the overflow bucket. Only meaningful if there was at least one observation.
+    fn percentile(&self, percentile: f64) -> Option<u64> {
+        let rank = (self.count as f64 * percentile / 100.0).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (idx, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return BUCKET_BOUNDS_MS.get(idx).copied();
+            }
+        }
+        None
+    }
+}
+
+#[derive(Clone, Default)]
+struct UpstreamLatency {
+    connect: Histogram,
+    first_byte: Histogram,
+    total: Histogram,
+}
+
+impl UpstreamLatency {
+    fn histogram(&self, phase: Phase) -> &Histogram {
+        match phase {
+            Phase::Connect => &self.connect,
+            Phase::FirstByte => &self.first_byte,
+            Phase::Total => &self.total,
+        }
+    }
+
+    fn histogram_mut(&mut self, phase: Phase) -> &mut Histogram {
+        match phase {
+            Phase::Connect => &mut self.connect,
+            Phase::FirstByte => &mut self.first_byte,
+            Phase::Total => &mut self.total,
+        }
+    }
+}
+
+#[derive(Default)]
+struct UpstreamStats {
+    /// Everything observed since balancebeam started, which is what gets exported
+    cumulative: UpstreamLatency,
+    /// Observations since the last periodic log line
+    since_last_log: UpstreamLatency,
+}
+
+/// Latency histograms per upstream. Exported in Prometheus text format at /metrics on the admin
+/// API, and summarized in the log every --latency-log-interval seconds.
+#[derive(Default)]
+pub struct LatencyMetrics {
+    upstreams: Mutex<HashMap<String, UpstreamStats>>,
+}
+
+impl LatencyMetrics {
+    pub async fn record(&self, upstream: &str, phase: Phase, elapsed: Duration) {
+        let mut upstreams = self.upstreams.lock().await;
+        let stats = upstreams.entry(upstream.to_string()).or_default();
+        stats.cumulative.histogram_mut(phase).observe(elapsed);
+        stats.since_last_log.histogram_mut(phase).observe(elapsed);
+    }
+
+    /// Logs approximate p50/p99 latencies for each upstream that served requests since the last
+    /// call, then starts a new logging interval.
+    pub async fn log_summary(&self) {
+        let mut upstreams = self.upstreams.lock().await;
+        let mut addresses: Vec<&String> = upstreams.keys().collect();
+        addresses.sort();
+        for address in addresses {
+            let recent = &upstreams[address].since_last_log;
+            if recent.total.count == 0 && recent.connect.count == 0 {
+                continue;
+            }
+            let mut line = format!("Latency for upstream {}:", address);
+            for phase in Phase::ALL {
+                let histogram = recent.histogram(phase);
+                if histogram.count > 0 {
+                    write!(
+                        line,
+                        " {} p50 {} p99 {} ({} samples);",
+                        phase.name(),
+                        format_bound(histogram.percentile(50.0)),
+                        format_bound(histogram.percentile(99.0)),
+                        histogram.count
+                    )
+                    .unwrap();
+                }
+            }
+            tracing::info!("{}", line.trim_end_matches(';'));
+        }
+        for stats in upstreams.values_mut() {
+            stats.since_last_log = UpstreamLatency::default();
+        }
+    }
+
+    /// Renders all histograms in the Prometheus text exposition format.
+    pub async fn render_prometheus(&self) -> String {
+        let upstreams = self.upstreams.lock().await;
+        let mut addresses: Vec<&String> = upstreams.keys().collect();
+        addresses.sort();
+
+        let name = "balancebeam_upstream_latency_seconds";
+        let mut out = String::new();
+        writeln!(
+            out,
+            "# HELP {} Latency of requests proxied to each upstream",
+            name
+        )
+        .unwrap();
+        writeln!(out, "# TYPE {} histogram", name).unwrap();
+        for address in addresses {
+            let upstream = escape_label(address);
+            for phase in Phase::ALL {
+                let histogram = upstreams[address].cumulative.histogram(phase);
+                let labels = format!("upstream=\"{}\",phase=\"{}\"", upstream, phase.name());
+                let mut cumulative_count = 0;
+                for (idx, count) in histogram.buckets.iter().enumerate() {
+                    cumulative_count += count;
+                    let le = match BUCKET_BOUNDS_MS.get(idx) {
+                        Some(bound) => (*bound as f64 / 1000.0).to_string(),
+                        None => "+Inf".to_string(),
+                    };
+                    writeln!(
+                        out,
+                        "{}_bucket{{{},le=\"{}\"}} {}",
+                        name, labels, le, cumulative_count
+                    )
+                    .unwrap();
+                }
+                writeln!(
+                    out,
+                    "{}_sum{{{}}} {}",
+                    name,
+                    labels,
+                    histogram.sum.as_secs_f64()
+                )
+                .unwrap();
+                writeln!(out, "{}_count{{{}}} {}", name, labels, histogram.count).unwrap();
+            }
+        }
+        out
+    }
+}
+
+fn format_bound(bound: Option<u64>) -> String {
+    match bound {
+        Some(ms) => format!("<={}ms", ms),
+        None => format!(">{}ms", BUCKET_BOUNDS_MS[BUCKET_BOUNDS_MS.len() - 1]),
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Request counts and bytes for one upstream
+#[derive(Clone, Default)]
+struct Traffic {
+    /// Requests forwarded to the upstream, whether or not a response came back
+    requests: u64,
+    /// Requests the upstream failed without a (usable) response: broken connections, timeouts
+    failures: u64,
+    /// Responses by status class (1xx to 5xx)
+    responses: [u64; 5],
+    /// Request body bytes sent to the upstream
+    bytes_sent: u64,
+    /// Response body bytes received from the upstream
+    bytes_received: u64,
+}
+
+impl Traffic {
+    fn errors(&self) -> u64 {
+        self.failures + self.responses[4]
+    }
+}
+
+#[derive(Default)]
+struct UpstreamTraffic {
+    /// Everything counted since balancebeam started, which is what gets exported
+    cumulative: Traffic,
+    /// Counts since the last periodic log line
+    since_last_log: Traffic,
+}
+
+/// Request totals, responses by status class and bytes transferred per upstream, so that an
+/// upstream quietly answering with errors stands out. Exported at /metrics and /traffic on the
+/// admin API, and summarized in the log every --latency-log-interval seconds.
+#[derive(Default)]
+pub struct TrafficMetrics {
+    upstreams: Mutex<HashMap<String, UpstreamTraffic>>,
+}
+
+impl TrafficMetrics {
+    /// Counts a request that got a response from the upstream.
+    pub async fn record_response(
+        &self,
+        upstream: &str,
+        status: http::StatusCode,
+        bytes_sent: usize,
+        bytes_received: usize,
+    ) {
+        let class = (status.as_u16() / 100).clamp(1, 5) as usize - 1;
+        let mut upstreams = self.upstreams.lock().await;
+        let traffic = upstreams.entry(upstream.to_string()).or_default();
+        for counts in [&mut traffic.cumulative, &mut traffic.since_last_log] {
+            counts.requests += 1;
+            counts.responses[class] += 1;
+            counts.bytes_sent += bytes_sent as u64;
+            counts.bytes_received += bytes_received as u64;
+        }
+    }
+
+    /// Counts a request the upstream failed without a response.
+    pub async fn record_failure(&self, upstream: &str, bytes_sent: usize) {
+        let mut upstreams = self.upstreams.lock().await;
+        let traffic = upstreams.entry(upstream.to_string()).or_default();
+        for counts in [&mut traffic.cumulative, &mut traffic.since_last_log] {
+            counts.requests += 1;
+            counts.failures += 1;
+            counts.bytes_sent += bytes_sent as u64;
+        }
+    }
+
+    /// Logs request and error counts for each upstream that was sent requests since the last
+    /// call, then starts a new logging interval. Upstreams with errors are logged as warnings.
+    pub async fn log_summary(&self) {
+        let mut upstreams = self.upstreams.lock().await;
+        let mut addresses: Vec<&String> = upstreams.keys().collect();
+        addresses.sort();
+        for address in addresses {
+            let recent = &upstreams[address].since_last_log;
+            if recent.requests == 0 {
+                continue;
+            }
+            let line = format!(
+                "Traffic for upstream {}: {} requests, {} errors ({} failed, {} 5xx, {} 4xx); \
+                 {} bytes sent, {} bytes received",
+                address,
+                recent.requests,
+                recent.errors(),
+                recent.failures,
+                recent.responses[4],
+                recent.responses[3],
+                recent.bytes_sent,
+                recent.bytes_received
+            );
+            if recent.errors() > 0 {
+                tracing::warn!("{}", line);
+            } else {
+                tracing::info!("{}", line);
+            }
+        }
+        for traffic in upstreams.values_mut() {
+            traffic.since_last_log = Traffic::default();
+        }
+    }
+
+    /// Renders the counters in the Prometheus text exposition format.
+    pub async fn render_prometheus(&self) -> String {
+        let upstreams = self.upstreams.lock().await;
+        let mut addresses: Vec<&String> = upstreams.keys().collect();
+        addresses.sort();
+
+        let mut out = String::new();
+        let requests = "balancebeam_upstream_requests_total";
+        writeln!(
+            out,
+            "# HELP {} Requests proxied to each upstream, by outcome",
+            requests
+        )
+        .unwrap();
+        writeln!(out, "# TYPE {} counter", requests).unwrap();
+        for address in addresses.iter() {
+            let traffic = &upstreams[*address].cumulative;
+            let upstream = escape_label(address);
+            for (idx, count) in traffic.responses.iter().enumerate() {
+                writeln!(
+                    out,
+                    "{}{{upstream=\"{}\",outcome=\"{}xx\"}} {}",
+                    requests,
+                    upstream,
+                    idx + 1,
+                    count
+                )
+                .unwrap();
+            }
+            writeln!(
+                out,
+                "{}{{upstream=\"{}\",outcome=\"failed\"}} {}",
+                requests, upstream, traffic.failures
+            )
+            .unwrap();
+        }
+        let bytes = "balancebeam_upstream_bytes_total";
+        writeln!(
+            out,
+            "# HELP {} Body bytes sent to and received from each upstream",
+            bytes
+        )
+        .unwrap();
+        writeln!(out, "# TYPE {} counter", bytes).unwrap();
+        for address in addresses.iter() {
+            let traffic = &upstreams[*address].cumulative;
+            let upstream = escape_label(address);
+            writeln!(
+                out,
+                "{}{{upstream=\"{}\",direction=\"sent\"}} {}",
+                bytes, upstream, traffic.bytes_sent
+            )
+            .unwrap();
+            writeln!(
+                out,
+                "{}{{upstream=\"{}\",direction=\"received\"}} {}",
+                bytes, upstream, traffic.bytes_received
+            )
+            .unwrap();
+        }
+        out
+    }
+
+    /// Renders the counters as a JSON object with an entry per upstream.
+    pub async fn render_json(&self) -> String {
+        let upstreams = self.upstreams.lock().await;
+        let mut addresses: Vec<&String> = upstreams.keys().collect();
+        addresses.sort();
+
+        let mut out = String::from("{\"upstreams\":[");
+        for (idx, address) in addresses.iter().enumerate() {
+            let traffic = &upstreams[*address].cumulative;
+            if idx > 0 {
+                out.push(',');
+            }
+            write!(
+                out,
+                "{{\"address\":{},\"requests\":{},\"errors\":{},\"failed\":{},\"responses\":{{",
+                crate::status::json_string(address),
+                traffic.requests,
+                traffic.errors(),
+                traffic.failures
+            )
+            .unwrap();
+            for (class, count) in traffic.responses.iter().enumerate() {
+                if class > 0 {
+                    out.push(',');
+                }
+                write!(out, "\"{}xx\":{}", class + 1, count).unwrap();
+            }
+            write!(
+                out,
+                "}},\"bytes_sent\":{},\"bytes_received\":{}}}",
+                traffic.bytes_sent, traffic.bytes_received
+            )
+            .unwrap();
+        }
+        out.push_str("]}\n");
+        out
+    }
+}
@@ -0,0 +1,156 @@
+use crate::status::json_string;
+use crate::{request, response};
+use std::fmt::Write;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+
+/// Maximum number of finished spans buffered for export. If the collector can't keep up, further
+/// spans are dropped rather than slowing down request handling.
+const QUEUE_SIZE: usize = 4096;
+
+/// How often buffered spans are sent to the collector
+const EXPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A finished span describing balancebeam's handling of one request
+pub struct Span {
+    pub trace_id: String,
+    pub span_id: String,
+    pub parent_span_id: Option<String>,
+    pub method: String,
+    pub path: String,
+    pub status: http::StatusCode,
+    pub upstream: Option<String>,
+    pub start: SystemTime,
+    pub end: SystemTime,
+}
+
+/// Sends spans to an OpenTelemetry collector using OTLP over HTTP with JSON encoding
+/// (POST /v1/traces). Spans are queued and exported in batches by a background task.
+pub struct Exporter {
+    queue: mpsc::Sender<Span>,
+}
+
+impl Exporter {
+    /// Starts the background export task for a collector at `endpoint` (HOST:PORT).
+    pub fn start(endpoint: String) -> Exporter {
+        let (queue, mut pending) = mpsc::channel(QUEUE_SIZE);
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(EXPORT_INTERVAL).await;
+                let mut batch = Vec::new();
+                while let Ok(span) = pending.try_recv() {
+                    batch.push(span);
+                }
+                if !batch.is_empty() {
+                    if let Err(err) = send_batch(&endpoint, &batch).await {
+                        tracing::warn!(
+                            "Failed to export {} spans to {}: {}",
+                            batch.len(),
+                            endpoint,
+                            err
+                        );
+                    }
+                }
+            }
+        });
+        Exporter { queue }
+    }
+
+    pub fn export(&self, span: Span) {
+        if self.queue.try_send(span).is_err() {
+            tracing::debug!("Span export queue is full, dropping span");
+        }
+    }
+}
+
+async fn send_batch(endpoint: &str, batch: &[Span]) -> Result<(), String> {
+    let body = encode(batch).into_bytes();
+    let request = http::Request::builder()
+        .method(http::Method::POST)
+        .uri("/v1/traces")
+        .header("Host", endpoint)
+        .header("Content-Type", "application/json")
+        .header("Content-Length", body.len().to_string())
+        .header("Connection", "close")
+        .version(http::Version::HTTP_11)
+        .body(body)
+        .unwrap();
+    let mut stream = TcpStream::connect(endpoint)
+        .await
+        .map_err(|err| err.to_string())?;
+    request::write_to_stream(&request, &mut stream)
+        .await
+        .map_err(|err| err.to_string())?;
+    let response =
+        response::read_from_stream(&mut stream, request.method(), response::MAX_BODY_SIZE)
+            .await
+            .map_err(|err| format!("{:?}", err))?;
+    if !response.status().is_success() {
+        return Err(format!("collector replied {}", response.status()));
+    }
+    tracing::debug!("Exported {} spans to {}", batch.len(), endpoint);
+    Ok(())
+}
+
+fn unix_nanos(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+}
+
+/// Encodes spans as an OTLP ExportTraceServiceRequest in the protobuf JSON mapping.
+fn encode(batch: &[Span]) -> String {
+    let mut spans = Vec::new();
+    for span in batch {
+        let mut attributes = vec![
+            format!(
+                "{{\"key\":\"http.request.method\",\"value\":{{\"stringValue\":{}}}}}",
+                json_string(&span.method)
+            ),
+            format!(
+                "{{\"key\":\"url.path\",\"value\":{{\"stringValue\":{}}}}}",
+                json_string(&span.path)
+            ),
+            format!(
+                "{{\"key\":\"http.response.status_code\",\"value\":{{\"intValue\":\"{}\"}}}}",
+                span.status.as_u16()
+            ),
+        ];
+        if let Some(upstream) = &span.upstream {
+            attributes.push(format!(
+                "{{\"key\":\"server.address\",\"value\":{{\"stringValue\":{}}}}}",
+                json_string(upstream)
+            ));
+        }
+        let mut encoded = String::new();
+        write!(
+            encoded,
+            "{{\"traceId\":\"{}\",\"spanId\":\"{}\",",
+            span.trace_id, span.span_id
+        )
+        .unwrap();
+        if let Some(parent_span_id) = &span.parent_span_id {
+            write!(encoded, "\"parentSpanId\":\"{}\",", parent_span_id).unwrap();
+        }
+        write!(
+            encoded,
+            "\"name\":{},\"kind\":2,\"startTimeUnixNano\":\"{}\",\"endTimeUnixNano\":\"{}\",\
+             \"attributes\":[{}],\"status\":{{\"code\":{}}}}}",
+            json_string(&span.method),
+            unix_nanos(span.start),
+            unix_nanos(span.end),
+            attributes.join(","),
+            // STATUS_CODE_ERROR for server errors, STATUS_CODE_UNSET otherwise
+            if span.status.is_server_error() { 2 } else { 0 }
+        )
+        .unwrap();
+        spans.push(encoded);
+    }
+    format!(
+        "{{\"resourceSpans\":[{{\"resource\":{{\"attributes\":[{{\"key\":\"service.name\",\
+         \"value\":{{\"stringValue\":\"balancebeam\"}}}}]}},\
+         \"scopeSpans\":[{{\"scope\":{{\"name\":\"balancebeam\"}},\"spans\":[{}]}}]}}]}}",
+        spans.join(",")
+    )
+}
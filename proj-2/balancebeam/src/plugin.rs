@@ -0,0 +1,1751 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+
+/// A request filter loaded from a script passed with --plugin. A script defines one or both of the
+/// hook functions:
+///
+/// ```text
+/// -- Called before a request is forwarded upstream. Returning a response (made with respond)
+/// -- answers the client with it instead; returning nothing lets the request through.
+/// function on_request(req)
+///   if req:header("x-api-key") ~= "secret" then
+///     return respond(401, "unauthorized")
+///   end
+///   req:set_header("x-authenticated", "yes")
+/// end
+///
+/// -- Called with every upstream response before it is sent back to the client. Returning a
+/// -- response replaces the upstream's.
+/// function on_response(req, resp)
+///   resp:remove_header("x-powered-by")
+/// end
+/// ```
+///
+/// Scripts are written in balancebeam's own small scripting language, interpreted below. Its syntax
+/// looks like Lua's but it is not Lua, and only has `local` variables, assignment, `if`/`elseif`/
+/// `else`, `return`, `and`/`or`/`not`, comparisons, arithmetic, `..` and `#`, with nil, booleans,
+/// numbers and strings as values. There are no loops, tables or user-defined helper functions, so
+/// a hook always runs to completion quickly. The functions available are:
+///
+/// * `respond(status [, body])`: a new response to short-circuit with
+/// * `log(...)`, `tostring(value)`, `tonumber(value)`
+/// * on requests: `method()`, `path()`, `query()`, `set_path(path)` (which may include a query
+///   string), `client_ip()`, `body()`
+/// * on responses: `status()`, `set_status(status)`, `body()`, `set_body(body)`
+/// * on both: `header(name)`, `set_header(name, value)`, `add_header(name, value)`,
+///   `remove_header(name)`
+/// * on strings: `len()`, `lower()`, `upper()`, `sub(i [, j])` and `find(text [, init])`, which
+///   always searches for plain text and only returns where the match starts
+#[derive(Debug)]
+pub struct Plugin {
+    /// Where the script was loaded from, used in log messages
+    name: String,
+    on_request: Option<Function>,
+    on_response: Option<Function>,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// The script couldn't be read
+    Io(std::io::Error),
+    /// The script isn't valid. Contains the (1-based) line number and a description of the problem
+    Parse(usize, String),
+    /// A hook failed while running. Contains the line number and a description of the problem
+    Runtime(usize, String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "{}", err),
+            Error::Parse(line, message) => write!(f, "line {}: {}", line, message),
+            Error::Runtime(line, message) => write!(f, "line {}: {}", line, message),
+        }
+    }
+}
+
+impl Plugin {
+    pub fn from_file(path: &str) -> Result<Plugin, Error> {
+        Plugin::parse(path, &std::fs::read_to_string(path).map_err(Error::Io)?)
+    }
+
+    pub fn parse(name: &str, source: &str) -> Result<Plugin, Error> {
+        let mut parser = Parser {
+            tokens: tokenize(source)?,
+            pos: 0,
+        };
+        let mut plugin = Plugin {
+            name: name.to_string(),
+            on_request: None,
+            on_response: None,
+        };
+        while parser.peek() != &Token::Eof {
+            if parser.eat_symbol(";") {
+                continue;
+            }
+            let line = parser.line();
+            if !parser.eat_keyword("function") {
+                return Err(Error::Parse(
+                    line,
+                    "only function definitions are allowed at the top level".to_string(),
+                ));
+            }
+            let name = parser.name()?;
+            let function = parser.function_body()?;
+            let slot = match name.as_str() {
+                "on_request" => &mut plugin.on_request,
+                "on_response" => &mut plugin.on_response,
+                _ => {
+                    return Err(Error::Parse(
+                        line,
+                        format!(
+                            "unknown hook {:?} (expected on_request or on_response)",
+                            name
+                        ),
+                    ))
+                }
+            };
+            if slot.replace(function).is_some() {
+                return Err(Error::Parse(line, format!("{} is defined twice", name)));
+            }
+        }
+        if plugin.on_request.is_none() && plugin.on_response.is_none() {
+            return Err(Error::Parse(
+                1,
+                "script defines neither on_request nor on_response".to_string(),
+            ));
+        }
+        Ok(plugin)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Runs the on_request hook, which may modify the request. Returns the response to answer the
+    /// client with instead of forwarding the request, if the hook returned one.
+    pub fn on_request(
+        &self,
+        request: &mut http::Request<Vec<u8>>,
+        client_ip: IpAddr,
+    ) -> Result<Option<http::Response<Vec<u8>>>, Error> {
+        let Some(function) = &self.on_request else {
+            return Ok(None);
+        };
+        let mut context = Context {
+            plugin: &self.name,
+            request: RequestRef::Mutable(request),
+            client_ip,
+            responses: Vec::new(),
+            scopes: Vec::new(),
+        };
+        match context.call(function, vec![Value::Request])? {
+            Value::Nil => Ok(None),
+            Value::Response(idx) => Ok(Some(context.responses.swap_remove(idx))),
+            value => Err(Error::Runtime(
+                function.line,
+                format!(
+                    "on_request returned a {}, not a response",
+                    value.type_name()
+                ),
+            )),
+        }
+    }
+
+    /// Runs the on_response hook, which may modify the response or replace it entirely.
+    pub fn on_response(
+        &self,
+        request: &http::Request<Vec<u8>>,
+        response: &mut http::Response<Vec<u8>>,
+        client_ip: IpAddr,
+    ) -> Result<(), Error> {
+        let Some(function) = &self.on_response else {
+            return Ok(());
+        };
+        let mut context = Context {
+            plugin: &self.name,
+            request: RequestRef::ReadOnly(request),
+            client_ip,
+            responses: vec![std::mem::take(response)],
+            scopes: Vec::new(),
+        };
+        let returned = context.call(function, vec![Value::Request, Value::Response(0)]);
+        let idx = match returned {
+            Ok(Value::Nil) => 0,
+            Ok(Value::Response(idx)) => idx,
+            Ok(value) => {
+                *response = context.responses.swap_remove(0);
+                return Err(Error::Runtime(
+                    function.line,
+                    format!(
+                        "on_response returned a {}, not a response",
+                        value.type_name()
+                    ),
+                ));
+            }
+            Err(err) => {
+                *response = context.responses.swap_remove(0);
+                return Err(err);
+            }
+        };
+        *response = context.responses.swap_remove(idx);
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    /// An identifier or keyword
+    Name(String),
+    Number(f64),
+    Str(String),
+    Symbol(&'static str),
+    Eof,
+}
+
+/// Operators and punctuation, longest first so that e.g. `==` isn't read as two `=`
+const SYMBOLS: [&str; 20] = [
+    "..", "==", "~=", "<=", ">=", "+", "-", "*", "/", "%", "#", "<", ">", "=", "(", ")", ",", ":",
+    ";", ".",
+];
+
+/// Splits a script into tokens, each with the line it is on.
+fn tokenize(source: &str) -> Result<Vec<(Token, usize)>, Error> {
+    let bytes = source.as_bytes();
+    let mut tokens = Vec::new();
+    let mut pos = 0;
+    let mut line = 1;
+    while pos < bytes.len() {
+        let c = bytes[pos];
+        if c == b'\n' {
+            line += 1;
+            pos += 1;
+        } else if c.is_ascii_whitespace() {
+            pos += 1;
+        } else if source[pos..].starts_with("--") {
+            // Block comments are --[[ ... ]], anything else runs to the end of the line
+            if source[pos + 2..].starts_with("[[") {
+                let end = source[pos..]
+                    .find("]]")
+                    .ok_or_else(|| Error::Parse(line, "unterminated comment".to_string()))?;
+                line += source[pos..pos + end].matches('\n').count();
+                pos += end + 2;
+            } else {
+                pos = source[pos..]
+                    .find('\n')
+                    .map_or(bytes.len(), |end| pos + end);
+            }
+        } else if c.is_ascii_alphabetic() || c == b'_' {
+            let start = pos;
+            while pos < bytes.len() && (bytes[pos].is_ascii_alphanumeric() || bytes[pos] == b'_') {
+                pos += 1;
+            }
+            tokens.push((Token::Name(source[start..pos].to_string()), line));
+        } else if c.is_ascii_digit() {
+            let start = pos;
+            while pos < bytes.len() && (bytes[pos].is_ascii_digit() || bytes[pos] == b'.') {
+                pos += 1;
+            }
+            let number = source[start..pos].parse::<f64>().map_err(|_| {
+                Error::Parse(line, format!("invalid number {:?}", &source[start..pos]))
+            })?;
+            tokens.push((Token::Number(number), line));
+        } else if c == b'"' || c == b'\'' {
+            let (value, end) = read_string(source, pos, line)?;
+            tokens.push((Token::Str(value), line));
+            pos = end;
+        } else if let Some(symbol) = SYMBOLS
+            .iter()
+            .find(|symbol| source[pos..].starts_with(**symbol))
+        {
+            tokens.push((Token::Symbol(symbol), line));
+            pos += symbol.len();
+        } else {
+            let c = source[pos..].chars().next().unwrap();
+            return Err(Error::Parse(line, format!("unexpected character {:?}", c)));
+        }
+    }
+    tokens.push((Token::Eof, line));
+    Ok(tokens)
+}
+
+/// Reads the quoted string starting at `start`. Returns its value and the offset just past the
+/// closing quote.
+fn read_string(source: &str, start: usize, line: usize) -> Result<(String, usize), Error> {
+    let quote = source.as_bytes()[start] as char;
+    let mut value = String::new();
+    let mut chars = source[start + 1..].char_indices();
+    while let Some((offset, c)) = chars.next() {
+        match c {
+            '\n' => break,
+            c if c == quote => return Ok((value, start + 1 + offset + 1)),
+            '\\' => match chars.next().map(|(_, c)| c) {
+                Some('n') => value.push('\n'),
+                Some('t') => value.push('\t'),
+                Some('r') => value.push('\r'),
+                Some(c @ ('\\' | '"' | '\'')) => value.push(c),
+                _ => return Err(Error::Parse(line, "invalid escape in string".to_string())),
+            },
+            c => value.push(c),
+        }
+    }
+    Err(Error::Parse(line, "unterminated string".to_string()))
+}
+
+const KEYWORDS: [&str; 21] = [
+    "and", "break", "do", "else", "elseif", "end", "false", "for", "function", "goto", "if", "in",
+    "local", "nil", "not", "or", "repeat", "return", "then", "true", "until",
+];
+
+#[derive(Debug)]
+struct Function {
+    /// Line the function is defined on
+    line: usize,
+    params: Vec<String>,
+    body: Vec<Stmt>,
+}
+
+#[derive(Debug)]
+struct Stmt {
+    line: usize,
+    kind: StmtKind,
+}
+
+#[derive(Debug)]
+enum StmtKind {
+    Local(String, Option<Expr>),
+    Assign(String, Expr),
+    /// A function or method call whose result is discarded
+    Call(Expr),
+    /// Condition/body pairs for the `if` and each `elseif`, then the `else` body
+    If(Vec<(Expr, Vec<Stmt>)>, Vec<Stmt>),
+    Return(Option<Expr>),
+}
+
+#[derive(Debug)]
+enum Expr {
+    Literal(Value),
+    Name(String),
+    Not(Box<Expr>),
+    Negate(Box<Expr>),
+    Length(Box<Expr>),
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+    /// A call to one of the global functions
+    Call(String, Vec<Expr>),
+    /// `object:method(args)`
+    Method(Box<Expr>, String, Vec<Expr>),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum BinOp {
+    Or,
+    And,
+    Eq,
+    NotEq,
+    Less,
+    LessEq,
+    Greater,
+    GreaterEq,
+    Concat,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Modulo,
+}
+
+impl BinOp {
+    /// Returns the operator for a token, along with its precedence (higher binds tighter)
+    fn from_token(token: &Token) -> Option<(BinOp, u8)> {
+        let op = match token {
+            Token::Name(name) if name == "or" => (BinOp::Or, 1),
+            Token::Name(name) if name == "and" => (BinOp::And, 2),
+            Token::Symbol("==") => (BinOp::Eq, 3),
+            Token::Symbol("~=") => (BinOp::NotEq, 3),
+            Token::Symbol("<") => (BinOp::Less, 3),
+            Token::Symbol("<=") => (BinOp::LessEq, 3),
+            Token::Symbol(">") => (BinOp::Greater, 3),
+            Token::Symbol(">=") => (BinOp::GreaterEq, 3),
+            Token::Symbol("..") => (BinOp::Concat, 4),
+            Token::Symbol("+") => (BinOp::Add, 5),
+            Token::Symbol("-") => (BinOp::Subtract, 5),
+            Token::Symbol("*") => (BinOp::Multiply, 6),
+            Token::Symbol("/") => (BinOp::Divide, 6),
+            Token::Symbol("%") => (BinOp::Modulo, 6),
+            _ => return None,
+        };
+        Some(op)
+    }
+}
+
+/// Precedence of the unary operators, which bind tighter than any binary operator
+const UNARY_PRECEDENCE: u8 = 7;
+
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.pos].0
+    }
+
+    fn line(&self) -> usize {
+        self.tokens[self.pos].1
+    }
+
+    fn next(&mut self) -> Token {
+        let token = self.tokens[self.pos].0.clone();
+        if self.pos + 1 < self.tokens.len() {
+            self.pos += 1;
+        }
+        token
+    }
+
+    fn error<T>(&self, message: &str) -> Result<T, Error> {
+        let found = match self.peek() {
+            Token::Name(name) => format!("{:?}", name),
+            Token::Number(number) => number.to_string(),
+            Token::Str(value) => format!("{:?}", value),
+            Token::Symbol(symbol) => format!("{:?}", symbol),
+            Token::Eof => "end of file".to_string(),
+        };
+        Err(Error::Parse(
+            self.line(),
+            format!("{}, found {}", message, found),
+        ))
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Token::Name(name) if name == keyword)
+    }
+
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        let found = self.is_keyword(keyword);
+        if found {
+            self.next();
+        }
+        found
+    }
+
+    fn eat_symbol(&mut self, symbol: &str) -> bool {
+        let found = self.peek() == &Token::Symbol(symbol_str(symbol));
+        if found {
+            self.next();
+        }
+        found
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<(), Error> {
+        if self.eat_keyword(keyword) {
+            Ok(())
+        } else {
+            self.error(&format!("expected {:?}", keyword))
+        }
+    }
+
+    fn expect_symbol(&mut self, symbol: &str) -> Result<(), Error> {
+        if self.eat_symbol(symbol) {
+            Ok(())
+        } else {
+            self.error(&format!("expected {:?}", symbol))
+        }
+    }
+
+    /// Reads an identifier that isn't a keyword.
+    fn name(&mut self) -> Result<String, Error> {
+        match self.peek() {
+            Token::Name(name) if !KEYWORDS.contains(&name.as_str()) => {
+                let name = name.clone();
+                self.next();
+                Ok(name)
+            }
+            _ => self.error("expected a name"),
+        }
+    }
+
+    /// Parses `(params) body end`, the part of a function definition after its name.
+    fn function_body(&mut self) -> Result<Function, Error> {
+        let line = self.line();
+        self.expect_symbol("(")?;
+        let mut params = Vec::new();
+        if !self.eat_symbol(")") {
+            loop {
+                params.push(self.name()?);
+                if self.eat_symbol(")") {
+                    break;
+                }
+                self.expect_symbol(",")?;
+            }
+        }
+        let body = self.block()?;
+        self.expect_keyword("end")?;
+        Ok(Function { line, params, body })
+    }
+
+    /// Parses statements up to (but not including) the `end`, `else` or `elseif` closing the
+    /// block.
+    fn block(&mut self) -> Result<Vec<Stmt>, Error> {
+        let mut stmts = Vec::new();
+        loop {
+            if self.eat_symbol(";") {
+                continue;
+            }
+            if self.is_keyword("end")
+                || self.is_keyword("else")
+                || self.is_keyword("elseif")
+                || self.peek() == &Token::Eof
+            {
+                return Ok(stmts);
+            }
+            let stmt = self.statement()?;
+            let returned = matches!(stmt.kind, StmtKind::Return(_));
+            stmts.push(stmt);
+            if returned {
+                self.eat_symbol(";");
+                if !(self.is_keyword("end") || self.is_keyword("else") || self.is_keyword("elseif"))
+                {
+                    return self.error("return must be the last statement in a block");
+                }
+            }
+        }
+    }
+
+    fn statement(&mut self) -> Result<Stmt, Error> {
+        let line = self.line();
+        let kind = if self.eat_keyword("local") {
+            let name = self.name()?;
+            let value = if self.eat_symbol("=") {
+                Some(self.expression(0)?)
+            } else {
+                None
+            };
+            StmtKind::Local(name, value)
+        } else if self.eat_keyword("if") {
+            let mut branches = Vec::new();
+            let mut otherwise = Vec::new();
+            let condition = self.expression(0)?;
+            self.expect_keyword("then")?;
+            branches.push((condition, self.block()?));
+            loop {
+                if self.eat_keyword("elseif") {
+                    let condition = self.expression(0)?;
+                    self.expect_keyword("then")?;
+                    branches.push((condition, self.block()?));
+                } else if self.eat_keyword("else") {
+                    otherwise = self.block()?;
+                    self.expect_keyword("end")?;
+                    break;
+                } else {
+                    self.expect_keyword("end")?;
+                    break;
+                }
+            }
+            StmtKind::If(branches, otherwise)
+        } else if self.eat_keyword("return") {
+            if self.is_keyword("end")
+                || self.is_keyword("else")
+                || self.is_keyword("elseif")
+                || self.peek() == &Token::Symbol(";")
+            {
+                StmtKind::Return(None)
+            } else {
+                StmtKind::Return(Some(self.expression(0)?))
+            }
+        } else if ["while", "for", "repeat", "goto", "break", "do"]
+            .iter()
+            .any(|keyword| self.is_keyword(keyword))
+        {
+            return self.error("loops and blocks are not supported in plugins");
+        } else if self.is_keyword("function") {
+            return self.error("functions can only be defined at the top level");
+        } else {
+            let expr = self.expression(0)?;
+            if self.eat_symbol("=") {
+                let Expr::Name(name) = expr else {
+                    return Err(Error::Parse(
+                        line,
+                        "can only assign to a variable".to_string(),
+                    ));
+                };
+                StmtKind::Assign(name, self.expression(0)?)
+            } else if matches!(expr, Expr::Call(..) | Expr::Method(..)) {
+                StmtKind::Call(expr)
+            } else {
+                return Err(Error::Parse(
+                    line,
+                    "expected a statement, not an expression".to_string(),
+                ));
+            }
+        };
+        Ok(Stmt { line, kind })
+    }
+
+    /// Parses an expression whose binary operators all bind tighter than `min_precedence`.
+    fn expression(&mut self, min_precedence: u8) -> Result<Expr, Error> {
+        let mut lhs = if self.eat_keyword("not") {
+            Expr::Not(Box::new(self.expression(UNARY_PRECEDENCE)?))
+        } else if self.eat_symbol("-") {
+            Expr::Negate(Box::new(self.expression(UNARY_PRECEDENCE)?))
+        } else if self.eat_symbol("#") {
+            Expr::Length(Box::new(self.expression(UNARY_PRECEDENCE)?))
+        } else {
+            self.postfix()?
+        };
+        while let Some((op, precedence)) = BinOp::from_token(self.peek()) {
+            if precedence <= min_precedence {
+                break;
+            }
+            self.next();
+            // Concatenation is right associative, everything else left associative
+            let rhs = if op == BinOp::Concat {
+                self.expression(precedence - 1)?
+            } else {
+                self.expression(precedence)?
+            };
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    /// Parses a primary expression followed by any method calls on it.
+    fn postfix(&mut self) -> Result<Expr, Error> {
+        let start = self.pos;
+        let mut expr = match self.next() {
+            Token::Number(number) => Expr::Literal(Value::Number(number)),
+            Token::Str(value) => Expr::Literal(Value::Str(value)),
+            Token::Name(name) if name == "nil" => Expr::Literal(Value::Nil),
+            Token::Name(name) if name == "true" => Expr::Literal(Value::Bool(true)),
+            Token::Name(name) if name == "false" => Expr::Literal(Value::Bool(false)),
+            Token::Name(name) if !KEYWORDS.contains(&name.as_str()) => {
+                if self.eat_symbol("(") {
+                    Expr::Call(name, self.arguments()?)
+                } else {
+                    Expr::Name(name)
+                }
+            }
+            Token::Symbol("(") => {
+                let expr = self.expression(0)?;
+                self.expect_symbol(")")?;
+                expr
+            }
+            _ => {
+                self.pos = start;
+                return self.error("expected an expression");
+            }
+        };
+        while self.eat_symbol(":") {
+            let method = self.name()?;
+            self.expect_symbol("(")?;
+            expr = Expr::Method(Box::new(expr), method, self.arguments()?);
+        }
+        if self.peek() == &Token::Symbol(".") || self.peek() == &Token::Symbol("(") {
+            return self.error("tables and function values are not supported in plugins");
+        }
+        Ok(expr)
+    }
+
+    /// Parses call arguments, after the opening parenthesis.
+    fn arguments(&mut self) -> Result<Vec<Expr>, Error> {
+        let mut args = Vec::new();
+        if self.eat_symbol(")") {
+            return Ok(args);
+        }
+        loop {
+            args.push(self.expression(0)?);
+            if self.eat_symbol(")") {
+                return Ok(args);
+            }
+            self.expect_symbol(",")?;
+        }
+    }
+}
+
+/// Returns the entry of SYMBOLS equal to `symbol`, so that it can be compared against tokens.
+fn symbol_str(symbol: &str) -> &'static str {
+    SYMBOLS
+        .iter()
+        .find(|candidate| **candidate == symbol)
+        .expect("unknown symbol")
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Value {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    Str(String),
+    /// The request being filtered
+    Request,
+    /// A response, by index into Context::responses
+    Response(usize),
+}
+
+impl Value {
+    fn type_name(&self) -> &'static str {
+        match self {
+            Value::Nil => "nil",
+            Value::Bool(_) => "boolean",
+            Value::Number(_) => "number",
+            Value::Str(_) => "string",
+            Value::Request => "request",
+            Value::Response(_) => "response",
+        }
+    }
+
+    fn is_truthy(&self) -> bool {
+        !matches!(self, Value::Nil | Value::Bool(false))
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Nil => write!(f, "nil"),
+            Value::Bool(value) => write!(f, "{}", value),
+            Value::Number(number) if number.fract() == 0.0 && number.abs() < 1e15 => {
+                write!(f, "{}", *number as i64)
+            }
+            Value::Number(number) => write!(f, "{}", number),
+            Value::Str(value) => write!(f, "{}", value),
+            Value::Request => write!(f, "request"),
+            Value::Response(_) => write!(f, "response"),
+        }
+    }
+}
+
+/// The request a hook runs on. on_response hooks can look at the request but not change it, since
+/// it has already been sent.
+enum RequestRef<'a> {
+    Mutable(&'a mut http::Request<Vec<u8>>),
+    ReadOnly(&'a http::Request<Vec<u8>>),
+}
+
+impl RequestRef<'_> {
+    fn get(&self) -> &http::Request<Vec<u8>> {
+        match self {
+            RequestRef::Mutable(request) => request,
+            RequestRef::ReadOnly(request) => request,
+        }
+    }
+}
+
+/// Everything a running hook can see
+struct Context<'a> {
+    plugin: &'a str,
+    request: RequestRef<'a>,
+    client_ip: IpAddr,
+    /// Responses the hook can work on: the upstream's in on_response, and any made with respond
+    responses: Vec<http::Response<Vec<u8>>>,
+    /// Local variables, innermost block last
+    scopes: Vec<HashMap<String, Value>>,
+}
+
+impl Context<'_> {
+    fn call(&mut self, function: &Function, args: Vec<Value>) -> Result<Value, Error> {
+        let mut scope = HashMap::new();
+        let mut args = args.into_iter();
+        for param in &function.params {
+            scope.insert(param.clone(), args.next().unwrap_or(Value::Nil));
+        }
+        self.scopes.push(scope);
+        let returned = self.block(&function.body);
+        self.scopes.pop();
+        Ok(returned?.unwrap_or(Value::Nil))
+    }
+
+    /// Runs a block of statements. Returns Some(value) if a `return` was reached.
+    fn block(&mut self, stmts: &[Stmt]) -> Result<Option<Value>, Error> {
+        self.scopes.push(HashMap::new());
+        let mut returned = Ok(None);
+        for stmt in stmts {
+            returned = self.statement(stmt);
+            if !matches!(returned, Ok(None)) {
+                break;
+            }
+        }
+        self.scopes.pop();
+        returned
+    }
+
+    fn statement(&mut self, stmt: &Stmt) -> Result<Option<Value>, Error> {
+        let line = stmt.line;
+        match &stmt.kind {
+            StmtKind::Local(name, value) => {
+                let value = match value {
+                    Some(value) => self.eval(value, line)?,
+                    None => Value::Nil,
+                };
+                self.scopes.last_mut().unwrap().insert(name.clone(), value);
+            }
+            StmtKind::Assign(name, value) => {
+                let value = self.eval(value, line)?;
+                let Some(slot) = self
+                    .scopes
+                    .iter_mut()
+                    .rev()
+                    .find_map(|scope| scope.get_mut(name))
+                else {
+                    return Err(Error::Runtime(
+                        line,
+                        format!("assignment to undeclared variable {:?}", name),
+                    ));
+                };
+                *slot = value;
+            }
+            StmtKind::Call(call) => {
+                self.eval(call, line)?;
+            }
+            StmtKind::If(branches, otherwise) => {
+                for (condition, body) in branches {
+                    if self.eval(condition, line)?.is_truthy() {
+                        return self.block(body);
+                    }
+                }
+                return self.block(otherwise);
+            }
+            StmtKind::Return(value) => {
+                return Ok(Some(match value {
+                    Some(value) => self.eval(value, line)?,
+                    None => Value::Nil,
+                }))
+            }
+        }
+        Ok(None)
+    }
+
+    fn eval(&mut self, expr: &Expr, line: usize) -> Result<Value, Error> {
+        let error = |message: String| Error::Runtime(line, message);
+        match expr {
+            Expr::Literal(value) => Ok(value.clone()),
+            Expr::Name(name) => self
+                .scopes
+                .iter()
+                .rev()
+                .find_map(|scope| scope.get(name))
+                .cloned()
+                .ok_or_else(|| error(format!("unknown variable {:?}", name))),
+            Expr::Not(operand) => Ok(Value::Bool(!self.eval(operand, line)?.is_truthy())),
+            Expr::Negate(operand) => match self.eval(operand, line)? {
+                Value::Number(number) => Ok(Value::Number(-number)),
+                value => Err(error(format!("can't negate a {}", value.type_name()))),
+            },
+            Expr::Length(operand) => match self.eval(operand, line)? {
+                Value::Str(value) => Ok(Value::Number(value.len() as f64)),
+                value => Err(error(format!(
+                    "can't take the length of a {}",
+                    value.type_name()
+                ))),
+            },
+            Expr::Binary(BinOp::And, lhs, rhs) => {
+                let lhs = self.eval(lhs, line)?;
+                if lhs.is_truthy() {
+                    self.eval(rhs, line)
+                } else {
+                    Ok(lhs)
+                }
+            }
+            Expr::Binary(BinOp::Or, lhs, rhs) => {
+                let lhs = self.eval(lhs, line)?;
+                if lhs.is_truthy() {
+                    Ok(lhs)
+                } else {
+                    self.eval(rhs, line)
+                }
+            }
+            Expr::Binary(op, lhs, rhs) => {
+                let lhs = self.eval(lhs, line)?;
+                let rhs = self.eval(rhs, line)?;
+                binary(*op, lhs, rhs).map_err(error)
+            }
+            Expr::Call(name, args) => {
+                let args = self.eval_all(args, line)?;
+                self.call_builtin(name, args).map_err(error)
+            }
+            Expr::Method(object, method, args) => {
+                let object = self.eval(object, line)?;
+                let args = self.eval_all(args, line)?;
+                let result = match object {
+                    Value::Request => self.request_method(method, args),
+                    Value::Response(idx) => self.response_method(idx, method, args),
+                    Value::Str(value) => string_method(&value, method, args),
+                    value => Err(format!("can't call methods on a {}", value.type_name())),
+                };
+                result.map_err(error)
+            }
+        }
+    }
+
+    fn eval_all(&mut self, exprs: &[Expr], line: usize) -> Result<Vec<Value>, Error> {
+        exprs.iter().map(|expr| self.eval(expr, line)).collect()
+    }
+
+    fn call_builtin(&mut self, name: &str, args: Vec<Value>) -> Result<Value, String> {
+        match name {
+            "respond" => {
+                let status = status_arg(&args, 0)?;
+                let body = match args.get(1) {
+                    None | Some(Value::Nil) => Vec::new(),
+                    Some(_) => string_arg(&args, 1)?.into_bytes(),
+                };
+                let mut response = http::Response::builder()
+                    .status(status)
+                    .version(http::Version::HTTP_11);
+                if !body.is_empty() {
+                    response = response.header("Content-Type", "text/plain; charset=utf-8");
+                }
+                let response = response
+                    .header("Content-Length", body.len().to_string())
+                    .body(body)
+                    .unwrap();
+                self.responses.push(response);
+                Ok(Value::Response(self.responses.len() - 1))
+            }
+            "log" => {
+                let message: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+                tracing::info!("[plugin {}] {}", self.plugin, message.join("\t"));
+                Ok(Value::Nil)
+            }
+            "tostring" => Ok(Value::Str(args.first().unwrap_or(&Value::Nil).to_string())),
+            "tonumber" => Ok(match args.first() {
+                Some(Value::Number(number)) => Value::Number(*number),
+                Some(Value::Str(value)) => value
+                    .trim()
+                    .parse::<f64>()
+                    .map_or(Value::Nil, Value::Number),
+                _ => Value::Nil,
+            }),
+            _ => Err(format!("unknown function {:?}", name)),
+        }
+    }
+
+    fn request_method(&mut self, method: &str, args: Vec<Value>) -> Result<Value, String> {
+        let request = self.request.get();
+        match method {
+            "method" => return Ok(Value::Str(request.method().to_string())),
+            "path" => return Ok(Value::Str(request.uri().path().to_string())),
+            "query" => {
+                return Ok(request
+                    .uri()
+                    .query()
+                    .map_or(Value::Nil, |query| Value::Str(query.to_string())))
+            }
+            "client_ip" => return Ok(Value::Str(self.client_ip.to_string())),
+            "body" => {
+                return Ok(Value::Str(
+                    String::from_utf8_lossy(request.body()).into_owned(),
+                ))
+            }
+            "header" => return header(request.headers(), &args),
+            _ => {}
+        }
+        let RequestRef::Mutable(request) = &mut self.request else {
+            return Err(format!(
+                "request:{} can't be used in on_response; the request was already sent",
+                method
+            ));
+        };
+        match method {
+            "set_path" => {
+                let path = string_arg(&args, 0)?;
+                if !path.starts_with('/') {
+                    return Err(format!("invalid path {:?}", path));
+                }
+                *request.uri_mut() = path
+                    .parse()
+                    .map_err(|_| format!("invalid path {:?}", path))?;
+                Ok(Value::Nil)
+            }
+            _ => set_header(request.headers_mut(), method, &args),
+        }
+    }
+
+    fn response_method(
+        &mut self,
+        idx: usize,
+        method: &str,
+        args: Vec<Value>,
+    ) -> Result<Value, String> {
+        let response = &mut self.responses[idx];
+        match method {
+            "status" => Ok(Value::Number(response.status().as_u16() as f64)),
+            "set_status" => {
+                *response.status_mut() = status_arg(&args, 0)?;
+                Ok(Value::Nil)
+            }
+            "body" => Ok(Value::Str(
+                String::from_utf8_lossy(response.body()).into_owned(),
+            )),
+            "set_body" => {
+                let body = string_arg(&args, 0)?.into_bytes();
+                response
+                    .headers_mut()
+                    .insert("content-length", http::HeaderValue::from(body.len()));
+                *response.body_mut() = body;
+                Ok(Value::Nil)
+            }
+            "header" => header(response.headers(), &args),
+            _ => set_header(response.headers_mut(), method, &args),
+        }
+    }
+}
+
+/// Implements the `header` method of requests and responses.
+fn header(headers: &http::HeaderMap, args: &[Value]) -> Result<Value, String> {
+    let name = string_arg(args, 0)?;
+    Ok(headers.get(name.as_str()).map_or(Value::Nil, |value| {
+        Value::Str(String::from_utf8_lossy(value.as_bytes()).into_owned())
+    }))
+}
+
+/// Implements the methods that change headers of requests and responses.
+fn set_header(
+    headers: &mut http::HeaderMap,
+    method: &str,
+    args: &[Value],
+) -> Result<Value, String> {
+    if !["set_header", "add_header", "remove_header"].contains(&method) {
+        return Err(format!("unknown method {:?}", method));
+    }
+    let name = string_arg(args, 0)?;
+    let name = http::HeaderName::from_bytes(name.as_bytes())
+        .map_err(|_| format!("invalid header name {:?}", name))?;
+    if method == "remove_header" {
+        headers.remove(name);
+        return Ok(Value::Nil);
+    }
+    let value = string_arg(args, 1)?;
+    let value = http::HeaderValue::from_str(&value)
+        .map_err(|_| format!("invalid header value {:?}", value))?;
+    if method == "set_header" {
+        headers.insert(name, value);
+    } else {
+        headers.append(name, value);
+    }
+    Ok(Value::Nil)
+}
+
+/// Implements the methods of strings. Positions are 1-based and may be negative to count from the
+/// end.
+fn string_method(value: &str, method: &str, args: Vec<Value>) -> Result<Value, String> {
+    let len = value.len() as i64;
+    // Converts a script position to a 0-based offset, clamped to the string
+    let offset = |pos: i64| -> usize {
+        let pos = if pos < 0 { len + pos + 1 } else { pos };
+        pos.clamp(1, len + 1) as usize - 1
+    };
+    match method {
+        "len" => Ok(Value::Number(len as f64)),
+        "lower" => Ok(Value::Str(value.to_lowercase())),
+        "upper" => Ok(Value::Str(value.to_uppercase())),
+        "sub" => {
+            let start = offset(number_arg(&args, 0)? as i64);
+            let end = match args.get(1) {
+                None | Some(Value::Nil) => value.len(),
+                Some(_) => {
+                    let end = number_arg(&args, 1)? as i64;
+                    let end = if end < 0 { len + end + 1 } else { end };
+                    end.clamp(0, len) as usize
+                }
+            };
+            let bytes = value.as_bytes().get(start..end.max(start)).unwrap_or(&[]);
+            Ok(Value::Str(String::from_utf8_lossy(bytes).into_owned()))
+        }
+        "find" => {
+            let needle = string_arg(&args, 0)?;
+            let start = match args.get(1) {
+                None | Some(Value::Nil) => 0,
+                Some(_) => offset(number_arg(&args, 1)? as i64),
+            };
+            let found = if needle.is_empty() {
+                Some(0)
+            } else {
+                value.as_bytes()[start..]
+                    .windows(needle.len())
+                    .position(|window| window == needle.as_bytes())
+            };
+            Ok(found.map_or(Value::Nil, |pos| Value::Number((start + pos + 1) as f64)))
+        }
+        _ => Err(format!("unknown string method {:?}", method)),
+    }
+}
+
+fn binary(op: BinOp, lhs: Value, rhs: Value) -> Result<Value, String> {
+    let type_error = |lhs: &Value, rhs: &Value| {
+        Err(format!(
+            "can't apply {:?} to a {} and a {}",
+            op,
+            lhs.type_name(),
+            rhs.type_name()
+        ))
+    };
+    match op {
+        BinOp::Eq => Ok(Value::Bool(lhs == rhs)),
+        BinOp::NotEq => Ok(Value::Bool(lhs != rhs)),
+        BinOp::Less | BinOp::LessEq | BinOp::Greater | BinOp::GreaterEq => {
+            let ordering = match (&lhs, &rhs) {
+                (Value::Number(a), Value::Number(b)) => a.partial_cmp(b),
+                (Value::Str(a), Value::Str(b)) => Some(a.cmp(b)),
+                _ => return type_error(&lhs, &rhs),
+            };
+            let Some(ordering) = ordering else {
+                return Ok(Value::Bool(false));
+            };
+            Ok(Value::Bool(match op {
+                BinOp::Less => ordering.is_lt(),
+                BinOp::LessEq => ordering.is_le(),
+                BinOp::Greater => ordering.is_gt(),
+                _ => ordering.is_ge(),
+            }))
+        }
+        BinOp::Concat => match (&lhs, &rhs) {
+            (Value::Str(_) | Value::Number(_), Value::Str(_) | Value::Number(_)) => {
+                Ok(Value::Str(format!("{}{}", lhs, rhs)))
+            }
+            _ => type_error(&lhs, &rhs),
+        },
+        _ => {
+            let (Value::Number(a), Value::Number(b)) = (&lhs, &rhs) else {
+                return type_error(&lhs, &rhs);
+            };
+            Ok(Value::Number(match op {
+                BinOp::Add => a + b,
+                BinOp::Subtract => a - b,
+                BinOp::Multiply => a * b,
+                BinOp::Divide => a / b,
+                // Modulo takes the sign of the divisor, so -1 % 3 == 2
+                _ => a - (a / b).floor() * b,
+            }))
+        }
+    }
+}
+
+fn string_arg(args: &[Value], idx: usize) -> Result<String, String> {
+    match args.get(idx) {
+        Some(Value::Str(value)) => Ok(value.clone()),
+        Some(Value::Number(number)) => Ok(Value::Number(*number).to_string()),
+        arg => Err(format!(
+            "argument {} must be a string, not {}",
+            idx + 1,
+            arg.map_or("nothing", Value::type_name)
+        )),
+    }
+}
+
+fn number_arg(args: &[Value], idx: usize) -> Result<f64, String> {
+    match args.get(idx) {
+        Some(Value::Number(number)) => Ok(*number),
+        arg => Err(format!(
+            "argument {} must be a number, not {}",
+            idx + 1,
+            arg.map_or("nothing", Value::type_name)
+        )),
+    }
+}
+
+fn status_arg(args: &[Value], idx: usize) -> Result<http::StatusCode, String> {
+    let status = number_arg(args, idx)?;
+    if status.fract() != 0.0 {
+        return Err(format!("invalid status {}", status));
+    }
+    http::StatusCode::from_u16(status as u16)
+        .ok()
+        .filter(|_| (100.0..1000.0).contains(&status))
+        .ok_or_else(|| format!("invalid status {}", status))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(source: &str) -> Vec<(Token, usize)> {
+        tokenize(source).unwrap()
+    }
+
+    fn name(name: &str) -> Token {
+        Token::Name(name.to_string())
+    }
+
+    fn string(value: &str) -> Token {
+        Token::Str(value.to_string())
+    }
+
+    /// Evaluates a single expression with an empty request and no local variables.
+    fn eval(source: &str) -> Result<Value, Error> {
+        let mut parser = Parser {
+            tokens: tokenize(source)?,
+            pos: 0,
+        };
+        let expr = parser.expression(0)?;
+        assert_eq!(
+            parser.peek(),
+            &Token::Eof,
+            "trailing tokens in {:?}",
+            source
+        );
+        let request = http::Request::new(Vec::new());
+        let mut context = Context {
+            plugin: "test",
+            request: RequestRef::ReadOnly(&request),
+            client_ip: IpAddr::from([127, 0, 0, 1]),
+            responses: Vec::new(),
+            scopes: vec![HashMap::new()],
+        };
+        context.eval(&expr, 1)
+    }
+
+    fn number(source: &str) -> f64 {
+        match eval(source).unwrap() {
+            Value::Number(number) => number,
+            value => panic!("{:?} evaluated to {:?}", source, value),
+        }
+    }
+
+    fn text(source: &str) -> String {
+        match eval(source).unwrap() {
+            Value::Str(value) => value,
+            value => panic!("{:?} evaluated to {:?}", source, value),
+        }
+    }
+
+    fn parse_error(source: &str) -> (usize, String) {
+        match Plugin::parse("test", source) {
+            Err(Error::Parse(line, message)) => (line, message),
+            other => panic!("expected a parse error, got {:?}", other),
+        }
+    }
+
+    fn runtime_error(source: &str) -> (usize, String) {
+        match eval(source) {
+            Err(Error::Runtime(line, message)) => (line, message),
+            other => panic!("expected a runtime error, got {:?}", other),
+        }
+    }
+
+    fn request() -> http::Request<Vec<u8>> {
+        http::Request::builder()
+            .method("GET")
+            .uri("/items?id=7")
+            .header("x-api-key", "secret")
+            .header("x-powered-by", "php")
+            .body(Vec::new())
+            .unwrap()
+    }
+
+    fn client_ip() -> IpAddr {
+        IpAddr::from([10, 0, 0, 1])
+    }
+
+    #[test]
+    fn test_tokenize() {
+        assert_eq!(
+            tokens("local x = a:b(1, 2.5)"),
+            vec![
+                (name("local"), 1),
+                (name("x"), 1),
+                (Token::Symbol("="), 1),
+                (name("a"), 1),
+                (Token::Symbol(":"), 1),
+                (name("b"), 1),
+                (Token::Symbol("("), 1),
+                (Token::Number(1.0), 1),
+                (Token::Symbol(","), 1),
+                (Token::Number(2.5), 1),
+                (Token::Symbol(")"), 1),
+                (Token::Eof, 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tokenize_longest_symbol() {
+        let symbols: Vec<Token> = tokens("== ~= <= >= .. < = .")
+            .into_iter()
+            .map(|(token, _)| token)
+            .collect();
+        assert_eq!(
+            symbols,
+            vec![
+                Token::Symbol("=="),
+                Token::Symbol("~="),
+                Token::Symbol("<="),
+                Token::Symbol(">="),
+                Token::Symbol(".."),
+                Token::Symbol("<"),
+                Token::Symbol("="),
+                Token::Symbol("."),
+                Token::Eof,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tokenize_lines_and_comments() {
+        let source = "a -- comment\n--[[ block\ncomment ]] b\n\nc";
+        assert_eq!(
+            tokens(source),
+            vec![
+                (name("a"), 1),
+                (name("b"), 3),
+                (name("c"), 5),
+                (Token::Eof, 5)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tokenize_strings() {
+        assert_eq!(
+            tokens(r#""a\"b" 'c\'d' "\t\n\r\\" 'say "hi"'"#),
+            vec![
+                (string("a\"b"), 1),
+                (string("c'd"), 1),
+                (string("\t\n\r\\"), 1),
+                (string("say \"hi\""), 1),
+                (Token::Eof, 1),
+            ]
+        );
+        assert_eq!(tokens("'héllo'")[0].0, string("héllo"));
+    }
+
+    #[test]
+    fn test_tokenize_errors() {
+        let error = |source: &str| match tokenize(source) {
+            Err(Error::Parse(line, message)) => (line, message),
+            other => panic!("expected a parse error, got {:?}", other),
+        };
+        assert_eq!(error("\n\"abc"), (2, "unterminated string".to_string()));
+        assert_eq!(error("'abc\n'"), (1, "unterminated string".to_string()));
+        assert_eq!(error("'\\q'"), (1, "invalid escape in string".to_string()));
+        assert_eq!(error("--[[ open"), (1, "unterminated comment".to_string()));
+        assert_eq!(error("1.2.3"), (1, "invalid number \"1.2.3\"".to_string()));
+        assert_eq!(
+            error("\n\na @ b"),
+            (3, "unexpected character '@'".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_hooks() {
+        let plugin = Plugin::parse(
+            "test",
+            "function on_request(req) end\n;\nfunction on_response(req, resp)\n  return resp\nend",
+        )
+        .unwrap();
+        assert_eq!(plugin.name(), "test");
+        let on_request = plugin.on_request.unwrap();
+        assert_eq!(
+            (on_request.line, on_request.params),
+            (1, vec!["req".to_string()])
+        );
+        let on_response = plugin.on_response.unwrap();
+        assert_eq!(on_response.line, 3);
+        assert_eq!(on_response.params, vec!["req", "resp"]);
+        assert_eq!(on_response.body.len(), 1);
+        assert_eq!(on_response.body[0].line, 4);
+
+        let plugin = Plugin::parse("test", "function on_response() end").unwrap();
+        assert!(plugin.on_request.is_none() && plugin.on_response.is_some());
+    }
+
+    #[test]
+    fn test_parse_top_level_errors() {
+        assert_eq!(
+            parse_error("function on_request() end\n\nfunction helper() end"),
+            (
+                3,
+                "unknown hook \"helper\" (expected on_request or on_response)".to_string()
+            )
+        );
+        assert_eq!(
+            parse_error("function on_request() end\nfunction on_request() end"),
+            (2, "on_request is defined twice".to_string())
+        );
+        assert_eq!(
+            parse_error("function on_request() end\nlocal x = 1"),
+            (
+                2,
+                "only function definitions are allowed at the top level".to_string()
+            )
+        );
+        assert_eq!(
+            parse_error("-- nothing here\n"),
+            (
+                1,
+                "script defines neither on_request nor on_response".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_syntax_errors() {
+        assert_eq!(
+            parse_error("function on_request(req)\n  if true then\n    log(1)\n"),
+            (4, "expected \"end\", found end of file".to_string())
+        );
+        assert_eq!(
+            parse_error("function on_request(req)\n  local = 1\nend"),
+            (2, "expected a name, found \"=\"".to_string())
+        );
+        assert_eq!(
+            parse_error("function on_request(req)\n  log(1 +)\nend"),
+            (2, "expected an expression, found \")\"".to_string())
+        );
+        assert_eq!(
+            parse_error("function on_request(req)\n  while true do end\nend"),
+            (
+                2,
+                "loops and blocks are not supported in plugins, found \"while\"".to_string()
+            )
+        );
+        assert_eq!(
+            parse_error("function on_request(req)\n  function f() end\nend"),
+            (
+                2,
+                "functions can only be defined at the top level, found \"function\"".to_string()
+            )
+        );
+        assert_eq!(
+            parse_error("function on_request(req)\n  return 1\n  log(2)\nend"),
+            (
+                3,
+                "return must be the last statement in a block, found \"log\"".to_string()
+            )
+        );
+        assert_eq!(
+            parse_error("function on_request(req)\n  req:path() = 1\nend"),
+            (2, "can only assign to a variable".to_string())
+        );
+        assert_eq!(
+            parse_error("function on_request(req)\n  1 + 2\nend"),
+            (2, "expected a statement, not an expression".to_string())
+        );
+        assert_eq!(
+            parse_error("function on_request(req)\n  log(req.path)\nend"),
+            (
+                2,
+                "tables and function values are not supported in plugins, found \".\"".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn test_eval_arithmetic() {
+        assert_eq!(number("1 + 2 * 3"), 7.0);
+        assert_eq!(number("(1 + 2) * 3"), 9.0);
+        assert_eq!(number("10 - 4 - 3"), 3.0);
+        assert_eq!(number("12 / 4 / 3"), 1.0);
+        assert_eq!(number("-2 * 3"), -6.0);
+        assert_eq!(number("- -2"), 2.0);
+        assert_eq!(number("7 % 3"), 1.0);
+        // The result of % takes the sign of the divisor
+        assert_eq!(number("-1 % 3"), 2.0);
+        assert_eq!(number("1 % -3"), -2.0);
+        assert_eq!(number("5.5 % 2"), 1.5);
+    }
+
+    #[test]
+    fn test_eval_strings() {
+        assert_eq!(text("'a' .. 'b' .. 'c'"), "abc");
+        assert_eq!(text("'n=' .. 1 + 2"), "n=3");
+        assert_eq!(text("1 .. 2"), "12");
+        assert_eq!(text("'x' .. 1.5"), "x1.5");
+        assert_eq!(number("#'hello'"), 5.0);
+        assert_eq!(number("#'ab' + 1"), 3.0);
+        assert_eq!(number("#('ab' .. 'cd')"), 4.0);
+    }
+
+    #[test]
+    fn test_eval_logic() {
+        assert_eq!(
+            eval("1 < 2 and 'yes' or 'no'").unwrap(),
+            Value::Str("yes".into())
+        );
+        assert_eq!(
+            eval("1 > 2 and 'yes' or 'no'").unwrap(),
+            Value::Str("no".into())
+        );
+        assert_eq!(eval("nil or false").unwrap(), Value::Bool(false));
+        assert_eq!(eval("false and undefined").unwrap(), Value::Bool(false));
+        assert_eq!(eval("1 or undefined").unwrap(), Value::Number(1.0));
+        assert_eq!(eval("not nil").unwrap(), Value::Bool(true));
+        assert_eq!(eval("not 0").unwrap(), Value::Bool(false));
+        assert_eq!(eval("not 1 == 2").unwrap(), Value::Bool(false));
+        assert_eq!(eval("'a' < 'b'").unwrap(), Value::Bool(true));
+        assert_eq!(eval("2 >= 2").unwrap(), Value::Bool(true));
+        assert_eq!(eval("1 == '1'").unwrap(), Value::Bool(false));
+        assert_eq!(eval("nil ~= false").unwrap(), Value::Bool(true));
+        assert_eq!(eval("0 / 0 < 1").unwrap(), Value::Bool(false));
+    }
+
+    #[test]
+    fn test_eval_builtins() {
+        assert_eq!(text("tostring(nil)"), "nil");
+        assert_eq!(text("tostring(3)"), "3");
+        assert_eq!(text("tostring(0.25)"), "0.25");
+        assert_eq!(number("tonumber(' 42 ')"), 42.0);
+        assert_eq!(eval("tonumber('abc')").unwrap(), Value::Nil);
+        assert_eq!(eval("log('a', 1)").unwrap(), Value::Nil);
+    }
+
+    #[test]
+    fn test_eval_string_methods() {
+        assert_eq!(number("('hello'):len()"), 5.0);
+        assert_eq!(text("('MiXeD'):lower()"), "mixed");
+        assert_eq!(text("('MiXeD'):upper()"), "MIXED");
+        assert_eq!(text("('hello'):sub(2, 4)"), "ell");
+        assert_eq!(text("('hello'):sub(2)"), "ello");
+        assert_eq!(text("('hello'):sub(-3)"), "llo");
+        assert_eq!(text("('hello'):sub(-3, -2)"), "ll");
+        assert_eq!(text("('hello'):sub(0, 100)"), "hello");
+        assert_eq!(text("('hello'):sub(4, 2)"), "");
+        assert_eq!(number("('hello'):find('l')"), 3.0);
+        assert_eq!(number("('hello'):find('l', 4)"), 4.0);
+        assert_eq!(number("('a.b'):find('.')"), 2.0);
+        assert_eq!(eval("('hello'):find('z')").unwrap(), Value::Nil);
+        assert_eq!(text("('a'):upper():lower() .. 'b'"), "ab");
+    }
+
+    #[test]
+    fn test_eval_errors() {
+        assert_eq!(
+            runtime_error("undefined"),
+            (1, "unknown variable \"undefined\"".to_string())
+        );
+        assert_eq!(
+            runtime_error("1 + 'a'"),
+            (1, "can't apply Add to a number and a string".to_string())
+        );
+        assert_eq!(
+            runtime_error("'a' .. nil"),
+            (1, "can't apply Concat to a string and a nil".to_string())
+        );
+        assert_eq!(
+            runtime_error("1 < 'a'"),
+            (1, "can't apply Less to a number and a string".to_string())
+        );
+        assert_eq!(
+            runtime_error("-'a'"),
+            (1, "can't negate a string".to_string())
+        );
+        assert_eq!(
+            runtime_error("#1"),
+            (1, "can't take the length of a number".to_string())
+        );
+        assert_eq!(
+            runtime_error("missing()"),
+            (1, "unknown function \"missing\"".to_string())
+        );
+        assert_eq!(
+            runtime_error("('a'):reverse()"),
+            (1, "unknown string method \"reverse\"".to_string())
+        );
+        assert_eq!(
+            runtime_error("(true):len()"),
+            (1, "can't call methods on a boolean".to_string())
+        );
+        assert_eq!(
+            runtime_error("('a'):sub('x')"),
+            (1, "argument 1 must be a number, not string".to_string())
+        );
+        assert_eq!(
+            runtime_error("respond(99)"),
+            (1, "invalid status 99".to_string())
+        );
+    }
+
+    #[test]
+    fn test_on_request_modifies_request() {
+        let plugin = Plugin::parse(
+            "test",
+            r#"
+            function on_request(req)
+              local key = req:header("x-api-key")
+              if key == nil then
+                return respond(401)
+              elseif key ~= "secret" then
+                return respond(403, "bad key")
+              end
+              req:set_header("x-user", "user-" .. key:upper())
+              req:add_header("x-seen", "1")
+              req:add_header("x-seen", req:client_ip())
+              req:remove_header("x-powered-by")
+              req:set_path("/v2" .. req:path() .. "?" .. req:query())
+            end
+            "#,
+        )
+        .unwrap();
+        let mut request = request();
+        assert!(plugin
+            .on_request(&mut request, client_ip())
+            .unwrap()
+            .is_none());
+        assert_eq!(request.uri(), "/v2/items?id=7");
+        assert_eq!(request.headers()["x-user"], "user-SECRET");
+        let seen: Vec<_> = request.headers().get_all("x-seen").iter().collect();
+        assert_eq!(seen, vec!["1", "10.0.0.1"]);
+        assert!(!request.headers().contains_key("x-powered-by"));
+    }
+
+    #[test]
+    fn test_on_request_responds() {
+        let plugin = Plugin::parse(
+            "test",
+            r#"
+            function on_request(req)
+              local key = req:header("x-api-key")
+              if key == nil then
+                return respond(401)
+              elseif key ~= "secret" then
+                return respond(403, "bad key")
+              end
+            end
+            "#,
+        )
+        .unwrap();
+        let mut request = request();
+        request
+            .headers_mut()
+            .insert("x-api-key", http::HeaderValue::from_static("wrong"));
+        let response = plugin
+            .on_request(&mut request, client_ip())
+            .unwrap()
+            .unwrap();
+        assert_eq!(response.status(), http::StatusCode::FORBIDDEN);
+        assert_eq!(response.body(), b"bad key");
+        assert_eq!(response.headers()["content-length"], "7");
+        assert_eq!(
+            response.headers()["content-type"],
+            "text/plain; charset=utf-8"
+        );
+
+        request.headers_mut().remove("x-api-key");
+        let response = plugin
+            .on_request(&mut request, client_ip())
+            .unwrap()
+            .unwrap();
+        assert_eq!(response.status(), http::StatusCode::UNAUTHORIZED);
+        assert!(response.body().is_empty());
+        assert!(!response.headers().contains_key("content-type"));
+    }
+
+    #[test]
+    fn test_on_response() {
+        let plugin = Plugin::parse(
+            "test",
+            r#"
+            function on_response(req, resp)
+              resp:remove_header("x-powered-by")
+              if resp:status() >= 500 then
+                return respond(503, "try again later")
+              end
+              resp:set_status(resp:status() + 1)
+              resp:set_body(resp:body() .. " for " .. req:method())
+            end
+            "#,
+        )
+        .unwrap();
+        let request = request();
+        let upstream_response = |status: u16| {
+            http::Response::builder()
+                .status(status)
+                .header("x-powered-by", "php")
+                .body(b"ok".to_vec())
+                .unwrap()
+        };
+
+        let mut response = upstream_response(200);
+        plugin
+            .on_response(&request, &mut response, client_ip())
+            .unwrap();
+        assert_eq!(response.status(), http::StatusCode::CREATED);
+        assert_eq!(response.body(), b"ok for GET");
+        assert_eq!(response.headers()["content-length"], "10");
+        assert!(!response.headers().contains_key("x-powered-by"));
+
+        let mut response = upstream_response(502);
+        plugin
+            .on_response(&request, &mut response, client_ip())
+            .unwrap();
+        assert_eq!(response.status(), http::StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.body(), b"try again later");
+    }
+
+    #[test]
+    fn test_hook_runtime_errors() {
+        let plugin = Plugin::parse(
+            "test",
+            "function on_request(req)\n  local n = 1\n  n = n .. nil\nend\n\
+             function on_response(req, resp)\n  resp:set_header(\"x-a\", \"b\")\n  \
+             req:set_header(\"x\", \"y\")\nend",
+        )
+        .unwrap();
+        let mut request = request();
+        match plugin.on_request(&mut request, client_ip()) {
+            Err(Error::Runtime(3, message)) => {
+                assert_eq!(message, "can't apply Concat to a number and a nil")
+            }
+            other => panic!("expected a runtime error on line 3, got {:?}", other),
+        }
+
+        // A failed on_response hook leaves the (possibly modified) upstream response in place
+        let mut response = http::Response::new(b"body".to_vec());
+        match plugin.on_response(&request, &mut response, client_ip()) {
+            Err(Error::Runtime(7, message)) => assert_eq!(
+                message,
+                "request:set_header can't be used in on_response; the request was already sent"
+            ),
+            other => panic!("expected a runtime error on line 7, got {:?}", other),
+        }
+        assert_eq!(response.body(), b"body");
+        assert_eq!(response.headers()["x-a"], "b");
+
+        let plugin = Plugin::parse("test", "function on_request(req)\n  return 1\nend").unwrap();
+        match plugin.on_request(&mut request, client_ip()) {
+            Err(Error::Runtime(1, message)) => {
+                assert_eq!(message, "on_request returned a number, not a response")
+            }
+            other => panic!("expected a runtime error on line 1, got {:?}", other),
+        }
+
+        let plugin =
+            Plugin::parse("test", "function on_request(req)\n  undeclared = 1\nend").unwrap();
+        match plugin.on_request(&mut request, client_ip()) {
+            Err(Error::Runtime(2, message)) => {
+                assert_eq!(message, "assignment to undeclared variable \"undeclared\"")
+            }
+            other => panic!("expected a runtime error on line 2, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_scopes() {
+        let plugin = Plugin::parse(
+            "test",
+            r#"
+            function on_request(req)
+              local x = "outer"
+              if true then
+                local x = "inner"
+                local y = "block"
+              end
+              if true then
+                x = x .. "!"
+              end
+              req:set_header("x-result", x)
+              req:set_header("x-missing", tostring(y == nil))
+            end
+            "#,
+        )
+        .unwrap();
+        // y is out of scope after its block, so reading it fails rather than returning nil
+        let mut request = request();
+        match plugin.on_request(&mut request, client_ip()) {
+            Err(Error::Runtime(12, message)) => assert_eq!(message, "unknown variable \"y\""),
+            other => panic!("expected a runtime error on line 12, got {:?}", other),
+        }
+        assert_eq!(request.headers()["x-result"], "outer!");
+    }
+}
@@ -1,3 +1,4 @@
+use crate::chunked;
 use std::cmp::min;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
@@ -6,7 +7,29 @@ const MAX_HEADERS_SIZE: usize = 8000;
 const MAX_BODY_SIZE: usize = 10000000;
 const MAX_NUM_HEADERS: usize = 32;
 
+/// Limits on the size of requests read from clients
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    /// Maximum size of the request line and headers, in bytes
+    pub max_headers_size: usize,
+    /// Maximum number of headers
+    pub max_num_headers: usize,
+    /// Maximum size of a request body, in bytes
+    pub max_body_size: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_headers_size: MAX_HEADERS_SIZE,
+            max_num_headers: MAX_NUM_HEADERS,
+            max_body_size: MAX_BODY_SIZE,
+        }
+    }
+}
+
 #[derive(Debug)]
+#[allow(dead_code, clippy::enum_variant_names)]
 pub enum Error {
     /// Client hung up before sending a complete request. IncompleteRequest contains the number of
     /// bytes that were successfully read before the client hung up
@@ -17,8 +40,13 @@ pub enum Error {
     InvalidContentLength,
     /// The Content-Length header does not match the size of the request body that was sent
     ContentLengthMismatch,
-    /// The request body is bigger than MAX_BODY_SIZE
+    /// The request body is bigger than Limits::max_body_size
     RequestBodyTooLarge,
+    /// The request body is sent with chunked transfer coding, but the chunks are malformed
+    MalformedChunkedBody,
+    /// The request line and headers are bigger than Limits::max_headers_size, or there are more
+    /// than Limits::max_num_headers headers
+    HeadersTooLarge,
     /// Encountered an I/O error when reading/writing a TcpStream
     ConnectionError(std::io::Error),
 }
@@ -74,18 +102,26 @@ pub fn extend_header_value(
 /// * If there is data in the buffer that is definitely not a valid HTTP request, returns Err(Error)
 ///
 /// You won't need to touch this function.
-fn parse_request(buffer: &[u8]) -> Result<Option<(http::Request<Vec<u8>>, usize)>, Error> {
-    let mut headers = [httparse::EMPTY_HEADER; MAX_NUM_HEADERS];
+#[allow(clippy::type_complexity)]
+fn parse_request(
+    buffer: &[u8],
+    max_num_headers: usize,
+) -> Result<Option<(http::Request<Vec<u8>>, usize)>, Error> {
+    let mut headers = vec![httparse::EMPTY_HEADER; max_num_headers];
     let mut req = httparse::Request::new(&mut headers);
-    let res = req
-        .parse(buffer)
-        .or_else(|err| Err(Error::MalformedRequest(err)))?;
+    let res = req.parse(buffer).map_err(|err| match err {
+        httparse::Error::TooManyHeaders => Error::HeadersTooLarge,
+        err => Error::MalformedRequest(err),
+    })?;
 
     if let httparse::Status::Complete(len) = res {
         let mut request = http::Request::builder()
             .method(req.method.unwrap())
             .uri(req.path.unwrap())
-            .version(http::Version::HTTP_11);
+            .version(match req.version {
+                Some(0) => http::Version::HTTP_10,
+                _ => http::Version::HTTP_11,
+            });
         for header in req.headers {
             request = request.header(header.name, header.value);
         }
@@ -103,18 +139,25 @@ fn parse_request(buffer: &[u8]) -> Result<Option<(http::Request<Vec<u8>>, usize)
 /// Returns Ok(http::Request) if a valid request is received, or Error if not.
 ///
 /// You will need to modify this function in Milestone 2.
-async fn read_headers(stream: &mut TcpStream) -> Result<http::Request<Vec<u8>>, Error> {
+async fn read_headers(
+    stream: &mut TcpStream,
+    limits: &Limits,
+) -> Result<http::Request<Vec<u8>>, Error> {
     // Try reading the headers from the request. We may not receive all the headers in one shot
     // (e.g. we might receive the first few bytes of a request, and then the rest follows later).
     // Try parsing repeatedly until we read a valid HTTP request
-    let mut request_buffer = [0_u8; MAX_HEADERS_SIZE];
+    let mut request_buffer = vec![0_u8; limits.max_headers_size];
     let mut bytes_read = 0;
     loop {
+        if bytes_read == request_buffer.len() {
+            // The buffer is full and still doesn't hold a complete set of headers
+            return Err(Error::HeadersTooLarge);
+        }
         // Read bytes from the connection into the buffer, starting at position bytes_read
         let new_bytes = stream
             .read(&mut request_buffer[bytes_read..])
             .await
-            .or_else(|err| Err(Error::ConnectionError(err)))?;
+            .map_err(Error::ConnectionError)?;
         if new_bytes == 0 {
             // We didn't manage to read a complete request
             return Err(Error::IncompleteRequest(bytes_read));
@@ -122,7 +165,9 @@ async fn read_headers(stream: &mut TcpStream) -> Result<http::Request<Vec<u8>>,
         bytes_read += new_bytes;
 
         // See if we've read a valid request so far
-        if let Some((mut request, headers_len)) = parse_request(&request_buffer[..bytes_read])? {
+        if let Some((mut request, headers_len)) =
+            parse_request(&request_buffer[..bytes_read], limits.max_num_headers)?
+        {
             // We've read a complete set of headers. However, if this was a POST request, a request
             // body might have been included as well, and we might have read part of the body out of
             // the stream into header_buffer. We need to add those bytes to the Request body so that
@@ -153,11 +198,11 @@ async fn read_body(
         let bytes_read = stream
             .read(&mut buffer)
             .await
-            .or_else(|err| Err(Error::ConnectionError(err)))?;
+            .map_err(Error::ConnectionError)?;
 
         // Make sure the client is still sending us bytes
         if bytes_read == 0 {
-            log::debug!(
+            tracing::debug!(
                 "Client hung up after sending a body of length {}, even though it said the content \
                 length is {}",
                 request.body().len(),
@@ -168,7 +213,7 @@ async fn read_body(
 
         // Make sure the client didn't send us *too many* bytes
         if request.body().len() + bytes_read > content_length {
-            log::debug!(
+            tracing::debug!(
                 "Client sent more bytes than we expected based on the given content length!"
             );
             return Err(Error::ContentLengthMismatch);
@@ -184,12 +229,36 @@ async fn read_body(
 /// closes the connection prematurely or sends an invalid request.
 ///
 /// You will need to modify this function in Milestone 2.
-pub async fn read_from_stream(stream: &mut TcpStream) -> Result<http::Request<Vec<u8>>, Error> {
+pub async fn read_from_stream(
+    stream: &mut TcpStream,
+    limits: &Limits,
+) -> Result<http::Request<Vec<u8>>, Error> {
     // Read headers
-    let mut request = read_headers(stream).await?;
+    let mut request = read_headers(stream, limits).await?;
+    // Chunked bodies are decoded, so that the rest of balancebeam only deals with whole bodies.
+    // Trailer fields are kept in the request's extensions.
+    if chunked::is_chunked(request.headers()) {
+        let buffer = std::mem::take(request.body_mut());
+        let (body, trailers) = chunked::read_body(stream, buffer, limits.max_body_size)
+            .await
+            .map_err(|error| match error {
+                chunked::Error::Incomplete => Error::ContentLengthMismatch,
+                chunked::Error::Malformed => Error::MalformedChunkedBody,
+                chunked::Error::TooLarge => Error::RequestBodyTooLarge,
+                chunked::Error::Io(error) => Error::ConnectionError(error),
+            })?;
+        let headers = request.headers_mut();
+        headers.remove(http::header::TRANSFER_ENCODING);
+        headers.insert(http::header::CONTENT_LENGTH, body.len().into());
+        *request.body_mut() = body;
+        if !trailers.is_empty() {
+            request.extensions_mut().insert(chunked::Trailers(trailers));
+        }
+        return Ok(request);
+    }
     // Read body if the client supplied the Content-Length header (which it does for POST requests)
     if let Some(content_length) = get_content_length(&request)? {
-        if content_length > MAX_BODY_SIZE {
+        if content_length > limits.max_body_size {
             return Err(Error::RequestBodyTooLarge);
         } else {
             read_body(stream, &mut request, content_length).await?;
@@ -206,19 +275,24 @@ pub async fn write_to_stream(
     stream: &mut TcpStream,
 ) -> Result<(), std::io::Error> {
     stream
-        .write(&format_request_line(request).into_bytes())
+        .write_all(&format_request_line(request).into_bytes())
         .await?;
-    stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
+    stream.write_all(b"\r\n").await?;
     for (header_name, header_value) in request.headers() {
         stream
-            .write(&format!("{}: ", header_name).as_bytes())
+            .write_all(format!("{}: ", header_name).as_bytes())
             .await?;
-        stream.write(header_value.as_bytes()).await?;
-        stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
+        stream.write_all(header_value.as_bytes()).await?;
+        stream.write_all(b"\r\n").await?;
     }
-    stream.write(&['\r' as u8, '\n' as u8]).await?;
-    if request.body().len() > 0 {
-        stream.write(request.body()).await?;
+    stream.write_all(b"\r\n").await?;
+    if chunked::is_chunked(request.headers()) {
+        let trailers = request.extensions().get::<chunked::Trailers>();
+        let no_trailers = http::HeaderMap::new();
+        let trailers = trailers.map_or(&no_trailers, |trailers| &trailers.0);
+        chunked::write_body(stream, request.body(), trailers).await?;
+    } else if !request.body().is_empty() {
+        stream.write_all(request.body()).await?;
     }
     Ok(())
 }
@@ -1,11 +1,14 @@
+use crate::chunked;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
 
 const MAX_HEADERS_SIZE: usize = 8000;
-const MAX_BODY_SIZE: usize = 10000000;
+/// Response body limit for responses that aren't relayed to clients, such as health checks
+pub const MAX_BODY_SIZE: usize = 10000000;
 const MAX_NUM_HEADERS: usize = 32;
 
 #[derive(Debug)]
+#[allow(dead_code, clippy::enum_variant_names)]
 pub enum Error {
     /// Client hung up before sending a complete request
     IncompleteResponse,
@@ -15,8 +18,10 @@ pub enum Error {
     InvalidContentLength,
     /// The Content-Length header does not match the size of the request body that was sent
     ContentLengthMismatch,
-    /// The request body is bigger than MAX_BODY_SIZE
+    /// The response body is bigger than the limit passed to read_from_stream
     ResponseBodyTooLarge,
+    /// The response body is sent with chunked transfer coding, but the chunks are malformed
+    MalformedChunkedBody,
     /// Encountered an I/O error when reading/writing a TcpStream
     ConnectionError(std::io::Error),
 }
@@ -52,17 +57,19 @@ fn get_content_length(response: &http::Response<Vec<u8>>) -> Result<Option<usize
 ///   Err(Error)
 ///
 /// You won't need to touch this function.
+#[allow(clippy::type_complexity)]
 fn parse_response(buffer: &[u8]) -> Result<Option<(http::Response<Vec<u8>>, usize)>, Error> {
     let mut headers = [httparse::EMPTY_HEADER; MAX_NUM_HEADERS];
     let mut resp = httparse::Response::new(&mut headers);
-    let res = resp
-        .parse(buffer)
-        .or_else(|err| Err(Error::MalformedResponse(err)))?;
+    let res = resp.parse(buffer).map_err(Error::MalformedResponse)?;
 
     if let httparse::Status::Complete(len) = res {
         let mut response = http::Response::builder()
             .status(resp.code.unwrap())
-            .version(http::Version::HTTP_11);
+            .version(match resp.version {
+                Some(0) => http::Version::HTTP_10,
+                _ => http::Version::HTTP_11,
+            });
         for header in resp.headers {
             response = response.header(header.name, header.value);
         }
@@ -91,7 +98,7 @@ async fn read_headers(stream: &mut TcpStream) -> Result<http::Response<Vec<u8>>,
         let new_bytes = stream
             .read(&mut response_buffer[bytes_read..])
             .await
-            .or_else(|err| Err(Error::ConnectionError(err)))?;
+            .map_err(Error::ConnectionError)?;
         if new_bytes == 0 {
             // We didn't manage to read a complete response
             return Err(Error::IncompleteResponse);
@@ -111,25 +118,63 @@ async fn read_headers(stream: &mut TcpStream) -> Result<http::Response<Vec<u8>>,
     }
 }
 
-/// This function reads the body for a response from the stream. If the Content-Length header is
-/// present, it reads that many bytes; otherwise, it reads bytes until the connection is closed.
+/// Reads a body sent with chunked transfer coding and replaces it with the decoded bytes, so that
+/// the response can be sent on with a Content-Length no matter how the client frames messages.
+/// Trailer fields are kept in the response's extensions.
+async fn read_chunked_body(
+    stream: &mut TcpStream,
+    response: &mut http::Response<Vec<u8>>,
+    max_body_size: usize,
+) -> Result<(), Error> {
+    // Whatever was read along with the headers is the start of the chunked data
+    let buffer = std::mem::take(response.body_mut());
+    let (body, trailers) = chunked::read_body(stream, buffer, max_body_size)
+        .await
+        .map_err(|error| match error {
+            chunked::Error::Incomplete => Error::IncompleteResponse,
+            chunked::Error::Malformed => Error::MalformedChunkedBody,
+            chunked::Error::TooLarge => Error::ResponseBodyTooLarge,
+            chunked::Error::Io(error) => Error::ConnectionError(error),
+        })?;
+    let headers = response.headers_mut();
+    headers.remove(http::header::TRANSFER_ENCODING);
+    headers.insert(http::header::CONTENT_LENGTH, body.len().into());
+    *response.body_mut() = body;
+    if !trailers.is_empty() {
+        response
+            .extensions_mut()
+            .insert(chunked::Trailers(trailers));
+    }
+    Ok(())
+}
+
+/// This function reads the body for a response from the stream. If the body is chunked, it is
+/// decoded; if the Content-Length header is present, that many bytes are read; otherwise, it reads
+/// bytes until the connection is closed.
 ///
 /// You will need to modify this function in Milestone 2.
 async fn read_body(
     stream: &mut TcpStream,
     response: &mut http::Response<Vec<u8>>,
+    max_body_size: usize,
 ) -> Result<(), Error> {
+    if chunked::is_chunked(response.headers()) {
+        return read_chunked_body(stream, response, max_body_size).await;
+    }
     // The response may or may not supply a Content-Length header. If it provides the header, then
     // we want to read that number of bytes; if it does not, we want to keep reading bytes until
     // the connection is closed.
     let content_length = get_content_length(response)?;
+    if content_length.is_some_and(|len| len > max_body_size) {
+        return Err(Error::ResponseBodyTooLarge);
+    }
 
     while content_length.is_none() || response.body().len() < content_length.unwrap() {
         let mut buffer = [0_u8; 512];
         let bytes_read = stream
             .read(&mut buffer)
             .await
-            .or_else(|err| Err(Error::ConnectionError(err)))?;
+            .map_err(Error::ConnectionError)?;
         if bytes_read == 0 {
             // The server has hung up!
             if content_length.is_none() {
@@ -149,23 +194,36 @@ async fn read_body(
         }
 
         // Make sure server doesn't send more bytes than we allow
-        if response.body().len() + bytes_read > MAX_BODY_SIZE {
+        if response.body().len() + bytes_read > max_body_size {
             return Err(Error::ResponseBodyTooLarge);
         }
 
         // Append received bytes to the response body
         response.body_mut().extend_from_slice(&buffer[..bytes_read]);
     }
+    if content_length.is_none() {
+        // The body was delimited by the upstream closing the connection. Record both facts, so the
+        // body can be sent on with a length and the connection isn't reused.
+        let len = response.body().len();
+        let headers = response.headers_mut();
+        headers.insert(http::header::CONTENT_LENGTH, len.into());
+        headers.insert(
+            http::header::CONNECTION,
+            http::HeaderValue::from_static("close"),
+        );
+    }
     Ok(())
 }
 
 /// This function reads and returns an HTTP response from a stream, returning an Error if the server
-/// closes the connection prematurely or sends an invalid response.
+/// closes the connection prematurely, sends an invalid response, or sends a body larger than
+/// max_body_size bytes.
 ///
 /// You will need to modify this function in Milestone 2.
 pub async fn read_from_stream(
     stream: &mut TcpStream,
     request_method: &http::Method,
+    max_body_size: usize,
 ) -> Result<http::Response<Vec<u8>>, Error> {
     let mut response = read_headers(stream).await?;
     // A response may have a body as long as it is not responding to a HEAD request and as long as
@@ -175,12 +233,14 @@ pub async fn read_from_stream(
         || response.status() == http::StatusCode::NO_CONTENT
         || response.status() == http::StatusCode::NOT_MODIFIED)
     {
-        read_body(stream, &mut response).await?;
+        read_body(stream, &mut response, max_body_size).await?;
     }
     Ok(response)
 }
 
-/// This function serializes a response to bytes and writes those bytes to the provided stream.
+/// This function serializes a response to bytes and writes those bytes to the provided stream. If
+/// the response is framed as chunked, the body is sent chunked, followed by any trailers in the
+/// response's extensions.
 ///
 /// You will need to modify this function in Milestone 2.
 pub async fn write_to_stream(
@@ -188,19 +248,24 @@ pub async fn write_to_stream(
     stream: &mut TcpStream,
 ) -> Result<(), std::io::Error> {
     stream
-        .write(&format_response_line(response).into_bytes())
+        .write_all(&format_response_line(response).into_bytes())
         .await?;
-    stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
+    stream.write_all(b"\r\n").await?;
     for (header_name, header_value) in response.headers() {
         stream
-            .write(&format!("{}: ", header_name).as_bytes())
+            .write_all(format!("{}: ", header_name).as_bytes())
             .await?;
-        stream.write(header_value.as_bytes()).await?;
-        stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
+        stream.write_all(header_value.as_bytes()).await?;
+        stream.write_all(b"\r\n").await?;
     }
-    stream.write(&['\r' as u8, '\n' as u8]).await?;
-    if response.body().len() > 0 {
-        stream.write(response.body()).await?;
+    stream.write_all(b"\r\n").await?;
+    if chunked::is_chunked(response.headers()) {
+        let trailers = response.extensions().get::<chunked::Trailers>();
+        let no_trailers = http::HeaderMap::new();
+        let trailers = trailers.map_or(&no_trailers, |trailers| &trailers.0);
+        chunked::write_body(stream, response.body(), trailers).await?;
+    } else if !response.body().is_empty() {
+        stream.write_all(response.body()).await?;
     }
     Ok(())
 }
@@ -0,0 +1,62 @@
+use rand::Rng;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Most retries the budget can save up while things are going well, so that a short burst of
+/// failures can still be retried
+const MAX_TOKENS: f64 = 10.0;
+
+/// Limits retries (of requests on another upstream, and of connections after one fails) to a
+/// share of the requests being handled, so that when upstreams fail, retries can't multiply the
+/// load on the ones that are left. Every request adds `ratio` of a token, up to MAX_TOKENS, and
+/// every retry takes a whole one.
+#[derive(Debug)]
+pub struct RetryBudget {
+    /// Retries allowed per request, on average (None = unlimited)
+    ratio: Option<f64>,
+    tokens: Mutex<f64>,
+}
+
+impl RetryBudget {
+    /// Creates a budget allowing retries to add `percent` percent on top of the requests handled,
+    /// or unlimited retries if `percent` is 0.
+    pub fn new(percent: usize) -> RetryBudget {
+        RetryBudget {
+            ratio: (percent > 0).then(|| percent as f64 / 100.0),
+            tokens: Mutex::new(MAX_TOKENS),
+        }
+    }
+
+    /// Counts a request towards the budget.
+    pub fn deposit(&self) {
+        if let Some(ratio) = self.ratio {
+            let mut tokens = self.tokens.lock().unwrap();
+            *tokens = (*tokens + ratio).min(MAX_TOKENS);
+        }
+    }
+
+    /// Takes a retry out of the budget. Returns false if the budget is used up, in which case the
+    /// retry must not be made.
+    pub fn withdraw(&self) -> bool {
+        if self.ratio.is_none() {
+            return true;
+        }
+        let mut tokens = self.tokens.lock().unwrap();
+        if *tokens < 1.0 {
+            return false;
+        }
+        *tokens -= 1.0;
+        true
+    }
+}
+
+/// Returns how long to leave an upstream alone after `failures` consecutive failed connects:
+/// `base` doubled for every failure after the first, capped at `max`. The delay is jittered
+/// between half and all of that, so that reconnects from many clients don't line up.
+pub fn backoff_delay(failures: u32, base: Duration, max: Duration) -> Duration {
+    let delay = base
+        .saturating_mul(1 << failures.saturating_sub(1).min(16))
+        .min(max);
+    let delay_ms = delay.as_millis() as u64;
+    Duration::from_millis(rand::thread_rng().gen_range(delay_ms / 2..=delay_ms))
+}
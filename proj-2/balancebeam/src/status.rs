@@ -0,0 +1,108 @@
+use crate::ProxyState;
+use std::fmt::Write;
+use std::sync::atomic::Ordering;
+
+/// Path on the main listener that balancebeam answers itself instead of proxying
+pub const STATUS_PATH: &str = "/__balancebeam/status";
+
+/// Builds the status report served at STATUS_PATH and at /status on the admin API: a JSON object
+/// with uptime, the number of open client connections, the number of clients the rate limiter is
+/// keeping counts for and the state of every upstream. Upstream addresses are internal, so they
+/// are only included if show_addresses is set, as it is for the admin API. The response is a 200
+/// if at least one upstream can take traffic and a 503 otherwise, so that external health
+/// checkers can use the status code alone. Upstreams being drained don't count as able to take
+/// traffic.
+pub async fn make_status_response(
+    state: &ProxyState,
+    show_addresses: bool,
+) -> http::Response<Vec<u8>> {
+    let mut upstreams: Vec<(String, bool, bool, bool, u32, bool)> = state
+        .upstream_addresses
+        .read()
+        .unwrap()
+        .iter()
+        .map(|(address, health)| {
+            (
+                address.clone(),
+                health.is_routable() && !state.drains.is_draining(address),
+                health.stats().is_ejected(),
+                state.drains.is_draining(address),
+                health.priority,
+                health.canary,
+            )
+        })
+        .collect();
+    upstreams.sort();
+    let healthy = upstreams
+        .iter()
+        .filter(|(_, routable, ..)| *routable)
+        .count();
+
+    let rate_limited_clients = state.rate_limiter_service.tracked_clients().await;
+
+    let mut body = String::new();
+    write!(
+        body,
+        "{{\"status\":\"{}\",\"uptime_seconds\":{},\"active_connections\":{},\
+         \"rate_limited_clients\":{},\"healthy_upstreams\":{},\"unhealthy_upstreams\":{},\
+         \"upstreams\":[",
+        if healthy > 0 { "ok" } else { "unavailable" },
+        state.started.elapsed().as_secs(),
+        state.active_connections.load(Ordering::SeqCst),
+        rate_limited_clients,
+        healthy,
+        upstreams.len() - healthy,
+    )
+    .unwrap();
+    for (idx, (address, routable, ejected, draining, priority, canary)) in
+        upstreams.iter().enumerate()
+    {
+        if idx > 0 {
+            body.push(',');
+        }
+        body.push('{');
+        if show_addresses {
+            write!(body, "\"address\":{},", json_string(address)).unwrap();
+        }
+        write!(
+            body,
+            "\"healthy\":{},\"ejected\":{},\"draining\":{},\"priority\":{},\"canary\":{}}}",
+            routable, ejected, draining, priority, canary
+        )
+        .unwrap();
+    }
+    body.push_str("]}\n");
+
+    let body = body.into_bytes();
+    http::Response::builder()
+        .status(if healthy > 0 {
+            http::StatusCode::OK
+        } else {
+            http::StatusCode::SERVICE_UNAVAILABLE
+        })
+        .header("Content-Type", "application/json")
+        .header("Content-Length", body.len().to_string())
+        .header("Cache-Control", "no-store")
+        .version(http::Version::HTTP_11)
+        .body(body)
+        .unwrap()
+}
+
+/// Quotes and escapes a string for inclusion in JSON output.
+pub fn json_string(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(quoted, "\\u{:04x}", c as u32).unwrap(),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
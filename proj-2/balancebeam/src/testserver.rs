@@ -0,0 +1,247 @@
+//! An HTTP server for exercising balancebeam: it answers every request with a configurable
+//! response, after a configurable delay, and can be told to misbehave in the ways upstreams do
+//! in production (dropping connections, hanging, cutting responses short). The behavior can be
+//! changed while the server runs, so tests can take an upstream down and bring it back, and
+//! individual requests can override it with X-Testserver-* headers sent through the proxy.
+
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::oneshot;
+
+/// Request header overriding the response status, e.g. `X-Testserver-Status: 503`
+pub const STATUS_HEADER: &str = "x-testserver-status";
+/// Request header overriding the response delay, in milliseconds
+pub const DELAY_HEADER: &str = "x-testserver-delay-ms";
+/// Request header making this request fail, e.g. `X-Testserver-Fail: close`
+pub const FAIL_HEADER: &str = "x-testserver-fail";
+
+/// Ways the server can fail a request
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Failure {
+    /// Close the connection without sending a response
+    Close,
+    /// Never respond, leaving the connection open
+    Hang,
+    /// Send the headers and part of the body, then close the connection
+    Truncate,
+}
+
+impl std::str::FromStr for Failure {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Failure, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "close" => Ok(Failure::Close),
+            "hang" => Ok(Failure::Hang),
+            "truncate" => Ok(Failure::Truncate),
+            _ => Err(format!(
+                "unknown failure mode {} (expected close, hang or truncate)",
+                s
+            )),
+        }
+    }
+}
+
+/// How the server answers requests
+#[derive(Clone, Debug)]
+pub struct Behavior {
+    /// Status of every response
+    pub status: u16,
+    /// Body of every response. If None, the request is echoed back instead: its request line,
+    /// headers and body.
+    pub body: Option<String>,
+    /// Extra headers added to every response
+    pub headers: Vec<(String, String)>,
+    /// How long to wait before responding
+    pub latency: Duration,
+    /// If set, requests fail this way instead of getting a response
+    pub failure: Option<Failure>,
+    /// Only every nth request fails (1 = all of them)
+    pub fail_every: usize,
+}
+
+impl Default for Behavior {
+    fn default() -> Behavior {
+        Behavior {
+            status: 200,
+            body: None,
+            headers: Vec::new(),
+            latency: Duration::ZERO,
+            failure: None,
+            fail_every: 1,
+        }
+    }
+}
+
+struct ServerState {
+    behavior: Mutex<Behavior>,
+    requests_received: AtomicUsize,
+}
+
+/// A running test server. It stops when `stop` is called or the server is dropped.
+pub struct TestServer {
+    pub address: String,
+    state: Arc<ServerState>,
+    shutdown_signal_sender: oneshot::Sender<()>,
+    server_task: tokio::task::JoinHandle<()>,
+}
+
+impl TestServer {
+    /// Starts a server on `address` (e.g. 127.0.0.1:0 for any free port). Must be called from
+    /// within a tokio runtime.
+    pub fn start(address: &str, behavior: Behavior) -> Result<TestServer, String> {
+        let bind_addr: SocketAddr = address
+            .parse()
+            .map_err(|_| format!("invalid address {}", address))?;
+        let state = Arc::new(ServerState {
+            behavior: Mutex::new(behavior),
+            requests_received: AtomicUsize::new(0),
+        });
+        let server_task_state = state.clone();
+        let service = make_service_fn(move |_| {
+            let server_task_state = server_task_state.clone();
+            async move {
+                Ok::<_, hyper::Error>(service_fn(move |req| {
+                    respond(server_task_state.clone(), req)
+                }))
+            }
+        });
+        let server = hyper::Server::try_bind(&bind_addr)
+            .map_err(|err| format!("could not bind to {}: {}", address, err))?
+            .serve(service);
+        let address = server.local_addr().to_string();
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+        let server = server.with_graceful_shutdown(async {
+            shutdown_rx.await.ok();
+        });
+        let server_task = tokio::spawn(async move {
+            if let Err(err) = server.await {
+                log::error!("Error in test server: {}", err);
+            }
+        });
+        Ok(TestServer {
+            address,
+            state,
+            shutdown_signal_sender: shutdown_tx,
+            server_task,
+        })
+    }
+
+    /// Changes how the server answers requests from now on.
+    pub fn set_behavior(&self, behavior: Behavior) {
+        *self.state.behavior.lock().unwrap() = behavior;
+    }
+
+    /// Returns the number of requests received so far, including ones that were failed.
+    pub fn requests_received(&self) -> usize {
+        self.state.requests_received.load(Ordering::SeqCst)
+    }
+
+    /// Stops the server, and returns the number of requests it received.
+    pub async fn stop(self) -> usize {
+        let _ = self.shutdown_signal_sender.send(());
+        // Hanging requests would otherwise hold up the graceful shutdown forever
+        let mut server_task = self.server_task;
+        if tokio::time::timeout(Duration::from_secs(1), &mut server_task)
+            .await
+            .is_err()
+        {
+            server_task.abort();
+        }
+        self.state.requests_received.load(Ordering::SeqCst)
+    }
+}
+
+/// Parses a request header, ignoring it if it is missing or malformed.
+fn header<T: std::str::FromStr>(req: &Request<Body>, name: &str) -> Option<T> {
+    req.headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok())
+}
+
+async fn respond(
+    state: Arc<ServerState>,
+    req: Request<Body>,
+) -> Result<Response<Body>, std::io::Error> {
+    let count = state.requests_received.fetch_add(1, Ordering::SeqCst) + 1;
+    let mut behavior = state.behavior.lock().unwrap().clone();
+    if let Some(status) = header(&req, STATUS_HEADER) {
+        behavior.status = status;
+    }
+    if let Some(delay) = header(&req, DELAY_HEADER) {
+        behavior.latency = Duration::from_millis(delay);
+    }
+    let failure = match header(&req, FAIL_HEADER) {
+        Some(failure) => Some(failure),
+        None => behavior
+            .failure
+            .filter(|_| count.is_multiple_of(behavior.fail_every.max(1))),
+    };
+
+    if !behavior.latency.is_zero() {
+        tokio::time::sleep(behavior.latency).await;
+    }
+    let body = match behavior.body {
+        Some(body) => body.into_bytes(),
+        None => echo(req).await?,
+    };
+    let mut response = Response::builder().status(behavior.status);
+    for (name, value) in &behavior.headers {
+        response = response.header(name, value);
+    }
+    match failure {
+        None => {}
+        Some(Failure::Close) => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::ConnectionAborted,
+                "closing connection without a response",
+            ))
+        }
+        Some(Failure::Hang) => std::future::pending::<()>().await,
+        Some(Failure::Truncate) => {
+            // Promise the whole body, then fail the stream halfway through it
+            let half = body[..body.len() / 2].to_vec();
+            let chunks: Vec<Result<Vec<u8>, std::io::Error>> = vec![
+                Ok(half),
+                Err(std::io::Error::new(
+                    std::io::ErrorKind::ConnectionAborted,
+                    "truncating response",
+                )),
+            ];
+            return Ok(response
+                .header(hyper::header::CONTENT_LENGTH, body.len().max(1))
+                .body(Body::wrap_stream(futures_util::stream::iter(chunks)))
+                .unwrap());
+        }
+    }
+    response.body(Body::from(body)).map_err(|err| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("invalid response: {}", err),
+        )
+    })
+}
+
+/// Formats a request the way it was received: request line, headers, a blank line and the body.
+async fn echo(req: Request<Body>) -> Result<Vec<u8>, std::io::Error> {
+    let mut text = format!("{} {} {:?}\n", req.method(), req.uri(), req.version());
+    for (name, value) in req.headers() {
+        text += &format!(
+            "{}: {}\n",
+            name.as_str(),
+            value.to_str().unwrap_or("<binary value>")
+        );
+    }
+    text += "\n";
+    let mut bytes = text.into_bytes();
+    let body = hyper::body::to_bytes(req.into_body())
+        .await
+        .map_err(std::io::Error::other)?;
+    bytes.extend(body);
+    Ok(bytes)
+}
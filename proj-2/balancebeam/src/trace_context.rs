@@ -0,0 +1,106 @@
+use rand::Rng;
+
+/// W3C Trace Context (https://www.w3.org/TR/trace-context/) for a request passing through
+/// balancebeam. If the client sent a valid `traceparent` header, we join its trace; otherwise a new
+/// trace is started. Either way balancebeam acts as a hop of its own: the upstream receives a
+/// `traceparent` naming balancebeam's span as the parent.
+pub struct TraceContext {
+    /// 32 lowercase hex digits
+    pub trace_id: String,
+    /// The span ID the client sent us, if we joined an existing trace (16 lowercase hex digits)
+    pub parent_span_id: Option<String>,
+    /// ID of the span representing balancebeam's handling of this request
+    pub span_id: String,
+    /// Trace flags (bit 0 = sampled)
+    pub flags: u8,
+}
+
+impl TraceContext {
+    pub fn from_request(request: &http::Request<Vec<u8>>) -> TraceContext {
+        let incoming = request
+            .headers()
+            .get("traceparent")
+            .and_then(|value| value.to_str().ok())
+            .and_then(parse_traceparent);
+        match incoming {
+            Some((trace_id, parent_span_id, flags)) => TraceContext {
+                trace_id,
+                parent_span_id: Some(parent_span_id),
+                span_id: random_hex_id(8),
+                flags,
+            },
+            None => TraceContext {
+                trace_id: random_hex_id(16),
+                parent_span_id: None,
+                span_id: random_hex_id(8),
+                flags: 0x01,
+            },
+        }
+    }
+
+    /// Sets the `traceparent` header the upstream should see. `tracestate` is forwarded as is when
+    /// we joined the client's trace, and dropped otherwise, since it is meaningless without a valid
+    /// `traceparent`.
+    pub fn apply(&self, request: &mut http::Request<Vec<u8>>) {
+        let traceparent = format!("00-{}-{}-{:02x}", self.trace_id, self.span_id, self.flags);
+        request.headers_mut().insert(
+            "traceparent",
+            http::HeaderValue::from_str(&traceparent).unwrap(),
+        );
+        if self.parent_span_id.is_none() {
+            request.headers_mut().remove("tracestate");
+        }
+    }
+
+    pub fn is_sampled(&self) -> bool {
+        self.flags & 0x01 != 0
+    }
+}
+
+/// Parses a traceparent header into (trace ID, parent span ID, flags). Versions other than 00 are
+/// parsed as far as the fields 00 defines, as required by the spec.
+fn parse_traceparent(value: &str) -> Option<(String, String, u8)> {
+    let mut parts = value.trim().split('-');
+    let version = parts.next()?;
+    let trace_id = parts.next()?;
+    let parent_span_id = parts.next()?;
+    let flags = parts.next()?;
+    let extra = parts.next();
+    let version_ok = is_hex(version, 2) && version != "ff" && (version != "00" || extra.is_none());
+    if !version_ok
+        || !is_nonzero_hex(trace_id, 32)
+        || !is_nonzero_hex(parent_span_id, 16)
+        || !is_hex(flags, 2)
+    {
+        return None;
+    }
+    Some((
+        trace_id.to_string(),
+        parent_span_id.to_string(),
+        u8::from_str_radix(flags, 16).ok()?,
+    ))
+}
+
+fn is_hex(value: &str, len: usize) -> bool {
+    value.len() == len
+        && value
+            .bytes()
+            .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+}
+
+fn is_nonzero_hex(value: &str, len: usize) -> bool {
+    is_hex(value, len) && value.bytes().any(|b| b != b'0')
+}
+
+fn random_hex_id(num_bytes: usize) -> String {
+    let mut rng = rand::thread_rng();
+    loop {
+        let id: String = (0..num_bytes)
+            .map(|_| format!("{:02x}", rng.gen::<u8>()))
+            .collect();
+        // All-zero IDs are invalid
+        if id.bytes().any(|b| b != b'0') {
+            return id;
+        }
+    }
+}
@@ -1,6 +1,7 @@
 mod common;
 
-use common::{init_logging, BalanceBeam, EchoServer, Server};
+use common::{init_logging, write_config, BalanceBeam, EchoServer, Server};
+use rand::Rng;
 use std::sync::Arc;
 
 async fn setup() -> (BalanceBeam, EchoServer) {
@@ -24,6 +25,10 @@ async fn test_simple_connections() {
     assert!(response_text.contains("GET /first_url HTTP/1.1"));
     assert!(response_text.contains("x-sent-by: balancebeam-tests"));
     assert!(response_text.contains("x-forwarded-for: 127.0.0.1"));
+    assert!(response_text.contains("x-forwarded-proto: http"));
+    assert!(response_text.contains(&format!("x-forwarded-host: {}", balancebeam.address)));
+    let port = balancebeam.address.rsplit(':').next().unwrap();
+    assert!(response_text.contains(&format!("x-forwarded-port: {}", port)));
 
     log::info!("Sending a POST request");
     let response_text = balancebeam
@@ -69,7 +74,7 @@ async fn test_multiple_requests_per_connection() {
                 );
                 let path = format!("/conn-{}/req-{}", task_num, req_num);
                 let response_text = client
-                    .get(&format!("http://{}{}", balancebeam_shared.address, path))
+                    .get(format!("http://{}{}", balancebeam_shared.address, path))
                     .header("x-sent-by", "balancebeam-tests")
                     .send()
                     .await
@@ -99,3 +104,1539 @@ async fn test_multiple_requests_per_connection() {
 
     log::info!("All done :)");
 }
+
+/// Make sure header rewrite rules from the config file are applied to requests and responses, and
+/// only on the route they are configured for.
+#[tokio::test]
+async fn test_header_rewrite_rules() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let config_path = write_config(
+        "[route /api]\n\
+         request-header set X-Env prod\n\
+         request-header remove x-sent-by\n\
+         response-header add X-Proxied-By balancebeam\n",
+    );
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--config", &config_path]).await;
+
+    log::info!("Sending a request that matches the /api route");
+    let response = reqwest::Client::new()
+        .get(format!("http://{}/api/users", balancebeam.address))
+        .header("x-sent-by", "balancebeam-tests")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(
+        response.headers().get("x-proxied-by").unwrap(),
+        "balancebeam"
+    );
+    let response_text = response.text().await.unwrap();
+    assert!(response_text.contains("x-env: prod"));
+    assert!(!response_text.contains("x-sent-by"));
+
+    log::info!("Sending a request outside of the /api route");
+    let response_text = balancebeam
+        .get("/static/index.html")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(!response_text.contains("x-env"));
+    assert!(response_text.contains("x-sent-by: balancebeam-tests"));
+
+    log::info!("Sending a request that only shares a prefix with the /api route");
+    let response_text = balancebeam
+        .get("/apikeys")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(!response_text.contains("x-env"));
+
+    log::info!("Sending a request with a query string on the /api route");
+    let response_text = balancebeam
+        .get("/api?page=2")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains("x-env: prod"));
+
+    Box::new(upstream).stop().await;
+    std::fs::remove_file(config_path).unwrap();
+    log::info!("All done :)");
+}
+
+/// Make sure errors generated by balancebeam use the configured templates, picking the format
+/// based on the Accept header
+#[tokio::test]
+async fn test_custom_error_pages() {
+    init_logging();
+    let error_page_dir = std::env::temp_dir().join(format!(
+        "balancebeam-test-error-pages-{}",
+        rand::random::<u64>()
+    ));
+    std::fs::create_dir(&error_page_dir).unwrap();
+    std::fs::write(
+        error_page_dir.join("502.json"),
+        "{\"error\": {{status}}, \"message\": \"{{reason}}\"}",
+    )
+    .unwrap();
+    std::fs::write(
+        error_page_dir.join("error.html"),
+        "<h1>{{status}} {{reason}}</h1>",
+    )
+    .unwrap();
+
+    // Nothing is listening on the upstream address, so every request gets a 502 (as long as the
+    // upstream is tried again every time, rather than being backed off from)
+    let balancebeam = BalanceBeam::new_with_args(
+        &["127.0.0.1:1"],
+        &[
+            "--error-page-dir",
+            error_page_dir.to_str().unwrap(),
+            "--connect-backoff-base",
+            "0",
+        ],
+    )
+    .await;
+    let client = reqwest::Client::new();
+
+    let response = client
+        .get(format!("http://{}/", balancebeam.address))
+        .header("accept", "application/json")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 502);
+    assert_eq!(
+        response.headers().get("content-type").unwrap(),
+        "application/json"
+    );
+    assert_eq!(
+        response.text().await.unwrap(),
+        "{\"error\": 502, \"message\": \"Bad Gateway\"}"
+    );
+
+    let response = client
+        .get(format!("http://{}/", balancebeam.address))
+        .header("accept", "text/html,application/json;q=0.5")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.headers().get("content-type").unwrap(), "text/html");
+    assert_eq!(response.text().await.unwrap(), "<h1>502 Bad Gateway</h1>");
+
+    std::fs::remove_dir_all(error_page_dir).unwrap();
+    log::info!("All done :)");
+}
+
+/// Make sure redirects to the upstream's own address are rewritten to the address the client used,
+/// while redirects elsewhere are passed through untouched
+#[tokio::test]
+async fn test_location_rewriting() {
+    init_logging();
+    let upstream = balancebeam_testserver::TestServer::start(
+        "127.0.0.1:0",
+        balancebeam_testserver::Behavior::default(),
+    )
+    .unwrap();
+    let redirect_to = |location: String| balancebeam_testserver::Behavior {
+        status: 302,
+        headers: vec![("Location".to_string(), location)],
+        ..Default::default()
+    };
+    upstream.set_behavior(redirect_to(format!(
+        "http://{}/login?next=%2F",
+        upstream.address
+    )));
+    // The upstream redirects health checks too, so keep them from taking it out of rotation
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--active-health-check-interval", "60"],
+    )
+    .await;
+    let client = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap();
+
+    log::info!("Sending a request the upstream redirects to its own address");
+    let response = client
+        .get(format!("http://{}/", balancebeam.address))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 302);
+    assert_eq!(
+        response.headers().get("location").unwrap(),
+        format!("http://{}/login?next=%2F", balancebeam.address).as_str()
+    );
+
+    log::info!("Sending a request the upstream redirects to another site");
+    upstream.set_behavior(redirect_to("http://example.com/elsewhere".to_string()));
+    let response = client
+        .get(format!("http://{}/", balancebeam.address))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(
+        response.headers().get("location").unwrap(),
+        "http://example.com/elsewhere"
+    );
+
+    upstream.stop().await;
+    log::info!("All done :)");
+}
+
+/// Make sure proxied requests show up in the latency histograms exported by the admin API
+#[tokio::test]
+async fn test_latency_metrics() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let admin_address = format!("127.0.0.1:{}", rand::random::<u16>().max(1024));
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--admin-bind", &admin_address]).await;
+
+    for i in 0..3 {
+        balancebeam
+            .get(&format!("/timed-{}", i))
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+
+    let metrics = reqwest::get(format!("http://{}/metrics", admin_address))
+        .await
+        .expect("Error sending request to admin API")
+        .text()
+        .await
+        .unwrap();
+    let labels = format!("upstream=\"{}\",phase=", upstream.address);
+    for phase in ["connect", "first_byte", "total"] {
+        let count_line = format!(
+            "balancebeam_upstream_latency_seconds_count{{{}\"{}\"}} 3",
+            labels, phase
+        );
+        assert!(metrics.contains(&count_line), "{}", metrics);
+    }
+    assert!(metrics.contains("le=\"+Inf\""));
+    log::info!("All done :)");
+}
+
+/// Make sure requests are counted per upstream by status class, along with the bytes sent
+#[tokio::test]
+async fn test_traffic_metrics() {
+    init_logging();
+    let upstream = balancebeam_testserver::TestServer::start(
+        "127.0.0.1:0",
+        balancebeam_testserver::Behavior {
+            body: Some("0123456789".to_string()),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    let admin_address = format!("127.0.0.1:{}", rand::random::<u16>().max(1024));
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--admin-bind", &admin_address]).await;
+
+    let client = reqwest::Client::new();
+    for _ in 0..2 {
+        client
+            .post(format!("http://{}/upload", balancebeam.address))
+            .body("hello")
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+    let response = client
+        .get(format!("http://{}/missing", balancebeam.address))
+        .header(balancebeam_testserver::STATUS_HEADER, "404")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 404);
+
+    let traffic = reqwest::get(format!("http://{}/traffic", admin_address))
+        .await
+        .expect("Error sending request to admin API")
+        .text()
+        .await
+        .unwrap();
+    let expected = format!(
+        "{{\"address\":\"{}\",\"requests\":3,\"errors\":0,\"failed\":0,\
+         \"responses\":{{\"1xx\":0,\"2xx\":2,\"3xx\":0,\"4xx\":1,\"5xx\":0}},\
+         \"bytes_sent\":10,\"bytes_received\":30}}",
+        upstream.address
+    );
+    assert!(traffic.contains(&expected), "{}", traffic);
+
+    let metrics = reqwest::get(format!("http://{}/metrics", admin_address))
+        .await
+        .expect("Error sending request to admin API")
+        .text()
+        .await
+        .unwrap();
+    let line = format!(
+        "balancebeam_upstream_requests_total{{upstream=\"{}\",outcome=\"2xx\"}} 2",
+        upstream.address
+    );
+    assert!(metrics.contains(&line), "{}", metrics);
+    log::info!("All done :)");
+}
+
+/// Make sure W3C trace context is propagated: the upstream should see the client's trace ID with
+/// balancebeam's own span as the parent, and requests without a traceparent should get one
+#[tokio::test]
+async fn test_trace_context_propagation() {
+    let (balancebeam, _upstream) = setup().await;
+    let client = reqwest::Client::new();
+
+    let response_text = client
+        .get(format!("http://{}/traced", balancebeam.address))
+        .header(
+            "traceparent",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+        )
+        .header("tracestate", "vendor=value")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+        .text()
+        .await
+        .unwrap();
+    assert!(response_text.contains("traceparent: 00-4bf92f3577b34da6a3ce929d0e0e4736-"));
+    assert!(!response_text.contains("00f067aa0ba902b7"));
+    assert!(response_text.contains("tracestate: vendor=value"));
+
+    let response_text = balancebeam
+        .get("/untraced")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains("traceparent: 00-"));
+    log::info!("All done :)");
+}
+
+/// Make sure X-Forwarded-For is only believed when it comes from a trusted proxy
+#[tokio::test]
+async fn test_trusted_proxies() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let client = reqwest::Client::new();
+
+    // Without trusted proxies, the chain the client sent is kept and the peer is appended
+    let balancebeam = BalanceBeam::new_with_args(&[&upstream.address], &[]).await;
+    let response_text = client
+        .get(format!("http://{}/untrusted", balancebeam.address))
+        .header("x-forwarded-for", "203.0.113.7")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+        .text()
+        .await
+        .unwrap();
+    assert!(response_text.contains("x-forwarded-for: 203.0.113.7, 127.0.0.1"));
+
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--trusted-proxies",
+            "127.0.0.0/8,10.0.0.1",
+            "--forwarded-for",
+            "replace",
+        ],
+    )
+    .await;
+    let response_text = client
+        .get(format!("http://{}/trusted", balancebeam.address))
+        .header("x-forwarded-for", "198.51.100.1, 203.0.113.7, 10.0.0.1")
+        .header("x-forwarded-proto", "https")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+        .text()
+        .await
+        .unwrap();
+    assert!(response_text.contains("x-forwarded-for: 203.0.113.7\n"));
+    assert!(response_text.contains("x-forwarded-proto: https"));
+    log::info!("All done :)");
+}
+
+/// Make sure upstreams are told the scheme, host and port the client originally used, and that
+/// clients that aren't trusted proxies can't set those headers themselves
+#[tokio::test]
+async fn test_forwarding_headers() {
+    let (balancebeam, _upstream) = setup().await;
+    let port = balancebeam.address.rsplit(':').next().unwrap();
+
+    let response_text = reqwest::Client::new()
+        .get(format!("http://{}/forwarded", balancebeam.address))
+        .header("host", "www.example.com")
+        .header("x-forwarded-proto", "https")
+        .header("x-forwarded-port", "443")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+        .text()
+        .await
+        .unwrap();
+    assert!(response_text.contains("x-forwarded-proto: http\n"));
+    assert!(response_text.contains("x-forwarded-host: www.example.com\n"));
+    assert!(response_text.contains(&format!("x-forwarded-port: {}\n", port)));
+    log::info!("All done :)");
+}
+
+/// Writes a minimal MaxMind DB that places every IPv4 address in the given country, and returns
+/// its path
+fn write_country_db(country: &str) -> String {
+    let string = |value: &str| [&[0x40 | value.len() as u8], value.as_bytes()].concat();
+    let mut db = Vec::new();
+    // A single search tree node (24-bit records) whose branches both lead to the first data field
+    db.extend_from_slice(&[0, 0, 17, 0, 0, 17]);
+    db.extend_from_slice(&[0; 16]);
+    // {"country": {"iso_code": country}}
+    db.push(0xe1);
+    db.extend(string("country"));
+    db.push(0xe1);
+    db.extend(string("iso_code"));
+    db.extend(string(country));
+    db.extend_from_slice(b"\xab\xcd\xefMaxMind.com");
+    // {"node_count": 1, "record_size": 24, "ip_version": 4}
+    db.push(0xe3);
+    for (key, value) in [("node_count", 1), ("record_size", 24), ("ip_version", 4)] {
+        db.extend(string(key));
+        db.extend_from_slice(&[0xa1, value]);
+    }
+    let path = std::env::temp_dir().join(format!(
+        "balancebeam-test-{}.mmdb",
+        rand::thread_rng().gen::<u64>()
+    ));
+    std::fs::write(&path, db).expect("Could not write GeoIP database");
+    path.to_str().unwrap().to_string()
+}
+
+/// Make sure clients' countries are looked up in the GeoIP database, passed upstream in
+/// X-Geo-Country, and used to allow or deny them
+#[tokio::test]
+async fn test_geoip_access_control() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let db_path = write_country_db("US");
+    let client = reqwest::Client::new();
+
+    log::info!("Sending a request from an allowed country");
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--geoip-db", &db_path, "--geo-allow", "us"],
+    )
+    .await;
+    let response = client
+        .get(format!("http://{}/geo", balancebeam.address))
+        .header("x-geo-country", "FR")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 200);
+    let response_text = response.text().await.unwrap();
+    assert!(response_text.contains("x-geo-country: US\n"));
+    assert!(!response_text.contains("x-geo-country: FR"));
+
+    log::info!("Sending a request from a denied country");
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--geoip-db", &db_path, "--geo-deny", "US"],
+    )
+    .await;
+    let response = client
+        .get(format!("http://{}/geo", balancebeam.address))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 403);
+
+    Box::new(upstream).stop().await;
+    std::fs::remove_file(db_path).unwrap();
+    log::info!("All done :)");
+}
+
+/// Make sure balancebeam identifies itself in Via headers and can mask the upstream's Server header
+#[tokio::test]
+async fn test_via_and_server_headers() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--server-header", "balancebeam"]).await;
+
+    let response = reqwest::Client::new()
+        .get(format!("http://{}/via", balancebeam.address))
+        .header("via", "1.1 edge")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.headers().get("via").unwrap(), "1.1 balancebeam");
+    assert_eq!(response.headers().get("server").unwrap(), "balancebeam");
+    let response_text = response.text().await.unwrap();
+    assert!(response_text.contains("via: 1.1 edge, 1.1 balancebeam"));
+    log::info!("All done :)");
+}
+
+/// Make sure security headers are added only on routes that enable them, with per-route overrides
+#[tokio::test]
+async fn test_security_headers() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let config_path = write_config(
+        "security-headers on\n\
+         [route /embed]\n\
+         security-header frame-options SAMEORIGIN\n\
+         security-header hsts off\n\
+         [route /raw]\n",
+    );
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--config", &config_path]).await;
+    let client = reqwest::Client::new();
+
+    let response = client
+        .get(format!("http://{}/index.html", balancebeam.address))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    let headers = response.headers();
+    assert_eq!(headers.get("x-frame-options").unwrap(), "DENY");
+    assert_eq!(headers.get("x-content-type-options").unwrap(), "nosniff");
+    assert!(headers.contains_key("strict-transport-security"));
+    assert!(headers.contains_key("referrer-policy"));
+
+    let response = client
+        .get(format!("http://{}/embed/widget", balancebeam.address))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(
+        response.headers().get("x-frame-options").unwrap(),
+        "SAMEORIGIN"
+    );
+    assert!(!response.headers().contains_key("strict-transport-security"));
+
+    let response = client
+        .get(format!("http://{}/raw/data", balancebeam.address))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(!response.headers().contains_key("x-frame-options"));
+
+    std::fs::remove_file(config_path).unwrap();
+    log::info!("All done :)");
+}
+
+/// Make sure CORS preflights are answered by balancebeam for allowed origins, and that proxied
+/// responses get Access-Control-Allow-Origin
+#[tokio::test]
+async fn test_cors() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let config_path = write_config(
+        "[route /api]\n\
+         cors-origin https://app.example.com\n\
+         cors-headers Content-Type\n\
+         cors-max-age 600\n",
+    );
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--config", &config_path]).await;
+    let client = reqwest::Client::new();
+    let url = format!("http://{}/api/items", balancebeam.address);
+
+    log::info!("Sending preflights from an allowed and a disallowed origin");
+    let response = client
+        .request(reqwest::Method::OPTIONS, &url)
+        .header("origin", "https://app.example.com")
+        .header("access-control-request-method", "POST")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 204);
+    let headers = response.headers();
+    assert_eq!(
+        headers.get("access-control-allow-origin").unwrap(),
+        "https://app.example.com"
+    );
+    assert_eq!(
+        headers.get("access-control-allow-headers").unwrap(),
+        "Content-Type"
+    );
+    assert_eq!(headers.get("access-control-max-age").unwrap(), "600");
+
+    let response = client
+        .request(reqwest::Method::OPTIONS, &url)
+        .header("origin", "https://evil.example.com")
+        .header("access-control-request-method", "POST")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 403);
+
+    log::info!("Sending a cross-origin request that is proxied");
+    let response = client
+        .get(&url)
+        .header("origin", "https://app.example.com")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(
+        response
+            .headers()
+            .get("access-control-allow-origin")
+            .unwrap(),
+        "https://app.example.com"
+    );
+    assert_eq!(response.headers().get("vary").unwrap(), "Origin");
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(
+        num_requests_received, 1,
+        "Preflights should not be forwarded upstream"
+    );
+    std::fs::remove_file(config_path).unwrap();
+    log::info!("All done :)");
+}
+
+/// Make sure clients must authenticate when --htpasswd is set, and that rejected requests never
+/// reach the upstream
+#[tokio::test]
+async fn test_basic_auth() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    // alice's password is "secret" (htpasswd -m), bob's is "hunter2" (htpasswd -s)
+    let htpasswd_path = write_config(
+        "alice:$apr1$saltsalt$LrttParrLPdxvgutaSXWJ0\n\
+         bob:{SHA}87u9ZqY9S/F0eUBXjsPQEDUw4h0=\n",
+    );
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--htpasswd", &htpasswd_path]).await;
+    let client = reqwest::Client::new();
+    let url = format!("http://{}/", balancebeam.address);
+
+    log::info!("Sending requests without valid credentials");
+    let response = client
+        .get(&url)
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 401);
+    assert_eq!(
+        response.headers().get("www-authenticate").unwrap(),
+        "Basic realm=\"balancebeam\", charset=\"UTF-8\""
+    );
+    let response = client
+        .get(&url)
+        .basic_auth("alice", Some("wrong"))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 401);
+
+    log::info!("Sending requests with valid credentials");
+    for (user, password) in [("alice", "secret"), ("bob", "hunter2")] {
+        let response = client
+            .get(&url)
+            .basic_auth(user, Some(password))
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response.status().as_u16(), 200);
+    }
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(
+        num_requests_received, 2,
+        "Unauthenticated requests should not be forwarded upstream"
+    );
+    std::fs::remove_file(htpasswd_path).unwrap();
+    log::info!("All done :)");
+}
+
+/// Make sure routes with a rate-limit directive get their own limit and bucket, and that
+/// `rate-limit 0` exempts a route from the global limit
+#[tokio::test]
+async fn test_per_route_rate_limits() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let config_path = write_config(
+        "[route /login]\n\
+         rate-limit 2\n\
+         [route /static]\n\
+         rate-limit 0\n",
+    );
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--config", &config_path, "--max-requests-per-minute", "3"],
+    )
+    .await;
+    let client = reqwest::Client::new();
+    let send = |path: &str| {
+        let request = client.get(format!("http://{}{}", balancebeam.address, path));
+        async move {
+            request
+                .send()
+                .await
+                .expect("Error sending request to balancebeam")
+                .status()
+                .as_u16()
+        }
+    };
+
+    log::info!("Exhausting the /login limit");
+    assert_eq!(send("/login").await, 200);
+    assert_eq!(send("/login").await, 200);
+    assert_eq!(send("/login").await, 429);
+
+    log::info!("Checking that other routes are counted separately");
+    for _ in 0..3 {
+        assert_eq!(send("/index.html").await, 200);
+    }
+    assert_eq!(send("/index.html").await, 429);
+
+    log::info!("Checking that /static isn't rate limited");
+    for _ in 0..5 {
+        assert_eq!(send("/static/app.js").await, 200);
+    }
+
+    std::fs::remove_file(config_path).unwrap();
+    log::info!("All done :)");
+}
+
+/// Make sure a client can't hold more than --max-connections-per-client connections open
+#[tokio::test]
+async fn test_max_connections_per_client() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--max-connections-per-client", "2"])
+            .await;
+
+    log::info!("Opening two idle connections");
+    let idle_connections = vec![
+        tokio::net::TcpStream::connect(&balancebeam.address)
+            .await
+            .unwrap(),
+        tokio::net::TcpStream::connect(&balancebeam.address)
+            .await
+            .unwrap(),
+    ];
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+
+    let response = reqwest::get(format!("http://{}/", balancebeam.address))
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 429);
+
+    log::info!("Closing the idle connections, after which requests should go through again");
+    drop(idle_connections);
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+    let response = reqwest::get(format!("http://{}/", balancebeam.address))
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 200);
+
+    log::info!("All done :)");
+}
+
+/// Make sure --max-connections stops balancebeam from serving more connections at once, leaving
+/// further connections waiting until one closes
+#[tokio::test]
+async fn test_max_connections() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--max-connections", "1"]).await;
+    let client = reqwest::Client::builder()
+        .timeout(std::time::Duration::from_millis(500))
+        .build()
+        .unwrap();
+
+    log::info!("Opening an idle connection, which takes the only slot");
+    let idle_connection = tokio::net::TcpStream::connect(&balancebeam.address)
+        .await
+        .unwrap();
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+    let result = client
+        .get(format!("http://{}/", balancebeam.address))
+        .send()
+        .await;
+    assert!(
+        result.is_err(),
+        "A connection beyond --max-connections shouldn't be served: {:?}",
+        result
+    );
+
+    log::info!("Closing the idle connection, after which requests should go through again");
+    drop(idle_connection);
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+    let response = client
+        .get(format!("http://{}/", balancebeam.address))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 200);
+
+    log::info!("All done :)");
+}
+
+/// Make sure connections that stay quiet for longer than --client-idle-timeout get closed
+#[tokio::test]
+async fn test_client_idle_timeout() {
+    use tokio::io::AsyncReadExt;
+
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--client-idle-timeout", "1"]).await;
+
+    let mut idle_connection = tokio::net::TcpStream::connect(&balancebeam.address)
+        .await
+        .unwrap();
+    let mut buf = [0; 16];
+    let read = tokio::time::timeout(
+        std::time::Duration::from_secs(5),
+        idle_connection.read(&mut buf),
+    )
+    .await
+    .expect("balancebeam did not close the idle connection");
+    assert_eq!(read.unwrap(), 0, "Expected the connection to be closed");
+
+    log::info!("All done :)");
+}
+
+/// Make sure an upstream that accepts requests but never answers gets the client a 504 once
+/// --upstream-read-timeout passes, instead of a hung connection
+#[tokio::test]
+async fn test_upstream_read_timeout() {
+    init_logging();
+    let hung_upstream = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let hung_address = hung_upstream.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        let mut connections = Vec::new();
+        while let Ok((stream, _)) = hung_upstream.accept().await {
+            connections.push(stream);
+        }
+    });
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&hung_address],
+        &[
+            "--upstream-read-timeout",
+            "1",
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await;
+
+    let response = tokio::time::timeout(
+        std::time::Duration::from_secs(10),
+        reqwest::get(format!("http://{}/", balancebeam.address)),
+    )
+    .await
+    .expect("balancebeam did not time out the upstream")
+    .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 504);
+
+    log::info!("All done :)");
+}
+
+/// Make sure requests with too many or too large headers are rejected with a 431
+#[tokio::test]
+async fn test_header_limits() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--max-header-size", "1000", "--max-header-count", "10"],
+    )
+    .await;
+    let url = format!("http://{}/", balancebeam.address);
+
+    log::info!("Sending a request with too many headers");
+    let mut request = reqwest::Client::new().get(&url);
+    for i in 0..20 {
+        request = request.header(format!("x-header-{}", i), "value");
+    }
+    let response = request
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 431);
+
+    log::info!("Sending a request with an oversized header");
+    let response = reqwest::Client::new()
+        .get(&url)
+        .header("x-large", "a".repeat(2000))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 431);
+
+    log::info!("Sending a request within the limits");
+    let response = reqwest::get(&url)
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 200);
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 1);
+    log::info!("All done :)");
+}
+
+#[tokio::test]
+async fn test_max_body_size() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--max-body-size", "4KB"]).await;
+    let url = format!("http://{}/", balancebeam.address);
+
+    log::info!("Sending a request with an oversized body");
+    let response = reqwest::Client::new()
+        .post(&url)
+        .body("a".repeat(5000))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 413);
+
+    log::info!("Sending a request whose echoed response is oversized");
+    let response = reqwest::Client::new()
+        .get(&url)
+        .header("x-large", "a".repeat(5000))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 502);
+
+    log::info!("Sending a request within the limits");
+    let response = balancebeam
+        .post("/small", "small body")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response.contains("small body"));
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 2);
+    log::info!("All done :)");
+}
+
+#[tokio::test]
+async fn test_max_requests_per_connection() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--max-requests-per-connection", "2"],
+    )
+    .await;
+    let url = format!("http://{}/", balancebeam.address);
+    let client = reqwest::Client::new();
+
+    log::info!("Sending requests over a keep-alive connection");
+    let mut connection_headers = Vec::new();
+    for _ in 0..4 {
+        let response = client
+            .get(&url)
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response.status().as_u16(), 200);
+        connection_headers.push(
+            response
+                .headers()
+                .get("connection")
+                .map(|value| value.to_str().unwrap().to_string()),
+        );
+        // Reading the body hands the connection back to the client's pool for the next request
+        response.text().await.expect("Error reading response body");
+    }
+    // Every second response closes the connection, and the client reconnects for the next one
+    assert_eq!(connection_headers[0], None);
+    assert_eq!(connection_headers[1].as_deref(), Some("close"));
+    assert_eq!(connection_headers[2], None);
+    assert_eq!(connection_headers[3].as_deref(), Some("close"));
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 4);
+    log::info!("All done :)");
+}
+
+#[tokio::test]
+async fn test_decompress_requests() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--decompress-requests"]).await;
+    let url = format!("http://{}/", balancebeam.address);
+    // "compressed request body " repeated 8 times, compressed with gzip
+    let compressed: &[u8] = &[
+        0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0x4b, 0xce, 0xcf, 0x2d, 0x28,
+        0x4a, 0x2d, 0x2e, 0x4e, 0x4d, 0x51, 0x28, 0x4a, 0x2d, 0x2c, 0x4d, 0x2d, 0x2e, 0x51, 0x48,
+        0xca, 0x4f, 0xa9, 0x54, 0x48, 0x1e, 0x22, 0xe2, 0x00, 0x00, 0x76, 0xab, 0x49, 0xc0, 0x00,
+        0x00, 0x00,
+    ];
+
+    log::info!("Sending a gzip-encoded request");
+    let response_text = reqwest::Client::new()
+        .post(&url)
+        .header("content-encoding", "gzip")
+        .body(compressed)
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+        .text()
+        .await
+        .expect("Error reading response body");
+    assert!(response_text.contains(&"compressed request body ".repeat(8)));
+    assert!(response_text.contains("content-length: 192\n"));
+    assert!(!response_text.contains("content-encoding"));
+
+    log::info!("Sending a request that claims to be gzip-encoded but isn't");
+    let response = reqwest::Client::new()
+        .post(&url)
+        .header("content-encoding", "gzip")
+        .body("not compressed")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 400);
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 1);
+    log::info!("All done :)");
+}
+
+#[tokio::test]
+async fn test_hostname_upstream() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let port = upstream.address.rsplit(':').next().unwrap();
+    let hostname = format!("localhost:{}", port);
+    let admin_address = format!("127.0.0.1:{}", rand::thread_rng().gen_range(1024..65535));
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&hostname], &["--admin-bind", &admin_address]).await;
+
+    log::info!("Checking that the hostname was resolved to its address");
+    let status = reqwest::get(format!("http://{}/status", admin_address))
+        .await
+        .expect("Error sending request to admin API")
+        .text()
+        .await
+        .unwrap();
+    assert!(
+        status.contains(&format!("\"address\":\"127.0.0.1:{}\"", port)),
+        "{}",
+        status
+    );
+    assert!(!status.contains("localhost"), "{}", status);
+
+    log::info!("Sending a request through the resolved upstream");
+    let response_text = balancebeam
+        .get("/resolved")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains("GET /resolved HTTP/1.1"));
+
+    log::info!("All done :)");
+}
+
+#[tokio::test]
+async fn test_bind_to_hostname() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let port = rand::thread_rng().gen_range(1024..65535);
+    let bind = format!("localhost:{}", port);
+    let balancebeam = BalanceBeam::new_with_args(&[&upstream.address], &["--bind", &bind]).await;
+
+    log::info!("Sending a request to balancebeam listening on {}", bind);
+    let response_text = balancebeam
+        .get("/bound")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains("GET /bound HTTP/1.1"));
+
+    log::info!("All done :)");
+}
+
+#[tokio::test]
+async fn test_plugin_hooks() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let script = write_config(
+        r#"
+-- Only let requests with the right API key through, and tell the upstream who they are
+function on_request(req)
+  local key = req:header("x-api-key")
+  if key ~= "secret" then
+    local resp = respond(401, "bad key: " .. tostring(key))
+    resp:set_header("www-authenticate", "ApiKey")
+    return resp
+  end
+  req:set_header("x-plugin-user", "user-" .. key:upper())
+  if req:path():sub(1, 5) == "/old/" then
+    req:set_path("/new/" .. req:path():sub(6))
+  end
+end
+
+function on_response(req, resp)
+  resp:set_header("x-plugin-status", tostring(resp:status()))
+end
+"#,
+    );
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--plugin", &script]).await;
+    let client = reqwest::Client::new();
+
+    log::info!("Sending a request the plugin rejects");
+    let response = client
+        .get(format!("http://{}/old/thing", balancebeam.address))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 401);
+    assert_eq!(response.headers()["www-authenticate"], "ApiKey");
+    assert_eq!(response.text().await.unwrap(), "bad key: nil");
+
+    log::info!("Sending a request the plugin lets through");
+    let response = client
+        .get(format!("http://{}/old/thing", balancebeam.address))
+        .header("x-api-key", "secret")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(response.headers()["x-plugin-status"], "200");
+    let response_text = response.text().await.unwrap();
+    assert!(response_text.contains("GET /new/thing HTTP/1.1"));
+    assert!(response_text.contains("x-plugin-user: user-SECRET"));
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 1);
+    log::info!("All done :)");
+}
+
+/// Make sure methods a route doesn't allow are rejected with a 405 without reaching the upstream
+#[tokio::test]
+async fn test_method_filtering() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let config_path = write_config(
+        "deny-methods TRACE\n\
+         [route /api]\n\
+         allow-methods GET, POST, PUT\n\
+         deny-methods put\n",
+    );
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--config", &config_path]).await;
+    let client = reqwest::Client::new();
+    let send = |method: reqwest::Method, path: &str| {
+        client
+            .request(method, format!("http://{}{}", balancebeam.address, path))
+            .send()
+    };
+
+    log::info!("Sending requests with allowed methods");
+    for (method, path) in [
+        (reqwest::Method::GET, "/api/users"),
+        (reqwest::Method::POST, "/api/users"),
+        (reqwest::Method::DELETE, "/other"),
+    ] {
+        let response = send(method, path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response.status().as_u16(), 200);
+    }
+
+    log::info!("Sending requests with methods that aren't allowed");
+    let response = send(reqwest::Method::PUT, "/api/users")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 405);
+    assert_eq!(response.headers()["allow"], "GET, POST");
+    let response = send(reqwest::Method::DELETE, "/api/users")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 405);
+    let response = send(reqwest::Method::TRACE, "/other")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 405);
+    assert_eq!(
+        response.headers()["allow"],
+        "GET, HEAD, POST, PUT, DELETE, PATCH, OPTIONS"
+    );
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 3);
+    std::fs::remove_file(config_path).unwrap();
+    log::info!("All done :)");
+}
+
+/// Make sure a route's settings can't be dodged by spelling its path differently
+#[tokio::test]
+async fn test_route_path_normalization() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let config_path = write_config("[route /admin]\nallow-methods GET\n");
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--config", &config_path]).await;
+    // reqwest would normalize these paths itself, so the requests are written out by hand
+    async fn send(address: &str, request: &str) -> String {
+        let mut stream = tokio::net::TcpStream::connect(address).await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        tokio::time::timeout(
+            std::time::Duration::from_secs(5),
+            stream.read_to_string(&mut response),
+        )
+        .await
+        .expect("Timed out waiting for a response")
+        .unwrap();
+        response
+    }
+
+    for path in [
+        "/admin",
+        "//admin",
+        "/./admin",
+        "/%61dmin",
+        "/x/../admin",
+        "/%2e/admin/",
+    ] {
+        log::info!("Sending a DELETE to {}", path);
+        let request = format!("DELETE {} HTTP/1.0\r\n\r\n", path);
+        let response = send(&balancebeam.address, &request).await;
+        assert!(
+            response.starts_with("HTTP/1.1 405"),
+            "{} wasn't matched to the route: {}",
+            path,
+            response
+        );
+    }
+
+    log::info!("Checking that allowed methods still get through");
+    let response = send(&balancebeam.address, "GET //admin HTTP/1.0\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 1);
+    std::fs::remove_file(config_path).unwrap();
+    log::info!("All done :)");
+}
+
+/// Make sure clients get a 503 with Retry-After while no upstream is available, and can carry on
+/// once one is back
+#[tokio::test]
+async fn test_no_available_upstreams() {
+    init_logging();
+    let upstream_address = format!("127.0.0.1:{}", rand::random::<u16>().max(1024));
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream_address],
+        &[
+            "--connect-backoff-base",
+            "2000",
+            "--active-health-check-interval",
+            "5",
+        ],
+    )
+    .await;
+    let client = reqwest::Client::new();
+    let url = format!("http://{}/", balancebeam.address);
+
+    log::info!("Sending a request while the upstream is down");
+    let response = client.get(&url).send().await.unwrap();
+    assert_eq!(response.status().as_u16(), 502);
+
+    log::info!("Sending a request while the upstream is backed off from");
+    let response = client.get(&url).send().await.unwrap();
+    assert_eq!(response.status().as_u16(), 503);
+    assert_eq!(response.headers()["retry-after"], "5");
+    response.text().await.unwrap();
+
+    log::info!("Starting the upstream and trying again");
+    let upstream = EchoServer::new_at_address(upstream_address).await;
+    tokio::time::sleep(std::time::Duration::from_millis(2100)).await;
+    let response_text = client.get(&url).send().await.unwrap().text().await.unwrap();
+    assert!(response_text.contains("GET / HTTP/1.1"));
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 1);
+    log::info!("All done :)");
+}
+
+/// Make sure `balancebeam bench` sends the requested load and reports on it
+#[tokio::test]
+async fn test_bench_subcommand() {
+    let (balancebeam, upstream) = setup().await;
+
+    let output = tokio::process::Command::new(BalanceBeam::target_bin_path())
+        .args(["bench", &balancebeam.address, "-n", "20", "-c", "3"])
+        .output()
+        .await
+        .expect("Could not run balancebeam bench");
+    assert!(output.status.success());
+    let report = String::from_utf8(output.stdout).unwrap();
+    log::info!("Benchmark report:\n{}", report);
+    assert!(report.contains("Completed 20 requests"));
+    assert!(report.contains("Status codes: 200: 20"));
+    assert!(report.contains("p99"));
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 20);
+    log::info!("All done :)");
+}
+
+/// Make sure hop-by-hop headers aren't forwarded in either direction, and that a client asking
+/// for its connection to be closed doesn't affect the connection to the upstream
+#[tokio::test]
+async fn test_hop_by_hop_headers() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    init_logging();
+    let upstream = balancebeam_testserver::TestServer::start(
+        "127.0.0.1:0",
+        balancebeam_testserver::Behavior {
+            headers: vec![
+                ("Keep-Alive".to_string(), "timeout=5".to_string()),
+                ("X-Upstream-Hop".to_string(), "1".to_string()),
+                ("Connection".to_string(), "x-upstream-hop".to_string()),
+                ("X-Upstream-End".to_string(), "1".to_string()),
+            ],
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    let mut stream = tokio::net::TcpStream::connect(&balancebeam.address)
+        .await
+        .unwrap();
+    stream
+        .write_all(
+            b"GET /hops HTTP/1.1\r\nHost: example.com\r\nConnection: close, x-client-hop\r\n\
+              X-Client-Hop: 1\r\nX-Client-End: 1\r\nKeep-Alive: timeout=5\r\nTE: deflate\r\n\
+              Proxy-Authorization: Basic Zm9vOmJhcg==\r\nUpgrade: websocket\r\n\r\n",
+        )
+        .await
+        .unwrap();
+    // The connection must be closed after the response, or this would never finish
+    let mut response = String::new();
+    tokio::time::timeout(
+        std::time::Duration::from_secs(5),
+        stream.read_to_string(&mut response),
+    )
+    .await
+    .expect("balancebeam didn't close the connection the client asked to close")
+    .unwrap();
+    let response = response.to_lowercase();
+    let (head, echoed_request) = response.split_once("\r\n\r\n").unwrap();
+    log::info!("Response headers:\n{}", head);
+
+    assert!(echoed_request.contains("x-client-end: 1"));
+    assert!(echoed_request.contains("connection: keep-alive"));
+    for header in [
+        "x-client-hop",
+        "keep-alive:",
+        "deflate",
+        "proxy-authorization",
+        "upgrade",
+        "close",
+    ] {
+        assert!(
+            !echoed_request.contains(header),
+            "{} was forwarded to the upstream",
+            header
+        );
+    }
+    assert!(head.contains("x-upstream-end: 1"));
+    assert!(head.contains("connection: close"));
+    assert!(!head.contains("x-upstream-hop"));
+    assert!(!head.contains("keep-alive"));
+
+    assert_eq!(upstream.stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// Make sure HTTP/1.0 clients get responses they can read: the connection is closed after the
+/// response unless they ask for keep-alive, and bodies always come with a Content-Length, even
+/// when the upstream sent them chunked
+#[tokio::test]
+async fn test_http10_clients() {
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+
+    init_logging();
+    // An upstream that sends every response chunked, which HTTP/1.0 clients can't decode
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let upstream_address = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut stream = BufReader::new(stream);
+                loop {
+                    // Read a request (these have no body), then answer it
+                    let mut line = String::new();
+                    loop {
+                        line.clear();
+                        if stream.read_line(&mut line).await.unwrap_or(0) == 0 {
+                            return;
+                        }
+                        if line == "\r\n" {
+                            break;
+                        }
+                    }
+                    stream
+                        .get_mut()
+                        .write_all(
+                            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+                              6\r\nhello \r\n5;ext=1\r\nworld\r\n0\r\nX-Trailer: 1\r\n\r\n",
+                        )
+                        .await
+                        .unwrap();
+                }
+            });
+        }
+    });
+    let balancebeam = BalanceBeam::new(&[&upstream_address], None, None).await;
+
+    log::info!("Sending a plain HTTP/1.0 request");
+    let mut stream = tokio::net::TcpStream::connect(&balancebeam.address)
+        .await
+        .unwrap();
+    stream.write_all(b"GET / HTTP/1.0\r\n\r\n").await.unwrap();
+    let mut response = String::new();
+    tokio::time::timeout(
+        std::time::Duration::from_secs(5),
+        stream.read_to_string(&mut response),
+    )
+    .await
+    .expect("balancebeam didn't close the HTTP/1.0 client's connection")
+    .unwrap();
+    let lowercase = response.to_lowercase();
+    assert!(lowercase.contains("content-length: 11\r\n"), "{}", response);
+    assert!(!lowercase.contains("transfer-encoding"), "{}", response);
+    assert!(lowercase.contains("connection: close\r\n"), "{}", response);
+    assert!(response.ends_with("\r\n\r\nhello world"), "{}", response);
+
+    log::info!("Sending two HTTP/1.0 requests over a keep-alive connection");
+    let mut stream = tokio::net::TcpStream::connect(&balancebeam.address)
+        .await
+        .unwrap();
+    for _ in 0..2 {
+        stream
+            .write_all(b"GET / HTTP/1.0\r\nConnection: keep-alive\r\n\r\n")
+            .await
+            .unwrap();
+        let mut buffer = vec![0_u8; 4096];
+        let mut response = String::new();
+        while !response.ends_with("hello world") {
+            let read =
+                tokio::time::timeout(std::time::Duration::from_secs(5), stream.read(&mut buffer))
+                    .await
+                    .expect("Timed out waiting for a response")
+                    .unwrap();
+            assert!(read > 0, "balancebeam closed a keep-alive connection");
+            response += std::str::from_utf8(&buffer[..read]).unwrap();
+        }
+        assert!(
+            response
+                .to_lowercase()
+                .contains("connection: keep-alive\r\n"),
+            "{}",
+            response
+        );
+    }
+    log::info!("All done :)");
+}
+
+#[tokio::test]
+async fn test_trailers() {
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+
+    init_logging();
+    // An upstream that sends back the request it received (as sent over the wire) as a chunked
+    // body, followed by a trailer
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let upstream_address = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut stream = BufReader::new(stream);
+                loop {
+                    // Read the headers, then the chunked body up to the end of its trailers
+                    let mut received = String::new();
+                    let mut in_trailers = false;
+                    let mut blank_lines = 0;
+                    while blank_lines < 2 {
+                        let mut line = String::new();
+                        if stream.read_line(&mut line).await.unwrap_or(0) == 0 {
+                            return;
+                        }
+                        if line == "0\r\n" {
+                            in_trailers = true;
+                        }
+                        if line == "\r\n" && (blank_lines == 0 || in_trailers) {
+                            blank_lines += 1;
+                        }
+                        received += &line;
+                    }
+                    let response = format!(
+                        "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nTrailer: Grpc-Status\r\n\r\n\
+                         {:x}\r\n{}\r\n0\r\nGrpc-Status: 0\r\n\r\n",
+                        received.len(),
+                        received
+                    );
+                    stream
+                        .get_mut()
+                        .write_all(response.as_bytes())
+                        .await
+                        .unwrap();
+                }
+            });
+        }
+    });
+    let balancebeam = BalanceBeam::new(&[&upstream_address], None, None).await;
+
+    log::info!("Sending a chunked request with a trailer");
+    let mut stream = tokio::net::TcpStream::connect(&balancebeam.address)
+        .await
+        .unwrap();
+    stream
+        .write_all(
+            b"POST / HTTP/1.1\r\nHost: example.com\r\nTransfer-Encoding: chunked\r\n\
+              Trailer: X-Checksum\r\n\r\n5\r\nhello\r\n0\r\nX-Checksum: abc\r\n\r\n",
+        )
+        .await
+        .unwrap();
+    let mut buffer = vec![0_u8; 4096];
+    let mut response = String::new();
+    while !response.ends_with("grpc-status: 0\r\n\r\n") {
+        let read =
+            tokio::time::timeout(std::time::Duration::from_secs(5), stream.read(&mut buffer))
+                .await
+                .expect("Timed out waiting for the response's trailers")
+                .unwrap();
+        assert!(read > 0, "balancebeam closed the connection: {}", response);
+        response += std::str::from_utf8(&buffer[..read]).unwrap();
+    }
+    let lowercase = response.to_lowercase();
+    assert!(
+        lowercase.contains("transfer-encoding: chunked\r\n"),
+        "{}",
+        response
+    );
+    assert!(
+        lowercase.contains("trailer: grpc-status\r\n"),
+        "{}",
+        response
+    );
+    assert!(!lowercase.contains("content-length"), "{}", response);
+    // The upstream got the request's trailer, and was told it may send its own
+    assert!(
+        lowercase.contains("\r\n0\r\nx-checksum: abc\r\n"),
+        "{}",
+        response
+    );
+    assert!(lowercase.contains("te: trailers\r\n"), "{}", response);
+    assert!(lowercase.contains("\r\nhello\r\n"), "{}", response);
+    log::info!("All done :)");
+}
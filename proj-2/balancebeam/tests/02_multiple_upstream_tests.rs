@@ -1,8 +1,17 @@
 mod common;
 
+use balancebeam_testserver::{Behavior, Failure, TestServer};
 use common::{init_logging, BalanceBeam, EchoServer, ErrorServer, Server};
 
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response};
+use rand::Rng;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
 use tokio::time::sleep;
 
 async fn setup_with_params(
@@ -80,6 +89,41 @@ async fn test_load_distribution() {
     log::info!("All done :)");
 }
 
+/// Make sure round-robin stays exact when many connections arrive at once: every upstream should
+/// get precisely its share
+#[tokio::test]
+async fn test_concurrent_load_distribution() {
+    init_logging();
+    let mut upstreams = Vec::new();
+    for _ in 0..3 {
+        upstreams.push(EchoServer::new().await);
+    }
+    let addresses: Vec<&str> = upstreams.iter().map(|u| u.address.as_str()).collect();
+    let balancebeam =
+        BalanceBeam::new_with_args(&addresses, &["--active-health-check-interval", "60"]).await;
+
+    log::info!("Sending 60 requests at once, each on its own connection");
+    let responses = futures_util::future::join_all(
+        (0..60).map(|i| reqwest::get(format!("http://{}/request-{}", balancebeam.address, i))),
+    )
+    .await;
+    for response in responses {
+        let response = response.expect("Error sending request to balancebeam");
+        assert_eq!(response.status().as_u16(), 200);
+    }
+
+    let mut request_counters = Vec::new();
+    for upstream in upstreams {
+        request_counters.push(Box::new(upstream).stop().await);
+    }
+    assert_eq!(
+        request_counters,
+        vec![20, 20, 20],
+        "Connections arriving at once weren't spread evenly"
+    );
+    log::info!("All done :)");
+}
+
 async fn try_failover(balancebeam: &BalanceBeam, upstreams: &mut Vec<Box<dyn Server>>) {
     // Send some initial requests. Everything should work
     log::info!("Sending some initial requests. These should definitely work.");
@@ -131,6 +175,491 @@ async fn test_passive_health_checks() {
     log::info!("All done :)");
 }
 
+/// Make sure passive health checks only mark an upstream unavailable once it has failed
+/// --passive-unhealthy-threshold requests within the window, rather than on the first failure
+#[tokio::test]
+async fn test_passive_unhealthy_threshold() {
+    init_logging();
+    let flaky = TestServer::start(
+        "127.0.0.1:0",
+        Behavior {
+            failure: Some(Failure::Close),
+            ..Behavior::default()
+        },
+    )
+    .unwrap();
+    let admin_address = format!("127.0.0.1:{}", rand::thread_rng().gen_range(1024..65535));
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&flaky.address],
+        &[
+            "--passive-unhealthy-threshold",
+            "3",
+            "--passive-window",
+            "60s",
+            "--max-retries",
+            "0",
+            "--active-health-check-interval",
+            "60",
+            "--admin-bind",
+            &admin_address,
+        ],
+    )
+    .await;
+    let status_url = format!("http://{}/status", admin_address);
+
+    for i in 0..3 {
+        let status = reqwest::get(&status_url)
+            .await
+            .expect("Error sending request to admin API")
+            .text()
+            .await
+            .unwrap();
+        assert!(
+            status.contains("\"healthy_upstreams\":1"),
+            "The upstream should stay available after {} failures: {}",
+            i,
+            status
+        );
+        log::info!("Sending request #{} to the upstream that drops requests", i);
+        let response = reqwest::get(format!("http://{}/request-{}", balancebeam.address, i))
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response.status().is_server_error());
+    }
+
+    let status = reqwest::get(&status_url)
+        .await
+        .expect("Error sending request to admin API")
+        .text()
+        .await
+        .unwrap();
+    assert!(
+        status.contains("\"healthy_upstreams\":0"),
+        "The upstream should be unavailable after 3 failures: {}",
+        status
+    );
+    assert_eq!(flaky.stop().await, 3);
+    log::info!("All done :)");
+}
+
+/// Make sure idempotent requests are retried on another upstream when the upstream a client
+/// connection is pinned to goes away. Two keep-alive clients are used so that their connections
+/// are pinned to different upstreams; whichever upstream we kill, one of them has to be retried.
+#[tokio::test]
+async fn test_retry_on_another_upstream() {
+    let n_upstreams = 2;
+    let (balancebeam, mut upstreams) = setup(n_upstreams).await;
+    let clients = [reqwest::Client::new(), reqwest::Client::new()];
+
+    log::info!("Opening one keep-alive connection per client");
+    for (client_num, client) in clients.iter().enumerate() {
+        let path = format!("/client-{}/request", client_num);
+        let response_text = client
+            .get(format!("http://{}{}", balancebeam.address, path))
+            .send()
+            .await
+            .expect("Error sending request to balancebeam")
+            .text()
+            .await
+            .expect("Balancebeam replied with a malformed response");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+
+    log::info!("Killing one of the upstream servers");
+    upstreams.pop().unwrap().stop().await;
+
+    for (client_num, client) in clients.iter().enumerate() {
+        let path = format!("/client-{}/retry", client_num);
+        let response = client
+            .get(format!("http://{}{}", balancebeam.address, path))
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(
+            response.status().as_u16(),
+            200,
+            "balancebeam returned an error. Retries may not be working."
+        );
+        let response_text = response.text().await.unwrap();
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+
+    log::info!("All done :)");
+}
+
+/// Make sure requests an upstream drops without answering are retried on another upstream, using
+/// test servers that fail on demand
+#[tokio::test]
+async fn test_retry_on_dropped_requests() {
+    init_logging();
+    let flaky = TestServer::start(
+        "127.0.0.1:0",
+        Behavior {
+            failure: Some(Failure::Close),
+            ..Behavior::default()
+        },
+    )
+    .unwrap();
+    let healthy = TestServer::start("127.0.0.1:0", Behavior::default()).unwrap();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&flaky.address, &healthy.address],
+        &["--active-health-check-interval", "60"],
+    )
+    .await;
+
+    // Each request gets its own connection, so that round-robin sends some to the flaky upstream
+    for i in 0..4 {
+        let response = reqwest::Client::new()
+            .get(format!("http://{}/request-{}", balancebeam.address, i))
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(
+            response.status().as_u16(),
+            200,
+            "Dropped requests should be retried on the other upstream"
+        );
+    }
+    assert!(flaky.requests_received() > 0);
+
+    log::info!("Making the healthy upstream drop one request by asking it to");
+    let response = reqwest::Client::new()
+        .get(format!("http://{}/dropped", balancebeam.address))
+        .header(balancebeam_testserver::FAIL_HEADER, "close")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(
+        response.status().is_server_error(),
+        "A request every upstream drops should fail"
+    );
+
+    flaky.stop().await;
+    healthy.stop().await;
+    log::info!("All done :)");
+}
+
+/// Make sure --max-upstream-concurrency caps the requests in flight to an upstream, turning away
+/// requests beyond the cap with a 503 until a slot frees up
+#[tokio::test]
+async fn test_max_upstream_concurrency() {
+    init_logging();
+    let upstream = TestServer::start(
+        "127.0.0.1:0",
+        Behavior {
+            latency: Duration::from_millis(1000),
+            ..Behavior::default()
+        },
+    )
+    .unwrap();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--max-upstream-concurrency",
+            "1",
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await;
+    let url = format!("http://{}/slow", balancebeam.address);
+
+    log::info!("Sending two requests at once to an upstream that takes a request at a time");
+    let (first, second) = tokio::join!(reqwest::get(&url), async {
+        sleep(Duration::from_millis(200)).await;
+        reqwest::get(&url).await
+    });
+    let first = first.expect("Error sending request to balancebeam");
+    let second = second.expect("Error sending request to balancebeam");
+    assert_eq!(first.status().as_u16(), 200);
+    assert_eq!(
+        second.status().as_u16(),
+        503,
+        "A request beyond the upstream's concurrency cap should be turned away"
+    );
+
+    log::info!("Sending another request now that the first is done");
+    let response = reqwest::get(&url)
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 200);
+
+    assert_eq!(upstream.stop().await, 2);
+    log::info!("All done :)");
+}
+
+/// Make sure a drained upstream gets no new requests but finishes the ones it has, whether it
+/// was drained through the admin API or the drain file
+#[tokio::test]
+async fn test_drain_upstream() {
+    init_logging();
+    let slow = TestServer::start(
+        "127.0.0.1:0",
+        Behavior {
+            latency: Duration::from_millis(1000),
+            ..Behavior::default()
+        },
+    )
+    .unwrap();
+    let fast = TestServer::start("127.0.0.1:0", Behavior::default()).unwrap();
+    let drain_file = common::write_config("");
+    let admin_address = format!("127.0.0.1:{}", rand::random::<u16>().max(1024));
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&slow.address, &fast.address],
+        &[
+            "--active-health-check-interval",
+            "60",
+            "--admin-bind",
+            &admin_address,
+            "--drain-file",
+            &drain_file,
+        ],
+    )
+    .await;
+    let drain_url =
+        |upstream: &str| format!("http://{}/drain?upstream={}", admin_address, upstream);
+
+    log::info!("Starting requests on both upstreams");
+    let in_flight: Vec<_> = (0..4)
+        .map(|i| {
+            let url = format!("http://{}/in-flight-{}", balancebeam.address, i);
+            tokio::spawn(async move { reqwest::Client::new().get(url).send().await })
+        })
+        .collect();
+    sleep(Duration::from_millis(300)).await;
+
+    log::info!("Draining the slow upstream while it has requests in flight");
+    let client = reqwest::Client::new();
+    let report = client
+        .put(drain_url(&slow.address))
+        .send()
+        .await
+        .expect("Error sending request to admin API")
+        .text()
+        .await
+        .unwrap();
+    assert!(report.contains("\"drained\":false"), "{}", report);
+    let slow_requests = slow.requests_received();
+    assert!(slow_requests > 0);
+    for i in 0..6 {
+        let response = reqwest::Client::new()
+            .get(format!("http://{}/new-{}", balancebeam.address, i))
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response.status().as_u16(), 200);
+    }
+    assert_eq!(slow.requests_received(), slow_requests);
+    for request in in_flight {
+        let response = request.await.unwrap().expect("In-flight request failed");
+        assert_eq!(response.status().as_u16(), 200);
+    }
+    let report = client
+        .get(format!("http://{}/drain", admin_address))
+        .send()
+        .await
+        .expect("Error sending request to admin API")
+        .text()
+        .await
+        .unwrap();
+    let expected = format!(
+        "{{\"address\":\"{}\",\"in_flight\":0,\"drained\":true}}",
+        slow.address
+    );
+    assert!(report.contains(&expected), "{}", report);
+
+    log::info!("Undraining it, and draining the fast upstream through the drain file");
+    client
+        .delete(drain_url(&slow.address))
+        .send()
+        .await
+        .expect("Error sending request to admin API");
+    std::fs::write(&drain_file, format!("# going down\n{}\n", fast.address)).unwrap();
+    balancebeam.signal(nix::sys::signal::Signal::SIGUSR1);
+    sleep(Duration::from_millis(200)).await;
+    let fast_requests = fast.requests_received();
+    for i in 0..2 {
+        let response = reqwest::Client::new()
+            .get(format!("http://{}/after-reload-{}", balancebeam.address, i))
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response.status().as_u16(), 200);
+    }
+    assert_eq!(fast.requests_received(), fast_requests);
+    assert_eq!(slow.requests_received(), slow_requests + 2);
+
+    std::fs::remove_file(&drain_file).unwrap();
+    slow.stop().await;
+    fast.stop().await;
+    log::info!("All done :)");
+}
+
+/// Make sure the built-in status route reports upstream health, and fails once no upstream is
+/// left to take traffic. Upstream addresses are only shown by the admin API's copy of the report.
+#[tokio::test]
+async fn test_status_endpoint() {
+    init_logging();
+    let mut upstreams = vec![EchoServer::new().await, EchoServer::new().await];
+    let admin_address = format!("127.0.0.1:{}", rand::thread_rng().gen_range(1024..65535));
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstreams[0].address, &upstreams[1].address],
+        &["--admin-bind", &admin_address],
+    )
+    .await;
+    let status_url = format!("http://{}/__balancebeam/status", balancebeam.address);
+    let admin_status_url = format!("http://{}/status", admin_address);
+
+    log::info!("Checking status while all upstreams are up");
+    let response = reqwest::get(&status_url)
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 200);
+    let status = response.text().await.unwrap();
+    assert!(status.contains("\"healthy_upstreams\":2"), "{}", status);
+    assert!(status.contains("\"unhealthy_upstreams\":0"), "{}", status);
+    assert!(
+        !status.contains("\"address\""),
+        "Public status shouldn't show upstream addresses: {}",
+        status
+    );
+
+    log::info!("Checking that the admin API's status shows upstream addresses");
+    let status = reqwest::get(&admin_status_url)
+        .await
+        .expect("Error sending request to admin API")
+        .text()
+        .await
+        .unwrap();
+    assert!(status.contains("\"healthy_upstreams\":2"), "{}", status);
+    assert!(status.contains(&format!("\"address\":\"{}\"", upstreams[0].address)));
+
+    log::info!("Killing all upstreams and sending requests until passive health checks notice");
+    for upstream in upstreams.drain(..) {
+        Box::new(upstream).stop().await;
+    }
+    for _ in 0..4 {
+        let _ = balancebeam.get("/").await;
+        // Give the failed upstreams time to come out of their connect backoff
+        sleep(Duration::from_millis(500)).await;
+    }
+
+    let response = reqwest::get(&status_url)
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(
+        response.status().as_u16(),
+        503,
+        "Status should fail when no upstream is healthy"
+    );
+    let status = response.text().await.unwrap();
+    assert!(status.contains("\"healthy_upstreams\":0"), "{}", status);
+
+    log::info!("All done :)");
+}
+
+/// Make sure backup upstream groups only receive traffic once the primary group is down
+#[tokio::test]
+async fn test_backup_upstream_group() {
+    init_logging();
+    let primary = EchoServer::new().await;
+    let backup = EchoServer::new().await;
+    let backup_group = format!("1={}", backup.address);
+    // Push active health checks out so they don't show up in the request counts
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&primary.address],
+        &[
+            "--upstream-group",
+            &backup_group,
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await;
+
+    log::info!("Sending requests while the primary upstream is up");
+    for i in 0..4 {
+        let path = format!("/primary-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+
+    log::info!("Killing the primary upstream");
+    let primary_req_count = Box::new(primary).stop().await;
+    assert_eq!(primary_req_count, 4);
+
+    log::info!("Sending requests that should fail over to the backup group");
+    for i in 0..4 {
+        let path = format!("/backup-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+    let backup_req_count = Box::new(backup).stop().await;
+    assert_eq!(
+        backup_req_count, 4,
+        "Backup upstream should only have received the requests sent after failover"
+    );
+
+    log::info!("All done :)");
+}
+
+/// Make sure canary upstreams get the configured share of traffic, and that the share can be
+/// changed at runtime through the admin API
+#[tokio::test]
+async fn test_canary_percent_via_admin_api() {
+    init_logging();
+    let stable = EchoServer::new().await;
+    let canary = EchoServer::new().await;
+    let admin_address = format!("127.0.0.1:{}", rand::thread_rng().gen_range(1024..65535));
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&stable.address],
+        &[
+            "--canary-upstream",
+            &canary.address,
+            "--canary-percent",
+            "100",
+            "--admin-bind",
+            &admin_address,
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await;
+
+    log::info!("Sending requests with all traffic going to the canary");
+    for i in 0..3 {
+        balancebeam
+            .get(&format!("/canary-{}", i))
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+
+    log::info!("Turning the canary off through the admin API");
+    let response = reqwest::Client::new()
+        .put(format!("http://{}/canary?percent=0", admin_address))
+        .send()
+        .await
+        .expect("Error sending request to the admin API");
+    assert_eq!(response.status().as_u16(), 200);
+
+    for i in 0..4 {
+        balancebeam
+            .get(&format!("/stable-{}", i))
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+
+    assert_eq!(Box::new(canary).stop().await, 3);
+    assert_eq!(Box::new(stable).stop().await, 4);
+    log::info!("All done :)");
+}
+
 /// Verify that the active health checks are monitoring HTTP status, rather than simply depending
 /// on whether connections can be established to determine whether an upstream is up:
 ///
@@ -190,6 +719,266 @@ async fn test_active_health_checks_check_http_status() {
     }
 }
 
+/// Make sure an upstream that hangs on health checks is marked unavailable once the check times
+/// out, without holding up the checks of the other upstreams
+#[tokio::test]
+async fn test_active_health_check_timeout() {
+    init_logging();
+    let hung = TestServer::start(
+        "127.0.0.1:0",
+        Behavior {
+            failure: Some(Failure::Hang),
+            ..Behavior::default()
+        },
+    )
+    .unwrap();
+    let healthy = TestServer::start("127.0.0.1:0", Behavior::default()).unwrap();
+    let admin_address = format!("127.0.0.1:{}", rand::thread_rng().gen_range(1024..65535));
+    let _balancebeam = BalanceBeam::new_with_args(
+        &[&hung.address, &healthy.address],
+        &[
+            "--active-health-check-interval",
+            "300ms",
+            "--active-health-check-timeout",
+            "200ms",
+            "--admin-bind",
+            &admin_address,
+        ],
+    )
+    .await;
+
+    log::info!("Waiting for a few health check sweeps");
+    sleep(Duration::from_millis(1500)).await;
+
+    let status = reqwest::get(format!("http://{}/status", admin_address))
+        .await
+        .expect("Error sending request to admin API")
+        .text()
+        .await
+        .unwrap();
+    assert!(
+        status.contains("\"healthy_upstreams\":1"),
+        "A timed-out health check should mark the upstream unavailable: {}",
+        status
+    );
+    assert!(
+        healthy.requests_received() >= 2,
+        "The hung upstream held up health checks of the healthy one"
+    );
+
+    hung.stop().await;
+    healthy.stop().await;
+    log::info!("All done :)");
+}
+
+/// Serves an upstream that answers requests straight away, except for health checks of /health,
+/// which take `delay`
+async fn slow_health_check_upstream(delay: Duration) -> String {
+    let service = make_service_fn(move |_| async move {
+        Ok::<_, hyper::Error>(service_fn(move |req: Request<Body>| async move {
+            if req.uri().path() == "/health" {
+                sleep(delay).await;
+            }
+            Ok::<_, hyper::Error>(Response::new(Body::from("ok")))
+        }))
+    });
+    let server = hyper::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(service);
+    let address = server.local_addr().to_string();
+    tokio::spawn(server);
+    address
+}
+
+/// Make sure requests keep being routed while a health check sweep is waiting on a slow upstream,
+/// rather than queueing up behind the sweep
+#[tokio::test]
+async fn test_routing_during_slow_health_checks() {
+    init_logging();
+    let upstream = slow_health_check_upstream(Duration::from_secs(3)).await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream],
+        &[
+            "--active-health-check-path",
+            "/health",
+            "--active-health-check-interval",
+            "200ms",
+            "--active-health-check-timeout",
+            "5s",
+        ],
+    )
+    .await;
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(1))
+        .build()
+        .unwrap();
+
+    log::info!("Waiting for a health check sweep to start");
+    sleep(Duration::from_millis(500)).await;
+    for i in 0..5 {
+        log::info!("Sending request #{} while the sweep is in progress", i);
+        let response = client
+            .get(format!("http://{}/request-{}", balancebeam.address, i))
+            .send()
+            .await
+            .expect("Request was held up by the health check sweep");
+        assert_eq!(response.status().as_u16(), 200);
+    }
+    log::info!("All done :)");
+}
+
+/// Make sure health check intervals can be given with units, including sub-second ones
+#[tokio::test]
+async fn test_subsecond_health_check_interval() {
+    init_logging();
+    let echo_server = EchoServer::new().await;
+    let error_server = ErrorServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&echo_server.address, &error_server.address],
+        &["--active-health-check-interval", "200ms"],
+    )
+    .await;
+
+    log::info!("Waiting briefly; a 200ms interval should have run a health check by now");
+    sleep(Duration::from_millis(600)).await;
+
+    for i in 0..6 {
+        let response = reqwest::get(format!("http://{}/request-{}", balancebeam.address, i))
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(
+            response.status().as_u16(),
+            200,
+            "The error server should have been taken out of rotation by a health check"
+        );
+    }
+
+    Box::new(echo_server).stop().await;
+    Box::new(error_server).stop().await;
+    log::info!("All done :)");
+}
+
+/// Make sure --active-health-check-jitter spreads health checks out by adding a random delay to
+/// each interval
+#[tokio::test]
+async fn test_active_health_check_jitter() {
+    init_logging();
+    let upstream = TestServer::start("127.0.0.1:0", Behavior::default()).unwrap();
+    let _balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--active-health-check-interval",
+            "100ms",
+            "--active-health-check-jitter",
+            "400ms",
+        ],
+    )
+    .await;
+
+    log::info!("Counting health checks over 3 seconds");
+    let start = upstream.requests_received();
+    sleep(Duration::from_secs(3)).await;
+    let checks = upstream.requests_received() - start;
+    // Without jitter this would be about 30 checks; with it, intervals average 300ms
+    assert!(
+        (4..20).contains(&checks),
+        "Expected about 10 jittered health checks, got {}",
+        checks
+    );
+
+    upstream.stop().await;
+    log::info!("All done :)");
+}
+
+/// Make sure the set of status codes accepted by the active health checks is configurable: an
+/// upstream returning 500s should stay in rotation if 500 is listed as a passing status.
+#[tokio::test]
+async fn test_active_health_checks_accepted_statuses() {
+    init_logging();
+    let echo_server = EchoServer::new().await;
+    let error_server = ErrorServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&echo_server.address, &error_server.address],
+        &[
+            "--active-health-check-interval",
+            "1",
+            "--active-health-check-status",
+            "200,500",
+        ],
+    )
+    .await;
+
+    log::info!("Waiting for a health check to run...");
+    sleep(Duration::from_secs(2)).await;
+
+    log::info!("Sending requests; some of them should reach the error server");
+    let mut num_errors = 0;
+    for i in 0..6 {
+        let response = reqwest::get(format!("http://{}/request-{}", balancebeam.address, i))
+            .await
+            .expect("Error sending request to balancebeam");
+        if response.status().as_u16() == 500 {
+            num_errors += 1;
+        }
+    }
+    assert!(
+        num_errors > 0,
+        "The error server was taken out of rotation even though 500 is an accepted status"
+    );
+
+    Box::new(echo_server).stop().await;
+    Box::new(error_server).stop().await;
+
+    log::info!("All done :)");
+}
+
+/// Make sure outlier detection ejects an upstream that responds, but with a much higher error rate
+/// than the rest of the pool. Active health checks are pushed far out so that only outlier
+/// detection can take the error server out of rotation.
+#[tokio::test]
+async fn test_outlier_detection() {
+    init_logging();
+    let echo_server = EchoServer::new().await;
+    let error_server = ErrorServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&echo_server.address, &error_server.address],
+        &[
+            "--active-health-check-interval",
+            "60",
+            "--outlier-ratio",
+            "1.5",
+            "--outlier-min-requests",
+            "2",
+            "--outlier-interval",
+            "1",
+        ],
+    )
+    .await;
+
+    log::info!("Sending requests to both upstreams so error rates can be computed");
+    for i in 0..10 {
+        reqwest::get(format!("http://{}/request-{}", balancebeam.address, i))
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+
+    log::info!("Waiting for outlier detection to run...");
+    sleep(Duration::from_secs(2)).await;
+
+    for i in 0..6 {
+        let response = reqwest::get(format!("http://{}/after-eject-{}", balancebeam.address, i))
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(
+            response.status().as_u16(),
+            200,
+            "Got an error response; outlier detection may not be working"
+        );
+    }
+
+    Box::new(echo_server).stop().await;
+    Box::new(error_server).stop().await;
+    log::info!("All done :)");
+}
+
 /// Make sure active health checks restore upstreams that were previously failed but are now
 /// working again:
 ///
@@ -240,6 +1029,49 @@ async fn test_active_health_checks_restore_failed_upstream() {
     log::info!("All done :)");
 }
 
+/// Make sure an upstream that refuses the active health check's connection is marked unavailable
+/// without any client request having to fail against it first
+#[tokio::test]
+async fn test_active_health_checks_mark_refused_upstream_unavailable() {
+    init_logging();
+    let upstreams = vec![EchoServer::new().await, EchoServer::new().await];
+    let admin_address = format!("127.0.0.1:{}", rand::thread_rng().gen_range(1024..65535));
+    let _balancebeam = BalanceBeam::new_with_args(
+        &[&upstreams[0].address, &upstreams[1].address],
+        &[
+            "--active-health-check-interval",
+            "200ms",
+            "--admin-bind",
+            &admin_address,
+        ],
+    )
+    .await;
+    let status_url = format!("http://{}/status", admin_address);
+
+    log::info!("Killing one upstream without sending any requests through balancebeam");
+    let mut upstreams = upstreams.into_iter();
+    let live_upstream = upstreams.next().unwrap();
+    Box::new(upstreams.next().unwrap()).stop().await;
+
+    log::info!("Waiting for the active health checks to notice");
+    sleep(Duration::from_secs(1)).await;
+
+    let status = reqwest::get(&status_url)
+        .await
+        .expect("Error sending request to balancebeam")
+        .text()
+        .await
+        .unwrap();
+    assert!(
+        status.contains("\"healthy_upstreams\":1"),
+        "A refused health check connection should mark the upstream unavailable: {}",
+        status
+    );
+
+    Box::new(live_upstream).stop().await;
+    log::info!("All done :)");
+}
+
 /// Enable rate limiting and ensure that requests fail after sending more than the threshold
 #[tokio::test]
 async fn test_rate_limiting() {
@@ -271,7 +1103,7 @@ async fn test_rate_limiting() {
     for i in 0..num_extra_requests {
         let client = reqwest::Client::new();
         let response = client
-            .get(&format!("http://{}/overboard-{}", balancebeam.address, i))
+            .get(format!("http://{}/overboard-{}", balancebeam.address, i))
             .header("x-sent-by", "balancebeam-tests")
             .send()
             .await
@@ -283,6 +1115,17 @@ async fn test_rate_limiting() {
         log::info!("{:?}", response);
         log::info!("Checking to make sure the server responded with HTTP 429");
         assert_eq!(response.status().as_u16(), 429);
+        let headers = response.headers();
+        assert_eq!(headers.get("x-ratelimit-limit").unwrap(), "5");
+        assert_eq!(headers.get("x-ratelimit-remaining").unwrap(), "0");
+        let retry_after: u64 = headers
+            .get("retry-after")
+            .expect("429 responses should say when to retry")
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!((1..=60).contains(&retry_after));
     }
 
     log::info!("Ensuring the extra requests didn't go through to the upstream servers");
@@ -294,3 +1137,545 @@ async fn test_rate_limiting() {
 
     log::info!("All done :)");
 }
+
+/// Make sure requests within the rate limit tell the client how many requests it has left and
+/// when the window resets, without a Retry-After
+#[tokio::test]
+async fn test_rate_limit_headers() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--max-requests-per-minute", "3"]).await;
+
+    for remaining in (0..3).rev() {
+        let response = reqwest::get(format!("http://{}/", balancebeam.address))
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response.status().as_u16(), 200);
+        let headers = response.headers();
+        assert_eq!(headers.get("x-ratelimit-limit").unwrap(), "3");
+        assert_eq!(
+            headers.get("x-ratelimit-remaining").unwrap(),
+            remaining.to_string().as_str()
+        );
+        let reset: u64 = headers
+            .get("x-ratelimit-reset")
+            .expect("Responses should say when the window resets")
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!((1..=60).contains(&reset));
+        assert!(!headers.contains_key("retry-after"));
+    }
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Rate limiting windows are wall-clock minutes. Waits for the next minute to start if this one is
+/// about to end, so that a test's requests are all counted in the same window.
+async fn wait_if_minute_ending() {
+    let seconds_into_minute = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+        % 60;
+    if seconds_into_minute >= 55 {
+        sleep(Duration::from_secs(61 - seconds_into_minute)).await;
+    }
+}
+
+/// Make sure the rate limiter forgets clients once their window has ended, rather than keeping
+/// an entry for every client it has ever seen. This waits for the current minute to run out.
+#[tokio::test]
+async fn test_rate_limiter_pruning() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let admin_address = format!("127.0.0.1:{}", rand::thread_rng().gen_range(1024..65535));
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--max-requests-per-minute",
+            "10",
+            "--trusted-proxies",
+            "127.0.0.1",
+            "--admin-bind",
+            &admin_address,
+        ],
+    )
+    .await;
+    let status_url = format!("http://{}/status", admin_address);
+    let get_status = || async {
+        reqwest::get(&status_url)
+            .await
+            .expect("Error sending request to admin API")
+            .text()
+            .await
+            .unwrap()
+    };
+
+    wait_if_minute_ending().await;
+    log::info!("Sending requests from three different clients");
+    let client = reqwest::Client::new();
+    for i in 1..=3 {
+        client
+            .get(format!("http://{}/", balancebeam.address))
+            .header("x-forwarded-for", format!("203.0.113.{}", i))
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+    let status = get_status().await;
+    assert!(status.contains("\"rate_limited_clients\":3"), "{}", status);
+
+    log::info!("Waiting for the rate limiting window to end");
+    let mut pruned = false;
+    for _ in 0..65 {
+        sleep(Duration::from_secs(1)).await;
+        if get_status().await.contains("\"rate_limited_clients\":0") {
+            pruned = true;
+            break;
+        }
+    }
+    assert!(pruned, "Clients from an expired window were never pruned");
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Starts a stand-in for a Redis server that understands just the commands balancebeam's rate
+/// limiter sends: INCR on a counter, and EXPIRE, AUTH and SELECT, which are acknowledged and
+/// ignored. Returns its address and the counters.
+async fn start_fake_redis() -> (String, Arc<Mutex<HashMap<String, i64>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let counters = Arc::new(Mutex::new(HashMap::new()));
+    let server_counters = counters.clone();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let counters = server_counters.clone();
+            tokio::spawn(async move {
+                let mut stream = BufReader::new(stream);
+                let mut line = String::new();
+                loop {
+                    // Each command is an array of bulk strings: *N, then $LEN and the data N times
+                    line.clear();
+                    if stream.read_line(&mut line).await.unwrap_or(0) == 0 {
+                        return;
+                    }
+                    let argc: usize = line.trim()[1..].parse().unwrap();
+                    let mut args = Vec::new();
+                    for _ in 0..argc * 2 {
+                        line.clear();
+                        stream.read_line(&mut line).await.unwrap();
+                        args.push(line.trim().to_string());
+                    }
+                    let args: Vec<String> = args.into_iter().skip(1).step_by(2).collect();
+                    let reply = if args[0].eq_ignore_ascii_case("incr") {
+                        let mut counters = counters.lock().unwrap();
+                        let count = counters.entry(args[1].clone()).or_insert(0);
+                        *count += 1;
+                        format!(":{}\r\n", count)
+                    } else {
+                        "+OK\r\n".to_string()
+                    };
+                    stream.get_mut().write_all(reply.as_bytes()).await.unwrap();
+                }
+            });
+        }
+    });
+    (address, counters)
+}
+
+/// Make sure balancebeam instances sharing a Redis rate limiting backend enforce one limit
+/// between them
+#[tokio::test]
+async fn test_redis_rate_limit_backend() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let (redis_address, counters) = start_fake_redis().await;
+    let backend = format!("redis://{}", redis_address);
+    let args = [
+        "--max-requests-per-minute",
+        "4",
+        "--rate-limit-backend",
+        &backend,
+    ];
+    let first = BalanceBeam::new_with_args(&[&upstream.address], &args).await;
+    let second = BalanceBeam::new_with_args(&[&upstream.address], &args).await;
+
+    wait_if_minute_ending().await;
+    log::info!("Sending requests alternately through two instances");
+    let mut statuses = Vec::new();
+    for i in 0..6 {
+        let balancebeam = if i % 2 == 0 { &first } else { &second };
+        let response = reqwest::get(format!("http://{}/request-{}", balancebeam.address, i))
+            .await
+            .expect("Error sending request to balancebeam");
+        statuses.push(response.status().as_u16());
+    }
+    assert_eq!(
+        statuses,
+        vec![200, 200, 200, 200, 429, 429],
+        "The instances should share one limit"
+    );
+    let keys: Vec<String> = counters.lock().unwrap().keys().cloned().collect();
+    assert_eq!(keys.len(), 1, "{:?}", keys);
+    assert!(keys[0].starts_with("balancebeam:ratelimit:"), "{:?}", keys);
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Starts a stand-in for a Redis server that never answers: it either keeps connections open
+/// without replying, or closes them right away. Returns its address and the number of
+/// connections made to it.
+async fn start_silent_redis(close: bool) -> (String, Arc<AtomicUsize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let connections = Arc::new(AtomicUsize::new(0));
+    let server_connections = connections.clone();
+    tokio::spawn(async move {
+        let mut open = Vec::new();
+        while let Ok((stream, _)) = listener.accept().await {
+            server_connections.fetch_add(1, Ordering::SeqCst);
+            if !close {
+                open.push(stream);
+            }
+        }
+    });
+    (address, connections)
+}
+
+/// Make sure requests don't hang on a Redis rate limiting backend that doesn't answer, and that
+/// one that can't be connected to isn't retried for every request
+#[tokio::test]
+async fn test_redis_rate_limit_backend_down() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+
+    log::info!("Checking that an unresponsive Redis falls back to local counts");
+    let (redis_address, _) = start_silent_redis(false).await;
+    let backend = format!("redis://{}", redis_address);
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--max-requests-per-minute",
+            "2",
+            "--rate-limit-backend",
+            &backend,
+        ],
+    )
+    .await;
+    wait_if_minute_ending().await;
+    let mut statuses = Vec::new();
+    for i in 0..3 {
+        let start = std::time::Instant::now();
+        let response = reqwest::get(format!("http://{}/request-{}", balancebeam.address, i))
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(
+            start.elapsed() < Duration::from_secs(2),
+            "Request waited {:?} for Redis",
+            start.elapsed()
+        );
+        statuses.push(response.status().as_u16());
+    }
+    assert_eq!(statuses, vec![200, 200, 429]);
+
+    log::info!("Checking that connecting to a Redis that hangs up is backed off");
+    let (redis_address, connections) = start_silent_redis(true).await;
+    // With a password, connecting needs a reply to AUTH, which never comes
+    let backend = format!("redis://:secret@{}", redis_address);
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--max-requests-per-minute",
+            "100",
+            "--rate-limit-backend",
+            &backend,
+        ],
+    )
+    .await;
+    for i in 0..5 {
+        let response = reqwest::get(format!("http://{}/request-{}", balancebeam.address, i))
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response.status().as_u16(), 200);
+    }
+    let connections = connections.load(Ordering::SeqCst);
+    assert!(
+        connections < 5,
+        "Redis was connected to {} times for 5 requests",
+        connections
+    );
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Make sure clients on the --rate-limit-exempt list are never rate limited
+#[tokio::test]
+async fn test_rate_limit_exemptions() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--max-requests-per-minute",
+            "1",
+            "--rate-limit-exempt",
+            "10.0.0.0/8,127.0.0.1",
+        ],
+    )
+    .await;
+
+    for i in 0..5 {
+        let response = reqwest::get(format!("http://{}/request-{}", balancebeam.address, i))
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response.status().as_u16(), 200);
+        assert!(!response.headers().contains_key("x-ratelimit-limit"));
+    }
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Make sure --rate-limit-key header gives every value of the header its own limit, counted per
+/// client address
+#[tokio::test]
+async fn test_rate_limit_by_header() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--max-requests-per-minute",
+            "1",
+            "--rate-limit-key",
+            "header",
+            "--rate-limit-header",
+            "x-api-key",
+            "--trusted-proxies",
+            "127.0.0.1",
+        ],
+    )
+    .await;
+    let client = reqwest::Client::new();
+    let status_for = |api_key: &str, client_ip: &str| {
+        let request = client
+            .get(format!("http://{}/", balancebeam.address))
+            .header("x-api-key", api_key)
+            .header("x-forwarded-for", client_ip);
+        async move {
+            request
+                .send()
+                .await
+                .expect("Error sending request to balancebeam")
+                .status()
+                .as_u16()
+        }
+    };
+
+    assert_eq!(status_for("key-a", "10.0.0.1").await, 200);
+    assert_eq!(status_for("key-a", "10.0.0.1").await, 429);
+    assert_eq!(status_for("key-b", "10.0.0.1").await, 200);
+
+    log::info!("Checking that another client sending the same value has a limit of its own");
+    assert_eq!(status_for("key-a", "10.0.0.2").await, 200);
+    assert_eq!(status_for("key-a", "10.0.0.2").await, 429);
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Serves a minimal Consul health API that lists `members` as the passing instances of every
+/// service. Blocking queries are answered after a short delay instead of waiting for a change.
+async fn fake_consul(members: Arc<Mutex<Vec<String>>>) -> String {
+    let address = format!("127.0.0.1:{}", rand::thread_rng().gen_range(1024..65535));
+    let bind_addr = address.parse().unwrap();
+    let service = make_service_fn(move |_| {
+        let members = members.clone();
+        async move {
+            Ok::<_, hyper::Error>(service_fn(move |req: Request<Body>| {
+                let members = members.clone();
+                async move {
+                    if req.uri().query().unwrap_or("").contains("index=") {
+                        sleep(Duration::from_millis(200)).await;
+                    }
+                    let entries: Vec<String> = members
+                        .lock()
+                        .unwrap()
+                        .iter()
+                        .map(|member| {
+                            let (host, port) = member.rsplit_once(':').unwrap();
+                            format!(
+                                "{{\"Node\":{{\"Address\":\"{}\"}},\"Service\":{{\"Address\":\"\",\"Port\":{}}}}}",
+                                host, port
+                            )
+                        })
+                        .collect();
+                    let index = std::time::SystemTime::now()
+                        .duration_since(std::time::UNIX_EPOCH)
+                        .unwrap()
+                        .as_millis();
+                    Ok::<_, hyper::Error>(
+                        Response::builder()
+                            .header("X-Consul-Index", index.to_string())
+                            .body(Body::from(format!("[{}]", entries.join(","))))
+                            .unwrap(),
+                    )
+                }
+            }))
+        }
+    });
+    tokio::spawn(hyper::Server::bind(&bind_addr).serve(service));
+    address
+}
+
+/// Make sure upstreams discovered through Consul are routed to, and that the pool follows the
+/// catalog as instances come and go
+#[tokio::test]
+async fn test_consul_discovery() {
+    init_logging();
+    let first = EchoServer::new().await;
+    let second = EchoServer::new().await;
+    let members = Arc::new(Mutex::new(vec![first.address.clone()]));
+    let consul_address = fake_consul(members.clone()).await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[],
+        &[
+            "--discover",
+            &format!("consul:{}/web", consul_address),
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await;
+
+    log::info!("Sending requests to the discovered upstream");
+    for i in 0..3 {
+        balancebeam
+            .get(&format!("/first-{}", i))
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+
+    log::info!("Replacing the instance in the catalog");
+    *members.lock().unwrap() = vec![second.address.clone()];
+    sleep(Duration::from_secs(1)).await;
+    for i in 0..4 {
+        balancebeam
+            .get(&format!("/second-{}", i))
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+
+    assert_eq!(Box::new(first).stop().await, 3);
+    assert_eq!(Box::new(second).stop().await, 4);
+    log::info!("All done :)");
+}
+
+/// Make sure an upstream that refuses connections is left alone for a while, instead of being
+/// tried again by every request
+#[tokio::test]
+async fn test_connect_backoff() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    // Nothing listens on this address, so connections to it are refused
+    let dead_address = format!("127.0.0.1:{}", rand::thread_rng().gen_range(1024..65535));
+    let admin_address = format!("127.0.0.1:{}", rand::thread_rng().gen_range(1024..65535));
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address, &dead_address],
+        &[
+            "--admin-bind",
+            &admin_address,
+            "--connect-backoff-base",
+            "5000",
+            "--passive-unhealthy-threshold",
+            "2",
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await;
+
+    log::info!("Sending requests while one upstream is down");
+    for i in 0..6 {
+        let response_text = balancebeam
+            .get(&format!("/request-{}", i))
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET /request-{} HTTP/1.1", i)));
+    }
+
+    // The dead upstream was only tried once, so it hasn't failed often enough to be marked
+    // unavailable
+    let status = reqwest::get(format!("http://{}/status", admin_address))
+        .await
+        .expect("Error sending request to admin API")
+        .text()
+        .await
+        .unwrap();
+    assert!(status.contains("\"unhealthy_upstreams\":0"), "{}", status);
+
+    assert_eq!(Box::new(upstream).stop().await, 6);
+    log::info!("All done :)");
+}
+
+/// Make sure dynamic weights shift traffic away from an upstream that keeps failing requests
+#[tokio::test]
+async fn test_dynamic_weights() {
+    init_logging();
+    let good = EchoServer::new().await;
+    let bad = ErrorServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&good.address, &bad.address],
+        &[
+            "--dynamic-weight-interval",
+            "1",
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await;
+    let count_errors = |n: usize| {
+        let url = format!("http://{}/", balancebeam.address);
+        async move {
+            let mut errors = 0;
+            for _ in 0..n {
+                let response = reqwest::get(&url)
+                    .await
+                    .expect("Error sending request to balancebeam");
+                if response.status().is_server_error() {
+                    errors += 1;
+                }
+            }
+            errors
+        }
+    };
+
+    log::info!("Sending traffic over a few weight adjustments");
+    let initial_errors = count_errors(20).await;
+    assert!(
+        initial_errors >= 5,
+        "{} of 20 requests failed",
+        initial_errors
+    );
+    // Weights only move halfway to their target each time, and only for upstreams that got enough
+    // requests to judge, so give them a few busy rounds to settle
+    for _ in 0..5 {
+        sleep(Duration::from_millis(1100)).await;
+        count_errors(40).await;
+    }
+
+    log::info!("Checking that the failing upstream now gets little traffic");
+    let errors = count_errors(40).await;
+    assert!(errors < 12, "{} of 40 requests failed", errors);
+
+    Box::new(good).stop().await;
+    Box::new(bad).stop().await;
+    log::info!("All done :)");
+}
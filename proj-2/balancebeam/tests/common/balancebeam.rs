@@ -12,7 +12,7 @@ pub struct BalanceBeam {
 }
 
 impl BalanceBeam {
-    fn target_bin_path() -> std::path::PathBuf {
+    pub fn target_bin_path() -> std::path::PathBuf {
         let mut path = std::env::current_exe().expect("Could not get current test executable path");
         path.pop();
         path.pop();
@@ -25,28 +25,45 @@ impl BalanceBeam {
         active_health_check_interval: Option<usize>,
         max_requests_per_minute: Option<usize>,
     ) -> BalanceBeam {
-        let mut rng = rand::thread_rng();
-        let address = format!("127.0.0.1:{}", rng.gen_range(1024..65535));
-        let mut cmd = Command::new(BalanceBeam::target_bin_path());
-        cmd.arg("--bind").arg(&address);
-        for upstream in upstreams {
-            cmd.arg("--upstream").arg(upstream);
-        }
+        let mut extra_args = Vec::new();
         if let Some(active_health_check_interval) = active_health_check_interval {
-            cmd.arg("--active-health-check-interval")
-                .arg(active_health_check_interval.to_string());
+            extra_args.push("--active-health-check-interval".to_string());
+            extra_args.push(active_health_check_interval.to_string());
         }
         if let Some(max_requests_per_minute) = max_requests_per_minute {
-            cmd.arg("--max-requests-per-minute")
-                .arg(max_requests_per_minute.to_string());
+            extra_args.push("--max-requests-per-minute".to_string());
+            extra_args.push(max_requests_per_minute.to_string());
         }
+        let extra_args: Vec<&str> = extra_args.iter().map(|arg| arg.as_str()).collect();
+        BalanceBeam::new_with_args(upstreams, &extra_args).await
+    }
+
+    /// Starts balancebeam with the given upstreams, passing any other command-line options through
+    /// verbatim. It listens on a random port unless the options include --bind.
+    pub async fn new_with_args(upstreams: &[&str], extra_args: &[&str]) -> BalanceBeam {
+        let mut cmd = Command::new(BalanceBeam::target_bin_path());
+        let address = match extra_args.iter().position(|arg| *arg == "--bind") {
+            Some(idx) => extra_args[idx + 1].to_string(),
+            None => {
+                let mut rng = rand::thread_rng();
+                let address = format!("127.0.0.1:{}", rng.gen_range(1024..65535));
+                cmd.arg("--bind").arg(&address);
+                address
+            }
+        };
+        for upstream in upstreams {
+            cmd.arg("--upstream").arg(upstream);
+        }
+        cmd.args(extra_args);
         cmd.kill_on_drop(true);
         cmd.stdout(std::process::Stdio::piped());
         cmd.stderr(std::process::Stdio::piped());
-        let mut child = cmd.spawn().expect(&format!(
-            "Could not execute balancebeam binary {}",
-            BalanceBeam::target_bin_path().to_str().unwrap()
-        ));
+        let mut child = cmd.spawn().unwrap_or_else(|_| {
+            panic!(
+                "Could not execute balancebeam binary {}",
+                BalanceBeam::target_bin_path().to_str().unwrap()
+            )
+        });
 
         // Print output from the child. We want to intercept and log this output (instead of letting
         // the child inherit stderr and print directly to the terminal) so that the output can be
@@ -85,11 +102,19 @@ impl BalanceBeam {
         BalanceBeam { child, address }
     }
 
+    /// Sends a signal to the balancebeam process.
+    #[allow(dead_code)]
+    pub fn signal(&self, signal: nix::sys::signal::Signal) {
+        let pid = self.child.id().expect("balancebeam has already exited");
+        nix::sys::signal::kill(nix::unistd::Pid::from_raw(pid as i32), signal)
+            .expect("Could not signal balancebeam");
+    }
+
     #[allow(dead_code)]
     pub async fn get(&self, path: &str) -> Result<String, reqwest::Error> {
         let client = reqwest::Client::new();
         client
-            .get(&format!("http://{}{}", self.address, path))
+            .get(format!("http://{}{}", self.address, path))
             .header("x-sent-by", "balancebeam-tests")
             .send()
             .await?
@@ -101,7 +126,7 @@ impl BalanceBeam {
     pub async fn post(&self, path: &str, body: &str) -> Result<String, reqwest::Error> {
         let client = reqwest::Client::new();
         client
-            .post(&format!("http://{}{}", self.address, path))
+            .post(format!("http://{}{}", self.address, path))
             .header("x-sent-by", "balancebeam-tests")
             .body(body.to_string())
             .send()
@@ -3,10 +3,12 @@ mod echo_server;
 mod error_server;
 mod server;
 
+use rand::Rng;
 use std::sync;
 
 pub use balancebeam::BalanceBeam;
 pub use echo_server::EchoServer;
+#[allow(unused_imports)]
 pub use error_server::ErrorServer;
 pub use server::Server;
 
@@ -20,3 +22,14 @@ pub fn init_logging() {
             .init();
     });
 }
+
+/// Writes a balancebeam config file to a fresh temporary path and returns the path.
+#[allow(dead_code)]
+pub fn write_config(contents: &str) -> String {
+    let path = std::env::temp_dir().join(format!(
+        "balancebeam-test-{}.conf",
+        rand::thread_rng().gen::<u64>()
+    ));
+    std::fs::write(&path, contents).expect("Could not write config file");
+    path.to_str().unwrap().to_string()
+}